@@ -1,31 +1,417 @@
-//! This build script copies the `memory.x` file from the crate root into
-//! a directory where the linker can always find it at build time.
-//! For many projects this is optional, as the linker always searches the
-//! project root directory -- wherever `Cargo.toml` is. However, if you
-//! are using a workspace or have a more complicated build setup, this
-//! build script becomes required. Additionally, by requesting that
-//! Cargo re-run the build script whenever `memory.x` is changed,
-//! updating `memory.x` ensures a rebuild of the application with the
-//! new memory settings.
+//! This build script generates `memory.x` from `memory.x.in` and puts it in a directory
+//! where the linker can always find it at build time. It also reserves the end of flash
+//! for the flash disk.
+//!
+//! The size of the disk is set (in KiB) by the `DISK_SIZE` environment variable, which
+//! defaults to 512 for the disks kept in flash and to 0, reserving nothing, for the others.
+//! The firmware gets the rest of flash.
+//! The disk region is emitted both as the `DISK` linker region and as Rust constants in
+//! `$OUT_DIR/disk_region.rs`, included by `storage.rs`.
+//!
+//! The image the RAM disk starts with is made from the directory named by `IMAGE_DIR`: an
+//! MBR with the partitions listed in `IMAGE_PARTITIONS` (see [`parse_partitions`], none for
+//! no MBR), one of them holding a FAT volume labelled `IMAGE_LABEL` with the files and
+//! directories in it. The image is `IMAGE_SIZE` KiB and the FAT type is picked from the size
+//! of the volume unless `IMAGE_FAT` is `12` or `16`. The image is written to
+//! `$OUT_DIR/disk_image.bin` and its size in blocks to `$OUT_DIR/disk_image.rs`, which sets
+//! `storage::BLOCKS`, so the two always match. The volume is laid out by the ghost disk's
+//! [`layout`](src/block_devices/ghost/layout.rs).
+//!
+//! With the `packed` feature the disk image named by `PACKED_IMAGE` (relative to the crate
+//! root), or the image above if it isn't set, is compressed into
+//! `$OUT_DIR/packed_image.bin` for the [`PackedBlockDevice`](src/block_devices/packed/mod.rs).
+//!
+//! The disk the firmware serves is picked from the enabled features and passed on as the
+//! `disk` cfg, see [`DISKS`].
+//!
+//! The build fails early if the cyw43 firmware blobs (when the `wifi` feature is enabled)
+//! can't possibly fit next to the disk. The exact check happens at link time, where the
+//! linker refuses to let the firmware overflow into the disk region.
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+#[allow(dead_code)]
+#[path = "src/block_devices/packed/format.rs"]
+mod packed_format;
+
+#[allow(dead_code)]
+#[path = "src/block_devices/ghost/layout.rs"]
+mod ghost_layout;
+
+const FLASH_BASE: u32 = 0x1000_0000;
+const FLASH_SIZE: u32 = 2048 * 1024;
+const BOOT2_SIZE: u32 = 0x100;
+const ERASE_SIZE: u32 = 4096;
+
+/// A rough lower bound for the code and data of the firmware itself, so that an oversized
+/// disk is reported here rather than as a linker error
+const MIN_FIRMWARE_SIZE: u32 = 128 * 1024;
+
+/// The volume id of the disk image. It's fixed so that the image only changes with its
+/// contents
+const IMAGE_VOLUME_ID: u32 = 0x80fb_3736;
+
+/// Primary partitions in an MBR
+const MBR_PARTITIONS: usize = 4;
+
+const CYW43_BLOBS: [&str; 2] = [
+    "cyw43-firmware/43439A0.bin",
+    "cyw43-firmware/43439A0_clm.bin",
+];
+
+/// Disks selected by features, in order of precedence. Enabling several (as `--all-features`
+/// does) picks the first one, and the RAM disk is used when none are enabled
+const DISKS: [&str; 7] = [
+    "flash", "sd", "overlay", "packed", "sparse", "ghost", "mirror",
+];
+
+/// Disks that keep their blocks in the flash region reserved by `DISK_SIZE`
+const FLASH_DISKS: [&str; 2] = ["flash", "mirror"];
+
+/// The `DISK_SIZE` used when it isn't set. The other disks are in RAM, on the card or in the
+/// firmware image, so flash is left to the firmware
+fn default_disk_size(disk: &str) -> &'static str {
+    if FLASH_DISKS.contains(&disk) {
+        "512"
+    } else {
+        "0"
+    }
+}
 
 fn main() {
+    let disk = DISKS
+        .into_iter()
+        .find(|disk| env::var_os(format!("CARGO_FEATURE_{}", disk.to_uppercase())).is_some())
+        .unwrap_or("ram");
+    println!("cargo:rustc-check-cfg=cfg(disk, values(\"ram\", \"flash\", \"sd\", \"overlay\", \"packed\", \"sparse\", \"ghost\", \"mirror\"))");
+    println!("cargo:rustc-cfg=disk=\"{disk}\"");
+
+    let disk_kib: u32 = env::var("DISK_SIZE")
+        .unwrap_or_else(|_| default_disk_size(disk).into())
+        .parse()
+        .expect("DISK_SIZE must be a size in KiB");
+    if disk_kib == 0 && FLASH_DISKS.contains(&disk) {
+        panic!("the {disk} disk is kept in flash, set DISK_SIZE to reserve some");
+    }
+    let disk_length = disk_kib * 1024;
+    if disk_length % ERASE_SIZE != 0 {
+        panic!("DISK_SIZE must be a multiple of the {ERASE_SIZE} byte flash erase size");
+    }
+    if disk_length > FLASH_SIZE - BOOT2_SIZE {
+        panic!(
+            "DISK_SIZE ({disk_kib}K) doesn't fit in {}K of flash",
+            FLASH_SIZE / 1024
+        );
+    }
+
+    let disk_offset = FLASH_SIZE - disk_length;
+    let flash_length = disk_offset - BOOT2_SIZE;
+
+    let mut firmware_size = MIN_FIRMWARE_SIZE;
+    if env::var_os("CARGO_FEATURE_WIFI").is_some() {
+        for blob in CYW43_BLOBS {
+            firmware_size += fs::metadata(blob)
+                .unwrap_or_else(|e| panic!("can't read {blob}: {e}"))
+                .len() as u32;
+            println!("cargo:rerun-if-changed={blob}");
+        }
+    }
+    if firmware_size > flash_length {
+        panic!(
+            "the firmware needs at least {}K of flash but DISK_SIZE={disk_kib}K leaves {}K, \
+             reduce DISK_SIZE",
+            firmware_size / 1024,
+            flash_length / 1024
+        );
+    }
+
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let memory_x = include_str!("memory.x.in")
+        .replace("${FLASH_LENGTH}", &format!("{flash_length:#x}"))
+        .replace(
+            "${DISK_ORIGIN}",
+            &format!("{:#x}", FLASH_BASE + disk_offset),
+        )
+        .replace("${DISK_LENGTH}", &format!("{disk_length:#x}"));
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory_x.as_bytes())
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
+    let image = make_image(out);
+    if env::var_os("CARGO_FEATURE_PACKED").is_some() {
+        pack_image(out, image);
+    }
+
+    File::create(out.join("disk_region.rs"))
+        .unwrap()
+        .write_all(
+            format!(
+                "/// Size of the flash chip\n\
+                 pub const FLASH_SIZE: usize = {FLASH_SIZE:#x};\n\
+                 /// Offset of the disk region from the start of flash\n\
+                 pub const DISK_FLASH_OFFSET: u32 = {disk_offset:#x};\n\
+                 /// Length of the disk region in bytes\n\
+                 pub const DISK_FLASH_LENGTH: u32 = {disk_length:#x};\n"
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
     // By default, Cargo will re-run a build script whenever
-    // any file in the project changes. By specifying `memory.x`
+    // any file in the project changes. By specifying `memory.x.in`
     // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
-    println!("cargo:rerun-if-changed=memory.x");
+    // `memory.x.in` or the disk size is changed.
+    println!("cargo:rerun-if-changed=memory.x.in");
+    println!("cargo:rerun-if-env-changed=DISK_SIZE");
+}
+
+/// A file or directory of the image, see [`read_dir`]
+struct ImageNode {
+    name: String,
+    parent: Option<usize>,
+    /// The contents of a file, `None` for a directory
+    data: Option<Vec<u8>>,
+}
+
+impl ghost_layout::Node for ImageNode {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn parent(&self) -> Option<usize> {
+        self.parent
+    }
+
+    fn size(&self) -> Option<u32> {
+        self.data.as_ref().map(|data| data.len() as u32)
+    }
+}
+
+/// A partition of the disk image, see [`parse_partitions`]
+struct ImagePartition {
+    /// The MBR system id, `None` for the partition holding the FAT volume
+    system_id: Option<u8>,
+    start: u32,
+    blocks: u32,
+}
+
+/// Parses a comma separated list of up to 4 partitions of an image of `image_blocks` blocks,
+/// each `type:start:size` in blocks. The type is `fat` for the one partition holding the FAT
+/// volume, or the hex MBR system id of a partition left empty, and a size of `*` runs to the
+/// end of the image. For example `fat:1:2047,83:2048:*`
+fn parse_partitions(list: &str, image_blocks: u32) -> Vec<ImagePartition> {
+    let mut partitions: Vec<ImagePartition> = Vec::new();
+    for entry in list
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let invalid = || -> ! {
+            panic!("IMAGE_PARTITIONS entries must be type:start:size, not {entry}");
+        };
+        let [system_id, start, size] = entry.split(':').collect::<Vec<_>>()[..] else {
+            invalid()
+        };
+        let system_id = match system_id {
+            "fat" => None,
+            id => Some(u8::from_str_radix(id, 16).unwrap_or_else(|_| invalid())),
+        };
+        let start: u32 = start.parse().unwrap_or_else(|_| invalid());
+        let blocks = match size {
+            "*" => image_blocks.saturating_sub(start),
+            size => size.parse().unwrap_or_else(|_| invalid()),
+        };
+
+        let fits = start > 0 && blocks > 0 && start.saturating_add(blocks) <= image_blocks;
+        let overlaps = partitions
+            .iter()
+            .any(|other| start < other.start + other.blocks && other.start < start + blocks);
+        if !fits || overlaps {
+            panic!(
+                "IMAGE_PARTITIONS entry {entry} must be within the {image_blocks} block image, \
+                 after the MBR and apart from the others"
+            );
+        }
+        partitions.push(ImagePartition {
+            system_id,
+            start,
+            blocks,
+        });
+    }
+
+    if partitions.len() > MBR_PARTITIONS {
+        panic!("IMAGE_PARTITIONS can't have more than {MBR_PARTITIONS} partitions");
+    }
+    let volumes = partitions
+        .iter()
+        .filter(|partition| partition.system_id.is_none())
+        .count();
+    if !partitions.is_empty() && volumes != 1 {
+        panic!("exactly one of IMAGE_PARTITIONS must be the fat one");
+    }
+    partitions
+}
+
+/// Adds the entries of `dir` to `nodes` in order of their names, each directory followed by
+/// what's in it
+fn read_dir(dir: &Path, parent: Option<usize>, nodes: &mut Vec<ImageNode>) {
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("can't read {}: {e}", dir.display()))
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        let name = path
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap_or_else(|| panic!("{} isn't a UTF-8 name", path.display()))
+            .to_string();
+        if path.is_dir() {
+            nodes.push(ImageNode {
+                name,
+                parent,
+                data: None,
+            });
+            read_dir(&path, Some(nodes.len() - 1), nodes);
+        } else {
+            let data =
+                fs::read(&path).unwrap_or_else(|e| panic!("can't read {}: {e}", path.display()));
+            nodes.push(ImageNode {
+                name,
+                parent,
+                data: Some(data),
+            });
+        }
+    }
+}
+
+/// Makes the disk image from `IMAGE_DIR` into `disk_image.bin`, with its size in
+/// `disk_image.rs`
+fn make_image(out: &Path) -> Vec<u8> {
+    use ghost_layout::{chs, Block, FatType, Layout, BLOCK_SIZE};
+
+    let var = |name: &str| env::var(name).unwrap_or_else(|_| panic!("{name} must be set"));
+    let source = var("IMAGE_DIR");
+    let image_kib: u32 = var("IMAGE_SIZE")
+        .parse()
+        .expect("IMAGE_SIZE must be a size in KiB");
+    let label = var("IMAGE_LABEL");
+    let fat_type = match var("IMAGE_FAT").as_str() {
+        "auto" => None,
+        "12" => Some(FatType::Fat12),
+        "16" => Some(FatType::Fat16),
+        other => panic!("IMAGE_FAT must be auto, 12 or 16, not {other}"),
+    };
+
+    let mut nodes = Vec::new();
+    read_dir(Path::new(&source), None, &mut nodes);
+    let blocks = image_kib * 1024 / BLOCK_SIZE as u32;
+    let partitions = parse_partitions(&var("IMAGE_PARTITIONS"), blocks);
+    // without partitions, the volume fills the image
+    let (volume_lba, volume_blocks) = partitions
+        .iter()
+        .find(|partition| partition.system_id.is_none())
+        .map_or((0, blocks), |partition| (partition.start, partition.blocks));
+    let layout = Layout::new(
+        &nodes,
+        volume_lba + volume_blocks,
+        volume_lba,
+        &label,
+        IMAGE_VOLUME_ID,
+        fat_type,
+    )
+    .unwrap_or_else(|e| panic!("can't make a {image_kib}K image of {source}: {e:?}"));
+
+    let mut image = vec![0; blocks as usize * BLOCK_SIZE];
+    let volume = image
+        .chunks_exact_mut(BLOCK_SIZE)
+        .enumerate()
+        .skip(volume_lba as usize)
+        .take(volume_blocks as usize);
+    for (lba, block) in volume {
+        if let Block::File { node, offset, len } = layout.read(&nodes, lba as u32, block) {
+            let data = nodes[node].data.as_ref().unwrap();
+            let offset = offset as usize;
+            block[..len].copy_from_slice(&data[offset..offset + len]);
+        }
+    }
+
+    if !partitions.is_empty() {
+        let mbr = &mut image[..BLOCK_SIZE];
+        mbr[440..444].copy_from_slice(&IMAGE_VOLUME_ID.to_le_bytes());
+        for (entry, partition) in mbr[446..510].chunks_exact_mut(16).zip(&partitions) {
+            let last = partition.start + partition.blocks - 1;
+            entry[1..4].copy_from_slice(&chs(partition.start));
+            entry[4] = partition.system_id.unwrap_or(layout.system_id());
+            entry[5..8].copy_from_slice(&chs(last));
+            entry[8..12].copy_from_slice(&partition.start.to_le_bytes());
+            entry[12..16].copy_from_slice(&partition.blocks.to_le_bytes());
+        }
+        mbr[510..].copy_from_slice(&[0x55, 0xaa]);
+    }
+
+    fs::write(out.join("disk_image.bin"), &image).unwrap();
+    fs::write(
+        out.join("disk_image.rs"),
+        format!(
+            "/// Size of the disk image in blocks\n\
+             pub const BLOCKS: u32 = {blocks};\n"
+        ),
+    )
+    .unwrap();
+
+    println!("cargo:rerun-if-changed={source}");
+    for name in [
+        "IMAGE_DIR",
+        "IMAGE_SIZE",
+        "IMAGE_PARTITIONS",
+        "IMAGE_LABEL",
+        "IMAGE_FAT",
+    ] {
+        println!("cargo:rerun-if-env-changed={name}");
+    }
+    println!("cargo:rerun-if-changed=src/block_devices/ghost/layout.rs");
+    image
+}
+
+/// Compresses the image named by `PACKED_IMAGE`, or `image` if it isn't set, into
+/// `packed_image.bin`, padding it to a whole number of blocks
+fn pack_image(out: &Path, image: Vec<u8>) {
+    use packed_format::{compress, BLOCK_SIZE, MAGIC};
+
+    let mut image = match env::var("PACKED_IMAGE") {
+        Ok(source) => {
+            println!("cargo:rerun-if-changed={source}");
+            fs::read(&source).unwrap_or_else(|e| panic!("can't read {source}: {e}"))
+        }
+        Err(_) => image,
+    };
+    image.resize(image.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+
+    let blocks = image.len() / BLOCK_SIZE;
+    let mut index = Vec::with_capacity((blocks + 1) * 4);
+    let mut data = Vec::new();
+    let mut stored = [0; BLOCK_SIZE];
+    for block in image.chunks_exact(BLOCK_SIZE) {
+        index.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        let len = compress(block.try_into().unwrap(), &mut stored);
+        data.extend_from_slice(&stored[..len]);
+    }
+    index.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+    let mut packed = File::create(out.join("packed_image.bin")).unwrap();
+    packed.write_all(&MAGIC).unwrap();
+    packed.write_all(&(blocks as u32).to_le_bytes()).unwrap();
+    packed.write_all(&index).unwrap();
+    packed.write_all(&data).unwrap();
+
+    println!("cargo:rerun-if-env-changed=PACKED_IMAGE");
 }
@@ -0,0 +1,7 @@
+//! Builds the firmware's modules as they are for the mirrored disk, the one that uses the
+//! most of them, see the firmware's build script for the `disk` cfg
+
+fn main() {
+    println!("cargo:rustc-check-cfg=cfg(disk, values(\"mirror\"))");
+    println!("cargo:rustc-cfg=disk=\"mirror\"");
+}
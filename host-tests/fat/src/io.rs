@@ -0,0 +1,193 @@
+//! Reads and writes a range of blocks at any byte position, holding them until they're written
+//! back
+
+use std::cell::RefCell;
+
+use embassy_futures::block_on;
+use fatfs::{Read as _, Seek as _, SeekFrom, Write as _};
+
+use crate::fat12_partition::{BlockDeviceIo, IoError};
+use crate::probe::Probe;
+use crate::ram::{Ram, BLOCK_SIZE};
+use crate::scsi::{BlockDevice, BlockDeviceError};
+
+const BLOCKS: u32 = 16;
+
+/// Each block filled with its number
+fn disk() -> Ram {
+    Ram::from(
+        (0..BLOCKS as usize * BLOCK_SIZE)
+            .map(|i| (i / BLOCK_SIZE) as u8)
+            .collect::<Vec<_>>(),
+    )
+}
+
+fn read_at(io: &mut BlockDeviceIo, position: u64, len: usize) -> Result<Vec<u8>, IoError> {
+    let mut buf = vec![0; len];
+    io.seek(SeekFrom::Start(position))?;
+    io.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn write_at(io: &mut BlockDeviceIo, position: u64, buf: &[u8]) -> Result<(), IoError> {
+    io.seek(SeekFrom::Start(position))?;
+    io.write_all(buf)
+}
+
+/// A RAM disk that logs the blocks read from and written to it, for the test to see while it's
+/// borrowed
+struct Logged<'l> {
+    ram: Ram,
+    read: &'l RefCell<Vec<u32>>,
+    written: &'l RefCell<Vec<u32>>,
+}
+
+impl BlockDevice for Logged<'_> {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.read.borrow_mut().push(lba);
+        self.ram.read_block(lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.written.borrow_mut().push(lba);
+        self.ram.write_block(lba, block).await
+    }
+
+    fn block_count(&self) -> u32 {
+        self.ram.block_count()
+    }
+}
+
+#[test]
+fn reads_the_blocks_an_operation_misses() {
+    let read = RefCell::new(Vec::new());
+    let written = RefCell::new(Vec::new());
+    let mut logged = Logged {
+        ram: disk(),
+        read: &read,
+        written: &written,
+    };
+    let mut io = BlockDeviceIo::new(2, 8);
+    let mut runs = 0;
+    // across the end of block 4 and into block 5
+    let bytes = block_on(io.run(&mut logged, |io| {
+        runs += 1;
+        write_at(io, 3 * BLOCK_SIZE as u64 - 4, &[0xaa; 8])?;
+        Ok(read_at(io, 3 * BLOCK_SIZE as u64 - 6, 10)?)
+    }))
+    .unwrap();
+    assert_eq!(
+        bytes,
+        [4, 4, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa]
+    );
+    // once for each block missed, each run from the same blocks
+    assert_eq!(runs, 3);
+    assert_eq!(*read.borrow(), [4, 5]);
+    assert!(written.borrow().is_empty());
+
+    // nothing reaches the device before it's written back
+    assert!(block_on(io.write_back(&mut logged, u32::MAX)).unwrap());
+    written.borrow_mut().sort();
+    assert_eq!(*written.borrow(), [4, 5]);
+    assert_eq!(logged.ram.block(4)[..BLOCK_SIZE - 4], [4; BLOCK_SIZE - 4]);
+    assert_eq!(logged.ram.block(4)[BLOCK_SIZE - 4..], [0xaa; 4]);
+    assert_eq!(logged.ram.block(5)[..4], [0xaa; 4]);
+    assert_eq!(logged.ram.block(5)[4..], [5; BLOCK_SIZE - 4]);
+
+    // and what's been written back is read again
+    read.borrow_mut().clear();
+    let byte = block_on(io.run(&mut logged, |io| Ok(read_at(io, 3 * BLOCK_SIZE as u64, 1)?)));
+    assert_eq!(byte.unwrap(), [0xaa]);
+    assert_eq!(*read.borrow(), [5]);
+}
+
+#[test]
+fn keeps_zeroed_blocks_as_runs() {
+    let mut ram = disk();
+    let mut io = BlockDeviceIo::new(0, BLOCKS);
+    // whole blocks aren't read first
+    write_at(&mut io, BLOCK_SIZE as u64, &[0; 8 * BLOCK_SIZE]).unwrap();
+    write_at(&mut io, 3 * BLOCK_SIZE as u64, &[0xcc; BLOCK_SIZE]).unwrap();
+    write_at(&mut io, 12 * BLOCK_SIZE as u64, &[0; BLOCK_SIZE]).unwrap();
+    assert_eq!(read_at(&mut io, 2 * BLOCK_SIZE as u64, 1), Ok(vec![0]));
+    assert_eq!(read_at(&mut io, 3 * BLOCK_SIZE as u64, 1), Ok(vec![0xcc]));
+    assert_eq!(
+        read_at(&mut io, 10 * BLOCK_SIZE as u64, 1),
+        Err(IoError::Miss(10))
+    );
+
+    // a few blocks at a time, the runs before the blocks written over them
+    assert!(!block_on(io.write_back(&mut ram, 4)).unwrap());
+    assert_eq!(ram.block(3), [0; BLOCK_SIZE]);
+    assert!(!block_on(io.write_back(&mut ram, 4)).unwrap());
+    assert_eq!(ram.block(12), [12; BLOCK_SIZE]);
+    assert!(block_on(io.write_back(&mut ram, 4)).unwrap());
+    for lba in 0..BLOCKS {
+        let fill = match lba {
+            3 => 0xcc,
+            1..=8 | 12 => 0,
+            lba => lba as u8,
+        };
+        assert_eq!(ram.block(lba), [fill; BLOCK_SIZE], "block {lba}");
+    }
+}
+
+#[test]
+fn holds_a_few_blocks() {
+    let mut ram = disk();
+    let mut io = BlockDeviceIo::new(0, BLOCKS);
+    let full = block_on(io.run(&mut ram, |io| {
+        for lba in 0..BLOCKS {
+            write_at(io, lba as u64 * BLOCK_SIZE as u64, &[0xaa; BLOCK_SIZE])?;
+        }
+        Ok(())
+    }));
+    assert!(matches!(full, Err(fatfs::Error::Io(IoError::Full))));
+    let full = block_on(io.run(&mut ram, |io| {
+        for lba in 0..BLOCKS {
+            read_at(io, lba as u64 * BLOCK_SIZE as u64, 1)?;
+        }
+        Ok(())
+    }));
+    assert!(matches!(full, Err(fatfs::Error::Io(IoError::Full))));
+}
+
+#[test]
+fn stays_in_the_volume() {
+    let mut ram = disk();
+    let mut io = BlockDeviceIo::new(2, 4);
+    let len = 4 * BLOCK_SIZE as u64;
+    let byte = block_on(io.run(&mut ram, |io| Ok(read_at(io, 0, 1)?)));
+    assert_eq!(byte.unwrap(), [2]);
+    assert_eq!(io.seek(SeekFrom::End(0)).unwrap(), len);
+    let mut buf = [0; 4];
+    assert_eq!(io.read(&mut buf).unwrap(), 0);
+    assert_eq!(io.write(&buf).unwrap(), 0);
+    // a read or write that runs off the end is cut short
+    let read = block_on(io.run(&mut ram, |io| {
+        io.seek(SeekFrom::End(-2))?;
+        Ok(io.read(&mut buf)?)
+    }));
+    assert_eq!(read.unwrap(), 2);
+
+    assert_eq!(io.seek(SeekFrom::End(1)), Err(IoError::InvalidSeek));
+    assert_eq!(io.seek(SeekFrom::Start(len + 1)), Err(IoError::InvalidSeek));
+    assert_eq!(
+        io.seek(SeekFrom::Current(-(len as i64) - 1)),
+        Err(IoError::InvalidSeek)
+    );
+    assert!(block_on(io.write_back(&mut ram, u32::MAX)).unwrap());
+    assert_eq!(ram.writes, 0);
+
+    // blocks past the last one the device can address
+    let mut probe = Probe::new(BLOCKS);
+    let mut io = BlockDeviceIo::new(u32::MAX - 1, 4);
+    io.seek(SeekFrom::Start(BLOCK_SIZE as u64)).unwrap();
+    assert_eq!(io.read(&mut buf), Err(IoError::Miss(u32::MAX)));
+    io.seek(SeekFrom::Start(2 * BLOCK_SIZE as u64)).unwrap();
+    assert_eq!(io.read(&mut buf), Err(IoError::InvalidSeek));
+    assert_eq!(io.write(&buf), Err(IoError::InvalidSeek));
+    assert!(block_on(io.write_back(&mut probe, u32::MAX)).unwrap());
+}
@@ -0,0 +1,76 @@
+//! Tests of the parts of the firmware built on fatfs, run on the host like those in
+//! host-tests. They're kept apart as they need the firmware's fatfs, which is fetched from git:
+//!
+//! ```text
+//! cargo test --manifest-path host-tests/fat/Cargo.toml --target x86_64-unknown-linux-gnu
+//! ```
+#![cfg(test)]
+#![allow(dead_code)]
+
+#[path = "../../../src/scsi"]
+mod scsi {
+    mod block_device;
+    pub use block_device::*;
+}
+
+#[path = "../../../src/block_devices"]
+mod block_devices {
+    pub const BLOCK_SIZE: usize = 512;
+
+    pub mod mkfs;
+}
+
+#[path = "../../../src/fat12_partition"]
+mod fat12_partition {
+    mod io;
+    mod mbr;
+    mod mkfs;
+    mod table;
+
+    pub use io::{error_name, BlockDeviceIo, IoError};
+    pub use mbr::read_partition;
+    pub use mkfs::{format, Format};
+    pub use table::PartitionType;
+}
+
+#[path = "../../src/probe.rs"]
+mod probe;
+#[path = "../../src/ram.rs"]
+mod ram;
+
+mod io;
+mod mkfs;
+
+/// Runs `f` on a thread named `main`, which embassy-sync takes as the Pico's thread mode, for
+/// the firmware's statics behind a `ThreadModeRawMutex`. Only one test runs in thread mode at
+/// a time, as only one thread could on the Pico
+fn in_thread_mode<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    static THREAD_MODE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let _thread_mode = THREAD_MODE.lock().unwrap_or_else(|e| e.into_inner());
+    std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new().name("main".into());
+        match thread.spawn_scoped(scope, f).unwrap().join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    })
+}
+
+/// Drops the firmware's logs
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("{=u8}", 0);
+
+/// Fails the test on the firmware's `defmt` panics and asserts
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
@@ -0,0 +1,217 @@
+//! Formats disks of every size, and the device that formats a disk without a filesystem
+
+use embassy_futures::block_on;
+use fatfs::{FatType, FileSystem, FsOptions};
+
+use crate::block_devices::mkfs::{MkfsBlockDevice, REQUEST};
+use crate::fat12_partition::{format, read_partition, BlockDeviceIo, IoError};
+use crate::in_thread_mode;
+use crate::probe::{call_every_method, Probe};
+use crate::ram::{Ram, BLOCK_SIZE};
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus};
+
+const LABEL: [u8; 11] = *b"PICO       ";
+const VOLUME_ID: u32 = 0x1234_5678;
+const MB: u32 = 1024 * 1024 / BLOCK_SIZE as u32;
+
+/// The FAT type and volume id of the filesystem in the only partition of `ram`, checking the
+/// partition and the boot sector agree
+fn mount(ram: &mut Ram) -> (FatType, u32) {
+    let mbr = ram.block(0).to_vec();
+    assert_eq!(mbr[510..], [0x55, 0xaa]);
+    let partition = read_partition(&mbr, 0);
+    assert!(read_partition(&mbr, 1).p_type == 0);
+    assert_eq!(partition.p_lba + partition.p_size, ram.block_count());
+    // the hidden sectors
+    let boot_sector = ram.block(partition.p_lba);
+    assert_eq!(boot_sector[28..32], partition.p_lba.to_le_bytes());
+
+    let mut disk = BlockDeviceIo::new(partition.p_lba, partition.p_size);
+    let (fat_type, volume_id) = block_on(disk.run(ram, |disk| {
+        let fs = FileSystem::new(disk, FsOptions::new())?;
+        Ok((fs.fat_type(), fs.volume_id()))
+    }))
+    .unwrap();
+    let system_id = match fat_type {
+        FatType::Fat12 => 0x01,
+        FatType::Fat16 if partition.p_size < 65536 => 0x04,
+        FatType::Fat16 => 0x06,
+        FatType::Fat32 => 0x0c,
+    };
+    assert_eq!(partition.p_type, system_id);
+    (fat_type, volume_id)
+}
+
+#[test]
+fn picks_the_fat_type_from_the_size() {
+    for (blocks, partition_lba, fat_type) in [
+        (MB, 1, FatType::Fat12),
+        (16 * MB, 1, FatType::Fat16),
+        // aligned to 1 MiB from 64 MiB
+        (64 * MB, 2048, FatType::Fat16),
+        (1024 * MB, 2048, FatType::Fat32),
+    ] {
+        let mut ram = Ram::new(blocks);
+        assert_eq!(
+            block_on(format(&mut ram, &LABEL, VOLUME_ID)).unwrap(),
+            fat_type
+        );
+        assert_eq!(read_partition(ram.block(0), 0).p_lba, partition_lba);
+        assert_eq!(mount(&mut ram), (fat_type, VOLUME_ID));
+
+        let boot_sector = ram.block(partition_lba).to_vec();
+        let label = match fat_type {
+            FatType::Fat32 => 71,
+            _ => 43,
+        };
+        assert_eq!(boot_sector[label..label + 11], LABEL);
+        if fat_type == FatType::Fat32 {
+            // the backup boot sector has the hidden sectors too
+            let backup = u16::from_le_bytes([boot_sector[50], boot_sector[51]]) as u32;
+            assert_eq!(ram.block(partition_lba + backup), boot_sector);
+        }
+    }
+
+    let mut ram = Ram::new(0);
+    assert!(matches!(
+        block_on(format(&mut ram, &LABEL, VOLUME_ID)),
+        Err(fatfs::Error::InvalidInput)
+    ));
+}
+
+/// A RAM disk that fails writes once it's written `writes_left` blocks
+struct Failing {
+    ram: Ram,
+    writes_left: usize,
+}
+
+impl BlockDevice for Failing {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.ram.read_block(lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        if self.writes_left == 0 {
+            return Err(BlockDeviceError::WriteError);
+        }
+        self.writes_left -= 1;
+        self.ram.write_block(lba, block).await
+    }
+
+    fn block_count(&self) -> u32 {
+        self.ram.block_count()
+    }
+}
+
+#[test]
+fn a_format_cut_short_leaves_a_blank_disk() {
+    let mut ram = Ram::new(MB);
+    block_on(format(&mut ram, &LABEL, VOLUME_ID)).unwrap();
+    let mut failing = Failing {
+        ram,
+        writes_left: 4,
+    };
+    assert!(matches!(
+        block_on(format(&mut failing, &LABEL, VOLUME_ID)),
+        Err(fatfs::Error::Io(IoError::Device(
+            BlockDeviceError::WriteError
+        )))
+    ));
+    // with no partition table, the old filesystem is gone too
+    assert_eq!(failing.ram.block(0), [0; BLOCK_SIZE]);
+}
+
+/// Polls the device and lets it work while the host is idle, until it's ready. Returns how
+/// many times it worked and the status it's ready with
+fn settle<B: BlockDevice>(mkfs: &mut MkfsBlockDevice<B>) -> (usize, MediaStatus) {
+    let mut steps = 0;
+    loop {
+        match block_on(mkfs.media_status()) {
+            MediaStatus::BecomingReady => {
+                let mut block = [0; BLOCK_SIZE];
+                assert_eq!(
+                    block_on(mkfs.read_block(0, &mut block)),
+                    Err(BlockDeviceError::BecomingReady)
+                );
+                assert!(mkfs.idle_deadline().is_some());
+                block_on(mkfs.idle());
+                steps += 1;
+            }
+            status => return (steps, status),
+        }
+    }
+}
+
+#[test]
+fn formats_a_disk_without_a_filesystem() {
+    in_thread_mode(|| {
+        let mut ram = Ram::new(MB);
+        let mut mkfs = MkfsBlockDevice::new(&mut ram, LABEL);
+        // a few blocks at a time, then the host is told
+        let (steps, status) = settle(&mut mkfs);
+        assert!(steps > 3, "{steps} steps");
+        assert_eq!(status, MediaStatus::Changed);
+        assert_eq!(block_on(mkfs.media_status()), MediaStatus::Present);
+        mount(&mut ram);
+
+        // a filesystem that mounts is left alone
+        ram.writes = 0;
+        let mut mkfs = MkfsBlockDevice::new(&mut ram, LABEL);
+        assert_eq!(settle(&mut mkfs), (1, MediaStatus::Present));
+        assert_eq!(ram.writes, 0);
+
+        // as is anything that isn't FAT
+        let mut other = Ram::from(
+            (0..MB as usize * BLOCK_SIZE)
+                .map(|i| i as u8)
+                .collect::<Vec<_>>(),
+        );
+        let mut mkfs = MkfsBlockDevice::new(&mut other, LABEL);
+        assert_eq!(settle(&mut mkfs), (1, MediaStatus::Present));
+        assert_eq!(other.writes, 0);
+
+        // but a corrupt filesystem is replaced, here with a cluster size that isn't a power of
+        // two
+        let lba = read_partition(ram.block(0), 0).p_lba as usize;
+        ram.data[lba * BLOCK_SIZE + 13] = 3;
+        let mut mkfs = MkfsBlockDevice::new(&mut ram, LABEL);
+        assert_eq!(settle(&mut mkfs).1, MediaStatus::Changed);
+        assert_eq!(mount(&mut ram).0, FatType::Fat12);
+    });
+}
+
+#[test]
+fn formats_on_request() {
+    in_thread_mode(|| {
+        let mut ram = Ram::new(MB);
+        block_on(format(&mut ram, &LABEL, VOLUME_ID)).unwrap();
+        ram.writes = 0;
+        let mut mkfs = MkfsBlockDevice::new(&mut ram, LABEL);
+        assert_eq!(settle(&mut mkfs).1, MediaStatus::Present);
+
+        REQUEST.signal(());
+        assert_eq!(settle(&mut mkfs).1, MediaStatus::Changed);
+        assert_eq!(block_on(mkfs.media_status()), MediaStatus::Present);
+        assert_eq!(mkfs.idle_deadline(), None);
+        // and when the host sends FORMAT UNIT, straight away
+        block_on(mkfs.format()).unwrap();
+        assert_eq!(block_on(mkfs.media_status()), MediaStatus::Present);
+
+        assert!(ram.writes > 0);
+        assert_ne!(mount(&mut ram).1, VOLUME_ID);
+    });
+}
+
+#[test]
+fn forwards_everything_else() {
+    let mut probe = Probe::new(MB);
+    in_thread_mode(|| {
+        let mut mkfs = MkfsBlockDevice::new(&mut probe, LABEL);
+        settle(&mut mkfs);
+        block_on(call_every_method(&mut mkfs))
+    });
+    // formatted by the device itself
+    assert_eq!(probe.missed(), vec!["format"]);
+}
@@ -0,0 +1,111 @@
+//! Caches the blocks of a base in RAM, and hides them while the base is locked
+
+use embassy_futures::block_on;
+
+use crate::block_devices::cache::CacheBlockDevice;
+use crate::block_devices::encrypted::{EncryptedBlockDevice, EncryptionCommand, Key, COMMANDS};
+use crate::block_devices::BLOCK_SIZE;
+use crate::in_thread_mode;
+use crate::probe::{call_every_method, Probe};
+use crate::ram::{read, write, Ram};
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus, PROTECTION_INFORMATION_BYTES};
+
+const BLOCKS: u32 = 64;
+const LINES: usize = 8;
+const KEY: Key = [0xc3; 32];
+
+#[test]
+fn writes_back_on_flush() {
+    let mut ram = Ram::new(BLOCKS);
+    let mut cache = CacheBlockDevice::<_, LINES>::new(&mut ram);
+    for lba in [3, 4, 5, 20] {
+        write(&mut cache, lba, lba as u8).unwrap();
+    }
+    write(&mut cache, 4, 0x44).unwrap();
+    assert_eq!(cache.dirty_blocks(), 4);
+    assert_eq!(read(&mut cache, 4).unwrap(), [0x44; BLOCK_SIZE]);
+
+    block_on(cache.flush()).unwrap();
+    assert_eq!(cache.dirty_blocks(), 0);
+    assert_eq!(ram.writes, 4);
+    assert_eq!(ram.block(4), [0x44; BLOCK_SIZE]);
+    assert_eq!(ram.block(20), [20; BLOCK_SIZE]);
+}
+
+#[test]
+fn a_locked_base_hides_the_cache() {
+    let mut ram = Ram::new(BLOCKS);
+    in_thread_mode(|| {
+        let mut cache = CacheBlockDevice::<_, LINES>::new(EncryptedBlockDevice::new(&mut ram));
+        block_on(cache.unlock(&KEY)).unwrap();
+        assert_eq!(block_on(cache.media_status()), MediaStatus::Changed);
+        write(&mut cache, 1, 0x11).unwrap();
+        write(&mut cache, 2, 0x22).unwrap();
+        block_on(cache.flush()).unwrap();
+        write(&mut cache, 2, 0x23).unwrap();
+        assert_eq!(read(&mut cache, 1).unwrap(), [0x11; BLOCK_SIZE]);
+
+        // locked under the cache, with a block still to be written back
+        COMMANDS.signal(EncryptionCommand::Lock);
+        assert_eq!(block_on(cache.media_status()), MediaStatus::Locked);
+        for lba in [1, 2] {
+            assert_eq!(
+                read(&mut cache, lba),
+                Err(BlockDeviceError::AccessNotAuthorized)
+            );
+        }
+        assert_eq!(
+            write(&mut cache, 3, 0x33),
+            Err(BlockDeviceError::AccessNotAuthorized)
+        );
+        assert_eq!(cache.dirty_blocks(), 1);
+
+        COMMANDS.signal(EncryptionCommand::Unlock(KEY));
+        assert_eq!(block_on(cache.media_status()), MediaStatus::Changed);
+        assert_eq!(cache.dirty_blocks(), 0);
+        assert_eq!(read(&mut cache, 2).unwrap(), [0x23; BLOCK_SIZE]);
+
+        // and through the cache
+        cache.lock();
+        assert_eq!(
+            read(&mut cache, 1),
+            Err(BlockDeviceError::AccessNotAuthorized)
+        );
+        block_on(cache.unlock(&KEY)).unwrap();
+        assert_eq!(read(&mut cache, 1).unwrap(), [0x11; BLOCK_SIZE]);
+    });
+
+    let mut encrypted = EncryptedBlockDevice::new(&mut ram);
+    block_on(encrypted.unlock(&KEY)).unwrap();
+    assert_eq!(read(&mut encrypted, 2).unwrap(), [0x23; BLOCK_SIZE]);
+}
+
+#[test]
+fn forwards_everything_else() {
+    let mut probe = Probe::new(64);
+    let mut cache = CacheBlockDevice::<_, LINES>::new(&mut probe);
+    block_on(async {
+        call_every_method(&mut cache).await;
+        // the methods called after `call_every_method` locked the cache
+        cache.unlock(b"key").await.unwrap();
+        let mut blocks = [0; BLOCK_SIZE];
+        let mut protection = [0; PROTECTION_INFORMATION_BYTES];
+        let _ = cache
+            .read_blocks_protected(0, &mut blocks, &mut protection)
+            .await;
+        let _ = cache.write_blocks_protected(0, &blocks, &protection).await;
+        let _ = cache.format().await;
+    });
+    // blocks go to and from the base in runs, FUA writes go through and are
+    // flushed, and the caching mode page is the cache's own
+    assert_eq!(
+        probe.missed(),
+        vec![
+            "read_block",
+            "write_block",
+            "write_blocks_fua",
+            "caching",
+            "set_caching"
+        ]
+    );
+}
@@ -0,0 +1,128 @@
+//! Joins two devices end to end, splitting accesses where they meet
+
+use embassy_futures::block_on;
+
+use crate::block_devices::concat::ConcatBlockDevice;
+use crate::block_devices::integrity::{protection_information, IntegrityBlockDevice};
+use crate::block_devices::sparse::SparseBlockDevice;
+use crate::probe::{call_every_method, Probe};
+use crate::ram::{Ram, BLOCK_SIZE};
+use crate::scsi::{BlockDevice, BlockDeviceError, PROTECTION_INFORMATION_BYTES};
+
+const PI: usize = PROTECTION_INFORMATION_BYTES;
+const FIRST: u32 = 10;
+const SECOND: u32 = 6;
+
+/// `count` blocks, each filled with its LBA + 1
+fn numbered(count: u32) -> Vec<u8> {
+    (0..count as usize * BLOCK_SIZE)
+        .map(|i| (i / BLOCK_SIZE + 1) as u8)
+        .collect()
+}
+
+#[test]
+fn splits_at_the_boundary() {
+    let mut first = Ram::new(FIRST);
+    let mut second = Ram::new(SECOND);
+    let mut concat = ConcatBlockDevice::new(&mut first, &mut second);
+    assert_eq!(concat.block_count(), FIRST + SECOND);
+
+    let blocks = numbered(FIRST + SECOND);
+    block_on(concat.write_blocks(0, &blocks)).unwrap();
+    let mut read_back = vec![0; 4 * BLOCK_SIZE];
+    block_on(concat.read_blocks(FIRST - 2, &mut read_back)).unwrap();
+    assert_eq!(
+        read_back,
+        blocks[(FIRST as usize - 2) * BLOCK_SIZE..][..4 * BLOCK_SIZE]
+    );
+
+    // either side of it
+    block_on(concat.write_block(FIRST - 1, &[0xaa; BLOCK_SIZE])).unwrap();
+    block_on(concat.write_block(FIRST, &[0xbb; BLOCK_SIZE])).unwrap();
+    for lba in [FIRST + SECOND - 1, u32::MAX] {
+        assert_eq!(
+            block_on(concat.read_blocks(lba, &mut read_back[..2 * BLOCK_SIZE])),
+            Err(BlockDeviceError::InvalidAddress)
+        );
+    }
+
+    assert_eq!(first.block(FIRST - 1), [0xaa; BLOCK_SIZE]);
+    assert_eq!(second.block(0), [0xbb; BLOCK_SIZE]);
+    assert_eq!(
+        second.block(SECOND - 1),
+        [FIRST as u8 + SECOND as u8; BLOCK_SIZE]
+    );
+    assert_eq!(first.writes, FIRST as usize + 1);
+    assert_eq!(second.writes, SECOND as usize + 1);
+}
+
+#[test]
+fn unmaps_across_the_boundary() {
+    let mut concat = ConcatBlockDevice::new(
+        SparseBlockDevice::<8>::new(FIRST, 0),
+        SparseBlockDevice::<8>::new(SECOND, 0),
+    );
+    let provisioning = concat.provisioning().unwrap();
+    assert_eq!((provisioning.used, provisioning.available), (0, 16));
+
+    block_on(concat.write_blocks(FIRST - 2, &numbered(4))).unwrap();
+    assert_eq!(concat.provisioning().unwrap().used, 4);
+    block_on(concat.unmap(FIRST - 1, 2)).unwrap();
+    assert_eq!(concat.provisioning().unwrap().used, 2);
+    let mut read_back = vec![0; 4 * BLOCK_SIZE];
+    block_on(concat.read_blocks(FIRST - 2, &mut read_back)).unwrap();
+    let mut expected = numbered(4);
+    expected[BLOCK_SIZE..3 * BLOCK_SIZE].fill(0);
+    assert_eq!(read_back, expected);
+
+    // a member without thin provisioning hides it
+    let concat = ConcatBlockDevice::new(SparseBlockDevice::<8>::new(FIRST, 0), Ram::new(SECOND));
+    assert_eq!(concat.provisioning(), None);
+}
+
+#[test]
+fn protection_information_counts_from_each_device() {
+    // 128 blocks for the host on each
+    let mut first = IntegrityBlockDevice::new(Ram::new(130), true);
+    let mut second = IntegrityBlockDevice::new(Ram::new(130), true);
+    let mut concat = ConcatBlockDevice::new(&mut first, &mut second);
+    assert!(concat.protection_information());
+
+    let lba = 126;
+    let blocks = numbered(4);
+    let mut protection = vec![0; 4 * PI];
+    for (i, (block, pi)) in blocks
+        .chunks_exact(BLOCK_SIZE)
+        .zip(protection.chunks_exact_mut(PI))
+        .enumerate()
+    {
+        pi.copy_from_slice(&protection_information(lba + i as u32, block, 0x1234));
+    }
+    block_on(concat.write_blocks_protected(lba, &blocks, &protection)).unwrap();
+
+    let mut read_back = vec![0; blocks.len()];
+    let mut read_protection = vec![0; protection.len()];
+    block_on(concat.read_blocks_protected(lba, &mut read_back, &mut read_protection)).unwrap();
+    assert_eq!(read_back, blocks);
+    assert_eq!(read_protection, protection);
+
+    let mut block = [0; BLOCK_SIZE];
+    let mut pi = [0; PI];
+    block_on(second.read_blocks_protected(1, &mut block, &mut pi)).unwrap();
+    assert_eq!(pi, protection_information(1, &block, 0x1234));
+}
+
+#[test]
+fn forwards_everything_else() {
+    // two block accesses from LBA 0 reach both
+    let mut first = Probe::new(1);
+    let mut second = Probe::new(64);
+    block_on(call_every_method(&mut ConcatBlockDevice::new(
+        &mut first,
+        &mut second,
+    )));
+    // single blocks are transferred as runs, and each would be formatted on its own
+    for probe in [first, second] {
+        assert_eq!(probe.missed(), vec!["read_block", "write_block", "format"]);
+    }
+}
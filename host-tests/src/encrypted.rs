@@ -0,0 +1,292 @@
+//! Encrypts a disk with XTS-AES-128, checked against the IEEE 1619 test vectors
+
+use embassy_futures::block_on;
+
+use crate::block_devices::encrypted::{
+    EncryptedBlockDevice, EncryptionCommand, Key, Xts, COMMANDS, RESULTS,
+};
+use crate::block_devices::integrity::{protection_information, IntegrityBlockDevice};
+use crate::block_devices::sparse::SparseBlockDevice;
+use crate::block_devices::BLOCK_SIZE;
+use crate::in_thread_mode;
+use crate::probe::{call_every_method, Probe};
+use crate::ram::{read, write, Ram};
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus, PROTECTION_INFORMATION_BYTES};
+
+const BLOCKS: u32 = 64;
+const KEY: Key = [0x5a; 32];
+
+fn hex(digits: &str) -> Vec<u8> {
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// Key 1, the data key, followed by key 2, the tweak key
+fn key(key1: &str, key2: &str) -> Key {
+    hex(&(key1.to_owned() + key2)).try_into().unwrap()
+}
+
+#[test]
+fn ieee_1619_vectors() {
+    // vectors 1 to 3, 32 byte data units
+    let vectors = [
+        (
+            key(&"00".repeat(16), &"00".repeat(16)),
+            0,
+            "00".repeat(32),
+            "917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e",
+        ),
+        (
+            key(&"11".repeat(16), &"22".repeat(16)),
+            0x3333333333,
+            "44".repeat(32),
+            "c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0",
+        ),
+        (
+            key("fffefdfcfbfaf9f8f7f6f5f4f3f2f1f0", &"22".repeat(16)),
+            0x3333333333,
+            "44".repeat(32),
+            "af85336b597afc1a900b2eb21ec949d292df4c047e0b21532186a5971a227a89",
+        ),
+    ];
+    for (key, sector, plaintext, ciphertext) in vectors {
+        let xts = Xts::new(&key);
+        let mut data = hex(&plaintext);
+        xts.encrypt(sector, &mut data);
+        assert_eq!(data, hex(ciphertext));
+        xts.decrypt(sector, &mut data);
+        assert_eq!(data, hex(&plaintext));
+    }
+}
+
+#[test]
+fn encrypts_each_block_as_a_data_unit() {
+    // vector 4, a 512 byte data unit
+    let key = key(
+        "27182818284590452353602874713526",
+        "31415926535897932384626433832795",
+    );
+    let plaintext: Vec<u8> = (0..=255).chain(0..=255).collect();
+    let ciphertext = hex(concat!(
+        "27a7479befa1d476489f308cd4cfa6e2a96e4bbe3208ff25287dd3819616e89c",
+        "c78cf7f5e543445f8333d8fa7f56000005279fa5d8b5e4ad40e736ddb4d35412",
+        "328063fd2aab53e5ea1e0a9f332500a5df9487d07a5c92cc512c8866c7e860ce",
+        "93fdf166a24912b422976146ae20ce846bb7dc9ba94a767aaef20c0d61ad0265",
+        "5ea92dc4c4e41a8952c651d33174be51a10c421110e6d81588ede82103a252d8",
+        "a750e8768defffed9122810aaeb99f9172af82b604dc4b8e51bcb08235a6f434",
+        "1332e4ca60482a4ba1a03b3e65008fc5da76b70bf1690db4eae29c5f1badd03c",
+        "5ccf2a55d705ddcd86d449511ceb7ec30bf12b1fa35b913f9f747a8afd1b130e",
+        "94bff94effd01a91735ca1726acd0b197c4e5b03393697e126826fb6bbde8ecc",
+        "1e08298516e2c9ed03ff3c1b7860f6de76d4cecd94c8119855ef5297ca67e9f3",
+        "e7ff72b1e99785ca0a7e7720c5b36dc6d72cac9574c8cbbc2f801e23e56fd344",
+        "b07f22154beba0f08ce8891e643ed995c94d9a69c9f1b5f499027a78572aeebd",
+        "74d20cc39881c213ee770b1010e4bea718846977ae119f7a023ab58cca0ad752",
+        "afe656bb3c17256a9f6e9bf19fdd5a38fc82bbe872c5539edb609ef4f79c203e",
+        "bb140f2e583cb2ad15b4aa5b655016a8449277dbd477ef2c8d6c017db738b18d",
+        "eb4a427d1923ce3ff262735779a418f20a282df920147beabe421ee5319d0568",
+    ));
+
+    let mut ram = Ram::new(BLOCKS);
+    let mut encrypted = EncryptedBlockDevice::new(&mut ram);
+    block_on(encrypted.unlock(&key)).unwrap();
+    block_on(encrypted.write_block(0, &plaintext)).unwrap();
+    assert_eq!(read(&mut encrypted, 0).unwrap()[..], plaintext);
+    // after the header
+    assert_eq!(ram.block(1), ciphertext);
+}
+
+#[test]
+fn unlocks_only_with_the_key_it_was_formatted_with() {
+    let mut ram = Ram::new(BLOCKS);
+    let mut encrypted = EncryptedBlockDevice::new(&mut ram);
+    assert_eq!(encrypted.block_count(), BLOCKS - 1);
+    assert_eq!(
+        read(&mut encrypted, 0),
+        Err(BlockDeviceError::AccessNotAuthorized)
+    );
+    assert_eq!(
+        write(&mut encrypted, 0, 1),
+        Err(BlockDeviceError::AccessNotAuthorized)
+    );
+    assert_eq!(
+        in_thread_mode(|| block_on(encrypted.media_status())),
+        MediaStatus::Locked
+    );
+
+    block_on(encrypted.unlock(&KEY)).unwrap();
+    assert_eq!(
+        in_thread_mode(|| block_on(encrypted.media_status())),
+        MediaStatus::Changed
+    );
+    // more blocks than are encrypted at once
+    let blocks: Vec<u8> = (0..6 * BLOCK_SIZE).map(|i| (i / 3) as u8).collect();
+    block_on(encrypted.write_blocks(BLOCKS - 7, &blocks)).unwrap();
+    assert_eq!(
+        write(&mut encrypted, BLOCKS - 1, 1),
+        Err(BlockDeviceError::InvalidAddress)
+    );
+    encrypted.lock();
+    assert_eq!(
+        read(&mut encrypted, 0),
+        Err(BlockDeviceError::AccessNotAuthorized)
+    );
+
+    let mut wrong = KEY;
+    wrong[31] ^= 1;
+    assert_eq!(
+        block_on(encrypted.unlock(&wrong)),
+        Err(BlockDeviceError::AccessNotAuthorized)
+    );
+    assert_eq!(
+        block_on(encrypted.unlock(&KEY[..16])),
+        Err(BlockDeviceError::AccessNotAuthorized)
+    );
+    assert!(encrypted.is_locked());
+
+    block_on(encrypted.unlock(&KEY)).unwrap();
+    let mut read_back = vec![0; blocks.len()];
+    block_on(encrypted.read_blocks(BLOCKS - 7, &mut read_back)).unwrap();
+    assert_eq!(read_back, blocks);
+    // nothing of it on the base
+    assert!(!ram.data.windows(4).any(|window| window == [0, 0, 0, 1]));
+}
+
+#[test]
+fn answers_each_command_with_its_outcome() {
+    let mut wrong = KEY;
+    wrong[0] ^= 1;
+    let outcomes = in_thread_mode(|| {
+        let mut encrypted = EncryptedBlockDevice::new(Ram::new(BLOCKS));
+        let mut outcomes = vec![];
+        for command in [
+            EncryptionCommand::Unlock(KEY),
+            EncryptionCommand::Lock,
+            EncryptionCommand::Unlock(wrong),
+            EncryptionCommand::Unlock(KEY),
+        ] {
+            COMMANDS.signal(command);
+            block_on(encrypted.media_status());
+            outcomes.push(RESULTS.try_take());
+        }
+        // nothing to answer without a command
+        block_on(encrypted.media_status());
+        outcomes.push(RESULTS.try_take());
+        outcomes
+    });
+    assert_eq!(
+        outcomes,
+        [
+            Some(Ok(())),
+            Some(Ok(())),
+            Some(Err(BlockDeviceError::AccessNotAuthorized)),
+            Some(Ok(())),
+            None,
+        ]
+    );
+}
+
+#[test]
+fn unmapped_blocks_read_as_zeros() {
+    let mut encrypted = EncryptedBlockDevice::new(SparseBlockDevice::<8>::new(BLOCKS, 0));
+    assert_eq!(
+        block_on(encrypted.unmap(0, 1)),
+        Err(BlockDeviceError::AccessNotAuthorized)
+    );
+    block_on(encrypted.unlock(&KEY)).unwrap();
+    // the header takes a block of the pool
+    assert_eq!(encrypted.provisioning().unwrap().used, 1);
+
+    write(&mut encrypted, 5, 0xaa).unwrap();
+    assert_eq!(encrypted.provisioning().unwrap().used, 2);
+    block_on(encrypted.unmap(5, 1)).unwrap();
+    assert_eq!(encrypted.provisioning().unwrap().used, 1);
+    assert_eq!(read(&mut encrypted, 5).unwrap(), [0; BLOCK_SIZE]);
+    assert_eq!(read(&mut encrypted, 6).unwrap(), [0; BLOCK_SIZE]);
+    assert_eq!(
+        block_on(encrypted.unmap(BLOCKS - 1, 1)),
+        Err(BlockDeviceError::InvalidAddress)
+    );
+}
+
+#[test]
+fn protection_information_covers_the_ciphertext_on_the_base() {
+    const PI: usize = PROTECTION_INFORMATION_BYTES;
+
+    let mut integrity = IntegrityBlockDevice::new(Ram::new(BLOCKS), true);
+    let mut encrypted = EncryptedBlockDevice::new(&mut integrity);
+    block_on(encrypted.unlock(&KEY)).unwrap();
+    assert!(encrypted.protection_information());
+
+    let count = 6;
+    let blocks: Vec<u8> = (0..count * BLOCK_SIZE).map(|i| (i / 5) as u8).collect();
+    let mut protection = vec![0; count * PI];
+    for (lba, (block, pi)) in blocks
+        .chunks_exact(BLOCK_SIZE)
+        .zip(protection.chunks_exact_mut(PI))
+        .enumerate()
+    {
+        pi.copy_from_slice(&protection_information(lba as u32 + 2, block, 0x1234));
+    }
+    block_on(encrypted.write_blocks_protected(2, &blocks, &protection)).unwrap();
+
+    let mut read_back = vec![0; count * BLOCK_SIZE];
+    let mut read_protection = vec![0; count * PI];
+    block_on(encrypted.read_blocks_protected(2, &mut read_back, &mut read_protection)).unwrap();
+    assert_eq!(read_back, blocks);
+    assert_eq!(read_protection, protection);
+
+    // the base checked the ciphertext against its own LBAs
+    let mut block = [0; BLOCK_SIZE];
+    let mut pi = [0; PI];
+    block_on(integrity.read_blocks_protected(3, &mut block, &mut pi)).unwrap();
+    assert_ne!(block[..], blocks[..BLOCK_SIZE]);
+    assert_eq!(pi, protection_information(3, &block, 0x1234));
+}
+
+#[test]
+fn formatting_keeps_the_key() {
+    let mut ram = Ram::new(BLOCKS);
+    let mut encrypted = EncryptedBlockDevice::new(&mut ram);
+    assert_eq!(
+        block_on(encrypted.format()),
+        Err(BlockDeviceError::AccessNotAuthorized)
+    );
+    block_on(encrypted.unlock(&KEY)).unwrap();
+    // the RAM disk can't be formatted, and the header is left as it was
+    assert_eq!(
+        block_on(encrypted.format()),
+        Err(BlockDeviceError::Unsupported)
+    );
+    write(&mut encrypted, 0, 1).unwrap();
+    encrypted.lock();
+    block_on(encrypted.unlock(&KEY)).unwrap();
+    assert_eq!(read(&mut encrypted, 0).unwrap(), [1; BLOCK_SIZE]);
+}
+
+#[test]
+fn forwards_everything_else() {
+    let probe = in_thread_mode(|| {
+        let mut probe = Probe::new(64);
+        let mut encrypted = EncryptedBlockDevice::new(&mut probe);
+        block_on(async {
+            encrypted.unlock(&KEY).await.unwrap();
+            call_every_method(&mut encrypted).await;
+            // the methods called after `call_every_method` locked the device
+            encrypted.unlock(&KEY).await.unwrap();
+            let mut blocks = [0; BLOCK_SIZE];
+            let mut protection = [0; PROTECTION_INFORMATION_BYTES];
+            let _ = encrypted
+                .read_blocks_protected(0, &mut blocks, &mut protection)
+                .await;
+            let _ = encrypted
+                .write_blocks_protected(0, &blocks, &protection)
+                .await;
+            let _ = encrypted.format().await;
+        });
+        probe
+    });
+    // the key is the encrypted device's own
+    assert_eq!(probe.missed(), vec!["unlock", "lock"]);
+}
@@ -0,0 +1,175 @@
+//! Parses fault scripts and injects their faults into the accesses to a RAM disk
+
+use std::io::{Cursor, Write};
+
+use embassy_futures::block_on;
+
+use crate::block_devices::fault::{
+    parse_line, Fault, FaultBlockDevice, FaultCommand, FaultRule, Operation, ScriptError, COMMANDS,
+};
+use crate::in_thread_mode;
+use crate::probe::{call_every_method, Probe};
+use crate::ram::{read, Ram, BLOCK_SIZE};
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus};
+
+/// A 4MiB FAT12 filesystem, without a partition table
+const BLOCKS: u32 = 8192;
+
+fn rule(line: &str) -> FaultRule {
+    match parse_line(line) {
+        Ok(Some(FaultCommand::Add(rule))) => rule,
+        other => panic!("{:?} from {:?}", other, line),
+    }
+}
+
+#[test]
+fn parses_the_script_grammar() {
+    // the examples in the module docs
+    assert_eq!(
+        rule("fail lba=100+8 op=read             # reads touching blocks 100 to 107 fail"),
+        FaultRule {
+            fault: Fault::Fail(None),
+            operation: Some(Operation::Read),
+            lbas: Some((100, 8)),
+            after: 0,
+            times: None,
+        }
+    );
+    assert_eq!(
+        rule("fail after=500 error=hardware # every access fails after the first 500").fault,
+        Fault::Fail(Some(BlockDeviceError::HardwareFailure { fru: 0 }))
+    );
+    assert_eq!(rule("fail after=500").after, 500);
+    assert_eq!(rule("delay ms=250 op=write").fault, Fault::Delay(250));
+    assert_eq!(
+        rule("flip lba=40 bit=7 times=1"),
+        FaultRule {
+            fault: Fault::FlipBit(7),
+            operation: None,
+            lbas: Some((40, 1)),
+            after: 0,
+            times: Some(1),
+        }
+    );
+    // windows only open once by default
+    let not_ready = rule("not-ready after=20");
+    assert_eq!(
+        (not_ready.fault, not_ready.times),
+        (Fault::NotReady(5000), Some(1))
+    );
+    let yank = rule("yank ms=0x2710 times=3");
+    assert_eq!((yank.fault, yank.times), (Fault::Yank(10000), Some(3)));
+
+    assert_eq!(parse_line("  # a comment"), Ok(None));
+    assert_eq!(parse_line(""), Ok(None));
+    assert_eq!(parse_line("clear"), Ok(Some(FaultCommand::Clear)));
+    assert_eq!(parse_line("reload # again"), Ok(Some(FaultCommand::Reload)));
+
+    assert_eq!(parse_line("explode"), Err(ScriptError::UnknownCommand));
+    for line in [
+        "fail lba",
+        "fail lba=",
+        "fail lba=1+",
+        "fail lba=0x",
+        "fail op=erase",
+        "fail error=bogus",
+        "fail after=-1",
+        "fail times=4294967296",
+        // arguments of other faults
+        "fail ms=10",
+        "fail bit=1",
+        "delay error=read",
+        "flip bit=4096",
+    ] {
+        assert_eq!(
+            parse_line(line),
+            Err(ScriptError::InvalidArgument),
+            "{}",
+            line
+        );
+    }
+}
+
+#[test]
+fn fails_and_flips() {
+    let mut ram = Ram::new(16);
+    in_thread_mode(|| fail_and_flip(&mut ram));
+    assert_eq!(ram.block(3), [3; BLOCK_SIZE]);
+}
+
+fn fail_and_flip(ram: &mut Ram) {
+    ram.data[..BLOCK_SIZE].fill(0xff);
+    let mut faults = FaultBlockDevice::new(ram);
+    faults.add_rule(rule("fail lba=3+2 op=read")).unwrap();
+    faults.add_rule(rule("flip lba=1 bit=9 times=1")).unwrap();
+    faults
+        .add_rule(rule("fail error=recovered op=write"))
+        .unwrap();
+
+    assert_eq!(read(&mut faults, 4), Err(BlockDeviceError::ReadError));
+    let mut blocks = vec![0; 4 * BLOCK_SIZE];
+    assert_eq!(
+        block_on(faults.read_blocks(2, &mut blocks)),
+        Err(BlockDeviceError::ReadError)
+    );
+    assert_eq!(read(&mut faults, 5).unwrap(), [0; BLOCK_SIZE]);
+    // writes still go ahead, reporting the recovered error
+    assert_eq!(
+        block_on(faults.write_block(3, &[3; BLOCK_SIZE])),
+        Err(BlockDeviceError::RecoveredWithRetries)
+    );
+
+    // the bit is flipped in the first read of block 1 only
+    let mut blocks = vec![0; 2 * BLOCK_SIZE];
+    block_on(faults.read_blocks(0, &mut blocks)).unwrap();
+    assert_eq!(blocks[..BLOCK_SIZE], [0xff; BLOCK_SIZE]);
+    assert_eq!(blocks[BLOCK_SIZE + 1], 0x02);
+    assert_eq!(read(&mut faults, 1).unwrap(), [0; BLOCK_SIZE]);
+}
+
+#[test]
+fn loads_the_script_from_the_filesystem() {
+    let mut image = vec![0; BLOCKS as usize * BLOCK_SIZE];
+    std_fatfs::format_volume(
+        Cursor::new(&mut image),
+        std_fatfs::FormatVolumeOptions::new(),
+    )
+    .unwrap();
+    {
+        let fs = std_fatfs::FileSystem::new(Cursor::new(&mut image), std_fatfs::FsOptions::new())
+            .unwrap();
+        let mut script = fs.root_dir().create_file("FAULTS.TXT").unwrap();
+        script
+            .write_all(b"# faults\nfail lba=100 op=read\nbogus\nyank\n")
+            .unwrap();
+    }
+
+    in_thread_mode(|| {
+        let mut faults = FaultBlockDevice::new(Ram::from(image));
+        // the yank opens on the first poll, without an access
+        assert_eq!(block_on(faults.media_status()), MediaStatus::Absent);
+        assert_eq!(
+            read(&mut faults, 0),
+            Err(BlockDeviceError::MediumNotPresent)
+        );
+
+        COMMANDS.try_send(FaultCommand::Clear).unwrap();
+        assert_eq!(block_on(faults.media_status()), MediaStatus::Changed);
+        read(&mut faults, 100).unwrap();
+
+        // the yank opens again, after the line that can't be parsed
+        COMMANDS.try_send(FaultCommand::Reload).unwrap();
+        assert_eq!(block_on(faults.media_status()), MediaStatus::Absent);
+    });
+}
+
+#[test]
+fn forwards_everything_else() {
+    let probe = in_thread_mode(|| {
+        let mut probe = Probe::new(64);
+        block_on(call_every_method(&mut FaultBlockDevice::new(&mut probe)));
+        probe
+    });
+    // blocks go to the base in runs
+    assert_eq!(probe.missed(), vec!["read_block", "write_block"]);
+}
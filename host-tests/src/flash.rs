@@ -0,0 +1,207 @@
+//! Runs the flash translation layer on the simulated flash, cutting the power part way through
+//! programs and erases
+
+use embassy_futures::block_on;
+
+use crate::block_devices::flash::{sector_count, FlashBlockDevice};
+use crate::nor_flash::{Operation, SimulatedNorFlash};
+use crate::ram::BLOCK_SIZE;
+use crate::scsi::BlockDevice;
+
+const ERASE_SIZE: usize = 4096;
+const BLOCKS: usize = 32;
+const SECTORS: usize = sector_count(BLOCKS, ERASE_SIZE);
+
+type Flash = SimulatedNorFlash<{ BLOCKS * ERASE_SIZE }>;
+type Ftl<'a> = FlashBlockDevice<&'a mut Flash, BLOCKS, SECTORS>;
+
+fn mount(flash: &mut Flash) -> Ftl<'_> {
+    FlashBlockDevice::mount(flash, 0).unwrap()
+}
+
+/// The contents of `lba` after its `version`th write, different for every sector and version
+fn sector(lba: u32, version: u32) -> [u8; BLOCK_SIZE] {
+    let mut sector = [0; BLOCK_SIZE];
+    for (i, chunk) in sector.chunks_exact_mut(8).enumerate() {
+        chunk[..4].copy_from_slice(&lba.to_le_bytes());
+        chunk[4..].copy_from_slice(&(version ^ i as u32).to_le_bytes());
+    }
+    sector
+}
+
+fn read(ftl: &mut Ftl, lba: u32) -> [u8; BLOCK_SIZE] {
+    let mut sector = [0; BLOCK_SIZE];
+    block_on(ftl.read_block(lba, &mut sector)).unwrap();
+    sector
+}
+
+/// Writes every sector, `versions[lba]` times over
+fn fill(ftl: &mut Ftl, versions: &[u32]) {
+    for (lba, &version) in versions.iter().enumerate() {
+        for version in 1..=version {
+            let lba = lba as u32;
+            block_on(ftl.write_block(lba, &sector(lba, version))).unwrap();
+        }
+    }
+}
+
+/// Checks that each sector holds its last version, or the zeros of one never written
+fn check(ftl: &mut Ftl, versions: &[u32]) {
+    for (lba, &version) in versions.iter().enumerate() {
+        let lba = lba as u32;
+        let expected = match version {
+            0 => [0; BLOCK_SIZE],
+            version => sector(lba, version),
+        };
+        assert!(
+            read(ftl, lba) == expected,
+            "lba {lba} isn't version {version}"
+        );
+    }
+}
+
+/// The erase count in the header of each block
+fn erase_counts(flash: &Flash) -> Vec<u32> {
+    flash
+        .as_bytes()
+        .chunks(ERASE_SIZE)
+        .map(|block| u32::from_le_bytes(block[4..8].try_into().unwrap()))
+        .collect()
+}
+
+/// A small xorshift, so the tests are the same every run
+fn random(state: &mut u32) -> u32 {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    *state
+}
+
+#[test]
+fn remount_keeps_the_sectors() {
+    let mut flash = Flash::new();
+    let mut versions = vec![0; SECTORS];
+    versions[..SECTORS / 2].fill(1);
+    versions[3] = 5;
+    fill(&mut mount(&mut flash), &versions);
+
+    let mut ftl = mount(&mut flash);
+    check(&mut ftl, &versions);
+    assert_eq!(ftl.block_count(), SECTORS as u32);
+}
+
+#[test]
+fn collects_garbage_on_a_full_disk() {
+    let mut flash = Flash::new();
+    let mut versions = vec![1; SECTORS];
+    let mut ftl = mount(&mut flash);
+    fill(&mut ftl, &versions);
+
+    let mut state = 1;
+    for _ in 0..20 * SECTORS {
+        let lba = random(&mut state) % SECTORS as u32;
+        versions[lba as usize] += 1;
+        block_on(ftl.write_block(lba, &sector(lba, versions[lba as usize]))).unwrap();
+    }
+    check(&mut ftl, &versions);
+
+    check(&mut mount(&mut flash), &versions);
+    // every block has been through the collection
+    assert!(erase_counts(&flash).iter().all(|&count| count > 1));
+}
+
+/// Writes the versions of each sector after `written` up to `versions`, stopping at the first
+/// write that fails and returning its lba
+fn write_versions(ftl: &mut Ftl, written: &mut [u32], versions: &[u32]) -> Result<(), u32> {
+    for (lba, &version) in versions.iter().enumerate() {
+        let lba = lba as u32;
+        while written[lba as usize] < version {
+            let next = written[lba as usize] + 1;
+            block_on(ftl.write_block(lba, &sector(lba, next))).map_err(|_| lba)?;
+            written[lba as usize] = next;
+        }
+    }
+    Ok(())
+}
+
+/// Cuts the power at each program and erase in turn while `versions` are written over a disk
+/// holding `before`, checking that the sectors written before the cut survive it, the one
+/// being written has either version and the disk can be written afterwards. Returns the
+/// operations that were cut
+fn cut_everywhere(before: &[u32], versions: &[u32]) -> Vec<Operation> {
+    let mut formatted = Flash::new();
+    fill(&mut mount(&mut formatted), before);
+
+    let mut cuts = Vec::new();
+    for operations in 0.. {
+        let mut flash = formatted.clone();
+        let mut written = before.to_vec();
+        flash.cut_power_after(operations);
+        let Err(cut) = write_versions(&mut mount(&mut flash), &mut written, versions) else {
+            // the writes got through without reaching the cut
+            return cuts;
+        };
+        cuts.push(flash.cut().unwrap());
+        flash.restore_power();
+
+        let mut ftl = mount(&mut flash);
+        let old = written[cut as usize];
+        let torn = read(&mut ftl, cut);
+        if torn == sector(cut, old + 1) {
+            written[cut as usize] = old + 1;
+        } else {
+            assert!(
+                torn == sector(cut, old),
+                "lba {cut} torn by a cut at operation {operations}"
+            );
+        }
+        check(&mut ftl, &written);
+        // the rest still fits, however much room was lost to the cut
+        write_versions(&mut ftl, &mut written, versions).unwrap();
+        check(&mut mount(&mut flash), versions);
+    }
+    unreachable!()
+}
+
+#[test]
+fn power_cut_while_programming() {
+    let before = vec![1; 20];
+    let versions = vec![3; 20];
+    let cuts = cut_everywhere(&before, &versions);
+    assert!(cuts.contains(&Operation::Program));
+}
+
+#[test]
+fn power_cut_while_collecting_garbage() {
+    // a full disk, so every block opened for the writes needs a collection first, which
+    // relocates the sectors that weren't rewritten
+    let before = vec![1; SECTORS];
+    let versions: Vec<_> = (0..SECTORS).map(|lba| 1 + lba as u32 % 2).collect();
+    let cuts = cut_everywhere(&before, &versions);
+    assert!(cuts.contains(&Operation::Erase));
+    assert!(cuts.contains(&Operation::Program));
+}
+
+#[test]
+fn erase_counts_converge_with_cold_data() {
+    let mut flash = Flash::new();
+    let mut ftl = mount(&mut flash);
+    // half the disk is written once and never again, the other half over and over, so the
+    // blocks garbage is collected from still hold valid sectors
+    let hot = SECTORS as u32 / 2;
+    fill(&mut ftl, &vec![1; SECTORS]);
+
+    let mut versions = vec![1; SECTORS];
+    let mut state = 7;
+    for _ in 0..40_000 {
+        let lba = random(&mut state) % hot;
+        versions[lba as usize] += 1;
+        block_on(ftl.write_block(lba, &sector(lba, versions[lba as usize]))).unwrap();
+    }
+    check(&mut ftl, &versions);
+
+    let counts = erase_counts(&flash);
+    let (min, max) = (counts.iter().min().unwrap(), counts.iter().max().unwrap());
+    assert!(max - min <= 2 * 32, "erase counts from {min} to {max}");
+    check(&mut mount(&mut flash), &versions);
+}
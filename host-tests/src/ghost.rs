@@ -0,0 +1,146 @@
+//! Lays out ghost FAT volumes and reads them back the way a host would
+
+use std::io::{Cursor, Read};
+
+use embassy_futures::block_on;
+
+use crate::block_devices::ghost::layout::{FatType, BLOCK_SIZE};
+use crate::block_devices::ghost::{Contents, GhostFatBlockDevice, GhostFile, PARTITION_LBA};
+use crate::scsi::{BlockDevice, BlockDeviceError};
+
+/// A byte of a generated file, which repeats every 251 bytes so it doesn't line up with
+/// blocks
+fn pattern(offset: u32, buf: &mut [u8]) {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = ((offset as usize + i) % 251) as u8;
+    }
+}
+
+/// Longer than a FAT12 block of entries, with a byte over, on a volume with one block
+/// clusters
+const LOG_SIZE: u32 = 400 * BLOCK_SIZE as u32 + 1;
+
+static FILES: [GhostFile; 7] = [
+    GhostFile::new("README.TXT", None, Contents::Static(b"hello")),
+    GhostFile::new("Logs", None, Contents::Directory),
+    GhostFile::new(
+        "Boot log with a name longer than one entry.txt",
+        Some(1),
+        Contents::Generated {
+            size: LOG_SIZE,
+            read: pattern,
+        },
+    ),
+    GhostFile::new("Deep", Some(1), Contents::Directory),
+    GhostFile::new("a.bin", Some(3), Contents::Static(&[1, 2, 3])),
+    GhostFile::new("empty", None, Contents::Static(b"")),
+    GhostFile::new("README.TXT.bak", None, Contents::Static(b"old")),
+];
+
+/// The disk as the host reads it
+fn image(device: &mut GhostFatBlockDevice) -> Vec<u8> {
+    let mut image = vec![0; device.block_count() as usize * BLOCK_SIZE];
+    for (lba, block) in image.chunks_mut(BLOCK_SIZE).enumerate() {
+        block_on(device.read_block(lba as u32, block)).unwrap();
+    }
+    image
+}
+
+fn mount(image: &mut [u8]) -> std_fatfs::FileSystem<Cursor<&mut [u8]>> {
+    let volume = &mut image[PARTITION_LBA as usize * BLOCK_SIZE..];
+    std_fatfs::FileSystem::new(Cursor::new(volume), std_fatfs::FsOptions::new()).unwrap()
+}
+
+fn contents(fs: &std_fatfs::FileSystem<Cursor<&mut [u8]>>, path: &str) -> Vec<u8> {
+    let mut contents = Vec::new();
+    let mut file = fs.root_dir().open_file(path).unwrap();
+    file.read_to_end(&mut contents).unwrap();
+    contents
+}
+
+#[test]
+fn the_host_reads_every_file() {
+    let mut device = GhostFatBlockDevice::new(&FILES, 4000, "GHOST", 0x1234_5678).unwrap();
+    assert_eq!(device.fat_type(), FatType::Fat12);
+    assert!(device.write_protected());
+    assert_eq!(
+        block_on(device.write_block(0, &[0; BLOCK_SIZE])),
+        Err(BlockDeviceError::WriteProtected)
+    );
+
+    let mut image = image(&mut device);
+    let fs = mount(&mut image);
+    assert_eq!(fs.fat_type(), std_fatfs::FatType::Fat12);
+    assert_eq!(fs.volume_label(), "GHOST");
+
+    let mut names: Vec<(String, String)> = fs
+        .root_dir()
+        .iter()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            (entry.file_name(), entry.short_file_name())
+        })
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        [
+            ("Logs", "LOGS~1"),
+            ("README.TXT", "README.TXT"),
+            ("README.TXT.bak", "README~1.BAK"),
+            ("empty", "EMPTY~1"),
+        ]
+        .map(|(long, short)| (long.to_string(), short.to_string()))
+    );
+
+    assert_eq!(contents(&fs, "README.TXT"), b"hello");
+    assert_eq!(contents(&fs, "empty"), b"");
+    assert_eq!(contents(&fs, "Logs/Deep/a.bin"), [1, 2, 3]);
+    // its chain crosses from the first block of the FAT into the second
+    let log = contents(&fs, "Logs/Boot log with a name longer than one entry.txt");
+    let mut expected = vec![0; LOG_SIZE as usize];
+    pattern(0, &mut expected);
+    assert_eq!(log, expected);
+
+    let deep = fs.root_dir().open_dir("Logs/Deep").unwrap();
+    let dots: Vec<String> = deep
+        .iter()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name.starts_with('.'))
+        .collect();
+    assert_eq!(dots, [".", ".."]);
+    assert_eq!(
+        deep.open_dir("..")
+            .unwrap()
+            .open_file("Deep/a.bin")
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap(),
+        3
+    );
+}
+
+#[test]
+fn fat12_up_to_4084_clusters() {
+    let mut seen = Vec::new();
+    // around 4085 clusters of a block each, whatever the FATs and root directory take
+    for block_count in 4130..4170 {
+        let mut device = GhostFatBlockDevice::new(&FILES, block_count, "", 1).unwrap();
+        let mut image = image(&mut device);
+        let fs = mount(&mut image);
+        let fat_type = match fs.fat_type() {
+            std_fatfs::FatType::Fat12 => FatType::Fat12,
+            std_fatfs::FatType::Fat16 => FatType::Fat16,
+            std_fatfs::FatType::Fat32 => panic!("FAT32 at {} blocks", block_count),
+        };
+        assert_eq!(device.fat_type(), fat_type, "{} blocks", block_count);
+        assert_eq!(contents(&fs, "Logs/Deep/a.bin"), [1, 2, 3]);
+        assert_eq!(
+            fs.stats().unwrap().total_clusters() > 4084,
+            fat_type == FatType::Fat16
+        );
+        seen.push(fat_type);
+    }
+    seen.dedup();
+    assert_eq!(seen, [FatType::Fat12, FatType::Fat16]);
+}
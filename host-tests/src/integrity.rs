@@ -0,0 +1,165 @@
+//! Checks every block read against the CRC kept for it
+
+use embassy_futures::block_on;
+
+use crate::block_devices::integrity::{protection_information, IntegrityBlockDevice};
+use crate::block_devices::sparse::SparseBlockDevice;
+use crate::block_devices::BLOCK_SIZE;
+use crate::probe::{call_every_method, Probe};
+use crate::ram::{read, write, Ram};
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus, PROTECTION_INFORMATION_BYTES};
+
+const PI: usize = PROTECTION_INFORMATION_BYTES;
+/// 128 blocks for the host and 2 of metadata
+const BLOCKS: u32 = 130;
+
+fn read_protection(device: &mut impl BlockDevice, lba: u32) -> [u8; PI] {
+    let mut block = [0; BLOCK_SIZE];
+    let mut pi = [0; PI];
+    block_on(device.read_blocks_protected(lba, &mut block, &mut pi)).unwrap();
+    pi
+}
+
+#[test]
+fn round_trip() {
+    let mut integrity = IntegrityBlockDevice::new(Ram::new(BLOCKS), true);
+    assert_eq!(integrity.block_count(), 128);
+
+    // zeros at LBA 0, whose protection information is all zeros too
+    write(&mut integrity, 0, 0).unwrap();
+    // across the two metadata blocks
+    let blocks: Vec<u8> = (0..4 * BLOCK_SIZE).map(|i| (i / 9) as u8).collect();
+    block_on(integrity.write_blocks(62, &blocks)).unwrap();
+    let mut read_back = vec![0; blocks.len()];
+    block_on(integrity.read_blocks(62, &mut read_back)).unwrap();
+    assert_eq!(read_back, blocks);
+
+    assert_eq!(read(&mut integrity, 0).unwrap(), [0; BLOCK_SIZE]);
+    assert_eq!(
+        read_protection(&mut integrity, 0),
+        protection_information(0, &[0; BLOCK_SIZE], 0)
+    );
+    assert_eq!(
+        read_protection(&mut integrity, 64),
+        protection_information(64, &blocks[2 * BLOCK_SIZE..3 * BLOCK_SIZE], 0)
+    );
+
+    // the host's application tag is kept
+    let block = [7; BLOCK_SIZE];
+    let pi = protection_information(5, &block, 0xbeef);
+    block_on(integrity.write_blocks_protected(5, &block, &pi)).unwrap();
+    assert_eq!(read_protection(&mut integrity, 5), pi);
+
+    // never written
+    assert_eq!(read(&mut integrity, 6).unwrap(), [0; BLOCK_SIZE]);
+    assert_eq!(read_protection(&mut integrity, 6), [0xff; PI]);
+    assert_eq!(
+        read(&mut integrity, 128),
+        Err(BlockDeviceError::InvalidAddress)
+    );
+}
+
+#[test]
+fn detects_corruption() {
+    let mut ram = Ram::new(BLOCKS);
+    let mut integrity = IntegrityBlockDevice::new(&mut ram, false);
+    write(&mut integrity, 0, 0).unwrap();
+    write(&mut integrity, 3, 0x33).unwrap();
+    write(&mut integrity, 100, 0x64).unwrap();
+
+    ram.data[7] = 1;
+    ram.data[3 * BLOCK_SIZE + 100] ^= 0x10;
+    // the reference tag of block 100 in the second metadata block
+    ram.data[129 * BLOCK_SIZE + 36 * PI + 7] ^= 1;
+
+    let mut integrity = IntegrityBlockDevice::new(&mut ram, false);
+    for lba in [0, 3, 100] {
+        assert_eq!(read(&mut integrity, lba), Err(BlockDeviceError::ReadError));
+    }
+    assert_eq!(read(&mut integrity, 4).unwrap(), [0; BLOCK_SIZE]);
+}
+
+#[test]
+fn unmapped_blocks_are_unchecked() {
+    let mut integrity = IntegrityBlockDevice::new(SparseBlockDevice::<8>::new(BLOCKS, 0), true);
+    write(&mut integrity, 10, 1).unwrap();
+    write(&mut integrity, 11, 2).unwrap();
+    block_on(integrity.unmap(10, 1)).unwrap();
+    assert_eq!(read(&mut integrity, 10).unwrap(), [0; BLOCK_SIZE]);
+    assert_eq!(read_protection(&mut integrity, 10), [0xff; PI]);
+    assert_eq!(read(&mut integrity, 11).unwrap(), [2; BLOCK_SIZE]);
+    assert_eq!(
+        block_on(integrity.unmap(127, 2)),
+        Err(BlockDeviceError::InvalidAddress)
+    );
+}
+
+/// A drive whose medium can be swapped for another
+struct Drive {
+    medium: Ram,
+    changed: bool,
+}
+
+impl BlockDevice for Drive {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.medium.read_block(lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.medium.write_block(lba, block).await
+    }
+
+    fn block_count(&self) -> u32 {
+        self.medium.block_count()
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        match core::mem::take(&mut self.changed) {
+            true => MediaStatus::Changed,
+            false => MediaStatus::Present,
+        }
+    }
+}
+
+#[test]
+fn a_new_medium_has_its_own_metadata() {
+    let mut integrity = IntegrityBlockDevice::new(
+        Drive {
+            medium: Ram::new(BLOCKS),
+            changed: false,
+        },
+        false,
+    );
+    write(&mut integrity, 0, 1).unwrap();
+
+    let mut medium = Ram::new(2 * BLOCKS);
+    medium.data[..BLOCK_SIZE].fill(2);
+    let drive = crate::scsi::Wrapper::base_mut(&mut integrity);
+    drive.medium = medium;
+    drive.changed = true;
+    assert_eq!(block_on(integrity.media_status()), MediaStatus::Changed);
+    assert_eq!(integrity.block_count(), 256);
+    // checked against the metadata of the new medium, where it was never written
+    assert_eq!(read(&mut integrity, 0).unwrap(), [2; BLOCK_SIZE]);
+    write(&mut integrity, 255, 3).unwrap();
+    assert_eq!(read(&mut integrity, 255).unwrap(), [3; BLOCK_SIZE]);
+}
+
+#[test]
+fn forwards_everything_else() {
+    let mut probe = Probe::new(130);
+    block_on(call_every_method(&mut IntegrityBlockDevice::new(
+        &mut probe, true,
+    )));
+    // it keeps the protection information itself
+    assert_eq!(
+        probe.missed(),
+        vec![
+            "protection_information",
+            "read_blocks_protected",
+            "write_blocks_protected"
+        ]
+    );
+}
@@ -0,0 +1,316 @@
+//! Cuts the power at every step of a journaled write command, and of the recovery after it,
+//! checking the disk always comes back with all of the command or none of it
+
+use std::collections::BTreeMap;
+
+use embassy_futures::block_on;
+
+use crate::block_devices::journal::JournalBlockDevice;
+use crate::probe::{call_every_method, Probe};
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus};
+
+const BLOCK_SIZE: usize = 512;
+const DISK_BLOCKS: u32 = 48;
+const JOURNAL_BLOCKS: usize = 8;
+/// The blocks the host can see
+const HOST_BLOCKS: u32 = DISK_BLOCKS - 1 - JOURNAL_BLOCKS as u32;
+
+/// A RAM disk that loses power after a number of steps, each a write, a flush or a block
+/// written back by a flush. Blocks written since the last flush are held in a volatile cache
+/// and lost with the power, unless `write_through` is set
+struct Disk {
+    medium: Vec<[u8; BLOCK_SIZE]>,
+    cache: BTreeMap<u32, [u8; BLOCK_SIZE]>,
+    write_through: bool,
+    /// Steps left until the power goes, `None` to never lose it
+    steps_left: Option<usize>,
+    steps: usize,
+}
+
+impl Disk {
+    fn new(write_through: bool) -> Self {
+        Self {
+            medium: (0..DISK_BLOCKS).map(old_block).collect(),
+            cache: BTreeMap::new(),
+            write_through,
+            steps_left: None,
+            steps: 0,
+        }
+    }
+
+    /// Counts a step, failing once the power has gone
+    fn step(&mut self) -> Result<(), BlockDeviceError> {
+        match &mut self.steps_left {
+            Some(0) => return Err(BlockDeviceError::NotReady),
+            Some(left) => *left -= 1,
+            None => {}
+        }
+        self.steps += 1;
+        Ok(())
+    }
+
+    /// Loses the cache and brings the power back
+    fn power_cycle(&mut self) {
+        self.cache.clear();
+        self.steps_left = None;
+    }
+}
+
+impl BlockDevice for Disk {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        if self.steps_left == Some(0) {
+            return Err(BlockDeviceError::NotReady);
+        }
+        let stored = self.cache.get(&lba).unwrap_or(&self.medium[lba as usize]);
+        block.copy_from_slice(stored);
+        Ok(())
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.step()?;
+        let block = block.try_into().unwrap();
+        if self.write_through {
+            self.medium[lba as usize] = block;
+        } else {
+            self.cache.insert(lba, block);
+        }
+        Ok(())
+    }
+
+    fn block_count(&self) -> u32 {
+        DISK_BLOCKS
+    }
+
+    /// Writes the cached blocks back one step at a time, in order of their address rather
+    /// than the order they were written
+    async fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        self.step()?;
+        while let Some((lba, block)) = self.cache.pop_first() {
+            self.step()?;
+            self.medium[lba as usize] = block;
+        }
+        Ok(())
+    }
+}
+
+fn old_block(lba: u32) -> [u8; BLOCK_SIZE] {
+    [lba as u8; BLOCK_SIZE]
+}
+
+fn new_block(lba: u32) -> [u8; BLOCK_SIZE] {
+    [0x80 | lba as u8; BLOCK_SIZE]
+}
+
+/// The writes of the command, as the SCSI layer would send them in chunks, with a block
+/// written twice
+const WRITES: [(u32, u32); 3] = [(2, 4), (6, 3), (3, 1)];
+
+async fn write_command(device: &mut impl BlockDevice) -> Result<(), BlockDeviceError> {
+    device.begin_transaction();
+    for (lba, count) in WRITES {
+        let blocks: Vec<u8> = (lba..lba + count).flat_map(new_block).collect();
+        if let Err(e) = device.write_blocks(lba, &blocks).await {
+            device.end_transaction(false).await?;
+            return Err(e);
+        }
+    }
+    device.end_transaction(true).await
+}
+
+fn written(lba: u32) -> bool {
+    WRITES
+        .iter()
+        .any(|&(start, count)| (start..start + count).contains(&lba))
+}
+
+/// Whether the host sees all of the command, or none of it
+async fn check_disk(device: &mut impl BlockDevice) -> bool {
+    let mut block = [0u8; BLOCK_SIZE];
+    let mut applied = Vec::new();
+    for lba in 0..HOST_BLOCKS {
+        device.read_block(lba, &mut block).await.unwrap();
+        if written(lba) {
+            applied.push(block == new_block(lba));
+            assert!(block == new_block(lba) || block == old_block(lba));
+        } else {
+            assert_eq!(block, old_block(lba));
+        }
+    }
+    assert!(
+        applied.iter().all(|&a| a == applied[0]),
+        "torn write: {:?}",
+        applied
+    );
+    applied[0]
+}
+
+/// Runs the command on a disk that loses power after `cut` steps, then brings it back
+fn cut_command(write_through: bool, cut: Option<usize>) -> Disk {
+    let mut disk = Disk::new(write_through);
+    disk.steps_left = cut;
+    let mut device = JournalBlockDevice::<_, JOURNAL_BLOCKS>::new(&mut disk);
+    let _ = block_on(write_command(&mut device));
+    disk.power_cycle();
+    disk
+}
+
+/// Boots from `disk`, which recovers the journal on the first access, losing power after
+/// `cut` steps, then brings it back
+fn cut_recovery(mut disk: Disk, cut: Option<usize>) -> Disk {
+    disk.steps = 0;
+    disk.steps_left = cut;
+    let mut device = JournalBlockDevice::<_, JOURNAL_BLOCKS>::new(&mut disk);
+    let mut block = [0u8; BLOCK_SIZE];
+    let _ = block_on(device.read_block(0, &mut block));
+    disk.power_cycle();
+    disk
+}
+
+/// Boots from `disk`, returning whether the command was applied
+fn applied(disk: Disk) -> bool {
+    let mut device = JournalBlockDevice::<_, JOURNAL_BLOCKS>::new(disk);
+    block_on(check_disk(&mut device))
+}
+
+#[test]
+fn power_cut_at_every_step() {
+    for write_through in [false, true] {
+        let steps = cut_command(write_through, None).steps;
+        assert!(applied(cut_command(write_through, None)));
+
+        let mut was_applied = false;
+        for cut in 0..steps {
+            let is_applied = applied(cut_command(write_through, Some(cut)));
+            // once the header is on the medium the command stays applied
+            assert!(is_applied || !was_applied, "lost at step {cut}");
+            was_applied = is_applied;
+        }
+        assert!(!applied(cut_command(write_through, Some(0))));
+    }
+}
+
+#[test]
+fn power_cut_during_recovery() {
+    for write_through in [false, true] {
+        let steps = cut_command(write_through, None).steps;
+        for cut in 0..steps {
+            let expected = applied(cut_command(write_through, Some(cut)));
+            let recovery_steps = cut_recovery(cut_command(write_through, Some(cut)), None).steps;
+            for recovery_cut in 0..recovery_steps {
+                let disk = cut_recovery(cut_command(write_through, Some(cut)), Some(recovery_cut));
+                assert_eq!(
+                    applied(disk),
+                    expected,
+                    "step {cut}, recovery step {recovery_cut}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn writes_outside_a_transaction_pass_through() {
+    let mut disk = Disk::new(true);
+    let mut device = JournalBlockDevice::<_, JOURNAL_BLOCKS>::new(&mut disk);
+    block_on(device.write_block(5, &new_block(5))).unwrap();
+    assert_eq!(disk.medium[5], new_block(5));
+    assert_eq!(disk.steps, 1);
+}
+
+#[test]
+fn transaction_too_big() {
+    let mut device = JournalBlockDevice::<_, JOURNAL_BLOCKS>::new(Disk::new(true));
+    assert_eq!(device.block_count(), HOST_BLOCKS);
+    device.begin_transaction();
+    let blocks = vec![0xaa; (JOURNAL_BLOCKS + 1) * BLOCK_SIZE];
+    assert_eq!(
+        block_on(device.write_blocks(0, &blocks)),
+        Err(BlockDeviceError::SpaceAllocationFailed)
+    );
+    block_on(device.end_transaction(false)).unwrap();
+    assert!(!block_on(check_disk(&mut device)));
+}
+
+/// A drive whose disk is swapped for another, found on the next media poll
+struct Drive {
+    disk: Disk,
+    changed: bool,
+}
+
+impl BlockDevice for Drive {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.disk.read_block(lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.disk.write_block(lba, block).await
+    }
+
+    fn block_count(&self) -> u32 {
+        self.disk.block_count()
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        match std::mem::take(&mut self.changed) {
+            true => MediaStatus::Changed,
+            false => MediaStatus::Present,
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        self.disk.flush().await
+    }
+}
+
+#[test]
+fn recovers_a_new_medium() {
+    // a disk with the command committed in its journal, but not yet in place
+    let steps = cut_command(true, None).steps;
+    let cut = (0..steps)
+        .find(|&cut| {
+            cut_command(true, Some(cut)).medium[2] == old_block(2)
+                && applied(cut_command(true, Some(cut)))
+        })
+        .unwrap();
+
+    let drive = Drive {
+        disk: Disk::new(true),
+        changed: false,
+    };
+    let mut device = JournalBlockDevice::<_, JOURNAL_BLOCKS>::new(drive);
+    assert!(!block_on(check_disk(&mut device)));
+
+    let drive = crate::scsi::Wrapper::base_mut(&mut device);
+    drive.disk = cut_command(true, Some(cut));
+    drive.changed = true;
+    assert_eq!(block_on(device.media_status()), MediaStatus::Changed);
+    assert!(block_on(check_disk(&mut device)));
+}
+
+#[test]
+fn forwards_everything_else() {
+    let mut probe = Probe::new(64);
+    block_on(call_every_method(&mut JournalBlockDevice::<
+        _,
+        JOURNAL_BLOCKS,
+    >::new(&mut probe)));
+    // single blocks are written through the journal, which keeps its transactions to itself
+    // and can't pass on protection information or a format of the base
+    assert_eq!(
+        probe.missed(),
+        vec![
+            "write_block",
+            "protection_information",
+            "read_blocks_protected",
+            "write_blocks_protected",
+            "transaction_blocks",
+            "begin_transaction",
+            "end_transaction",
+            "format"
+        ]
+    );
+}
@@ -0,0 +1,110 @@
+//! Tests of the parts of the firmware that don't need the Pico, run on the host. The modules
+//! under test are included from the firmware's sources, at the same paths. The firmware's
+//! cargo config builds for the Pico, so the host has to be asked for explicitly:
+//!
+//! ```text
+//! cargo test --manifest-path host-tests/Cargo.toml --target x86_64-unknown-linux-gnu
+//! ```
+#![cfg(test)]
+#![allow(dead_code)]
+
+#[path = "../../src/scsi"]
+mod scsi {
+    mod block_device;
+    pub mod trace;
+    pub use block_device::*;
+}
+
+#[path = "../../src/block_devices"]
+mod block_devices {
+    pub const BLOCK_SIZE: usize = 512;
+
+    pub mod cache;
+    pub mod concat;
+    pub mod encrypted;
+    pub(crate) mod fat;
+    pub mod fault;
+    pub mod flash;
+    pub mod ghost;
+    pub mod image;
+    pub mod integrity;
+    pub mod journal;
+    pub mod mirror;
+    pub mod overlay;
+    pub mod packed;
+    pub mod partition;
+    pub mod sd;
+    pub mod snapshot;
+    pub mod sparse;
+    pub mod stripe;
+    pub mod trace;
+    pub mod watch;
+}
+
+#[path = "../../src/fat12_partition"]
+mod fat12_partition {
+    mod mbr;
+    mod table;
+    pub use mbr::*;
+    pub use table::*;
+}
+
+#[path = "../../src/display.rs"]
+mod display;
+
+mod cache;
+mod concat;
+mod encrypted;
+mod fault;
+mod flash;
+mod ghost;
+mod integrity;
+mod journal;
+mod mirror;
+mod nor_flash;
+mod overlay;
+mod packed;
+mod partition;
+mod probe;
+mod ram;
+mod sd;
+mod snapshot;
+mod sparse;
+mod stripe;
+mod table;
+mod trace;
+mod watch;
+
+/// Runs `f` on a thread named `main`, which embassy-sync takes as the Pico's thread mode, for
+/// the firmware's statics behind a `ThreadModeRawMutex`. Only one test runs in thread mode at
+/// a time, as only one thread could on the Pico
+fn in_thread_mode<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    static THREAD_MODE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let _thread_mode = THREAD_MODE.lock().unwrap_or_else(|e| e.into_inner());
+    std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new().name("main".into());
+        match thread.spawn_scoped(scope, f).unwrap().join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    })
+}
+
+/// Drops the firmware's logs
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("{=u8}", 0);
+
+/// Fails the test on the firmware's `defmt` panics and asserts
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
@@ -0,0 +1,275 @@
+//! Mirrors two drives, losing and resyncing members along the way
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use embassy_futures::block_on;
+
+use crate::block_devices::mirror::{
+    Member, MirrorBlockDevice, MirrorStatus, RESYNC_BLOCKS_PER_POLL,
+};
+use crate::in_thread_mode;
+use crate::ram::{disk, read, Ram, BLOCK_SIZE};
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus};
+
+/// Resynced over two media polls
+const BLOCKS: u32 = 2 * RESYNC_BLOCKS_PER_POLL;
+
+#[derive(Default)]
+struct DriveState {
+    medium: Option<Ram>,
+    /// Found on the next media poll, like an SD card that's initialised then
+    inserted: Option<Ram>,
+    /// Blocks that fail to read until they're written
+    bad: Vec<u32>,
+    fail_writes: bool,
+}
+
+/// A drive whose medium can be swapped and made to fail, shared with the test
+#[derive(Clone, Default)]
+struct Drive(Rc<RefCell<DriveState>>);
+
+impl Drive {
+    fn with(medium: Ram) -> Self {
+        let drive = Self::default();
+        drive.0.borrow_mut().medium = Some(medium);
+        drive
+    }
+
+    fn insert(&self, medium: Ram) {
+        self.0.borrow_mut().inserted = Some(medium);
+    }
+
+    fn remove(&self) {
+        self.0.borrow_mut().medium = None;
+    }
+
+    fn block(&self, lba: u32) -> Vec<u8> {
+        self.0.borrow().medium.as_ref().unwrap().block(lba).to_vec()
+    }
+
+    fn writes(&self) -> usize {
+        self.0
+            .borrow()
+            .medium
+            .as_ref()
+            .map_or(0, |medium| medium.writes)
+    }
+}
+
+impl BlockDevice for Drive {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.read_blocks(lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_blocks(lba, block).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let state = self.0.borrow();
+        let medium = state
+            .medium
+            .as_ref()
+            .ok_or(BlockDeviceError::MediumNotPresent)?;
+        let count = (blocks.len() / BLOCK_SIZE) as u32;
+        if state.bad.iter().any(|bad| (lba..lba + count).contains(bad)) {
+            return Err(BlockDeviceError::ReadError);
+        }
+        blocks.copy_from_slice(&medium.data[lba as usize * BLOCK_SIZE..][..blocks.len()]);
+        Ok(())
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        let mut state = self.0.borrow_mut();
+        if state.fail_writes {
+            return Err(BlockDeviceError::WriteError);
+        }
+        let count = (blocks.len() / BLOCK_SIZE) as u32;
+        state.bad.retain(|bad| !(lba..lba + count).contains(bad));
+        let medium = state
+            .medium
+            .as_mut()
+            .ok_or(BlockDeviceError::MediumNotPresent)?;
+        medium.data[lba as usize * BLOCK_SIZE..][..blocks.len()].copy_from_slice(blocks);
+        medium.writes += count as usize;
+        Ok(())
+    }
+
+    fn block_count(&self) -> u32 {
+        self.0.borrow().medium.as_ref().map_or(0, Ram::block_count)
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        let mut state = self.0.borrow_mut();
+        if let Some(medium) = state.inserted.take() {
+            state.medium = Some(medium);
+            return MediaStatus::Changed;
+        }
+        match state.medium {
+            Some(_) => MediaStatus::Present,
+            None => MediaStatus::Absent,
+        }
+    }
+}
+
+#[test]
+fn the_secondary_joins_once_it_has_a_medium() {
+    in_thread_mode(|| {
+        let primary = Drive::with(disk(BLOCKS));
+        // the card's size isn't known until the first media poll
+        let secondary = Drive::default();
+        secondary.insert(disk(BLOCKS));
+        let mut mirror = MirrorBlockDevice::new(primary.clone(), secondary.clone());
+        assert_eq!(mirror.block_count(), BLOCKS);
+
+        assert_eq!(block_on(mirror.media_status()), MediaStatus::Present);
+        assert_eq!(
+            mirror.status(),
+            MirrorStatus::Resyncing {
+                member: Member::Secondary,
+                percent: 50
+            }
+        );
+        block_on(mirror.media_status());
+        assert_eq!(mirror.status(), MirrorStatus::InSync);
+        // even if it holds the same data, there's no telling
+        assert_eq!(secondary.writes(), BLOCKS as usize);
+        block_on(mirror.write_block(5, &[0xaa; BLOCK_SIZE])).unwrap();
+        assert_eq!(primary.block(5), [0xaa; BLOCK_SIZE]);
+        assert_eq!(secondary.block(5), [0xaa; BLOCK_SIZE]);
+
+        // a card smaller than the mirror never joins
+        let small = Drive::default();
+        small.insert(Ram::new(BLOCKS - 1));
+        let mut mirror = MirrorBlockDevice::new(Drive::with(disk(BLOCKS)), small.clone());
+        block_on(mirror.media_status());
+        assert_eq!(mirror.status(), MirrorStatus::Degraded(Member::Secondary));
+        assert_eq!(
+            block_on(mirror.write_block(0, &[1; BLOCK_SIZE])),
+            Err(BlockDeviceError::Degraded)
+        );
+        assert_eq!(small.writes(), 0);
+    });
+}
+
+#[test]
+fn degrades_and_repairs() {
+    in_thread_mode(|| {
+        let primary = Drive::with(disk(BLOCKS));
+        let secondary = Drive::with(disk(BLOCKS));
+        let mut mirror = MirrorBlockDevice::new(primary.clone(), secondary.clone());
+        block_on(mirror.media_status());
+        block_on(mirror.media_status());
+        assert_eq!(mirror.status(), MirrorStatus::InSync);
+
+        // a bad block on the primary is read from the secondary and rewritten
+        primary.0.borrow_mut().bad.push(7);
+        assert_eq!(read(&mut mirror, 7).unwrap(), [7; BLOCK_SIZE]);
+        assert!(primary.0.borrow().bad.is_empty());
+        assert_eq!(primary.writes(), 1);
+        assert_eq!(mirror.status(), MirrorStatus::InSync);
+
+        // a failed write drops the secondary, which the host is told about once
+        secondary.0.borrow_mut().fail_writes = true;
+        assert_eq!(
+            block_on(mirror.write_block(3, &[0x33; BLOCK_SIZE])),
+            Err(BlockDeviceError::Degraded)
+        );
+        assert_eq!(mirror.status(), MirrorStatus::Degraded(Member::Secondary));
+        block_on(mirror.write_block(4, &[0x44; BLOCK_SIZE])).unwrap();
+        assert_eq!(primary.block(3), [0x33; BLOCK_SIZE]);
+        assert_eq!(read(&mut mirror, 4).unwrap(), [0x44; BLOCK_SIZE]);
+
+        // and with the primary gone too, there's nothing left
+        primary.remove();
+        assert_eq!(block_on(mirror.media_status()), MediaStatus::Absent);
+        assert_eq!(mirror.status(), MirrorStatus::Failed);
+        let mut block = [0; BLOCK_SIZE];
+        assert_eq!(
+            block_on(mirror.read_block(4, &mut block)),
+            Err(BlockDeviceError::MediumNotPresent)
+        );
+    });
+}
+
+#[test]
+fn resyncs_a_new_medium() {
+    in_thread_mode(|| {
+        let primary = Drive::with(disk(BLOCKS));
+        let secondary = Drive::with(disk(BLOCKS));
+        let mut mirror = MirrorBlockDevice::new(primary.clone(), secondary.clone());
+        block_on(mirror.media_status());
+        block_on(mirror.media_status());
+
+        secondary.remove();
+        assert_eq!(block_on(mirror.media_status()), MediaStatus::Present);
+        assert_eq!(mirror.status(), MirrorStatus::Degraded(Member::Secondary));
+        // written while the secondary is missing
+        assert_eq!(
+            block_on(mirror.write_block(BLOCKS - 1, &[0xee; BLOCK_SIZE])),
+            Err(BlockDeviceError::Degraded)
+        );
+
+        secondary.insert(Ram::new(BLOCKS));
+        block_on(mirror.media_status());
+        assert_eq!(
+            mirror.status(),
+            MirrorStatus::Resyncing {
+                member: Member::Secondary,
+                percent: 50
+            }
+        );
+        assert_eq!(secondary.writes(), RESYNC_BLOCKS_PER_POLL as usize);
+        // written to both ahead of the resync
+        block_on(mirror.write_block(BLOCKS - 2, &[0xdd; BLOCK_SIZE])).unwrap();
+        assert_eq!(secondary.block(BLOCKS - 2), [0xdd; BLOCK_SIZE]);
+
+        // a bad block on the primary can't come from the part not yet resynced
+        primary.0.borrow_mut().bad.push(BLOCKS - 3);
+        let mut block = [0; BLOCK_SIZE];
+        assert_eq!(
+            block_on(mirror.read_block(BLOCKS - 3, &mut block)),
+            Err(BlockDeviceError::ReadError)
+        );
+        primary.0.borrow_mut().bad.clear();
+
+        block_on(mirror.media_status());
+        assert_eq!(mirror.status(), MirrorStatus::InSync);
+        for lba in 0..BLOCKS {
+            assert_eq!(secondary.block(lba), primary.block(lba), "lba {}", lba);
+        }
+        assert_eq!(secondary.block(BLOCKS - 1), [0xee; BLOCK_SIZE]);
+    });
+}
+
+#[test]
+fn resyncs_a_blank_secondary_that_joins_after_writes() {
+    in_thread_mode(|| {
+        let primary = Drive::with(disk(BLOCKS));
+        let secondary = Drive::default();
+        let mut mirror = MirrorBlockDevice::new(primary.clone(), secondary.clone());
+        // before the first media poll finds the card
+        block_on(mirror.write_block(2, &[0x22; BLOCK_SIZE])).unwrap();
+        block_on(mirror.write_block(BLOCKS - 1, &[0xee; BLOCK_SIZE])).unwrap();
+        assert_eq!(mirror.status(), MirrorStatus::Degraded(Member::Secondary));
+
+        secondary.insert(Ram::new(BLOCKS));
+        block_on(mirror.media_status());
+        assert_eq!(
+            mirror.status(),
+            MirrorStatus::Resyncing {
+                member: Member::Secondary,
+                percent: 50
+            }
+        );
+        block_on(mirror.media_status());
+        assert_eq!(mirror.status(), MirrorStatus::InSync);
+        for lba in 0..BLOCKS {
+            assert_eq!(secondary.block(lba), primary.block(lba), "lba {}", lba);
+        }
+        assert_eq!(secondary.block(2), [0x22; BLOCK_SIZE]);
+    });
+}
@@ -0,0 +1,216 @@
+//! An in-memory NOR flash for exercising the FTL.
+//!
+//! Writes can only clear bits (programming a byte that isn't erased is reported as an error,
+//! since that is a bug in the FTL) and a power cut can be scheduled to hit after a number of
+//! program/erase operations. The operation that is cut is only partially applied, and every
+//! operation after it fails until [`SimulatedNorFlash::restore_power`] is called, after which
+//! the FTL can be mounted again to check what survived.
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimulatedFlashError {
+    /// The operation is outside of the flash or not aligned
+    OutOfBounds,
+    /// A write tried to set bits that weren't erased
+    NotErased,
+    /// The power was cut during or before this operation
+    PowerCut,
+}
+
+impl NorFlashError for SimulatedFlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            SimulatedFlashError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            SimulatedFlashError::NotErased | SimulatedFlashError::PowerCut => {
+                NorFlashErrorKind::Other
+            }
+        }
+    }
+}
+
+/// A program or an erase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Program,
+    Erase,
+}
+
+/// `SIZE` bytes of NOR flash with 4KiB erase blocks, matching the RP2040's QSPI flash
+#[derive(Clone)]
+pub struct SimulatedNorFlash<const SIZE: usize> {
+    data: [u8; SIZE],
+    /// Program/erase operations left before the power is cut
+    power_cut_after: Option<usize>,
+    powered: bool,
+    /// The operation the power was cut during
+    cut: Option<Operation>,
+    operations: usize,
+    erases: usize,
+}
+
+impl<const SIZE: usize> SimulatedNorFlash<SIZE> {
+    /// A fully erased flash
+    pub const fn new() -> Self {
+        Self {
+            data: [0xff; SIZE],
+            power_cut_after: None,
+            powered: true,
+            cut: None,
+            operations: 0,
+            erases: 0,
+        }
+    }
+
+    /// Cut the power during the program/erase operation `operations` from now. Zero cuts the
+    /// next operation before it changes anything
+    pub fn cut_power_after(&mut self, operations: usize) {
+        self.power_cut_after = Some(operations);
+    }
+
+    /// Power the flash back up, keeping whatever made it to the cells
+    pub fn restore_power(&mut self) {
+        self.power_cut_after = None;
+        self.powered = true;
+        self.cut = None;
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.powered
+    }
+
+    /// The operation the power was cut during, until it's restored
+    pub fn cut(&self) -> Option<Operation> {
+        self.cut
+    }
+
+    /// Number of program/erase operations completed since creation
+    pub fn operations(&self) -> usize {
+        self.operations
+    }
+
+    /// Number of block erases completed since creation
+    pub fn erases(&self) -> usize {
+        self.erases
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns how much of `operation` on `len` bytes gets applied before the power goes
+    fn start_operation(
+        &mut self,
+        operation: Operation,
+        len: usize,
+    ) -> Result<usize, SimulatedFlashError> {
+        if !self.powered {
+            return Err(SimulatedFlashError::PowerCut);
+        }
+        match self.power_cut_after {
+            Some(0) => {
+                self.powered = false;
+                self.cut = Some(operation);
+                // a torn operation gets roughly half way
+                Ok(len / 2)
+            }
+            Some(n) => {
+                self.power_cut_after = Some(n - 1);
+                Ok(len)
+            }
+            None => Ok(len),
+        }
+    }
+
+    fn check_bounds(
+        &self,
+        offset: u32,
+        len: usize,
+        align: usize,
+    ) -> Result<(), SimulatedFlashError> {
+        let offset = offset as usize;
+        if !offset.is_multiple_of(align) || offset + len > SIZE {
+            return Err(SimulatedFlashError::OutOfBounds);
+        }
+        Ok(())
+    }
+}
+
+impl<const SIZE: usize> Default for SimulatedNorFlash<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SIZE: usize> ErrorType for SimulatedNorFlash<SIZE> {
+    type Error = SimulatedFlashError;
+}
+
+impl<const SIZE: usize> ReadNorFlash for SimulatedNorFlash<SIZE> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        if !self.powered {
+            return Err(SimulatedFlashError::PowerCut);
+        }
+        self.check_bounds(offset, bytes.len(), Self::READ_SIZE)?;
+
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+impl<const SIZE: usize> NorFlash for SimulatedNorFlash<SIZE> {
+    const WRITE_SIZE: usize = 1;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if to < from {
+            return Err(SimulatedFlashError::OutOfBounds);
+        }
+        self.check_bounds(from, (to - from) as usize, Self::ERASE_SIZE)?;
+        self.check_bounds(to, 0, Self::ERASE_SIZE)?;
+
+        let len = self.start_operation(Operation::Erase, (to - from) as usize)?;
+        let from = from as usize;
+        self.data[from..from + len].fill(0xff);
+        if !self.powered {
+            return Err(SimulatedFlashError::PowerCut);
+        }
+
+        self.operations += 1;
+        self.erases += (to as usize - from) / Self::ERASE_SIZE;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        if !self.powered {
+            return Err(SimulatedFlashError::PowerCut);
+        }
+        self.check_bounds(offset, bytes.len(), Self::WRITE_SIZE)?;
+
+        let offset = offset as usize;
+        let target = &self.data[offset..offset + bytes.len()];
+        if target.iter().zip(bytes).any(|(old, new)| old & new != *new) {
+            return Err(SimulatedFlashError::NotErased);
+        }
+
+        let len = self.start_operation(Operation::Program, bytes.len())?;
+        for (cell, byte) in self.data[offset..offset + len].iter_mut().zip(bytes) {
+            *cell &= byte;
+        }
+        if !self.powered {
+            return Err(SimulatedFlashError::PowerCut);
+        }
+
+        self.operations += 1;
+        Ok(())
+    }
+}
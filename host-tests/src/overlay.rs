@@ -0,0 +1,153 @@
+//! Writes to an overlay over an image, then commits or discards the changes
+
+use embassy_futures::block_on;
+
+use crate::block_devices::image::ImageBlockDevice;
+use crate::block_devices::overlay::{OverlayBlockDevice, OverlayCommand, COMMANDS};
+use crate::in_thread_mode;
+use crate::probe::{call_every_method, Probe};
+use crate::ram::{read, write, Ram, BLOCK_SIZE};
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus};
+
+const BLOCKS: u32 = 16;
+const SLOTS: usize = 4;
+
+/// The base contents, different for every block
+fn base(lba: u32) -> [u8; BLOCK_SIZE] {
+    [lba as u8; BLOCK_SIZE]
+}
+
+fn image() -> Vec<u8> {
+    (0..BLOCKS).flat_map(base).collect()
+}
+
+/// An image that stays in memory for good, as the one in flash does
+fn flash_image() -> ImageBlockDevice {
+    ImageBlockDevice::new(Box::leak(image().into_boxed_slice()))
+}
+
+#[test]
+fn keeps_the_writes_in_the_delta() {
+    let mut ram = Ram::from(image());
+    let mut overlay = OverlayBlockDevice::<_, SLOTS>::new(&mut ram);
+
+    write(&mut overlay, 3, 0xa0).unwrap();
+    let blocks = [0xb0; 2 * BLOCK_SIZE];
+    block_on(overlay.write_blocks(7, &blocks)).unwrap();
+    assert_eq!(overlay.delta_len(), 3);
+    assert_eq!(read(&mut overlay, 3).unwrap(), [0xa0; BLOCK_SIZE]);
+    let mut read_back = [0; 3 * BLOCK_SIZE];
+    block_on(overlay.read_blocks(6, &mut read_back)).unwrap();
+    assert_eq!(read_back[..BLOCK_SIZE], base(6));
+    assert_eq!(read_back[BLOCK_SIZE..], blocks);
+
+    // written back to what the base holds, it takes no room
+    block_on(overlay.write_block(3, &base(3))).unwrap();
+    block_on(overlay.write_block(4, &base(4))).unwrap();
+    assert_eq!(overlay.delta_len(), 2);
+    assert_eq!(read(&mut overlay, 3).unwrap(), base(3));
+
+    assert_eq!(
+        block_on(overlay.read_blocks(BLOCKS - 1, &mut read_back)),
+        Err(BlockDeviceError::InvalidAddress)
+    );
+    assert_eq!(
+        block_on(overlay.write_blocks(BLOCKS - 1, &blocks)),
+        Err(BlockDeviceError::InvalidAddress)
+    );
+    assert_eq!(ram.writes, 0);
+    assert_eq!(ram.data, image());
+}
+
+#[test]
+fn fails_writes_once_the_delta_is_full() {
+    let mut overlay = OverlayBlockDevice::<_, SLOTS>::new(flash_image());
+    for lba in 0..SLOTS as u32 {
+        write(&mut overlay, lba, 0xc0).unwrap();
+    }
+    assert_eq!(
+        write(&mut overlay, 10, 0xc0),
+        Err(BlockDeviceError::SpaceAllocationFailed)
+    );
+    assert_eq!(read(&mut overlay, 10).unwrap(), base(10));
+    // blocks already in the delta can still change
+    write(&mut overlay, 2, 0xd0).unwrap();
+    assert_eq!(read(&mut overlay, 2).unwrap(), [0xd0; BLOCK_SIZE]);
+}
+
+#[test]
+fn discard_goes_back_to_the_image() {
+    in_thread_mode(|| {
+        let mut overlay = OverlayBlockDevice::<_, SLOTS>::new(flash_image());
+        write(&mut overlay, 5, 0xe0).unwrap();
+        assert_eq!(block_on(overlay.media_status()), MediaStatus::Present);
+
+        COMMANDS.signal(OverlayCommand::Discard);
+        // the host is told its view of the disk changed
+        assert_eq!(block_on(overlay.media_status()), MediaStatus::Changed);
+        assert_eq!(block_on(overlay.media_status()), MediaStatus::Present);
+        assert_eq!(overlay.delta_len(), 0);
+        assert_eq!(read(&mut overlay, 5).unwrap(), base(5));
+    });
+}
+
+#[test]
+fn commit_writes_the_delta_to_the_base() {
+    in_thread_mode(|| {
+        let mut ram = Ram::from(image());
+        let mut overlay = OverlayBlockDevice::<_, SLOTS>::new(&mut ram);
+        write(&mut overlay, 1, 0xf0).unwrap();
+        write(&mut overlay, 9, 0xf1).unwrap();
+
+        COMMANDS.signal(OverlayCommand::Commit);
+        assert_eq!(block_on(overlay.media_status()), MediaStatus::Present);
+        assert_eq!(overlay.delta_len(), 0);
+        assert_eq!(read(&mut overlay, 9).unwrap(), [0xf1; BLOCK_SIZE]);
+        assert_eq!(ram.block(1), [0xf0; BLOCK_SIZE]);
+        assert_eq!(ram.block(9), [0xf1; BLOCK_SIZE]);
+        assert_eq!(ram.writes, 2);
+    });
+}
+
+#[test]
+fn commit_to_a_write_protected_image_keeps_the_delta() {
+    let mut overlay = OverlayBlockDevice::<_, SLOTS>::new(flash_image());
+    assert!(!overlay.write_protected());
+    write(&mut overlay, 1, 0xf0).unwrap();
+    assert_eq!(
+        block_on(overlay.commit()),
+        Err(BlockDeviceError::WriteProtected)
+    );
+    assert_eq!(overlay.delta_len(), 1);
+    assert_eq!(read(&mut overlay, 1).unwrap(), [0xf0; BLOCK_SIZE]);
+}
+
+#[test]
+fn forwards_what_it_doesnt_keep_itself() {
+    let mut probe = Probe::new(64);
+    in_thread_mode(|| {
+        block_on(call_every_method(&mut OverlayBlockDevice::<_, SLOTS>::new(
+            &mut probe,
+        )))
+    });
+    // blocks are read from the base one at a time, and none are written to it
+    assert_eq!(
+        probe.missed(),
+        [
+            "write_block",
+            "read_blocks",
+            "write_blocks",
+            "write_blocks_fua",
+            "write_protected",
+            "provisioning",
+            "unmap",
+            "protection_information",
+            "read_blocks_protected",
+            "write_blocks_protected",
+            "transaction_blocks",
+            "begin_transaction",
+            "end_transaction",
+            "format",
+        ]
+    );
+}
@@ -0,0 +1,151 @@
+//! Packs blocks the way `build.rs` does and reads them back
+
+use embassy_futures::block_on;
+
+use crate::block_devices::packed::format::{compress, decompress, BLOCK_SIZE, MAGIC};
+use crate::block_devices::packed::PackedBlockDevice;
+use crate::scsi::{BlockDevice, BlockDeviceError};
+
+/// Bytes that don't compress, from an xorshift generator
+fn noise(seed: u32, len: usize) -> Vec<u8> {
+    let mut state = seed.max(1);
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+/// One block of each kind the packer meets
+fn blocks() -> Vec<[u8; BLOCK_SIZE]> {
+    let mut blocks = vec![[0; BLOCK_SIZE], [0xff; BLOCK_SIZE]];
+
+    let mut text = [0; BLOCK_SIZE];
+    let sentence = b"The quick brown fox jumps over the lazy dog. ";
+    for (byte, &letter) in text.iter_mut().zip(sentence.iter().cycle()) {
+        *byte = letter;
+    }
+    blocks.push(text);
+
+    // a FAT boot sector: a little code, a long run of zeros and the signature
+    let mut boot = [0; BLOCK_SIZE];
+    boot[..62].copy_from_slice(&noise(1, 62));
+    boot[510..].copy_from_slice(&[0x55, 0xaa]);
+    blocks.push(boot);
+
+    // runs and literals longer than a token can hold
+    let mut mixed = [0; BLOCK_SIZE];
+    mixed[..200].copy_from_slice(&noise(2, 200));
+    mixed[200..450].fill(0x42);
+    mixed[450..].copy_from_slice(&noise(3, 62));
+    blocks.push(mixed);
+
+    // a match as far back as the block allows
+    let mut far = [0; BLOCK_SIZE];
+    far.copy_from_slice(&noise(4, BLOCK_SIZE));
+    far.copy_within(..8, BLOCK_SIZE - 8);
+    blocks.push(far);
+
+    blocks.push(noise(5, BLOCK_SIZE).try_into().unwrap());
+    blocks
+}
+
+#[test]
+fn round_trip() {
+    for (i, block) in blocks().iter().enumerate() {
+        let mut stored = [0; BLOCK_SIZE];
+        let len = compress(block, &mut stored);
+        let mut restored = [0xa5; BLOCK_SIZE];
+        decompress(&stored[..len], &mut restored).unwrap();
+        assert_eq!(restored, *block, "block {i}");
+    }
+}
+
+#[test]
+fn stores_blocks_in_as_little_as_it_can() {
+    let blocks = blocks();
+    let mut stored = [0; BLOCK_SIZE];
+    assert_eq!(compress(&blocks[0], &mut stored), 0);
+    // a run is one literal then matches with an offset of 1
+    assert!(compress(&blocks[1], &mut stored) < 16);
+    assert!(compress(&blocks[2], &mut stored) < 64);
+    assert!(compress(&blocks[3], &mut stored) < 100);
+    // noise is kept as it is
+    let len = compress(blocks.last().unwrap(), &mut stored);
+    assert_eq!(len, BLOCK_SIZE);
+    assert_eq!(stored, *blocks.last().unwrap());
+}
+
+#[test]
+fn rejects_corrupt_blocks() {
+    let text = blocks()[2];
+    let mut stored = [0; BLOCK_SIZE];
+    let len = compress(&text, &mut stored);
+    let mut out = [0; BLOCK_SIZE];
+
+    // cut short, a literal runs off the end or the block comes out short
+    for cut in 1..len {
+        assert_eq!(
+            decompress(&stored[..cut], &mut out),
+            Err(()),
+            "cut at {cut}"
+        );
+    }
+    // a match before the start of the block
+    assert_eq!(decompress(&[0x80, 1, 0], &mut out), Err(()));
+    assert_eq!(decompress(&[0x00, 7, 0x80, 0, 0], &mut out), Err(()));
+    // more than a block
+    let mut long = stored[..len].to_vec();
+    long.extend_from_slice(&[0x00, 7]);
+    assert_eq!(decompress(&long, &mut out), Err(()));
+}
+
+/// Lays out an image as `build.rs` does
+fn pack(image: &[u8]) -> Vec<u8> {
+    let blocks = image.len() / BLOCK_SIZE;
+    let mut index = Vec::new();
+    let mut data = Vec::new();
+    let mut stored = [0; BLOCK_SIZE];
+    for block in image.chunks_exact(BLOCK_SIZE) {
+        index.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        let len = compress(block.try_into().unwrap(), &mut stored);
+        data.extend_from_slice(&stored[..len]);
+    }
+    index.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+    let mut packed = MAGIC.to_vec();
+    packed.extend_from_slice(&(blocks as u32).to_le_bytes());
+    packed.extend(index);
+    packed.extend(data);
+    packed
+}
+
+#[test]
+fn reads_a_packed_image() {
+    let image: Vec<u8> = blocks().concat();
+    let packed: &'static [u8] = Box::leak(pack(&image).into_boxed_slice());
+    assert!(packed.len() < image.len());
+    let mut device = PackedBlockDevice::new(packed).unwrap();
+    assert_eq!(device.block_count(), blocks().len() as u32);
+
+    let mut read = vec![0; image.len()];
+    block_on(device.read_blocks(0, &mut read)).unwrap();
+    assert_eq!(read, image);
+    let mut block = [0; BLOCK_SIZE];
+    assert_eq!(
+        block_on(device.read_block(device.block_count(), &mut block)),
+        Err(BlockDeviceError::InvalidAddress)
+    );
+    assert_eq!(
+        block_on(device.write_block(0, &block)),
+        Err(BlockDeviceError::WriteProtected)
+    );
+    assert!(device.write_protected());
+
+    assert!(PackedBlockDevice::new(image.leak()).is_none());
+    // the header and part of the index
+    assert!(PackedBlockDevice::new(&packed[..12]).is_none());
+}
@@ -0,0 +1,142 @@
+//! Reaches one partition of a disk, and nothing else, through the partition view
+
+use embassy_futures::block_on;
+
+use crate::block_devices::integrity::{protection_information, IntegrityBlockDevice};
+use crate::block_devices::partition::{PartitionBlockDevice, PartitionError};
+use crate::block_devices::BLOCK_SIZE;
+use crate::probe::{call_every_method, Probe};
+use crate::ram::Ram;
+use crate::scsi::{BlockDevice, BlockDeviceError, PROTECTION_INFORMATION_BYTES};
+
+const BLOCKS: u32 = 256;
+const START: u32 = 100;
+const SIZE: u32 = 50;
+
+/// A disk with the second primary partition, of type FAT16, at `START`
+fn disk() -> Ram {
+    let mut ram = Ram::new(BLOCKS);
+    let entry = &mut ram.data[446 + 16..][..16];
+    entry[4] = 0x06;
+    entry[8..12].copy_from_slice(&START.to_le_bytes());
+    entry[12..16].copy_from_slice(&SIZE.to_le_bytes());
+    ram.data[510..512].copy_from_slice(&[0x55, 0xaa]);
+    ram
+}
+
+#[test]
+fn finds_the_partition_in_the_mbr() {
+    let mut ram = disk();
+    let partition = block_on(PartitionBlockDevice::new(&mut ram, 1)).unwrap();
+    assert_eq!(partition.start(), START);
+    assert_eq!(partition.block_count(), SIZE);
+
+    for index in [0, 2, 4] {
+        assert!(matches!(
+            block_on(PartitionBlockDevice::new(&mut ram, index)),
+            Err(PartitionError::InvalidPartition)
+        ));
+    }
+    // past the end of the disk
+    ram.data[446 + 16 + 12..][..4].copy_from_slice(&(BLOCKS - START + 1).to_le_bytes());
+    assert!(matches!(
+        block_on(PartitionBlockDevice::new(&mut ram, 1)),
+        Err(PartitionError::InvalidPartition)
+    ));
+    ram.data[511] = 0;
+    assert!(matches!(
+        block_on(PartitionBlockDevice::new(&mut ram, 1)),
+        Err(PartitionError::NoPartitionTable)
+    ));
+
+    assert!(PartitionBlockDevice::from_range(&mut ram, BLOCKS - 1, 1).is_some());
+    assert!(PartitionBlockDevice::from_range(&mut ram, BLOCKS - 1, 2).is_none());
+    assert!(PartitionBlockDevice::from_range(&mut ram, u32::MAX, 2).is_none());
+    assert!(PartitionBlockDevice::from_range(&mut ram, 0, 0).is_none());
+}
+
+#[test]
+fn offsets_and_bounds_every_access() {
+    let mut ram = disk();
+    let mut partition = PartitionBlockDevice::from_range(&mut ram, START, SIZE).unwrap();
+
+    block_on(partition.write_block(0, &[1; BLOCK_SIZE])).unwrap();
+    block_on(partition.write_blocks(SIZE - 2, &[2; 2 * BLOCK_SIZE])).unwrap();
+    block_on(partition.write_blocks_fua(10, &[3; BLOCK_SIZE])).unwrap();
+    let mut blocks = [0; 2 * BLOCK_SIZE];
+    block_on(partition.read_blocks(SIZE - 2, &mut blocks)).unwrap();
+    assert_eq!(blocks, [2; 2 * BLOCK_SIZE]);
+
+    for (lba, count) in [(SIZE, 1), (SIZE - 1, 2), (u32::MAX, 1)] {
+        let blocks = vec![0xee; count * BLOCK_SIZE];
+        assert_eq!(
+            block_on(partition.write_blocks(lba, &blocks)),
+            Err(BlockDeviceError::InvalidAddress)
+        );
+        let mut blocks = blocks;
+        assert_eq!(
+            block_on(partition.read_blocks(lba, &mut blocks)),
+            Err(BlockDeviceError::InvalidAddress)
+        );
+        assert_eq!(
+            block_on(partition.unmap(lba, count as u32)),
+            Err(BlockDeviceError::InvalidAddress)
+        );
+    }
+    block_on(partition.unmap(SIZE, 0)).unwrap();
+
+    assert_eq!(ram.block(START), [1; BLOCK_SIZE]);
+    assert_eq!(ram.block(START + 10), [3; BLOCK_SIZE]);
+    assert_eq!(ram.block(START + SIZE - 1), [2; BLOCK_SIZE]);
+    assert_eq!(ram.block(START + SIZE), [0; BLOCK_SIZE]);
+    assert_eq!(ram.writes, 4);
+}
+
+#[test]
+fn reference_tags_count_from_the_start_of_the_partition() {
+    const PI: usize = PROTECTION_INFORMATION_BYTES;
+
+    let mut integrity = IntegrityBlockDevice::new(Ram::new(BLOCKS), true);
+    // more blocks than the partition shifts the tags of at once
+    let count = 70;
+    let blocks: Vec<u8> = (0..count * BLOCK_SIZE).map(|i| (i / 7) as u8).collect();
+    let mut protection = vec![0; count * PI];
+    for (lba, (block, pi)) in blocks
+        .chunks_exact(BLOCK_SIZE)
+        .zip(protection.chunks_exact_mut(PI))
+        .enumerate()
+    {
+        pi.copy_from_slice(&protection_information(lba as u32 + 5, block, 0x1234));
+    }
+    let mut partition = PartitionBlockDevice::from_range(&mut integrity, START, SIZE + 30).unwrap();
+    assert!(partition.protection_information());
+    block_on(partition.write_blocks_protected(5, &blocks, &protection)).unwrap();
+
+    let mut read = vec![0; count * BLOCK_SIZE];
+    let mut read_protection = vec![0; count * PI];
+    block_on(partition.read_blocks_protected(5, &mut read, &mut read_protection)).unwrap();
+    assert_eq!(read, blocks);
+    assert_eq!(read_protection, protection);
+
+    // the base checked them against its own LBAs
+    let mut block = [0; BLOCK_SIZE];
+    let mut pi = [0; PI];
+    block_on(integrity.read_blocks_protected(START + 5, &mut block, &mut pi)).unwrap();
+    assert_eq!(
+        pi,
+        protection_information(START + 5, &blocks[..BLOCK_SIZE], 0x1234)
+    );
+
+    // the escape values that turn the host's checks off are passed on as they are
+    let mut partition = PartitionBlockDevice::from_range(&mut integrity, START, SIZE).unwrap();
+    block_on(partition.read_blocks_protected(0, &mut block, &mut pi)).unwrap();
+    assert_eq!(pi, [0xff; PI]);
+}
+
+#[test]
+fn forwards_everything_else() {
+    let mut probe = Probe::new(64);
+    let mut partition = PartitionBlockDevice::from_range(&mut probe, 8, 16).unwrap();
+    block_on(call_every_method(&mut partition));
+    assert_eq!(probe.missed(), Vec::<&str>::new());
+}
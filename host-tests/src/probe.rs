@@ -0,0 +1,252 @@
+//! A device that records the methods called on it, to check that wrappers pass on the ones
+//! they don't change
+
+use std::cell::RefCell;
+
+use embassy_time::Instant;
+
+use crate::scsi::{
+    BlockDevice, BlockDeviceError, Caching, MediaStatus, Provisioning, PROTECTION_INFORMATION_BYTES,
+};
+
+const BLOCK_SIZE: usize = 512;
+
+/// Every method of [`BlockDevice`]
+pub const METHODS: [&str; 24] = [
+    "read_block",
+    "write_block",
+    "read_blocks",
+    "write_blocks",
+    "write_blocks_fua",
+    "block_count",
+    "write_protected",
+    "media_status",
+    "provisioning",
+    "unmap",
+    "unlock",
+    "lock",
+    "flush",
+    "caching",
+    "set_caching",
+    "protection_information",
+    "read_blocks_protected",
+    "write_blocks_protected",
+    "transaction_blocks",
+    "begin_transaction",
+    "end_transaction",
+    "format",
+    "idle_deadline",
+    "idle",
+];
+
+/// A blank disk of `blocks` blocks, with every optional feature
+pub struct Probe {
+    pub blocks: u32,
+    pub calls: RefCell<Vec<&'static str>>,
+}
+
+impl Probe {
+    pub fn new(blocks: u32) -> Self {
+        Self {
+            blocks,
+            calls: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn call(&self, method: &'static str) {
+        self.calls.borrow_mut().push(method);
+    }
+
+    /// The methods in [`METHODS`] that weren't called
+    pub fn missed(&self) -> Vec<&'static str> {
+        let calls = self.calls.borrow();
+        METHODS
+            .into_iter()
+            .filter(|method| !calls.contains(method))
+            .collect()
+    }
+}
+
+impl BlockDevice for Probe {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, _lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.call("read_block");
+        block.fill(0);
+        Ok(())
+    }
+
+    async fn write_block(&mut self, _lba: u32, _block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.call("write_block");
+        Ok(())
+    }
+
+    async fn read_blocks(&mut self, _lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.call("read_blocks");
+        blocks.fill(0);
+        Ok(())
+    }
+
+    async fn write_blocks(&mut self, _lba: u32, _blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.call("write_blocks");
+        Ok(())
+    }
+
+    async fn write_blocks_fua(
+        &mut self,
+        _lba: u32,
+        _blocks: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.call("write_blocks_fua");
+        Ok(())
+    }
+
+    fn block_count(&self) -> u32 {
+        self.call("block_count");
+        self.blocks
+    }
+
+    fn write_protected(&self) -> bool {
+        self.call("write_protected");
+        false
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        self.call("media_status");
+        MediaStatus::Present
+    }
+
+    fn provisioning(&self) -> Option<Provisioning> {
+        self.call("provisioning");
+        Some(Provisioning {
+            used: 0,
+            available: self.blocks,
+            threshold_exponent: 0,
+        })
+    }
+
+    async fn unmap(&mut self, _lba: u32, _count: u32) -> Result<(), BlockDeviceError> {
+        self.call("unmap");
+        Ok(())
+    }
+
+    async fn unlock(&mut self, _key: &[u8]) -> Result<(), BlockDeviceError> {
+        self.call("unlock");
+        Ok(())
+    }
+
+    fn lock(&mut self) {
+        self.call("lock");
+    }
+
+    async fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        self.call("flush");
+        Ok(())
+    }
+
+    fn caching(&self) -> Option<Caching> {
+        self.call("caching");
+        Some(Caching {
+            write_cache_enabled: true,
+            read_cache_disable: false,
+        })
+    }
+
+    async fn set_caching(&mut self, _caching: Caching) -> Result<(), BlockDeviceError> {
+        self.call("set_caching");
+        Ok(())
+    }
+
+    fn protection_information(&self) -> bool {
+        self.call("protection_information");
+        true
+    }
+
+    async fn read_blocks_protected(
+        &mut self,
+        _lba: u32,
+        blocks: &mut [u8],
+        protection: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.call("read_blocks_protected");
+        blocks.fill(0);
+        protection.fill(0);
+        Ok(())
+    }
+
+    async fn write_blocks_protected(
+        &mut self,
+        _lba: u32,
+        _blocks: &[u8],
+        _protection: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.call("write_blocks_protected");
+        Ok(())
+    }
+
+    fn transaction_blocks(&self) -> Option<u32> {
+        self.call("transaction_blocks");
+        Some(self.blocks)
+    }
+
+    fn begin_transaction(&mut self) {
+        self.call("begin_transaction");
+    }
+
+    async fn end_transaction(&mut self, _commit: bool) -> Result<(), BlockDeviceError> {
+        self.call("end_transaction");
+        Ok(())
+    }
+
+    async fn format(&mut self) -> Result<(), BlockDeviceError> {
+        self.call("format");
+        Ok(())
+    }
+
+    /// Always due, so wrappers with work of their own pass it on too
+    fn idle_deadline(&self) -> Option<Instant> {
+        self.call("idle_deadline");
+        Some(Instant::MIN)
+    }
+
+    async fn idle(&mut self) {
+        self.call("idle");
+    }
+}
+
+/// Calls every method of `device` once, on its first blocks
+pub async fn call_every_method(device: &mut impl BlockDevice) {
+    let mut blocks = [0u8; 2 * BLOCK_SIZE];
+    let mut protection = [0u8; 2 * PROTECTION_INFORMATION_BYTES];
+    let caching = Caching {
+        write_cache_enabled: false,
+        read_cache_disable: false,
+    };
+
+    let _ = device.read_block(0, &mut blocks[..BLOCK_SIZE]).await;
+    let _ = device.write_block(0, &blocks[..BLOCK_SIZE]).await;
+    let _ = device.read_blocks(0, &mut blocks).await;
+    let _ = device.write_blocks(0, &blocks).await;
+    let _ = device.write_blocks_fua(0, &blocks).await;
+    device.block_count();
+    device.write_protected();
+    device.media_status().await;
+    device.provisioning();
+    let _ = device.unmap(0, 2).await;
+    let _ = device.unlock(b"key").await;
+    device.lock();
+    let _ = device.flush().await;
+    device.caching();
+    let _ = device.set_caching(caching).await;
+    device.protection_information();
+    let _ = device
+        .read_blocks_protected(0, &mut blocks, &mut protection)
+        .await;
+    let _ = device.write_blocks_protected(0, &blocks, &protection).await;
+    device.transaction_blocks();
+    device.begin_transaction();
+    let _ = device.end_transaction(true).await;
+    let _ = device.format().await;
+    device.idle_deadline();
+    device.idle().await;
+}
@@ -0,0 +1,79 @@
+//! A RAM disk for the wrappers under test to sit on
+
+use embassy_futures::block_on;
+
+use crate::scsi::{BlockDevice, BlockDeviceError};
+
+pub const BLOCK_SIZE: usize = 512;
+
+pub struct Ram {
+    pub data: Vec<u8>,
+    /// Blocks written so far
+    pub writes: usize,
+}
+
+impl Ram {
+    pub fn new(blocks: u32) -> Self {
+        Self::from(vec![0; blocks as usize * BLOCK_SIZE])
+    }
+
+    pub fn block(&self, lba: u32) -> &[u8] {
+        &self.data[lba as usize * BLOCK_SIZE..][..BLOCK_SIZE]
+    }
+
+    fn range(&self, lba: u32, len: usize) -> Result<std::ops::Range<usize>, BlockDeviceError> {
+        let start = lba as usize * BLOCK_SIZE;
+        match start.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(start..end),
+            _ => Err(BlockDeviceError::InvalidAddress),
+        }
+    }
+}
+
+/// A disk of `blocks` blocks, each filled with its LBA
+pub fn disk(blocks: u32) -> Ram {
+    let data: Vec<u8> = (0..blocks as usize * BLOCK_SIZE)
+        .map(|i| (i / BLOCK_SIZE) as u8)
+        .collect();
+    Ram::from(data)
+}
+
+/// Reads block `lba` of `device`
+pub fn read(device: &mut impl BlockDevice, lba: u32) -> Result<[u8; BLOCK_SIZE], BlockDeviceError> {
+    // so a block the device doesn't fill in shows
+    let mut block = [0xa5; BLOCK_SIZE];
+    block_on(device.read_block(lba, &mut block)).map(|()| block)
+}
+
+/// Fills block `lba` of `device` with `fill`
+pub fn write(device: &mut impl BlockDevice, lba: u32, fill: u8) -> Result<(), BlockDeviceError> {
+    block_on(device.write_block(lba, &[fill; BLOCK_SIZE]))
+}
+
+impl From<Vec<u8>> for Ram {
+    fn from(data: Vec<u8>) -> Self {
+        assert_eq!(data.len() % BLOCK_SIZE, 0);
+        Self { data, writes: 0 }
+    }
+}
+
+impl BlockDevice for Ram {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let range = self.range(lba, block.len())?;
+        block.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        let range = self.range(lba, block.len())?;
+        self.data[range].copy_from_slice(block);
+        self.writes += 1;
+        Ok(())
+    }
+
+    fn block_count(&self) -> u32 {
+        (self.data.len() / BLOCK_SIZE) as u32
+    }
+}
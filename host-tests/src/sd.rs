@@ -0,0 +1,222 @@
+//! Runs the SD card driver against the simulated card
+
+use std::cell::Cell;
+
+use embassy_futures::block_on;
+
+use crate::block_devices::sd::sim::{SimulatedFaults, SimulatedPin, SimulatedSdCard};
+use crate::block_devices::sd::SdCard;
+use crate::block_devices::BLOCK_SIZE;
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus};
+
+/// 1MiB, two of the SDHC capacity units
+const BLOCKS: u32 = 2048;
+
+type Card<'a, 'b> = SdCard<&'a mut SimulatedSdCard<'b>, SimulatedPin<'a>, SimulatedPin<'a>>;
+
+/// A card with the contents of each block different
+fn image() -> Vec<u8> {
+    (0..BLOCKS as usize * BLOCK_SIZE)
+        .map(|i| (i / BLOCK_SIZE) as u8 ^ i as u8)
+        .collect()
+}
+
+/// The slots of the card and the chip select
+#[derive(Default)]
+struct Pins {
+    inserted: Cell<bool>,
+    selected: Cell<bool>,
+}
+
+impl Pins {
+    fn inserted() -> Self {
+        let pins = Self::default();
+        pins.inserted.set(true);
+        pins
+    }
+
+    fn driver<'a, 'b>(&'a self, card: &'a mut SimulatedSdCard<'b>) -> Card<'a, 'b> {
+        SdCard::new(
+            card,
+            SimulatedPin(&self.selected),
+            SimulatedPin(&self.inserted),
+            |_, _| {},
+        )
+    }
+}
+
+/// Brings the card up the way the SCSI layer does, on its first media poll
+fn initialised<'a, 'b>(pins: &'a Pins, card: &'a mut SimulatedSdCard<'b>) -> Card<'a, 'b> {
+    let mut driver = pins.driver(card);
+    assert_eq!(block_on(driver.media_status()), MediaStatus::Changed);
+    assert_eq!(block_on(driver.media_status()), MediaStatus::Present);
+    driver
+}
+
+fn blocks(image: &[u8], lba: u32, count: u32) -> &[u8] {
+    &image[lba as usize * BLOCK_SIZE..][..count as usize * BLOCK_SIZE]
+}
+
+#[test]
+fn reads_the_capacity_from_the_csd() {
+    let faults = SimulatedFaults::default();
+    let pins = Pins::inserted();
+    let (mut high, mut standard) = (image(), image());
+    let mut sdhc = SimulatedSdCard::new(&mut high, &faults);
+    let mut sdsc = SimulatedSdCard::new_standard_capacity(&mut standard, &faults);
+    let expected = image();
+
+    for card in [&mut sdhc, &mut sdsc] {
+        let mut driver = pins.driver(card);
+        assert_eq!(driver.block_count(), 0);
+        let mut block = [0; BLOCK_SIZE];
+        assert_eq!(
+            block_on(driver.read_block(0, &mut block)),
+            Err(BlockDeviceError::MediumNotPresent)
+        );
+
+        let mut driver = initialised(&pins, card);
+        assert_eq!(driver.block_count(), BLOCKS);
+        // the standard capacity card is addressed in bytes
+        block_on(driver.read_block(BLOCKS - 1, &mut block)).unwrap();
+        assert_eq!(block, blocks(&expected, BLOCKS - 1, 1));
+        assert_eq!(
+            block_on(driver.read_block(BLOCKS, &mut block)),
+            Err(BlockDeviceError::InvalidAddress)
+        );
+    }
+}
+
+/// The commands sent for `transfer`, made on a card that has just been initialised
+fn commands_for(
+    pins: &Pins,
+    card: &mut SimulatedSdCard,
+    transfer: impl FnOnce(&mut Card),
+) -> usize {
+    let before = card.commands();
+    initialised(pins, card);
+    let init = card.commands() - before;
+    let mut driver = initialised(pins, card);
+    transfer(&mut driver);
+    card.commands() - before - 2 * init
+}
+
+#[test]
+fn transfers_several_blocks_with_one_command() {
+    let faults = SimulatedFaults::default();
+    let pins = Pins::inserted();
+    let mut image = image();
+    let expected = image.clone();
+    let mut card = SimulatedSdCard::new(&mut image, &faults);
+    let written: Vec<u8> = (0..4 * BLOCK_SIZE).map(|i| (i * 3) as u8).collect();
+
+    let mut read = [0; 4 * BLOCK_SIZE];
+    // CMD18 then CMD12
+    let commands = commands_for(&pins, &mut card, |driver| {
+        block_on(driver.read_blocks(BLOCKS - 4, &mut read)).unwrap();
+    });
+    assert_eq!(commands, 2);
+    assert_eq!(read, blocks(&expected, BLOCKS - 4, 4));
+    // CMD25 then CMD13 for the status
+    let commands = commands_for(&pins, &mut card, |driver| {
+        block_on(driver.write_blocks(10, &written)).unwrap();
+    });
+    assert_eq!(commands, 2);
+    let commands = commands_for(&pins, &mut card, |driver| {
+        block_on(driver.write_block(100, &written[..BLOCK_SIZE])).unwrap();
+        block_on(driver.read_blocks(10, &mut read)).unwrap();
+        assert_eq!(
+            block_on(driver.read_blocks(BLOCKS - 3, &mut read)),
+            Err(BlockDeviceError::InvalidAddress)
+        );
+    });
+    assert_eq!(commands, 3);
+    assert_eq!(read[..], written[..]);
+
+    assert_eq!(blocks(card.image(), 10, 4), written);
+    assert_eq!(blocks(card.image(), 100, 1), &written[..BLOCK_SIZE]);
+    assert_eq!(blocks(card.image(), 0, 10), blocks(&expected, 0, 10));
+    assert_eq!(blocks(card.image(), 14, 86), blocks(&expected, 14, 86));
+}
+
+#[test]
+fn retries_on_a_crc_error() {
+    let faults = SimulatedFaults::default();
+    let pins = Pins::inserted();
+    let mut image = image();
+    let expected = image.clone();
+    let mut card = SimulatedSdCard::new(&mut image, &faults);
+    let mut driver = initialised(&pins, &mut card);
+
+    let mut read = [0; 4 * BLOCK_SIZE];
+    faults.corrupt_reads.set(2);
+    assert_eq!(
+        block_on(driver.read_blocks(8, &mut read)),
+        Err(BlockDeviceError::RecoveredWithRetries)
+    );
+    assert_eq!(read, blocks(&expected, 8, 4));
+    faults.corrupt_reads.set(1);
+    assert_eq!(
+        block_on(driver.read_block(3, &mut read[..BLOCK_SIZE])),
+        Err(BlockDeviceError::RecoveredWithRetries)
+    );
+    assert_eq!(read[..BLOCK_SIZE], *blocks(&expected, 3, 1));
+
+    // every attempt fails
+    faults.corrupt_reads.set(usize::MAX);
+    assert_eq!(
+        block_on(driver.read_block(3, &mut read[..BLOCK_SIZE])),
+        Err(BlockDeviceError::ReadError)
+    );
+    faults.corrupt_reads.set(0);
+    block_on(driver.read_block(3, &mut read[..BLOCK_SIZE])).unwrap();
+}
+
+#[test]
+fn times_out_on_a_busy_card() {
+    let faults = SimulatedFaults::default();
+    let pins = Pins::inserted();
+    let mut image = image();
+    let mut card = SimulatedSdCard::new(&mut image, &faults);
+    let mut driver = initialised(&pins, &mut card);
+
+    let block = [0x5a; BLOCK_SIZE];
+    faults.stalled.set(true);
+    assert_eq!(
+        block_on(driver.write_block(1, &block)),
+        Err(BlockDeviceError::Timeout)
+    );
+    faults.stalled.set(false);
+    block_on(driver.write_block(1, &block)).unwrap();
+    assert_eq!(blocks(card.image(), 1, 1), block);
+}
+
+#[test]
+fn card_detect() {
+    let faults = SimulatedFaults::default();
+    let pins = Pins::default();
+    let mut image = image();
+    let mut card = SimulatedSdCard::new(&mut image, &faults);
+    let mut driver = pins.driver(&mut card);
+
+    let mut block = [0; BLOCK_SIZE];
+    assert_eq!(block_on(driver.media_status()), MediaStatus::Absent);
+    pins.inserted.set(true);
+    assert_eq!(block_on(driver.media_status()), MediaStatus::Changed);
+    block_on(driver.read_block(0, &mut block)).unwrap();
+
+    pins.inserted.set(false);
+    assert_eq!(block_on(driver.media_status()), MediaStatus::Absent);
+    assert_eq!(driver.block_count(), 0);
+    assert_eq!(
+        block_on(driver.read_block(0, &mut block)),
+        Err(BlockDeviceError::MediumNotPresent)
+    );
+
+    // the card put back is brought up again, as a new medium
+    pins.inserted.set(true);
+    assert_eq!(block_on(driver.media_status()), MediaStatus::Changed);
+    assert_eq!(block_on(driver.media_status()), MediaStatus::Present);
+    assert_eq!(driver.block_count(), BLOCKS);
+    block_on(driver.read_block(0, &mut block)).unwrap();
+}
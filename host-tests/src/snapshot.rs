@@ -0,0 +1,142 @@
+//! Takes snapshots of a drive, copying blocks as they're written and putting them back
+
+use embassy_futures::block_on;
+
+use crate::block_devices::integrity::{protection_information, IntegrityBlockDevice};
+use crate::block_devices::snapshot::{list, SnapshotBlockDevice};
+use crate::in_thread_mode;
+use crate::probe::{call_every_method, Probe};
+use crate::ram::{disk, write, Ram, BLOCK_SIZE};
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus, PROTECTION_INFORMATION_BYTES};
+
+const BLOCKS: u32 = 64;
+const POOL: usize = 4;
+
+type Pool = [[u8; BLOCK_SIZE]; POOL];
+
+/// The blocks each snapshot holds, oldest first
+fn pool_blocks() -> Vec<(u32, u32)> {
+    let snapshots = list().into_iter().flatten();
+    snapshots.map(|info| (info.id, info.blocks)).collect()
+}
+
+#[test]
+fn copies_on_write_and_rolls_back() {
+    in_thread_mode(|| {
+        let mut ram = disk(BLOCKS);
+        let mut pool: Pool = [[0; BLOCK_SIZE]; POOL];
+        let mut snapshots = SnapshotBlockDevice::new(&mut ram, &mut pool);
+        // nothing is copied until there's a snapshot
+        write(&mut snapshots, 1, 0x11).unwrap();
+        assert_eq!(pool_blocks(), []);
+
+        assert_eq!(snapshots.take(), 0);
+        write(&mut snapshots, 3, 0xaa).unwrap();
+        write(&mut snapshots, 3, 0xab).unwrap();
+        block_on(snapshots.write_blocks(5, &[0x55; 2 * BLOCK_SIZE])).unwrap();
+        // the same contents need no copy
+        write(&mut snapshots, 10, 10).unwrap();
+        assert_eq!(pool_blocks(), [(0, 3)]);
+
+        assert_eq!(snapshots.take(), 1);
+        write(&mut snapshots, 3, 0xbb).unwrap();
+        assert_eq!(pool_blocks(), [(0, 3), (1, 1)]);
+        // the pool is full
+        assert_eq!(
+            write(&mut snapshots, 20, 0x20),
+            Err(BlockDeviceError::SpaceAllocationFailed)
+        );
+        assert_eq!(
+            block_on(snapshots.write_blocks(BLOCKS - 1, &[0; 2 * BLOCK_SIZE])),
+            Err(BlockDeviceError::InvalidAddress)
+        );
+
+        block_on(snapshots.rollback(1)).unwrap();
+        assert_eq!(pool_blocks(), [(0, 3), (1, 0)]);
+        // ejected for the host to see the new contents
+        let mut block = [0; BLOCK_SIZE];
+        assert_eq!(
+            block_on(snapshots.read_block(3, &mut block)),
+            Err(BlockDeviceError::MediumNotPresent)
+        );
+        assert_eq!(block_on(snapshots.media_status()), MediaStatus::Absent);
+        let ram = crate::scsi::Wrapper::base(&snapshots);
+        assert_eq!(ram.block(3), [0xab; BLOCK_SIZE]);
+
+        block_on(snapshots.rollback(0)).unwrap();
+        assert_eq!(pool_blocks(), [(0, 0)]);
+        let ram = crate::scsi::Wrapper::base(&snapshots);
+        for lba in 2..BLOCKS {
+            assert_eq!(ram.block(lba), [lba as u8; BLOCK_SIZE], "lba {}", lba);
+        }
+        assert_eq!(ram.block(1), [0x11; BLOCK_SIZE]);
+    });
+}
+
+#[test]
+fn deleting_hands_copies_to_the_snapshot_before() {
+    in_thread_mode(|| {
+        let mut ram = disk(BLOCKS);
+        let mut pool: Pool = [[0; BLOCK_SIZE]; POOL];
+        let mut snapshots = SnapshotBlockDevice::new(&mut ram, &mut pool);
+        snapshots.take();
+        write(&mut snapshots, 3, 0xaa).unwrap();
+        snapshots.take();
+        write(&mut snapshots, 3, 0xbb).unwrap();
+        write(&mut snapshots, 4, 0xcc).unwrap();
+        block_on(snapshots.unmap(5, 1)).unwrap();
+        assert_eq!(pool_blocks(), [(0, 1), (1, 3)]);
+
+        // snapshot 0 has its own copy of block 3, and takes those of 4 and 5
+        assert!(snapshots.delete(1));
+        assert!(!snapshots.delete(1));
+        assert_eq!(pool_blocks(), [(0, 3)]);
+        // there's room for another copy
+        write(&mut snapshots, 6, 0x66).unwrap();
+
+        block_on(snapshots.rollback(0)).unwrap();
+        let ram = crate::scsi::Wrapper::base(&snapshots);
+        for lba in 3..7 {
+            assert_eq!(ram.block(lba), [lba as u8; BLOCK_SIZE], "lba {}", lba);
+        }
+    });
+}
+
+#[test]
+fn keeps_protection_information() {
+    in_thread_mode(|| {
+        let mut integrity = IntegrityBlockDevice::new(Ram::new(BLOCKS + 2), true);
+        let old = [0x42; BLOCK_SIZE];
+        let old_protection = protection_information(7, &old, 0x1234);
+        block_on(integrity.write_blocks_protected(7, &old, &old_protection)).unwrap();
+
+        let mut pool: Pool = [[0; BLOCK_SIZE]; POOL];
+        let mut snapshots = SnapshotBlockDevice::new(&mut integrity, &mut pool);
+        snapshots.take();
+        // only the application tag changes
+        let protection = protection_information(7, &old, 0x5678);
+        block_on(snapshots.write_blocks_protected(7, &old, &protection)).unwrap();
+        assert_eq!(pool_blocks(), [(0, 1)]);
+        block_on(snapshots.rollback(0)).unwrap();
+
+        let integrity = crate::scsi::Wrapper::base_mut(&mut snapshots);
+        let mut block = [0; BLOCK_SIZE];
+        let mut protection = [0; PROTECTION_INFORMATION_BYTES];
+        block_on(integrity.read_blocks_protected(7, &mut block, &mut protection)).unwrap();
+        assert_eq!(block, old);
+        assert_eq!(protection, old_protection);
+    });
+}
+
+#[test]
+fn forwards_every_method() {
+    let probe = in_thread_mode(|| {
+        let mut probe = Probe::new(64);
+        let mut pool: Pool = [[0; BLOCK_SIZE]; POOL];
+        block_on(call_every_method(&mut SnapshotBlockDevice::new(
+            &mut probe, &mut pool,
+        )));
+        probe
+    });
+    assert_eq!(probe.missed(), Vec::<&str>::new());
+}
@@ -0,0 +1,152 @@
+//! Allocates and frees the blocks of the thin provisioned RAM disk
+
+use embassy_futures::block_on;
+
+use crate::block_devices::journal::JournalBlockDevice;
+use crate::block_devices::sparse::SparseBlockDevice;
+use crate::block_devices::BLOCK_SIZE;
+use crate::ram::{read, write};
+use crate::scsi::{BlockDevice, BlockDeviceError, Provisioning};
+
+const BLOCKS: u32 = 1024;
+const POOL: usize = 24;
+const SOFT_THRESHOLD: usize = 16;
+
+fn used(device: &impl BlockDevice) -> u32 {
+    device.provisioning().unwrap().used
+}
+
+#[test]
+fn allocates_blocks_as_they_are_written() {
+    let mut sparse = SparseBlockDevice::<POOL>::new(BLOCKS, 0);
+    assert_eq!(sparse.block_count(), BLOCKS);
+    assert_eq!(
+        sparse.provisioning(),
+        Some(Provisioning {
+            used: 0,
+            available: POOL as u32,
+            threshold_exponent: 3,
+        })
+    );
+    assert_eq!(read(&mut sparse, 700).unwrap(), [0; BLOCK_SIZE]);
+
+    // out of order, so they're kept sorted
+    for lba in [900, 3, 500, 4] {
+        write(&mut sparse, lba, lba as u8 + 1).unwrap();
+    }
+    // zeros never take a block
+    write(&mut sparse, 10, 0).unwrap();
+    assert_eq!(used(&sparse), 4);
+    for lba in [900, 3, 500, 4] {
+        assert_eq!(read(&mut sparse, lba).unwrap(), [lba as u8 + 1; BLOCK_SIZE]);
+    }
+
+    // overwriting with zeros gives the block back
+    write(&mut sparse, 500, 0).unwrap();
+    assert_eq!(used(&sparse), 3);
+    assert_eq!(read(&mut sparse, 500).unwrap(), [0; BLOCK_SIZE]);
+    assert_eq!(read(&mut sparse, 900).unwrap(), [0x85; BLOCK_SIZE]);
+
+    assert_eq!(
+        write(&mut sparse, BLOCKS, 1),
+        Err(BlockDeviceError::InvalidAddress)
+    );
+    let mut block = [0; BLOCK_SIZE];
+    assert_eq!(
+        block_on(sparse.read_block(BLOCKS, &mut block)),
+        Err(BlockDeviceError::InvalidAddress)
+    );
+}
+
+#[test]
+fn fails_writes_once_the_pool_is_exhausted() {
+    let mut sparse = SparseBlockDevice::<POOL>::new(BLOCKS, 0);
+    for lba in 0..POOL as u32 {
+        write(&mut sparse, lba * 10, 1).unwrap();
+    }
+    assert_eq!(
+        write(&mut sparse, 5, 1),
+        Err(BlockDeviceError::SpaceAllocationFailed)
+    );
+    assert_eq!(read(&mut sparse, 5).unwrap(), [0; BLOCK_SIZE]);
+    // allocated blocks can still be rewritten
+    write(&mut sparse, 10, 2).unwrap();
+    assert_eq!(sparse.provisioning().unwrap().available, 0);
+
+    block_on(sparse.unmap(0, 1)).unwrap();
+    write(&mut sparse, 5, 1).unwrap();
+}
+
+#[test]
+fn unmap_frees_the_blocks_in_the_range() {
+    let mut sparse = SparseBlockDevice::<POOL>::new(BLOCKS, 0);
+    for lba in [1, 2, 5, 8, 9, 20] {
+        write(&mut sparse, lba, 1).unwrap();
+    }
+    // from the middle of a gap to an allocated block just past the end
+    block_on(sparse.unmap(3, 6)).unwrap();
+    assert_eq!(used(&sparse), 4);
+    for lba in [1, 2, 9, 20] {
+        assert_eq!(read(&mut sparse, lba).unwrap(), [1; BLOCK_SIZE]);
+    }
+    assert_eq!(read(&mut sparse, 8).unwrap(), [0; BLOCK_SIZE]);
+
+    block_on(sparse.unmap(0, BLOCKS)).unwrap();
+    assert_eq!(used(&sparse), 0);
+    assert_eq!(
+        block_on(sparse.unmap(BLOCKS - 1, 2)),
+        Err(BlockDeviceError::InvalidAddress)
+    );
+}
+
+#[test]
+fn reports_the_soft_threshold_once_per_crossing() {
+    let mut sparse = SparseBlockDevice::<POOL>::new(BLOCKS, SOFT_THRESHOLD + 3);
+    for lba in 0..SOFT_THRESHOLD as u32 - 1 {
+        write(&mut sparse, lba, 1).unwrap();
+    }
+    // rounded down to 16 blocks
+    assert_eq!(
+        write(&mut sparse, 100, 1),
+        Err(BlockDeviceError::SoftThresholdReached)
+    );
+    assert_eq!(read(&mut sparse, 100).unwrap(), [1; BLOCK_SIZE]);
+    write(&mut sparse, 101, 1).unwrap();
+    write(&mut sparse, 102, 1).unwrap();
+
+    // still over it after freeing one block, so it isn't reported again
+    write(&mut sparse, 102, 0).unwrap();
+    write(&mut sparse, 103, 1).unwrap();
+
+    // once back under it, going over is reported again, in the middle of a write
+    block_on(sparse.unmap(100, 4)).unwrap();
+    assert_eq!(used(&sparse), SOFT_THRESHOLD as u32 - 1);
+    let blocks = [1; 3 * BLOCK_SIZE];
+    assert_eq!(
+        block_on(sparse.write_blocks(200, &blocks)),
+        Err(BlockDeviceError::SoftThresholdReached)
+    );
+    assert_eq!(used(&sparse), SOFT_THRESHOLD as u32 + 2);
+}
+
+#[test]
+fn provisioning_reaches_the_host_through_the_journal() {
+    let mut journal =
+        JournalBlockDevice::<_, 4>::new(SparseBlockDevice::<POOL>::new(BLOCKS, SOFT_THRESHOLD));
+    assert_eq!(journal.block_count(), BLOCKS - 5);
+    for lba in 0..SOFT_THRESHOLD as u32 - 1 {
+        write(&mut journal, lba, 1).unwrap();
+    }
+    assert_eq!(
+        write(&mut journal, 100, 1),
+        Err(BlockDeviceError::SoftThresholdReached)
+    );
+    assert_eq!(used(&journal), SOFT_THRESHOLD as u32);
+
+    block_on(journal.unmap(0, 8)).unwrap();
+    assert_eq!(used(&journal), SOFT_THRESHOLD as u32 - 8);
+    assert_eq!(
+        block_on(journal.unmap(BLOCKS - 5, 1)),
+        Err(BlockDeviceError::InvalidAddress)
+    );
+}
@@ -0,0 +1,104 @@
+//! Stripes a disk across devices, splitting accesses between them
+
+use embassy_futures::block_on;
+
+use crate::block_devices::integrity::{protection_information, IntegrityBlockDevice};
+use crate::block_devices::stripe::StripeBlockDevice;
+use crate::probe::{call_every_method, Probe};
+use crate::ram::{Ram, BLOCK_SIZE};
+use crate::scsi::{BlockDevice, BlockDeviceError, PROTECTION_INFORMATION_BYTES};
+
+const PI: usize = PROTECTION_INFORMATION_BYTES;
+const STRIPE: u32 = 2;
+
+/// `count` blocks, each filled with its LBA + 1
+fn numbered(count: u32) -> Vec<u8> {
+    (0..count as usize * BLOCK_SIZE)
+        .map(|i| (i / BLOCK_SIZE + 1) as u8)
+        .collect()
+}
+
+#[test]
+fn stripes_in_turn() {
+    let mut members = [Ram::new(10), Ram::new(11), Ram::new(13)];
+    let [a, b, c] = &mut members;
+    let mut stripe = StripeBlockDevice::new([a, b, c], STRIPE);
+    // 5 whole stripes on the smallest
+    assert_eq!(stripe.block_count(), 30);
+
+    let blocks = numbered(30);
+    block_on(stripe.write_blocks(0, &blocks)).unwrap();
+    // from the last member back round to the first
+    let mut read_back = vec![0; 4 * BLOCK_SIZE];
+    block_on(stripe.read_blocks(5, &mut read_back)).unwrap();
+    assert_eq!(read_back, blocks[5 * BLOCK_SIZE..][..4 * BLOCK_SIZE]);
+    for lba in [29, u32::MAX] {
+        assert_eq!(
+            block_on(stripe.read_blocks(lba, &mut read_back[..2 * BLOCK_SIZE])),
+            Err(BlockDeviceError::InvalidAddress)
+        );
+    }
+
+    for (member, ram) in members.iter().enumerate() {
+        for member_lba in 0..10 {
+            let stripe = member_lba / STRIPE * 3 + member as u32;
+            let lba = stripe * STRIPE + member_lba % STRIPE;
+            assert_eq!(ram.block(member_lba), [lba as u8 + 1; BLOCK_SIZE]);
+        }
+        // the rest of the larger members is unused
+        assert_eq!(ram.writes, 10);
+    }
+
+    // a partial stripe at the end is left out
+    let stripe = StripeBlockDevice::new([Ram::new(8), Ram::new(8)], 3);
+    assert_eq!(stripe.block_count(), 12);
+}
+
+#[test]
+fn protection_information_counts_from_each_member() {
+    // 128 blocks for the host on each
+    let mut members = [
+        IntegrityBlockDevice::new(Ram::new(130), true),
+        IntegrityBlockDevice::new(Ram::new(130), true),
+    ];
+    let [a, b] = &mut members;
+    let mut stripe = StripeBlockDevice::new([a, b], 4);
+    assert!(stripe.protection_information());
+
+    let lba = 2;
+    let blocks = numbered(8);
+    let mut protection = vec![0; 8 * PI];
+    for (i, (block, pi)) in blocks
+        .chunks_exact(BLOCK_SIZE)
+        .zip(protection.chunks_exact_mut(PI))
+        .enumerate()
+    {
+        pi.copy_from_slice(&protection_information(lba + i as u32, block, 0x1234));
+    }
+    block_on(stripe.write_blocks_protected(lba, &blocks, &protection)).unwrap();
+
+    let mut read_back = vec![0; blocks.len()];
+    let mut read_protection = vec![0; protection.len()];
+    block_on(stripe.read_blocks_protected(lba, &mut read_back, &mut read_protection)).unwrap();
+    assert_eq!(read_back, blocks);
+    assert_eq!(read_protection, protection);
+
+    // LBA 4 is the start of the second member
+    let mut block = [0; BLOCK_SIZE];
+    let mut pi = [0; PI];
+    block_on(members[1].read_blocks_protected(0, &mut block, &mut pi)).unwrap();
+    assert_eq!(block[..], blocks[2 * BLOCK_SIZE..3 * BLOCK_SIZE]);
+    assert_eq!(pi, protection_information(0, &block, 0x1234));
+}
+
+#[test]
+fn forwards_everything_else() {
+    // two block accesses from LBA 0 reach both
+    let mut members = [Probe::new(64), Probe::new(64)];
+    let [a, b] = &mut members;
+    block_on(call_every_method(&mut StripeBlockDevice::new([a, b], 1)));
+    // single blocks are transferred as runs, and each would be formatted on its own
+    for probe in members {
+        assert_eq!(probe.missed(), vec!["read_block", "write_block", "format"]);
+    }
+}
@@ -0,0 +1,275 @@
+//! Reads MBR, EBR and GPT partition tables, including damaged ones, and finds the filesystem
+//! in them
+
+use std::io::Cursor;
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use embassy_futures::block_on;
+
+use crate::block_devices::fat::Volume;
+use crate::fat12_partition::{
+    read_partitions, DiskPartition, Guid, PartitionScheme, PartitionTable, PartitionTableError,
+    PartitionType,
+};
+use crate::ram::{Ram, BLOCK_SIZE};
+use crate::scsi::BlockDeviceError;
+
+const BLOCKS: u32 = 256;
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+fn read(ram: &mut Ram) -> Result<PartitionTable, PartitionTableError> {
+    block_on(read_partitions(ram))
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(data: &mut [u8], offset: usize, value: u64) {
+    data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn block(ram: &mut Ram, lba: u32) -> &mut [u8] {
+    &mut ram.data[lba as usize * BLOCK_SIZE..][..BLOCK_SIZE]
+}
+
+/// Fills entry `index` of the MBR or EBR at `lba` and signs it
+fn mbr_entry(ram: &mut Ram, lba: u32, index: usize, system_id: u8, start: u32, blocks: u32) {
+    let block = block(ram, lba);
+    let entry = &mut block[446 + 16 * index..][..16];
+    entry[4] = system_id;
+    put_u32(entry, 8, start);
+    put_u32(entry, 12, blocks);
+    block[510..].copy_from_slice(&[0x55, 0xaa]);
+}
+
+fn mbr(system_id: u8, lba: u32, blocks: u32) -> DiskPartition {
+    DiskPartition {
+        partition_type: PartitionType::Mbr(system_id),
+        lba,
+        blocks,
+        bootable: false,
+        name: [0; 36],
+    }
+}
+
+#[test]
+fn primary_partitions() {
+    let mut ram = Ram::new(BLOCKS);
+    assert_eq!(
+        read(&mut ram).unwrap_err(),
+        PartitionTableError::NoPartitionTable
+    );
+
+    mbr_entry(&mut ram, 0, 0, 0x01, 1, 63);
+    mbr_entry(&mut ram, 0, 2, 0x0c, 64, BLOCKS - 64);
+    block(&mut ram, 0)[446] = 0x80;
+    let table = read(&mut ram).unwrap();
+    assert_eq!(table.scheme, PartitionScheme::Mbr);
+    let expected = [
+        DiskPartition {
+            bootable: true,
+            ..mbr(0x01, 1, 63)
+        },
+        mbr(0x0c, 64, BLOCKS - 64),
+    ];
+    assert!(table.iter().eq(expected.iter()));
+    assert!(table
+        .iter()
+        .all(|partition| partition.partition_type.may_be_fat()));
+
+    mbr_entry(&mut ram, 0, 3, 0x83, BLOCKS - 1, 2);
+    assert_eq!(read(&mut ram).unwrap_err(), PartitionTableError::OutOfRange);
+}
+
+/// An extended partition from block 100 with logical partitions at 101, 131 and 162
+fn extended(ram: &mut Ram) {
+    mbr_entry(ram, 0, 0, 0x06, 1, 99);
+    mbr_entry(ram, 0, 1, 0x0f, 100, 100);
+    // each logical partition is relative to its EBR, each link to the extended partition
+    mbr_entry(ram, 100, 0, 0x0b, 1, 20);
+    mbr_entry(ram, 100, 1, 0x05, 30, 40);
+    mbr_entry(ram, 130, 0, 0x06, 1, 10);
+    mbr_entry(ram, 130, 1, 0x05, 60, 40);
+    mbr_entry(ram, 160, 0, 0x01, 2, 5);
+}
+
+#[test]
+fn logical_partitions_follow_the_primary_ones() {
+    let mut ram = Ram::new(BLOCKS);
+    extended(&mut ram);
+    mbr_entry(&mut ram, 0, 2, 0x83, 200, 56);
+    let table = read(&mut ram).unwrap();
+    let expected = [
+        mbr(0x06, 1, 99),
+        mbr(0x83, 200, 56),
+        mbr(0x0b, 101, 20),
+        mbr(0x06, 131, 10),
+        mbr(0x01, 162, 5),
+    ];
+    assert!(
+        table.iter().eq(expected.iter()),
+        "{:?}",
+        table.iter().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn stops_at_a_loop_in_the_ebr_chain() {
+    let mut ram = Ram::new(BLOCKS);
+    extended(&mut ram);
+    // the last EBR links back to the second one
+    mbr_entry(&mut ram, 160, 1, 0x05, 30, 40);
+    let table = read(&mut ram).unwrap();
+    assert_eq!(table.len(), 4);
+    assert_eq!(table.get(3), Some(&mbr(0x01, 162, 5)));
+
+    // and the first one to itself
+    mbr_entry(&mut ram, 100, 1, 0x05, 0, 40);
+    let table = read(&mut ram).unwrap();
+    assert_eq!(table.len(), 2);
+    assert_eq!(table.get(1), Some(&mbr(0x0b, 101, 20)));
+}
+
+const ENTRIES: u32 = 4;
+
+/// The GPT header at `lba`, pointing at the entries at `entries_lba`
+fn gpt_header(
+    lba: u32,
+    alternate_lba: u32,
+    entries_lba: u32,
+    entries_crc: u32,
+) -> [u8; BLOCK_SIZE] {
+    let mut header = [0; BLOCK_SIZE];
+    header[..8].copy_from_slice(b"EFI PART");
+    put_u32(&mut header, 8, 0x0001_0000);
+    put_u32(&mut header, 12, 92);
+    put_u64(&mut header, 24, lba as u64);
+    put_u64(&mut header, 32, alternate_lba as u64);
+    put_u64(&mut header, 40, 34);
+    put_u64(&mut header, 48, (BLOCKS - 34) as u64);
+    header[56..72].copy_from_slice(&[0x42; 16]);
+    put_u64(&mut header, 72, entries_lba as u64);
+    put_u32(&mut header, 80, ENTRIES);
+    put_u32(&mut header, 84, 128);
+    put_u32(&mut header, 88, entries_crc);
+    let crc = CRC32.checksum(&header[..92]);
+    put_u32(&mut header, 16, crc);
+    header
+}
+
+/// A GPT disk behind a protective MBR, with a basic data partition named "DATA" and an EFI
+/// system partition, and the backup in the last blocks
+fn gpt_disk() -> Ram {
+    let mut ram = Ram::new(BLOCKS);
+    mbr_entry(&mut ram, 0, 0, 0xee, 1, BLOCKS - 1);
+
+    let mut entries = [0; ENTRIES as usize * 128];
+    entries[..16].copy_from_slice(&Guid::BASIC_DATA.0);
+    entries[16..32].copy_from_slice(&[1; 16]);
+    put_u64(&mut entries, 32, 34);
+    put_u64(&mut entries, 40, 133);
+    for (i, &unit) in b"DATA".iter().enumerate() {
+        entries[56 + 2 * i] = unit;
+    }
+    // the third entry, after an unused one
+    let entry = &mut entries[256..384];
+    entry[..16].copy_from_slice(&Guid::EFI_SYSTEM.0);
+    entry[16..32].copy_from_slice(&[2; 16]);
+    put_u64(entry, 32, 134);
+    put_u64(entry, 40, BLOCKS as u64 - 35);
+    let entries_crc = CRC32.checksum(&entries);
+
+    let last = BLOCKS - 1;
+    block(&mut ram, 1).copy_from_slice(&gpt_header(1, last, 2, entries_crc));
+    block(&mut ram, 2).copy_from_slice(&entries);
+    block(&mut ram, last - 1).copy_from_slice(&entries);
+    block(&mut ram, last).copy_from_slice(&gpt_header(last, 1, last - 1, entries_crc));
+    ram
+}
+
+fn gpt_partitions() -> [DiskPartition; 2] {
+    let mut name = [0; 36];
+    for (unit, &letter) in name.iter_mut().zip(b"DATA") {
+        *unit = letter as u16;
+    }
+    [
+        DiskPartition {
+            partition_type: PartitionType::Gpt(Guid::BASIC_DATA),
+            lba: 34,
+            blocks: 100,
+            bootable: false,
+            name,
+        },
+        DiskPartition {
+            partition_type: PartitionType::Gpt(Guid::EFI_SYSTEM),
+            lba: 134,
+            blocks: BLOCKS - 168,
+            bootable: false,
+            name: [0; 36],
+        },
+    ]
+}
+
+#[test]
+fn gpt_behind_a_protective_mbr() {
+    let mut ram = gpt_disk();
+    let table = read(&mut ram).unwrap();
+    assert_eq!(table.scheme, PartitionScheme::Gpt);
+    assert!(table.iter().eq(gpt_partitions().iter()));
+
+    // a protective MBR without a GPT behind it
+    let mut ram = Ram::new(BLOCKS);
+    mbr_entry(&mut ram, 0, 0, 0xee, 1, BLOCKS - 1);
+    assert_eq!(read(&mut ram).unwrap_err(), PartitionTableError::InvalidGpt);
+}
+
+#[test]
+fn falls_back_on_the_backup_gpt() {
+    // a header that doesn't match its CRC
+    let mut ram = gpt_disk();
+    block(&mut ram, 1)[60] ^= 1;
+    let table = read(&mut ram).unwrap();
+    assert!(table.iter().eq(gpt_partitions().iter()));
+
+    // entries that don't match the CRC in the header
+    let mut ram = gpt_disk();
+    block(&mut ram, 2)[40] ^= 1;
+    let table = read(&mut ram).unwrap();
+    assert!(table.iter().eq(gpt_partitions().iter()));
+
+    // both copies
+    block(&mut ram, BLOCKS - 1)[60] ^= 1;
+    assert_eq!(read(&mut ram).unwrap_err(), PartitionTableError::InvalidGpt);
+}
+
+/// Formats the `blocks` blocks from `lba` the way a host would
+fn format(ram: &mut Ram, lba: u32, blocks: u32) {
+    let volume = &mut ram.data[lba as usize * BLOCK_SIZE..][..blocks as usize * BLOCK_SIZE];
+    std_fatfs::format_volume(Cursor::new(volume), std_fatfs::FormatVolumeOptions::new()).unwrap();
+}
+
+#[test]
+fn finds_the_filesystem_in_the_first_fat_partition() {
+    // the basic data partition of a GPT disk
+    let mut ram = gpt_disk();
+    format(&mut ram, 34, 100);
+    let volume = block_on(Volume::open(&mut ram)).unwrap();
+    assert!(volume.fat().start > 34 && volume.root_dir().end < 134);
+
+    // a logical partition, after a primary one that can't hold FAT
+    let mut ram = Ram::new(BLOCKS);
+    mbr_entry(&mut ram, 0, 0, 0x83, 1, 99);
+    mbr_entry(&mut ram, 0, 1, 0x0f, 100, 100);
+    mbr_entry(&mut ram, 100, 0, 0x06, 1, 99);
+    format(&mut ram, 101, 99);
+    let volume = block_on(Volume::open(&mut ram)).unwrap();
+    assert!(volume.fat().start > 101);
+
+    // no partition that can
+    mbr_entry(&mut ram, 100, 0, 0x83, 1, 99);
+    assert_eq!(
+        block_on(Volume::open(&mut ram)).err(),
+        Some(BlockDeviceError::MediumNotPresent)
+    );
+}
@@ -0,0 +1,148 @@
+//! Shows the access trace in a file on a FAT filesystem, the way a host would read it
+
+use std::io::{Cursor, Read, Write};
+
+use embassy_futures::block_on;
+use embassy_time::Timer;
+
+use crate::block_devices::integrity::IntegrityBlockDevice;
+use crate::block_devices::trace::TraceFileBlockDevice;
+use crate::in_thread_mode;
+use crate::probe::{call_every_method, Probe};
+use crate::ram::{Ram, BLOCK_SIZE};
+use crate::scsi::trace::{self, TraceOp, EXPORT_BYTES};
+use crate::scsi::{BlockDevice, MediaStatus, GUARD_CRC, PROTECTION_INFORMATION_BYTES};
+
+/// A 4MiB FAT12 filesystem, without a partition table
+const BLOCKS: u32 = 8192;
+/// What the trace file holds on the disk
+const STORED: u8 = 0x5a;
+
+/// A filesystem holding the trace file, `len` bytes long
+fn formatted(len: usize) -> Vec<u8> {
+    let mut image = vec![0; BLOCKS as usize * BLOCK_SIZE];
+    std_fatfs::format_volume(
+        Cursor::new(&mut image),
+        std_fatfs::FormatVolumeOptions::new(),
+    )
+    .unwrap();
+    let fs =
+        std_fatfs::FileSystem::new(Cursor::new(&mut image), std_fatfs::FsOptions::new()).unwrap();
+    let mut file = fs.root_dir().create_file("TRACE.BIN").unwrap();
+    file.write_all(&vec![STORED; len]).unwrap();
+    drop(file);
+    drop(fs);
+    image
+}
+
+fn image(device: &mut impl BlockDevice) -> Vec<u8> {
+    let mut image = vec![0; device.block_count() as usize * BLOCK_SIZE];
+    block_on(device.read_blocks(0, &mut image)).unwrap();
+    image
+}
+
+/// The trace file as the host reads it from `image`
+fn trace_file(mut image: Vec<u8>) -> Vec<u8> {
+    let fs =
+        std_fatfs::FileSystem::new(Cursor::new(&mut image), std_fatfs::FsOptions::new()).unwrap();
+    let mut contents = Vec::new();
+    let mut file = fs.root_dir().open_file("TRACE.BIN").unwrap();
+    file.read_to_end(&mut contents).unwrap();
+    contents
+}
+
+/// The blocks of the trace file
+fn file_blocks(image: &[u8]) -> Vec<u32> {
+    let blocks = image.chunks(BLOCK_SIZE).enumerate();
+    blocks
+        .filter(|(_, block)| block.iter().all(|&byte| byte == STORED))
+        .map(|(lba, _)| lba as u32)
+        .collect()
+}
+
+fn dump() -> Vec<u8> {
+    let mut dump = vec![0; EXPORT_BYTES];
+    assert_eq!(trace::export(0, &mut dump), EXPORT_BYTES);
+    dump
+}
+
+#[test]
+fn reads_the_trace_through_the_file() {
+    in_thread_mode(|| {
+        trace::clear();
+        trace::record(TraceOp::Write, 100, 8, BLOCKS);
+        let image = formatted(EXPORT_BYTES);
+        let blocks = file_blocks(&image);
+        let mut device = TraceFileBlockDevice::new(Ram::from(image));
+
+        // the file is found when the medium is
+        assert_eq!(block_on(device.media_status()), MediaStatus::Present);
+        assert_eq!(trace_file(self::image(&mut device)), dump());
+        trace::record(TraceOp::Read, 5, 1, BLOCKS);
+        assert_eq!(trace_file(self::image(&mut device)), dump());
+
+        // a write to the metadata shows what's stored until the file's looked up again
+        let mut root_dir = self::image(&mut device);
+        let at = root_dir
+            .windows(11)
+            .position(|name| name == b"TRACE   BIN")
+            .unwrap();
+        let lba = (at / BLOCK_SIZE) as u32;
+        root_dir[at + 22] ^= 1;
+        let block = &root_dir[lba as usize * BLOCK_SIZE..][..BLOCK_SIZE];
+        block_on(device.write_block(lba, block)).unwrap();
+        assert_eq!(trace_file(self::image(&mut device)), [STORED; EXPORT_BYTES]);
+        block_on(Timer::at(device.idle_deadline().unwrap()));
+        block_on(device.idle());
+        assert_eq!(device.idle_deadline(), None);
+        assert_eq!(trace_file(self::image(&mut device)), dump());
+
+        // writing to the file leaves it alone until the medium changes
+        block_on(device.write_block(blocks[1], &[1; BLOCK_SIZE])).unwrap();
+        let file = trace_file(self::image(&mut device));
+        assert_eq!(file[..BLOCK_SIZE], [STORED; BLOCK_SIZE]);
+        assert_eq!(file[BLOCK_SIZE..2 * BLOCK_SIZE], [1; BLOCK_SIZE]);
+    });
+}
+
+#[test]
+fn an_empty_file_has_no_blocks() {
+    in_thread_mode(|| {
+        let image = formatted(0);
+        let mut device = TraceFileBlockDevice::new(Ram::from(image.clone()));
+        assert_eq!(block_on(device.media_status()), MediaStatus::Present);
+        assert_eq!(self::image(&mut device), image);
+    });
+}
+
+#[test]
+fn sets_the_guards_of_the_trace() {
+    in_thread_mode(|| {
+        trace::clear();
+        let mut integrity = IntegrityBlockDevice::new(Ram::new(BLOCKS + 128), true);
+        let image = formatted(EXPORT_BYTES);
+        let lba = file_blocks(&image)[0];
+        block_on(integrity.write_blocks(0, &image)).unwrap();
+        let mut device = TraceFileBlockDevice::new(integrity);
+        block_on(device.media_status());
+
+        let mut block = [0; BLOCK_SIZE];
+        let mut pi = [0; PROTECTION_INFORMATION_BYTES];
+        block_on(device.read_blocks_protected(lba, &mut block, &mut pi)).unwrap();
+        assert_eq!(block, dump()[..BLOCK_SIZE]);
+        assert_eq!(pi[0..2], GUARD_CRC.checksum(&block).to_be_bytes());
+        assert_eq!(pi[4..8], lba.to_be_bytes());
+    });
+}
+
+#[test]
+fn forwards_every_method() {
+    let probe = in_thread_mode(|| {
+        let mut probe = Probe::new(64);
+        block_on(call_every_method(&mut TraceFileBlockDevice::new(
+            &mut probe,
+        )));
+        probe
+    });
+    assert_eq!(probe.missed(), Vec::<&str>::new());
+}
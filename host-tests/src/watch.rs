@@ -0,0 +1,225 @@
+//! Makes changes to a FAT filesystem the way a host would, checking the watcher publishes them
+
+use std::io::{Cursor, Seek, SeekFrom, Write};
+
+use embassy_futures::block_on;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, pubsub::Subscriber};
+use embassy_time::{Instant, Timer};
+
+use crate::block_devices::watch::{
+    FsEvent, WatchBlockDevice, EVENTS, EVENT_CAPACITY, IDLE, SUBSCRIBERS,
+};
+use crate::display::{DisplayState, SIGNAL};
+use crate::in_thread_mode;
+use crate::probe::{call_every_method, Probe};
+use crate::ram::{Ram, BLOCK_SIZE};
+use crate::scsi::BlockDevice;
+
+/// A 4MiB FAT12 filesystem, without a partition table
+const BLOCKS: u32 = 8192;
+
+type Watch = WatchBlockDevice<Ram, 32>;
+type Events = Subscriber<'static, ThreadModeRawMutex, FsEvent, EVENT_CAPACITY, SUBSCRIBERS, 0>;
+type HostFs<'a> = std_fatfs::FileSystem<Cursor<&'a mut Vec<u8>>>;
+
+fn formatted(label: &[u8; 11]) -> Vec<u8> {
+    let mut image = vec![0; BLOCKS as usize * BLOCK_SIZE];
+    let options = std_fatfs::FormatVolumeOptions::new().volume_label(*label);
+    std_fatfs::format_volume(Cursor::new(&mut image), options).unwrap();
+    image
+}
+
+fn mount(image: &mut Vec<u8>) -> HostFs<'_> {
+    std_fatfs::FileSystem::new(Cursor::new(image), std_fatfs::FsOptions::new()).unwrap()
+}
+
+fn image(device: &mut Watch) -> Vec<u8> {
+    let mut image = vec![0; device.block_count() as usize * BLOCK_SIZE];
+    block_on(device.read_blocks(0, &mut image)).unwrap();
+    image
+}
+
+/// Writes the blocks that differ between the disk and `image`, returning how many did
+fn write_image(device: &mut Watch, image: &[u8]) -> usize {
+    let before = self::image(device);
+    let blocks = before.chunks(BLOCK_SIZE).zip(image.chunks(BLOCK_SIZE));
+    let mut written = 0;
+    for (lba, _) in blocks.enumerate().filter(|(_, (old, new))| old != new) {
+        let block = &image[lba * BLOCK_SIZE..][..BLOCK_SIZE];
+        block_on(device.write_block(lba as u32, block)).unwrap();
+        written += 1;
+    }
+    written
+}
+
+/// Changes the filesystem with `change`, as the host would
+fn host(device: &mut Watch, change: impl FnOnce(&HostFs)) {
+    let mut image = image(device);
+    change(&mount(&mut image));
+    write_image(device, &image);
+}
+
+/// Waits for the watcher's deadline like the SCSI layer would, then runs it
+fn wait_idle(device: &mut Watch) {
+    let deadline = device.idle_deadline().unwrap();
+    block_on(Timer::at(deadline));
+    block_on(device.idle());
+}
+
+/// The events published so far, sorted, with `+` for created, `-` for deleted and `~` for
+/// modified
+fn events(subscriber: &mut Events) -> Vec<String> {
+    let mut events = Vec::new();
+    while let Some(event) = subscriber.try_next_message_pure() {
+        events.push(match event {
+            FsEvent::Created(path) => format!("+{path:?}"),
+            FsEvent::Deleted(path) => format!("-{path:?}"),
+            FsEvent::Modified(path) => format!("~{path:?}"),
+            FsEvent::LabelChanged(label) => format!("label {}", String::from_utf8_lossy(&label)),
+        });
+    }
+    events.sort();
+    events
+}
+
+/// A watcher of a filesystem with a file and a directory holding a file, after it's loaded
+/// them
+fn watched() -> (Watch, Events) {
+    let mut image = formatted(b"FIRST      ");
+    {
+        let fs = mount(&mut image);
+        let root = fs.root_dir();
+        root.create_file("old.txt")
+            .unwrap()
+            .write_all(b"old")
+            .unwrap();
+        let mut file = root
+            .create_dir("keep")
+            .unwrap()
+            .create_file("a.bin")
+            .unwrap();
+        file.write_all(&[1; 3000]).unwrap();
+    }
+
+    let mut subscriber = EVENTS.subscriber().unwrap();
+    let mut device = Watch::new(Ram::from(image));
+    // what's there at the start is read straight away, without publishing anything
+    assert!(device.idle_deadline().unwrap() <= Instant::now());
+    block_on(device.idle());
+    assert_eq!(device.idle_deadline(), None);
+    assert_eq!(events(&mut subscriber), Vec::<String>::new());
+    (device, subscriber)
+}
+
+#[test]
+fn forwards_every_method() {
+    let probe = in_thread_mode(|| {
+        let mut probe = Probe::new(64);
+        block_on(call_every_method(&mut WatchBlockDevice::<_, 8>::new(
+            &mut probe,
+        )));
+        probe
+    });
+    assert_eq!(probe.missed(), Vec::<&str>::new());
+}
+
+#[test]
+fn publishes_once_the_host_is_idle() {
+    in_thread_mode(|| {
+        let (mut device, mut subscriber) = watched();
+
+        let written = Instant::now();
+        host(&mut device, |fs| {
+            let docs = fs.root_dir().create_dir("docs").unwrap();
+            let mut file = docs
+                .create_dir("deep")
+                .unwrap()
+                .create_file("x.txt")
+                .unwrap();
+            file.write_all(b"hi").unwrap();
+            let mut file = docs.create_file("readme.txt").unwrap();
+            file.write_all(&[b'r'; 5000]).unwrap();
+        });
+        let deadline = device.idle_deadline().unwrap();
+        assert!(deadline >= written + IDLE);
+        // too soon
+        block_on(device.idle());
+        assert_eq!(events(&mut subscriber), Vec::<String>::new());
+        assert_eq!(device.idle_deadline(), Some(deadline));
+
+        wait_idle(&mut device);
+        assert_eq!(
+            events(&mut subscriber),
+            [
+                "+DOCS/",
+                "+DOCS/DEEP/",
+                "+DOCS/DEEP/X.TXT",
+                "+DOCS/README.TXT"
+            ]
+        );
+        assert_eq!(device.idle_deadline(), None);
+
+        host(&mut device, |fs| {
+            let root = fs.root_dir();
+            let mut file = root.open_file("keep/a.bin").unwrap();
+            file.seek(SeekFrom::End(0)).unwrap();
+            file.write_all(&[2; 100]).unwrap();
+            root.remove("old.txt").unwrap();
+            root.remove("docs/deep/x.txt").unwrap();
+            root.remove("docs/deep").unwrap();
+        });
+        wait_idle(&mut device);
+        assert_eq!(
+            events(&mut subscriber),
+            ["-DOCS/DEEP/", "-DOCS/DEEP/X.TXT", "-OLD.TXT", "~KEEP/A.BIN"]
+        );
+    });
+}
+
+#[test]
+fn flush_publishes_files_written_in_place() {
+    in_thread_mode(|| {
+        let (mut device, mut subscriber) = watched();
+
+        // the data changes without the directory entry or the FAT doing so
+        let mut image = image(&mut device);
+        let at = image.windows(64).position(|data| data == [1; 64]).unwrap();
+        image[at + 10] = 3;
+        assert_eq!(write_image(&mut device, &image), 1);
+        block_on(device.flush()).unwrap();
+        assert_eq!(events(&mut subscriber), ["~KEEP/A.BIN"]);
+        assert_eq!(device.idle_deadline(), None);
+    });
+}
+
+#[test]
+fn relabel_and_reformat() {
+    in_thread_mode(|| {
+        let (mut device, mut subscriber) = watched();
+
+        let mut image = image(&mut device);
+        for at in 0..image.len() - 11 {
+            if &image[at..at + 11] == b"FIRST      " {
+                image[at..at + 11].copy_from_slice(b"SECOND     ");
+            }
+        }
+        write_image(&mut device, &image);
+        block_on(device.flush()).unwrap();
+        assert_eq!(events(&mut subscriber), ["label SECOND     "]);
+
+        let mut image = formatted(b"THIRD      ");
+        write_image(&mut device, &image);
+        block_on(device.flush()).unwrap();
+        assert_eq!(
+            events(&mut subscriber),
+            ["-KEEP/", "-KEEP/A.BIN", "-OLD.TXT", "label THIRD      "]
+        );
+
+        let Some(DisplayState::FileSystem(label, free)) = SIGNAL.try_take() else {
+            panic!("the display wasn't told");
+        };
+        let stats = mount(&mut image).stats().unwrap();
+        assert_eq!(&label, b"THIRD      ");
+        assert_eq!(free, stats.free_clusters() * stats.cluster_size());
+    });
+}
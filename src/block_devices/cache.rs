@@ -0,0 +1,457 @@
+//! Keeps recently used blocks of a slower base device in RAM.
+//!
+//! A read that misses the cache reads ahead up to [`RUN_BLOCKS`] blocks, so sequential reads
+//! mostly hit. With the write cache enabled, writes complete once they're in the cache and
+//! repeated writes to a block only reach the base once. Dirty blocks are written back in runs
+//! of consecutive blocks when they're evicted and when the cache is flushed, which the SCSI
+//! layer does on SYNCHRONIZE CACHE, when the host stops or ejects the unit and when the USB
+//! bus is suspended.
+//!
+//! The host turns the write and read caches on and off with the WCE and RCD bits of the
+//! caching mode page, and can write through the cache with FUA. Both caches start enabled.
+//! Dirty blocks are lost if the power goes before they're written back.
+//!
+//! While the base is locked every access fails with LOGICAL UNIT ACCESS NOT AUTHORIZED, so
+//! nothing cached is served. Clean blocks are dropped when it's locked, dirty ones are kept
+//! until it's unlocked and then written back.
+
+use core::ops::Range;
+
+use defmt::{info, warn};
+
+use super::BLOCK_SIZE;
+use crate::scsi::{BlockDevice, BlockDeviceError, Caching, MediaStatus, Wrapper};
+
+/// Most blocks read ahead on a miss, or written back at once
+pub const RUN_BLOCKS: usize = 4;
+
+const NO_LBA: u32 = u32::MAX;
+
+struct Line {
+    /// The cached block, `NO_LBA` if the line is free
+    lba: u32,
+    /// `data` has changes that haven't been written to the base
+    dirty: bool,
+    /// When the line was last used, in ticks of `CacheBlockDevice::tick`
+    used: u32,
+    data: [u8; BLOCK_SIZE],
+}
+
+const FREE: Line = Line {
+    lba: NO_LBA,
+    dirty: false,
+    used: 0,
+    data: [0; BLOCK_SIZE],
+};
+
+pub struct CacheBlockDevice<B, const LINES: usize> {
+    base: B,
+    caching: Caching,
+    lines: [Line; LINES],
+    /// Counts line uses, for least recently used eviction
+    tick: u32,
+    /// Runs of blocks on their way to or from the base
+    buffer: [u8; RUN_BLOCKS * BLOCK_SIZE],
+    /// The base was locked, and hasn't been unlocked since
+    locked: bool,
+}
+
+impl<B: BlockDevice, const LINES: usize> CacheBlockDevice<B, LINES> {
+    /// Caches `LINES` blocks of `base`, with both the read and write caches enabled
+    pub const fn new(base: B) -> Self {
+        assert!(B::BLOCK_BYTES == BLOCK_SIZE);
+        assert!(LINES > 0);
+        Self {
+            base,
+            caching: Caching {
+                write_cache_enabled: true,
+                read_cache_disable: false,
+            },
+            lines: [FREE; LINES],
+            tick: 0,
+            buffer: [0; RUN_BLOCKS * BLOCK_SIZE],
+            locked: false,
+        }
+    }
+
+    /// The number of blocks waiting to be written back
+    pub fn dirty_blocks(&self) -> usize {
+        self.lines.iter().filter(|line| line.dirty).count()
+    }
+
+    /// The blocks from `lba`, if they're on the base and it isn't locked
+    fn check_range(&self, lba: u32, count: usize) -> Result<Range<u32>, BlockDeviceError> {
+        if self.locked {
+            return Err(BlockDeviceError::AccessNotAuthorized);
+        }
+        match (lba as u64).checked_add(count as u64) {
+            Some(end) if end <= self.base.block_count() as u64 => Ok(lba..end as u32),
+            _ => Err(BlockDeviceError::InvalidAddress),
+        }
+    }
+
+    fn find(&self, lba: u32) -> Option<usize> {
+        self.lines.iter().position(|line| line.lba == lba)
+    }
+
+    fn touch(&mut self, index: usize) {
+        self.tick = self.tick.wrapping_add(1);
+        self.lines[index].used = self.tick;
+    }
+
+    /// A line for `lba`, which isn't cached. Takes a free line or the least recently used
+    /// one, writing it back first if it's dirty. The line's data is left to the caller
+    async fn allocate(&mut self, lba: u32) -> Result<usize, BlockDeviceError> {
+        let tick = self.tick;
+        let (index, _) = self
+            .lines
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, line)| match line.lba {
+                NO_LBA => u32::MAX,
+                _ => tick.wrapping_sub(line.used),
+            })
+            .unwrap();
+
+        if self.lines[index].dirty {
+            match self.write_run(index).await {
+                Err(e) if !e.is_recovered() => return Err(e),
+                _ => {}
+            }
+        }
+
+        self.lines[index].lba = lba;
+        self.touch(index);
+        Ok(index)
+    }
+
+    /// Reads `lba` and the blocks after it that aren't cached yet into the cache
+    async fn fetch(&mut self, lba: u32) -> Result<(), BlockDeviceError> {
+        let remaining = (self.base.block_count() - lba) as usize;
+        let mut count = 1;
+        while count < RUN_BLOCKS.min(LINES).min(remaining)
+            && self.find(lba + count as u32).is_none()
+        {
+            count += 1;
+        }
+
+        // lines are taken before reading, as writing back an evicted line uses the buffer
+        let mut run = [0; RUN_BLOCKS];
+        for i in 0..count {
+            match self.allocate(lba + i as u32).await {
+                Ok(index) => run[i] = index,
+                Err(e) => {
+                    self.free(&run[..i]);
+                    return Err(e);
+                }
+            }
+        }
+
+        let buffer = &mut self.buffer[..count * BLOCK_SIZE];
+        let result = self.base.read_blocks(lba, buffer).await;
+        if matches!(result, Err(e) if !e.is_recovered()) {
+            self.free(&run[..count]);
+            return result;
+        }
+
+        for (&index, block) in run[..count].iter().zip(buffer.chunks_exact(BLOCK_SIZE)) {
+            self.lines[index].data.copy_from_slice(block);
+        }
+        result
+    }
+
+    fn free(&mut self, indices: &[usize]) {
+        for &index in indices {
+            self.lines[index].lba = NO_LBA;
+        }
+    }
+
+    /// Writes back the dirty line `index` along with the dirty lines of the blocks after it
+    async fn write_run(&mut self, index: usize) -> Result<(), BlockDeviceError> {
+        let lba = self.lines[index].lba;
+        let remaining = (self.base.block_count() - lba) as usize;
+        let mut run = [0; RUN_BLOCKS];
+        let mut count = 0;
+        while count < RUN_BLOCKS.min(remaining) {
+            match self.find(lba + count as u32) {
+                Some(index) if self.lines[index].dirty => {
+                    self.buffer[count * BLOCK_SIZE..(count + 1) * BLOCK_SIZE]
+                        .copy_from_slice(&self.lines[index].data);
+                    run[count] = index;
+                    count += 1;
+                }
+                _ => break,
+            }
+        }
+
+        let result = self
+            .base
+            .write_blocks(lba, &self.buffer[..count * BLOCK_SIZE])
+            .await;
+        if matches!(result, Err(e) if !e.is_recovered()) {
+            return result;
+        }
+        for &index in &run[..count] {
+            self.lines[index].dirty = false;
+        }
+        result
+    }
+
+    /// Writes back the dirty lines in `range`, lowest block first
+    async fn write_back(&mut self, range: Range<u32>) -> Result<(), BlockDeviceError> {
+        let mut result = Ok(());
+        loop {
+            let first = self
+                .lines
+                .iter()
+                .enumerate()
+                .filter(|(_, line)| line.dirty && range.contains(&line.lba))
+                .min_by_key(|(_, line)| line.lba);
+            let Some((index, _)) = first else {
+                return result;
+            };
+            match self.write_run(index).await {
+                Err(e) if e.is_recovered() => result = Err(e),
+                r => r?,
+            }
+        }
+    }
+
+    /// Drops the cached blocks in `range`, and the dirty ones only if `dirty`
+    fn discard(&mut self, range: Range<u32>, dirty: bool) {
+        for line in self.lines.iter_mut() {
+            if range.contains(&line.lba) && (dirty || !line.dirty) {
+                line.lba = NO_LBA;
+                line.dirty = false;
+            }
+        }
+    }
+
+    /// Hides the cache until the base is unlocked, dropping the clean blocks
+    fn locked(&mut self) {
+        if !self.locked {
+            info!(
+                "cache: locked, keeping {} dirty blocks",
+                self.dirty_blocks()
+            );
+        }
+        self.locked = true;
+        self.discard(0..NO_LBA, false);
+    }
+
+    /// Writes back the dirty blocks kept while the base was locked
+    async fn unlocked(&mut self) {
+        if !core::mem::take(&mut self.locked) {
+            return;
+        }
+        if let Err(e) = self.write_back(0..NO_LBA).await {
+            warn!("cache: writing back after unlock: {}", e);
+        }
+    }
+
+    /// Writes `blocks` to the base without caching them, updating any cached copies
+    async fn write_through(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+
+        let result = self.base.write_blocks(lba, blocks).await;
+        if matches!(result, Err(e) if !e.is_recovered()) {
+            return result;
+        }
+        for (i, block) in blocks.chunks_exact(BLOCK_SIZE).enumerate() {
+            if let Some(index) = self.find(lba + i as u32) {
+                self.lines[index].data.copy_from_slice(block);
+                self.lines[index].dirty = false;
+            }
+        }
+        result
+    }
+}
+
+impl<B: BlockDevice, const LINES: usize> Wrapper for CacheBlockDevice<B, LINES> {
+    type Base = B;
+
+    fn base(&self) -> &B {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut B {
+        &mut self.base
+    }
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        Wrapper::read_blocks(self, lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        Wrapper::write_blocks(self, lba, block).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let range = self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+
+        if self.caching.read_cache_disable {
+            // cached blocks are never older than the base's, so they replace what's read
+            let result = self.base.read_blocks(lba, blocks).await;
+            if matches!(result, Err(e) if !e.is_recovered()) {
+                return result;
+            }
+            for line in self.lines.iter().filter(|line| range.contains(&line.lba)) {
+                let offset = (line.lba - lba) as usize * BLOCK_SIZE;
+                blocks[offset..offset + BLOCK_SIZE].copy_from_slice(&line.data);
+            }
+            return result;
+        }
+
+        let mut result = Ok(());
+        for (i, block) in blocks.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            let lba = lba + i as u32;
+            if self.find(lba).is_none() {
+                match self.fetch(lba).await {
+                    Err(e) if e.is_recovered() => result = Err(e),
+                    r => r?,
+                }
+            }
+            let index = self.find(lba).unwrap();
+            self.touch(index);
+            block.copy_from_slice(&self.lines[index].data);
+        }
+        result
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        if !self.caching.write_cache_enabled {
+            return self.write_through(lba, blocks).await;
+        }
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+
+        for (i, block) in blocks.chunks_exact(BLOCK_SIZE).enumerate() {
+            let lba = lba + i as u32;
+            let index = match self.find(lba) {
+                Some(index) => index,
+                None => self.allocate(lba).await?,
+            };
+            self.touch(index);
+            self.lines[index].data.copy_from_slice(block);
+            self.lines[index].dirty = true;
+        }
+        Ok(())
+    }
+
+    async fn write_blocks_fua(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        let result = self.write_through(lba, blocks).await;
+        if matches!(result, Err(e) if !e.is_recovered()) {
+            return result;
+        }
+        match self.base.flush().await {
+            Err(e) if !e.is_recovered() => Err(e),
+            _ => result,
+        }
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        let status = self.base.media_status().await;
+        match status {
+            MediaStatus::Locked => self.locked(),
+            // unlocked again, the same medium is reported as changed
+            MediaStatus::Changed | MediaStatus::Present if self.locked => {
+                self.unlocked().await;
+                self.discard(0..NO_LBA, false);
+            }
+            // anything cached belongs to the old medium
+            MediaStatus::Absent | MediaStatus::Changed => {
+                let dirty = self.dirty_blocks();
+                if dirty > 0 {
+                    warn!("cache: medium changed, {} blocks weren't written", dirty);
+                }
+                self.discard(0..NO_LBA, true);
+            }
+            MediaStatus::Present | MediaStatus::BecomingReady => {}
+        }
+        status
+    }
+
+    async fn unmap(&mut self, lba: u32, count: u32) -> Result<(), BlockDeviceError> {
+        let range = self.check_range(lba, count as usize)?;
+        self.discard(range, true);
+        self.base.unmap(lba, count).await
+    }
+
+    async fn unlock(&mut self, key: &[u8]) -> Result<(), BlockDeviceError> {
+        self.base.unlock(key).await?;
+        self.unlocked().await;
+        Ok(())
+    }
+
+    fn lock(&mut self) {
+        self.locked();
+        self.base.lock()
+    }
+
+    async fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        let result = self.write_back(0..NO_LBA).await;
+        if matches!(result, Err(e) if !e.is_recovered()) {
+            return result;
+        }
+        match self.base.flush().await {
+            Err(e) if !e.is_recovered() => Err(e),
+            _ => result,
+        }
+    }
+
+    fn caching(&self) -> Option<Caching> {
+        Some(self.caching)
+    }
+
+    async fn set_caching(&mut self, caching: Caching) -> Result<(), BlockDeviceError> {
+        if self.caching.write_cache_enabled && !caching.write_cache_enabled {
+            match self.write_back(0..NO_LBA).await {
+                Err(e) if !e.is_recovered() => return Err(e),
+                _ => {}
+            }
+        }
+        info!("cache: {}", caching);
+        self.caching = caching;
+        Ok(())
+    }
+
+    /// Protected reads go to the base, which holds the protection information, once the
+    /// blocks' dirty lines are written back
+    async fn read_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        protection: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        let range = self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+        match self.write_back(range).await {
+            Err(e) if !e.is_recovered() => return Err(e),
+            _ => {}
+        }
+        self.base
+            .read_blocks_protected(lba, blocks, protection)
+            .await
+    }
+
+    /// Protected writes go straight to the base, replacing anything cached for the blocks
+    async fn write_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        let range = self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+        self.discard(range, true);
+        self.base
+            .write_blocks_protected(lba, blocks, protection)
+            .await
+    }
+
+    /// The base is formatted under the cache, which drops everything it holds
+    async fn format(&mut self) -> Result<(), BlockDeviceError> {
+        if self.locked {
+            return Err(BlockDeviceError::AccessNotAuthorized);
+        }
+        self.discard(0..NO_LBA, true);
+        self.base.format().await
+    }
+}
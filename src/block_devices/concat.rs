@@ -0,0 +1,407 @@
+//! Joins two devices end to end (linear concatenation), so several small devices such as
+//! RAM or flash regions appear to the host as one larger disk.
+//!
+//! LBA 0 is the first block of the first device and its last block is followed by the first
+//! block of the second. Accesses that cross from one device to the other are split between
+//! them. More than two devices are joined by nesting, e.g.
+//! `ConcatBlockDevice::new(ConcatBlockDevice::new(a, b), c)`.
+//!
+//! Features are only offered if every member has them: thin provisioning, caches, protection
+//! information and transactions. Each member commits its own part of a transaction, so a
+//! power cut between the commits can leave a write that crosses members half applied.
+
+use embassy_time::Instant;
+
+use super::BLOCK_SIZE;
+use crate::scsi::{
+    read_blocks_shifted, write_blocks_shifted, BlockDevice, BlockDeviceError, Caching, MediaStatus,
+    Provisioning, PROTECTION_INFORMATION_BYTES,
+};
+
+/// Combines the media status of the members of a composite device. A missing or locked
+/// member makes the whole device unavailable, and a change to any member is reported once
+/// they're all available again
+#[derive(Default)]
+pub(crate) struct MembersStatus {
+    /// A member reported a change that hasn't been passed on yet
+    changed: bool,
+}
+
+impl MembersStatus {
+    pub(crate) fn combine(
+        &mut self,
+        statuses: impl IntoIterator<Item = MediaStatus>,
+    ) -> MediaStatus {
+        let mut combined = MediaStatus::Present;
+        for status in statuses {
+            combined = match (combined, status) {
+                (MediaStatus::Absent, _) | (_, MediaStatus::Absent) => MediaStatus::Absent,
+                (MediaStatus::Locked, _) | (_, MediaStatus::Locked) => MediaStatus::Locked,
+                (MediaStatus::BecomingReady, _) | (_, MediaStatus::BecomingReady) => {
+                    MediaStatus::BecomingReady
+                }
+                (combined, _) => combined,
+            };
+            self.changed |= status == MediaStatus::Changed;
+        }
+        if combined == MediaStatus::Present && core::mem::take(&mut self.changed) {
+            MediaStatus::Changed
+        } else {
+            combined
+        }
+    }
+}
+
+/// Folds the result of one part of a split access into `result`. Errors that aren't
+/// recovered are returned to stop the access, recovered ones are kept in `result`
+pub(crate) fn combine_results(
+    result: &mut Result<(), BlockDeviceError>,
+    part: Result<(), BlockDeviceError>,
+) -> Result<(), BlockDeviceError> {
+    match part {
+        Err(e) if e.is_recovered() => {
+            *result = Err(e);
+            Ok(())
+        }
+        part => part,
+    }
+}
+
+/// Folds the result of unlocking one member into `result`, which starts as
+/// [`BlockDeviceError::Unsupported`]. Members without locking are skipped, and a failure to
+/// unlock is returned to stop
+pub(crate) fn combine_unlock(
+    result: &mut Result<(), BlockDeviceError>,
+    part: Result<(), BlockDeviceError>,
+) -> Result<(), BlockDeviceError> {
+    match part {
+        Err(BlockDeviceError::Unsupported) => Ok(()),
+        Err(e) if !e.is_recovered() => Err(e),
+        part => {
+            if *result == Err(BlockDeviceError::Unsupported) || part.is_err() {
+                *result = part;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// The blocks used and available across the members, if they're all thin provisioned
+pub(crate) fn combine_provisioning(
+    members: impl IntoIterator<Item = Option<Provisioning>>,
+) -> Option<Provisioning> {
+    members.into_iter().try_fold(
+        Provisioning {
+            used: 0,
+            available: 0,
+            threshold_exponent: u8::MAX,
+        },
+        |combined, member| {
+            let member = member?;
+            Some(Provisioning {
+                used: combined.used.saturating_add(member.used),
+                available: combined.available.saturating_add(member.available),
+                threshold_exponent: combined.threshold_exponent.min(member.threshold_exponent),
+            })
+        },
+    )
+}
+
+/// The cache settings of the members, if they all have a cache. Writes are cached if any
+/// member caches them, and reads only bypass the caches if they all do
+pub(crate) fn combine_caching(
+    members: impl IntoIterator<Item = Option<Caching>>,
+) -> Option<Caching> {
+    members.into_iter().try_fold(
+        Caching {
+            write_cache_enabled: false,
+            read_cache_disable: true,
+        },
+        |combined, member| {
+            let member = member?;
+            Some(Caching {
+                write_cache_enabled: combined.write_cache_enabled || member.write_cache_enabled,
+                read_cache_disable: combined.read_cache_disable && member.read_cache_disable,
+            })
+        },
+    )
+}
+
+/// The most blocks a transaction can write, if every member has transactions. A write may
+/// land on any one member, so that's the fewest any of them can take
+pub(crate) fn combine_transaction_blocks(
+    members: impl IntoIterator<Item = Option<u32>>,
+) -> Option<u32> {
+    members
+        .into_iter()
+        .try_fold(u32::MAX, |combined, member| Some(combined.min(member?)))
+}
+
+/// The earliest time any member has work of its own to do
+pub(crate) fn combine_idle_deadlines(
+    members: impl IntoIterator<Item = Option<Instant>>,
+) -> Option<Instant> {
+    members.into_iter().flatten().min()
+}
+
+/// Whether a member with `deadline` has work that's due
+pub(crate) fn idle_due(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| deadline <= Instant::now())
+}
+
+pub struct ConcatBlockDevice<A, B> {
+    first: A,
+    second: B,
+    status: MembersStatus,
+}
+
+impl<A: BlockDevice, B: BlockDevice> ConcatBlockDevice<A, B> {
+    /// The blocks of `first` followed by those of `second`
+    pub fn new(first: A, second: B) -> Self {
+        assert!(A::BLOCK_BYTES == BLOCK_SIZE && B::BLOCK_BYTES == BLOCK_SIZE);
+        Self {
+            first,
+            second,
+            status: MembersStatus::default(),
+        }
+    }
+
+    /// Splits `count` blocks from `lba` into the number of blocks on the first device and the
+    /// address of the rest on the second
+    fn split(&self, lba: u32, count: usize) -> Result<(usize, u32), BlockDeviceError> {
+        let end = lba as u64 + count as u64;
+        if end > self.block_count() as u64 {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+        let boundary = self.first.block_count();
+        let first_count = (boundary.saturating_sub(lba) as usize).min(count);
+        Ok((first_count, lba.saturating_sub(boundary)))
+    }
+}
+
+impl<A: BlockDevice, B: BlockDevice> BlockDevice for ConcatBlockDevice<A, B> {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.read_blocks(lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_blocks(lba, block).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let (first_count, second_lba) = self.split(lba, blocks.len() / BLOCK_SIZE)?;
+        let (first, second) = blocks.split_at_mut(first_count * BLOCK_SIZE);
+
+        let mut result = Ok(());
+        if !first.is_empty() {
+            let part = self.first.read_blocks(lba, first).await;
+            combine_results(&mut result, part)?;
+        }
+        if !second.is_empty() {
+            let part = self.second.read_blocks(second_lba, second).await;
+            combine_results(&mut result, part)?;
+        }
+        result
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        let (first_count, second_lba) = self.split(lba, blocks.len() / BLOCK_SIZE)?;
+        let (first, second) = blocks.split_at(first_count * BLOCK_SIZE);
+
+        let mut result = Ok(());
+        if !first.is_empty() {
+            let part = self.first.write_blocks(lba, first).await;
+            combine_results(&mut result, part)?;
+        }
+        if !second.is_empty() {
+            let part = self.second.write_blocks(second_lba, second).await;
+            combine_results(&mut result, part)?;
+        }
+        result
+    }
+
+    async fn write_blocks_fua(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        let (first_count, second_lba) = self.split(lba, blocks.len() / BLOCK_SIZE)?;
+        let (first, second) = blocks.split_at(first_count * BLOCK_SIZE);
+
+        let mut result = Ok(());
+        if !first.is_empty() {
+            let part = self.first.write_blocks_fua(lba, first).await;
+            combine_results(&mut result, part)?;
+        }
+        if !second.is_empty() {
+            let part = self.second.write_blocks_fua(second_lba, second).await;
+            combine_results(&mut result, part)?;
+        }
+        result
+    }
+
+    fn block_count(&self) -> u32 {
+        self.first
+            .block_count()
+            .saturating_add(self.second.block_count())
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        let first = self.first.media_status().await;
+        let second = self.second.media_status().await;
+        self.status.combine([first, second])
+    }
+
+    fn write_protected(&self) -> bool {
+        self.first.write_protected() || self.second.write_protected()
+    }
+
+    fn provisioning(&self) -> Option<Provisioning> {
+        combine_provisioning([self.first.provisioning(), self.second.provisioning()])
+    }
+
+    async fn unmap(&mut self, lba: u32, count: u32) -> Result<(), BlockDeviceError> {
+        let (first_count, second_lba) = self.split(lba, count as usize)?;
+        let second_count = count - first_count as u32;
+
+        let mut result = Ok(());
+        if first_count > 0 {
+            let part = self.first.unmap(lba, first_count as u32).await;
+            combine_results(&mut result, part)?;
+        }
+        if second_count > 0 {
+            let part = self.second.unmap(second_lba, second_count).await;
+            combine_results(&mut result, part)?;
+        }
+        result
+    }
+
+    async fn unlock(&mut self, key: &[u8]) -> Result<(), BlockDeviceError> {
+        let mut result = Err(BlockDeviceError::Unsupported);
+        combine_unlock(&mut result, self.first.unlock(key).await)?;
+        combine_unlock(&mut result, self.second.unlock(key).await)?;
+        result
+    }
+
+    fn lock(&mut self) {
+        self.first.lock();
+        self.second.lock();
+    }
+
+    async fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        // both are flushed even if the first fails
+        let mut result = Ok(());
+        let first = self.first.flush().await;
+        let second = self.second.flush().await;
+        for part in [first, second] {
+            combine_results(&mut result, part)?;
+        }
+        result
+    }
+
+    fn caching(&self) -> Option<Caching> {
+        combine_caching([self.first.caching(), self.second.caching()])
+    }
+
+    async fn set_caching(&mut self, caching: Caching) -> Result<(), BlockDeviceError> {
+        // both are set even if the first fails
+        let mut result = Ok(());
+        let first = self.first.set_caching(caching).await;
+        let second = self.second.set_caching(caching).await;
+        for part in [first, second] {
+            combine_results(&mut result, part)?;
+        }
+        result
+    }
+
+    fn protection_information(&self) -> bool {
+        self.first.protection_information() && self.second.protection_information()
+    }
+
+    /// The reference tags on the second device count from its own start
+    async fn read_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        protection: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        let (first_count, second_lba) = self.split(lba, blocks.len() / BLOCK_SIZE)?;
+        let (first, second) = blocks.split_at_mut(first_count * BLOCK_SIZE);
+        let (first_pi, second_pi) =
+            protection.split_at_mut(first_count * PROTECTION_INFORMATION_BYTES);
+
+        let mut result = Ok(());
+        if !first.is_empty() {
+            let part = self.first.read_blocks_protected(lba, first, first_pi).await;
+            combine_results(&mut result, part)?;
+        }
+        if !second.is_empty() {
+            let offset = second_lba.wrapping_sub(lba + first_count as u32);
+            let part =
+                read_blocks_shifted(&mut self.second, second_lba, second, second_pi, offset).await;
+            combine_results(&mut result, part)?;
+        }
+        result
+    }
+
+    async fn write_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        let (first_count, second_lba) = self.split(lba, blocks.len() / BLOCK_SIZE)?;
+        let (first, second) = blocks.split_at(first_count * BLOCK_SIZE);
+        let (first_pi, second_pi) = protection.split_at(first_count * PROTECTION_INFORMATION_BYTES);
+
+        let mut result = Ok(());
+        if !first.is_empty() {
+            let part = self
+                .first
+                .write_blocks_protected(lba, first, first_pi)
+                .await;
+            combine_results(&mut result, part)?;
+        }
+        if !second.is_empty() {
+            let offset = second_lba.wrapping_sub(lba + first_count as u32);
+            let part =
+                write_blocks_shifted(&mut self.second, second_lba, second, second_pi, offset).await;
+            combine_results(&mut result, part)?;
+        }
+        result
+    }
+
+    fn transaction_blocks(&self) -> Option<u32> {
+        combine_transaction_blocks([
+            self.first.transaction_blocks(),
+            self.second.transaction_blocks(),
+        ])
+    }
+
+    fn begin_transaction(&mut self) {
+        self.first.begin_transaction();
+        self.second.begin_transaction();
+    }
+
+    async fn end_transaction(&mut self, commit: bool) -> Result<(), BlockDeviceError> {
+        // both are ended even if the first fails, so neither is left open
+        let mut result = Ok(());
+        let first = self.first.end_transaction(commit).await;
+        let second = self.second.end_transaction(commit).await;
+        for part in [first, second] {
+            combine_results(&mut result, part)?;
+        }
+        result
+    }
+
+    fn idle_deadline(&self) -> Option<Instant> {
+        combine_idle_deadlines([self.first.idle_deadline(), self.second.idle_deadline()])
+    }
+
+    async fn idle(&mut self) {
+        if idle_due(self.first.idle_deadline()) {
+            self.first.idle().await;
+        }
+        if idle_due(self.second.idle_deadline()) {
+            self.second.idle().await;
+        }
+    }
+}
@@ -0,0 +1,452 @@
+//! Encrypts everything written to a base device with XTS-AES-128, using the LBA as the tweak,
+//! so data at rest in RAM, flash or on a card is unreadable without the key.
+//!
+//! The first block of the base holds a header with a check value for the key, the host sees
+//! the blocks after it. The device starts locked and reports LOGICAL UNIT ACCESS NOT
+//! AUTHORIZED until it's given the key by one of:
+//! - the vendor specific UNLOCK SCSI command, see [`BlockDevice::unlock`]
+//! - another task (e.g. the network server), through [`COMMANDS`], which learns whether it
+//!   worked through [`RESULTS`]
+//! - [`EncryptedBlockDevice::unlock`] at boot, with the key from [`provisioned_key`]
+//!
+//! A base without a header is formatted with the first key it's given, after which only
+//! that key unlocks it. The key schedule is wiped when the device is locked.
+//!
+//! Blocks that are all zeros on the base, as its unmapped blocks are on a thin provisioned
+//! base, read as zeros rather than as the zeros decrypted. Protection information is passed
+//! on with the guard of the blocks as they're stored, so the base checks the ciphertext and
+//! the host the plaintext.
+
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::{Aes128, Block};
+use defmt::{info, warn};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use zeroize::Zeroize;
+
+use super::BLOCK_SIZE;
+use crate::scsi::{
+    BlockDevice, BlockDeviceError, MediaStatus, Wrapper, GUARD_CRC, PROTECTION_INFORMATION_BYTES,
+};
+
+/// The data and tweak keys, AES-128 each
+pub const KEY_BYTES: usize = 32;
+
+pub type Key = [u8; KEY_BYTES];
+
+const HEADER_MAGIC: [u8; 4] = *b"XTSH";
+const CHECK_OFFSET: usize = 16;
+const CHECK_BYTES: usize = 16;
+/// The tweak the check value is encrypted with, out of reach of the host's LBAs
+const CHECK_TWEAK: u64 = u64::MAX;
+
+/// Marks a key stored in the first block of a provisioning partition, followed by the key
+const PROVISIONED_KEY_MAGIC: [u8; 4] = *b"XTSK";
+
+/// Blocks encrypted at a time for a write, each taking 512 bytes of the main task
+const CHUNK_BLOCKS: usize = 4;
+
+const AES_BLOCK: usize = 16;
+/// x^128 + x^7 + x^2 + x + 1, the low byte of the XTS field polynomial
+const GF_128_FEEDBACK: u8 = 0x87;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionCommand {
+    Unlock(Key),
+    Lock,
+}
+
+/// Requests for the encrypted device, handled on the next media poll
+pub static COMMANDS: Signal<ThreadModeRawMutex, EncryptionCommand> = Signal::new();
+/// The outcome of each request from [`COMMANDS`], once it's been handled
+pub static RESULTS: Signal<ThreadModeRawMutex, Result<(), BlockDeviceError>> = Signal::new();
+
+/// XTS-AES-128 as in IEEE 1619, without ciphertext stealing. Both keys are wiped when it's
+/// dropped
+pub(crate) struct Xts {
+    data: Aes128,
+    tweak: Aes128,
+}
+
+impl Xts {
+    pub(crate) fn new(key: &Key) -> Self {
+        Self {
+            data: Aes128::new(GenericArray::from_slice(&key[..16])),
+            tweak: Aes128::new(GenericArray::from_slice(&key[16..])),
+        }
+    }
+
+    pub(crate) fn encrypt(&self, sector: u64, data: &mut [u8]) {
+        self.apply(sector, data, |block| self.data.encrypt_block(block));
+    }
+
+    pub(crate) fn decrypt(&self, sector: u64, data: &mut [u8]) {
+        self.apply(sector, data, |block| self.data.decrypt_block(block));
+    }
+
+    /// `data` is a whole number of AES blocks, so there's no ciphertext stealing
+    fn apply(&self, sector: u64, data: &mut [u8], cipher: impl Fn(&mut Block)) {
+        let mut tweak = Block::from([0u8; AES_BLOCK]);
+        tweak[..8].copy_from_slice(&sector.to_le_bytes());
+        self.tweak.encrypt_block(&mut tweak);
+
+        for chunk in data.chunks_exact_mut(AES_BLOCK) {
+            let block = Block::from_mut_slice(chunk);
+            xor(block, &tweak);
+            cipher(block);
+            xor(block, &tweak);
+
+            // multiply the tweak by x in GF(2^128), little endian
+            let mut carry = 0;
+            for byte in tweak.iter_mut() {
+                let next = *byte >> 7;
+                *byte = (*byte << 1) | carry;
+                carry = next;
+            }
+            if carry != 0 {
+                tweak[0] ^= GF_128_FEEDBACK;
+            }
+        }
+    }
+
+    fn check_value(&self) -> [u8; CHECK_BYTES] {
+        let mut check = [0; CHECK_BYTES];
+        self.encrypt(CHECK_TWEAK, &mut check);
+        check
+    }
+}
+
+fn xor(block: &mut [u8], tweak: &[u8]) {
+    for (byte, tweak) in block.iter_mut().zip(tweak) {
+        *byte ^= tweak;
+    }
+}
+
+/// Sets the guard of the protection information `pi` to the CRC of `block` and moves its
+/// reference tag `offset` blocks on, unless it's the escape value that turns the checks off
+fn retag(pi: &mut [u8], block: &[u8], offset: u32) {
+    if pi[2..4] == [0xff; 2] {
+        return;
+    }
+    pi[0..2].copy_from_slice(&GUARD_CRC.checksum(block).to_be_bytes());
+    let tag = u32::from_be_bytes([pi[4], pi[5], pi[6], pi[7]]);
+    pi[4..8].copy_from_slice(&tag.wrapping_add(offset).to_be_bytes());
+}
+
+/// The key provisioned in the first block of `provisioning`, typically a block of internal
+/// flash that the host can't see, to unlock the device with at boot
+pub async fn provisioned_key<P: BlockDevice>(
+    provisioning: &mut P,
+) -> Result<Key, BlockDeviceError> {
+    let mut block = [0u8; BLOCK_SIZE];
+    match provisioning.read_block(0, &mut block).await {
+        Err(e) if !e.is_recovered() => return Err(e),
+        _ => {}
+    }
+    let key = if block[..4] == PROVISIONED_KEY_MAGIC {
+        Ok(block[4..4 + KEY_BYTES].try_into().unwrap())
+    } else {
+        warn!("encrypted: no provisioned key");
+        Err(BlockDeviceError::AccessNotAuthorized)
+    };
+    block.zeroize();
+    key
+}
+
+/// How [`EncryptedBlockDevice::write_encrypted`] passes the blocks on to the base
+#[derive(Clone, Copy)]
+enum Write<'a> {
+    Blocks,
+    ForceUnitAccess,
+    Protected(&'a [u8]),
+}
+
+pub struct EncryptedBlockDevice<B> {
+    base: B,
+    /// `None` while locked
+    cipher: Option<Xts>,
+    /// The device was unlocked since the last poll, to be reported as a media change
+    changed: bool,
+}
+
+impl<B: BlockDevice> EncryptedBlockDevice<B> {
+    /// A locked device over `base`
+    pub const fn new(base: B) -> Self {
+        assert!(B::BLOCK_BYTES == BLOCK_SIZE);
+        Self {
+            base,
+            cipher: None,
+            changed: false,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.cipher.is_none()
+    }
+
+    /// The address on the base of `count` blocks from `lba`, after the header
+    fn base_lba(&self, lba: u32, count: usize) -> Result<u32, BlockDeviceError> {
+        match (lba as u64).checked_add(count as u64) {
+            Some(end) if end <= Wrapper::block_count(self) as u64 => Ok(lba + 1),
+            _ => Err(BlockDeviceError::InvalidAddress),
+        }
+    }
+
+    /// Writes the header for the key of `cipher` to the first block of the base
+    async fn write_header(&mut self, cipher: &Xts) -> Result<(), BlockDeviceError> {
+        let mut header = [0u8; BLOCK_SIZE];
+        header[..4].copy_from_slice(&HEADER_MAGIC);
+        header[CHECK_OFFSET..CHECK_OFFSET + CHECK_BYTES].copy_from_slice(&cipher.check_value());
+        match self.base.write_block(0, &header).await {
+            Err(e) if !e.is_recovered() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads the blocks from `lba` and decrypts them, along with their protection information
+    /// if given
+    async fn read_decrypted(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        mut protection: Option<&mut [u8]>,
+    ) -> Result<(), BlockDeviceError> {
+        if self.is_locked() {
+            return Err(BlockDeviceError::AccessNotAuthorized);
+        }
+        let base_lba = self.base_lba(lba, blocks.len() / BLOCK_SIZE)?;
+
+        let result = match protection.as_deref_mut() {
+            Some(protection) => {
+                self.base
+                    .read_blocks_protected(base_lba, blocks, protection)
+                    .await
+            }
+            None => self.base.read_blocks(base_lba, blocks).await,
+        };
+        if matches!(result, Err(e) if !e.is_recovered()) {
+            return result;
+        }
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or(BlockDeviceError::AccessNotAuthorized)?;
+        for (i, block) in blocks.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            if block.iter().any(|&byte| byte != 0) {
+                cipher.decrypt(lba as u64 + i as u64, block);
+            }
+        }
+        if let Some(protection) = protection {
+            for (pi, block) in protection
+                .chunks_exact_mut(PROTECTION_INFORMATION_BYTES)
+                .zip(blocks.chunks_exact(BLOCK_SIZE))
+            {
+                retag(pi, block, 1u32.wrapping_neg());
+            }
+        }
+        result
+    }
+
+    /// Encrypts the blocks from `lba` a few at a time and writes them to the base
+    async fn write_encrypted(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        write: Write<'_>,
+    ) -> Result<(), BlockDeviceError> {
+        let mut base_lba = self.base_lba(lba, blocks.len() / BLOCK_SIZE)?;
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or(BlockDeviceError::AccessNotAuthorized)?;
+
+        let mut result = Ok(());
+        let mut encrypted = [0u8; CHUNK_BLOCKS * BLOCK_SIZE];
+        let mut protection = [0u8; CHUNK_BLOCKS * PROTECTION_INFORMATION_BYTES];
+        for (i, chunk) in blocks.chunks(CHUNK_BLOCKS * BLOCK_SIZE).enumerate() {
+            let count = chunk.len() / BLOCK_SIZE;
+            let first = (lba + (i * CHUNK_BLOCKS) as u32) as u64;
+            let encrypted = &mut encrypted[..chunk.len()];
+            encrypted.copy_from_slice(chunk);
+            for (j, block) in encrypted.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+                cipher.encrypt(first + j as u64, block);
+            }
+
+            let written = match write {
+                Write::Blocks => self.base.write_blocks(base_lba, encrypted).await,
+                Write::ForceUnitAccess => self.base.write_blocks_fua(base_lba, encrypted).await,
+                Write::Protected(all) => {
+                    let protection = &mut protection[..count * PROTECTION_INFORMATION_BYTES];
+                    protection.copy_from_slice(
+                        &all[i * CHUNK_BLOCKS * PROTECTION_INFORMATION_BYTES..]
+                            [..count * PROTECTION_INFORMATION_BYTES],
+                    );
+                    for (pi, block) in protection
+                        .chunks_exact_mut(PROTECTION_INFORMATION_BYTES)
+                        .zip(encrypted.chunks_exact(BLOCK_SIZE))
+                    {
+                        retag(pi, block, 1);
+                    }
+                    self.base
+                        .write_blocks_protected(base_lba, encrypted, protection)
+                        .await
+                }
+            };
+            match written {
+                Err(e) if e.is_recovered() => result = Err(e),
+                r => r?,
+            }
+            base_lba += count as u32;
+        }
+        encrypted.zeroize();
+        result
+    }
+}
+
+impl<B: BlockDevice> Wrapper for EncryptedBlockDevice<B> {
+    type Base = B;
+
+    fn base(&self) -> &B {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut B {
+        &mut self.base
+    }
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.read_decrypted(lba, block, None).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_encrypted(lba, block, Write::Blocks).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.read_decrypted(lba, blocks, None).await
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_encrypted(lba, blocks, Write::Blocks).await
+    }
+
+    async fn write_blocks_fua(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_encrypted(lba, blocks, Write::ForceUnitAccess)
+            .await
+    }
+
+    fn block_count(&self) -> u32 {
+        self.base.block_count().saturating_sub(1)
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        if let Some(command) = COMMANDS.try_take() {
+            let result = match command {
+                EncryptionCommand::Unlock(mut key) => {
+                    let result = Wrapper::unlock(self, &key).await;
+                    if let Err(e) = result {
+                        warn!("encrypted: unlock failed: {}", e);
+                    }
+                    key.zeroize();
+                    result
+                }
+                EncryptionCommand::Lock => {
+                    Wrapper::lock(self);
+                    Ok(())
+                }
+            };
+            RESULTS.signal(result);
+        }
+
+        match self.base.media_status().await {
+            MediaStatus::Absent => MediaStatus::Absent,
+            _ if self.is_locked() => MediaStatus::Locked,
+            MediaStatus::Present if core::mem::take(&mut self.changed) => MediaStatus::Changed,
+            status => status,
+        }
+    }
+
+    async fn unmap(&mut self, lba: u32, count: u32) -> Result<(), BlockDeviceError> {
+        if self.is_locked() {
+            return Err(BlockDeviceError::AccessNotAuthorized);
+        }
+        if count == 0 {
+            return Ok(());
+        }
+        let base_lba = self.base_lba(lba, count as usize)?;
+        self.base.unmap(base_lba, count).await
+    }
+
+    async fn unlock(&mut self, key: &[u8]) -> Result<(), BlockDeviceError> {
+        let key: &Key = key
+            .try_into()
+            .map_err(|_| BlockDeviceError::AccessNotAuthorized)?;
+        let cipher = Xts::new(key);
+
+        let mut header = [0u8; BLOCK_SIZE];
+        match self.base.read_block(0, &mut header).await {
+            Err(e) if !e.is_recovered() => return Err(e),
+            _ => {}
+        }
+
+        if header[..4] != HEADER_MAGIC {
+            info!("encrypted: formatting the base with a new key");
+            self.write_header(&cipher).await?;
+        } else {
+            // compare every byte, so a wrong key takes as long to reject as any other
+            let stored = &header[CHECK_OFFSET..CHECK_OFFSET + CHECK_BYTES];
+            let difference = stored
+                .iter()
+                .zip(cipher.check_value())
+                .fold(0, |difference, (a, b)| difference | (a ^ b));
+            if difference != 0 {
+                warn!("encrypted: wrong key");
+                return Err(BlockDeviceError::AccessNotAuthorized);
+            }
+        }
+
+        info!("encrypted: unlocked");
+        self.cipher = Some(cipher);
+        self.changed = true;
+        Ok(())
+    }
+
+    /// Drops the cipher, which wipes its key schedule
+    fn lock(&mut self) {
+        info!("encrypted: locked");
+        self.cipher = None;
+    }
+
+    async fn read_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        protection: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.read_decrypted(lba, blocks, Some(protection)).await
+    }
+
+    async fn write_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.write_encrypted(lba, blocks, Write::Protected(protection))
+            .await
+    }
+
+    /// Formats the base and writes the header for the current key back, so the same key
+    /// still unlocks it
+    async fn format(&mut self) -> Result<(), BlockDeviceError> {
+        let cipher = self
+            .cipher
+            .take()
+            .ok_or(BlockDeviceError::AccessNotAuthorized)?;
+        let mut result = self.base.format().await;
+        if result.is_ok() {
+            result = self.write_header(&cipher).await;
+        }
+        self.cipher = Some(cipher);
+        result
+    }
+}
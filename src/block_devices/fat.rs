@@ -0,0 +1,333 @@
+//! Just enough of FAT12 and FAT16 for the wrappers that look for a file in the root directory
+//! of the filesystem on their base, such as a script to load or a file to show their state in,
+//! or that watch its directories for changes
+
+use core::ops::Range;
+
+use super::BLOCK_SIZE;
+use crate::fat12_partition::{read_partitions, PartitionTableError};
+use crate::scsi::{BlockDevice, BlockDeviceError};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// Media descriptors in a boot sector are 0xf0 or 0xf8 to 0xff
+const MEDIA_DESCRIPTOR_MIN: u8 = 0xf0;
+const DIR_ENTRY_BYTES: usize = 32;
+const DIR_ENTRY_END: u8 = 0x00;
+const DIR_ENTRY_DELETED: u8 = 0xe5;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+/// The attributes of the entries holding parts of long file names
+const ATTR_LONG_NAME: u8 = 0x0f;
+/// Filesystems with fewer clusters are FAT12
+const FAT16_MIN_CLUSTERS: u32 = 4085;
+const FIRST_CLUSTER: u32 = 2;
+
+/// A FAT12 or FAT16 filesystem, in the first partition of a device that may hold one (see
+/// [`read_partitions`]) or filling all of it
+#[derive(Clone, Copy)]
+pub(crate) struct Volume {
+    fat_lba: u32,
+    root_lba: u32,
+    data_lba: u32,
+    cluster_blocks: u32,
+    clusters: u32,
+    fat16: bool,
+}
+
+/// A file in the root directory
+pub(crate) struct RootFile {
+    pub first_cluster: u32,
+    pub size: u32,
+}
+
+/// An entry of a directory, other than `.`, `..` and the entries holding long file names
+pub(crate) struct DirEntry {
+    /// The 8.3 name, padded with spaces
+    pub name: [u8; 11],
+    pub attributes: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    /// The time and date of the last change, as they're stored
+    pub modified: u32,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    /// Whether the entry is the volume label, which is only in the root directory
+    pub fn is_label(&self) -> bool {
+        self.attributes & ATTR_VOLUME_ID != 0
+    }
+}
+
+/// Reads a block, ignoring recovered errors
+async fn read<B: BlockDevice>(
+    base: &mut B,
+    lba: u32,
+    block: &mut [u8],
+) -> Result<(), BlockDeviceError> {
+    match base.read_blocks(lba, block).await {
+        Err(e) if !e.is_recovered() => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn u16_at(block: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([block[offset], block[offset + 1]])
+}
+
+fn u32_at(block: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap())
+}
+
+impl Volume {
+    /// Finds the filesystem on `base`, failing with `MediumNotPresent` if there isn't a FAT12
+    /// or FAT16 one. FAT32 keeps its root directory in a cluster chain, which isn't followed
+    pub async fn open<B: BlockDevice>(base: &mut B) -> Result<Self, BlockDeviceError> {
+        let mut block = [0u8; BLOCK_SIZE];
+        read(base, 0, &mut block).await?;
+
+        // a filesystem without a partition table starts with its boot sector
+        let boot_sector = u16_at(&block, 11) as usize == BLOCK_SIZE
+            && matches!(block[16], 1 | 2)
+            && block[21] >= MEDIA_DESCRIPTOR_MIN;
+        let mut start = 0;
+        if !boot_sector && block[510..] == MBR_SIGNATURE {
+            let table = match read_partitions(base).await {
+                Ok(table) => table,
+                Err(PartitionTableError::Io(e)) => return Err(e),
+                Err(_) => return Err(BlockDeviceError::MediumNotPresent),
+            };
+            start = table
+                .iter()
+                .find(|partition| partition.partition_type.may_be_fat())
+                .ok_or(BlockDeviceError::MediumNotPresent)?
+                .lba;
+            read(base, start, &mut block).await?;
+        }
+
+        let bytes_per_sector = u16_at(&block, 11) as usize;
+        let cluster_blocks = block[13] as u32;
+        let reserved_sectors = u16_at(&block, 14) as u32;
+        let fats = block[16] as u32;
+        let root_entries = u16_at(&block, 17) as u32;
+        let sectors = match u16_at(&block, 19) {
+            0 => u32_at(&block, 32),
+            sectors => sectors as u32,
+        };
+        let sectors_per_fat = u16_at(&block, 22) as u32;
+        if bytes_per_sector != BLOCK_SIZE || cluster_blocks == 0 || sectors_per_fat == 0 {
+            return Err(BlockDeviceError::MediumNotPresent);
+        }
+
+        let fat_lba = start + reserved_sectors;
+        let root_lba = fat_lba + fats * sectors_per_fat;
+        let root_blocks = (root_entries * DIR_ENTRY_BYTES as u32).div_ceil(BLOCK_SIZE as u32);
+        let data_lba = root_lba + root_blocks;
+        let clusters = (start + sectors).saturating_sub(data_lba) / cluster_blocks;
+        Ok(Self {
+            fat_lba,
+            root_lba,
+            data_lba,
+            cluster_blocks,
+            clusters,
+            fat16: clusters >= FAT16_MIN_CLUSTERS,
+        })
+    }
+
+    /// The blocks of the root directory
+    pub fn root_dir(&self) -> Range<u32> {
+        self.root_lba..self.data_lba
+    }
+
+    /// The blocks of all copies of the FAT
+    pub fn fat(&self) -> Range<u32> {
+        self.fat_lba..self.root_lba
+    }
+
+    /// The data clusters holding any of `blocks`
+    pub fn clusters_in(&self, blocks: Range<u32>) -> Range<u32> {
+        let start = blocks.start.max(self.data_lba);
+        let end = blocks
+            .end
+            .min(self.data_lba + self.clusters * self.cluster_blocks);
+        if start >= end {
+            return 0..0;
+        }
+        let first = FIRST_CLUSTER + (start - self.data_lba) / self.cluster_blocks;
+        let last = FIRST_CLUSTER + (end - 1 - self.data_lba) / self.cluster_blocks;
+        first..last + 1
+    }
+
+    /// The number of data clusters, which is also the longest a cluster chain can be
+    pub fn clusters(&self) -> u32 {
+        self.clusters
+    }
+
+    pub fn cluster_blocks(&self) -> u32 {
+        self.cluster_blocks
+    }
+
+    pub fn cluster_lba(&self, cluster: u32) -> u32 {
+        self.data_lba + (cluster - FIRST_CLUSTER) * self.cluster_blocks
+    }
+
+    /// Looks for the file `name`, an 8.3 directory entry name, in the root directory
+    pub async fn find<B: BlockDevice>(
+        &self,
+        base: &mut B,
+        name: &[u8; 11],
+    ) -> Result<Option<RootFile>, BlockDeviceError> {
+        let mut block = [0u8; BLOCK_SIZE];
+        for lba in self.root_dir() {
+            read(base, lba, &mut block).await?;
+            for entry in block.chunks_exact(DIR_ENTRY_BYTES) {
+                match entry[0] {
+                    DIR_ENTRY_END => return Ok(None),
+                    DIR_ENTRY_DELETED => continue,
+                    _ => {}
+                }
+                if entry[11] & (ATTR_VOLUME_ID | ATTR_DIRECTORY) == 0 && entry[..11] == name[..] {
+                    return Ok(Some(RootFile {
+                        first_cluster: u16_at(entry, 26) as u32,
+                        size: u32_at(entry, 28),
+                    }));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Calls `f` with each entry of the root directory if `first_cluster` is `None`, or else
+    /// of the directory starting at `first_cluster`
+    pub async fn read_dir<B: BlockDevice>(
+        &self,
+        base: &mut B,
+        first_cluster: Option<u32>,
+        mut f: impl FnMut(DirEntry),
+    ) -> Result<(), BlockDeviceError> {
+        let mut blocks = match first_cluster {
+            None => self.root_dir(),
+            Some(cluster) if self.is_data_cluster(cluster) => self.cluster_run(cluster),
+            Some(_) => return Ok(()),
+        };
+        let mut cluster = first_cluster;
+        let mut block = [0u8; BLOCK_SIZE];
+        // a chain can't be longer than the clusters, which stops at a loop
+        for _ in 0..self.clusters.max(1) {
+            for lba in blocks {
+                read(base, lba, &mut block).await?;
+                for entry in block.chunks_exact(DIR_ENTRY_BYTES) {
+                    match entry[0] {
+                        DIR_ENTRY_END => return Ok(()),
+                        DIR_ENTRY_DELETED | b'.' => continue,
+                        _ => {}
+                    }
+                    if entry[11] & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                        continue;
+                    }
+                    f(DirEntry {
+                        name: entry[..11].try_into().unwrap(),
+                        attributes: entry[11],
+                        first_cluster: u16_at(entry, 26) as u32,
+                        size: u32_at(entry, 28),
+                        modified: u32_at(entry, 22),
+                    });
+                }
+            }
+            let Some(current) = cluster else {
+                return Ok(());
+            };
+            cluster = self.next_cluster(base, current).await?;
+            let Some(next) = cluster else {
+                return Ok(());
+            };
+            blocks = self.cluster_run(next);
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, `None` at the end of the chain or if the
+    /// chain is broken
+    pub async fn next_cluster<B: BlockDevice>(
+        &self,
+        base: &mut B,
+        cluster: u32,
+    ) -> Result<Option<u32>, BlockDeviceError> {
+        if !self.is_data_cluster(cluster) {
+            return Ok(None);
+        }
+
+        let mut buffer = [0u8; 2 * BLOCK_SIZE];
+        let (lba, offset) = self.fat_entry(cluster);
+        let blocks = self.fat_blocks(&mut buffer);
+        read(base, lba, blocks).await?;
+        let next = self.entry_at(blocks, cluster, offset);
+        Ok(self.is_data_cluster(next).then_some(next))
+    }
+
+    /// The number of clusters that are free
+    pub async fn free_clusters<B: BlockDevice>(
+        &self,
+        base: &mut B,
+    ) -> Result<u32, BlockDeviceError> {
+        let mut buffer = [0u8; 2 * BLOCK_SIZE];
+        let mut loaded = None;
+        let mut free = 0;
+        for cluster in FIRST_CLUSTER..FIRST_CLUSTER + self.clusters {
+            let (lba, offset) = self.fat_entry(cluster);
+            let blocks = self.fat_blocks(&mut buffer);
+            if loaded != Some(lba) {
+                read(base, lba, blocks).await?;
+                loaded = Some(lba);
+            }
+            if self.entry_at(blocks, cluster, offset) == 0 {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+
+    fn cluster_run(&self, cluster: u32) -> Range<u32> {
+        let lba = self.cluster_lba(cluster);
+        lba..lba + self.cluster_blocks
+    }
+
+    /// The block of the first FAT holding the entry of `cluster`, and its offset in the block
+    fn fat_entry(&self, cluster: u32) -> (u32, usize) {
+        let offset = if self.fat16 {
+            cluster * 2
+        } else {
+            cluster + cluster / 2
+        };
+        let lba = self.fat_lba + offset / BLOCK_SIZE as u32;
+        (lba, offset as usize % BLOCK_SIZE)
+    }
+
+    /// The blocks of `buffer` to read a FAT entry into: FAT12 entries are a byte and a half,
+    /// so one can straddle two blocks
+    fn fat_blocks<'b>(&self, buffer: &'b mut [u8; 2 * BLOCK_SIZE]) -> &'b mut [u8] {
+        if self.fat16 {
+            &mut buffer[..BLOCK_SIZE]
+        } else {
+            &mut buffer[..]
+        }
+    }
+
+    /// The entry of `cluster` at `offset` in `blocks`
+    fn entry_at(&self, blocks: &[u8], cluster: u32, offset: usize) -> u32 {
+        let entry = u16_at(blocks, offset) as u32;
+        match (self.fat16, cluster % 2) {
+            (true, _) => entry,
+            (false, 0) => entry & 0x0fff,
+            (false, _) => entry >> 4,
+        }
+    }
+
+    /// Whether `cluster` holds data, which the first cluster of an empty file doesn't
+    pub fn is_data_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..FIRST_CLUSTER + self.clusters).contains(&cluster)
+    }
+}
@@ -0,0 +1,675 @@
+//! Injects faults into the accesses to a base device, to test how host drivers and software
+//! cope with a misbehaving drive. Every fault reaches the host through the usual sense data
+//! of the error or media status it produces.
+//!
+//! Faults are described by rules, one per line of a script:
+//!
+//! ```text
+//! # comments and blank lines are ignored
+//! fail lba=100+8 op=read             # reads touching blocks 100 to 107 fail
+//! fail after=500 error=hardware      # every access fails after the first 500
+//! delay ms=250 op=write              # writes take a quarter of a second longer
+//! flip lba=40 bit=7 times=1          # the next read of block 40 has bit 7 flipped
+//! not-ready after=20 ms=3000         # the unit is becoming ready for 3 seconds
+//! yank after=1000 ms=10000           # the medium is removed for 10 seconds
+//! ```
+//!
+//! Every rule takes these optional arguments:
+//! - `op=read|write`: only affects reads or writes, both by default
+//! - `lba=<lba>` or `lba=<lba>+<count>`: only affects accesses touching those blocks
+//! - `after=<n>`: only affects accesses once `n` have been made since the rules were loaded
+//! - `times=<n>`: stops affecting accesses after `n` of them, never by default
+//!
+//! `fail` takes the error as `error=`, one of `read`, `write`, `not-ready`, `becoming-ready`,
+//! `no-medium`, `protected`, `full`, `hardware`, `timeout` and `recovered`, defaulting to a
+//! read or write error. `flip` flips `bit=` (0 by default) of the first affected block, in the
+//! data returned by a read or stored by a write. `not-ready` and `yank` open a window of `ms=`
+//! milliseconds (5 seconds by default) during which the unit is becoming ready or has no
+//! medium, after which it reports a medium change. They only open once unless given
+//! `times=`. Without `lba=` or `op=` these two also open on a media poll, so the host sees
+//! them even when it isn't accessing the disk.
+//!
+//! Writes with FUA or protection information and unmaps are writes too, and reads with
+//! protection information are reads. A flipped bit is in the data, so it no longer matches
+//! its protection information.
+//!
+//! The rules are read from [`SCRIPT_FILE`] in the root directory of the FAT filesystem on the
+//! base on the first media poll, and can be changed at runtime by another task (e.g. the
+//! network server) through [`COMMANDS`].
+
+use defmt::{info, warn, Format};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
+
+use super::fat::Volume;
+use super::BLOCK_SIZE;
+use crate::scsi::{
+    BlockDevice, BlockDeviceError, MediaStatus, Wrapper, PROTECTION_INFORMATION_BYTES,
+};
+
+/// Rules beyond this are dropped
+pub const MAX_RULES: usize = 16;
+/// The script in the root directory of the drive, as an 8.3 directory entry name
+pub const SCRIPT_FILE: &[u8; 11] = b"FAULTS  TXT";
+/// Only the start of a longer script is read
+pub const SCRIPT_BYTES: usize = 2 * BLOCK_SIZE;
+
+const DEFAULT_WINDOW_MS: u32 = 5000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Fault {
+    /// Fails the access with the error, or the read or write error matching the access
+    Fail(Option<BlockDeviceError>),
+    /// Delays the access by this many milliseconds
+    Delay(u32),
+    /// Flips this bit of the first affected block
+    FlipBit(u16),
+    /// The unit is becoming ready for this many milliseconds
+    NotReady(u32),
+    /// The medium is removed for this many milliseconds
+    Yank(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct FaultRule {
+    pub fault: Fault,
+    /// Only reads or writes, `None` for both
+    pub operation: Option<Operation>,
+    /// The first block and the number of blocks affected, `None` for the whole device
+    pub lbas: Option<(u32, u32)>,
+    /// Accesses made before the rule takes effect
+    pub after: u32,
+    /// Accesses the rule still affects, `None` for no limit
+    pub times: Option<u32>,
+}
+
+impl FaultRule {
+    fn affects(&self, operation: Operation, lba: u32, count: u32, accesses: u32) -> bool {
+        accesses >= self.after
+            && self.times != Some(0)
+            && self.operation.is_none_or(|op| op == operation)
+            && self.lbas.is_none_or(|(start, blocks)| {
+                (lba as u64) < start as u64 + blocks as u64 && start < lba.saturating_add(count)
+            })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum FaultCommand {
+    Add(FaultRule),
+    /// Removes every rule and ends any not ready or yank window
+    Clear,
+    /// Replaces the rules with those in [`SCRIPT_FILE`]
+    Reload,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum ScriptError {
+    UnknownCommand,
+    InvalidArgument,
+}
+
+/// Requests for the fault injecting device, handled before its next access or media poll
+pub static COMMANDS: Channel<ThreadModeRawMutex, FaultCommand, 4> = Channel::new();
+
+/// Parses a line of a script, see the [module docs](self). Returns `None` for blank lines
+/// and comments
+pub fn parse_line(line: &str) -> Result<Option<FaultCommand>, ScriptError> {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    let mut words = line.split_ascii_whitespace();
+    let Some(command) = words.next() else {
+        return Ok(None);
+    };
+
+    let mut rule = FaultRule {
+        fault: match command {
+            "clear" => return Ok(Some(FaultCommand::Clear)),
+            "reload" => return Ok(Some(FaultCommand::Reload)),
+            "fail" => Fault::Fail(None),
+            "delay" => Fault::Delay(0),
+            "flip" => Fault::FlipBit(0),
+            "not-ready" => Fault::NotReady(DEFAULT_WINDOW_MS),
+            "yank" => Fault::Yank(DEFAULT_WINDOW_MS),
+            _ => return Err(ScriptError::UnknownCommand),
+        },
+        operation: None,
+        lbas: None,
+        after: 0,
+        times: None,
+    };
+    if matches!(rule.fault, Fault::NotReady(_) | Fault::Yank(_)) {
+        rule.times = Some(1);
+    }
+
+    for word in words {
+        let (key, value) = word.split_once('=').ok_or(ScriptError::InvalidArgument)?;
+        match (key, &mut rule.fault) {
+            ("op", _) => {
+                rule.operation = Some(match value {
+                    "read" => Operation::Read,
+                    "write" => Operation::Write,
+                    _ => return Err(ScriptError::InvalidArgument),
+                })
+            }
+            ("lba", _) => {
+                rule.lbas = Some(match value.split_once('+') {
+                    Some((lba, count)) => (parse_number(lba)?, parse_number(count)?),
+                    None => (parse_number(value)?, 1),
+                })
+            }
+            ("after", _) => rule.after = parse_number(value)?,
+            ("times", _) => rule.times = Some(parse_number(value)?),
+            ("error", Fault::Fail(error)) => *error = Some(parse_error(value)?),
+            ("ms", Fault::Delay(ms) | Fault::NotReady(ms) | Fault::Yank(ms)) => {
+                *ms = parse_number(value)?
+            }
+            ("bit", Fault::FlipBit(bit)) => {
+                *bit = parse_number(value)?
+                    .try_into()
+                    .ok()
+                    .filter(|&bit: &u16| (bit as usize) < BLOCK_SIZE * 8)
+                    .ok_or(ScriptError::InvalidArgument)?
+            }
+            _ => return Err(ScriptError::InvalidArgument),
+        }
+    }
+    Ok(Some(FaultCommand::Add(rule)))
+}
+
+/// A decimal or `0x` prefixed hexadecimal number
+fn parse_number(value: &str) -> Result<u32, ScriptError> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| ScriptError::InvalidArgument)
+}
+
+fn parse_error(value: &str) -> Result<BlockDeviceError, ScriptError> {
+    Ok(match value {
+        "read" => BlockDeviceError::ReadError,
+        "write" => BlockDeviceError::WriteError,
+        "not-ready" => BlockDeviceError::NotReady,
+        "becoming-ready" => BlockDeviceError::BecomingReady,
+        "no-medium" => BlockDeviceError::MediumNotPresent,
+        "protected" => BlockDeviceError::WriteProtected,
+        "full" => BlockDeviceError::SpaceAllocationFailed,
+        "hardware" => BlockDeviceError::HardwareFailure { fru: 0 },
+        "timeout" => BlockDeviceError::Timeout,
+        "recovered" => BlockDeviceError::RecoveredWithRetries,
+        _ => return Err(ScriptError::InvalidArgument),
+    })
+}
+
+/// How [`FaultBlockDevice::write_injected`] passes the blocks on to the base
+#[derive(Clone, Copy)]
+enum Write<'a> {
+    Blocks,
+    ForceUnitAccess,
+    Protected(&'a [u8]),
+}
+
+/// What an access that goes ahead is affected by
+#[derive(Default)]
+struct Injection {
+    /// The block and bit to flip
+    flip: Option<(u32, u16)>,
+    /// The recovered error to report once the access completes
+    recovered: Option<BlockDeviceError>,
+}
+
+pub struct FaultBlockDevice<B> {
+    base: B,
+    rules: [Option<FaultRule>; MAX_RULES],
+    /// Accesses made since the rules were loaded
+    accesses: u32,
+    /// The end of a window in which the unit is becoming ready
+    not_ready_until: Option<Instant>,
+    /// The end of a window in which the medium is removed
+    yanked_until: Option<Instant>,
+    /// A window ended and the medium is reported to have changed on the next poll
+    changed: bool,
+    script_loaded: bool,
+}
+
+impl<B: BlockDevice> FaultBlockDevice<B> {
+    /// Passes accesses through to `base` until it's given rules
+    pub fn new(base: B) -> Self {
+        assert!(B::BLOCK_BYTES == BLOCK_SIZE);
+        Self {
+            base,
+            rules: [None; MAX_RULES],
+            accesses: 0,
+            not_ready_until: None,
+            yanked_until: None,
+            changed: false,
+            script_loaded: false,
+        }
+    }
+
+    /// Adds `rule`, returning it if there's no room left
+    pub fn add_rule(&mut self, rule: FaultRule) -> Result<(), FaultRule> {
+        let slot = self
+            .rules
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(rule)?;
+        info!("faults: added {}", rule);
+        *slot = Some(rule);
+        Ok(())
+    }
+
+    /// Removes every rule, ends any window and starts counting accesses again
+    pub fn clear(&mut self) {
+        self.rules = [None; MAX_RULES];
+        self.accesses = 0;
+        // both windows end, even if the first was open
+        let not_ready = self.not_ready_until.take();
+        let yanked = self.yanked_until.take();
+        if not_ready.is_some() || yanked.is_some() {
+            self.changed = true;
+        }
+    }
+
+    /// Replaces the rules with the script in [`SCRIPT_FILE`] on the base. Lines that can't be
+    /// parsed are skipped. Fails if there's no FAT filesystem or script on the base
+    pub async fn load_script(&mut self) -> Result<(), BlockDeviceError> {
+        let mut script = [0u8; SCRIPT_BYTES];
+        let len = read_script(&mut self.base, &mut script).await?;
+
+        self.clear();
+        let script =
+            core::str::from_utf8(&script[..len]).map_err(|_| BlockDeviceError::ReadError)?;
+        for (number, line) in script.lines().enumerate() {
+            match parse_line(line) {
+                Ok(Some(FaultCommand::Add(rule))) => {
+                    if self.add_rule(rule).is_err() {
+                        warn!("faults: too many rules, ignoring line {}", number + 1);
+                    }
+                }
+                Ok(Some(_)) | Ok(None) => {}
+                Err(e) => warn!("faults: line {} of the script: {}", number + 1, e),
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_commands(&mut self) {
+        while let Ok(command) = COMMANDS.try_receive() {
+            match command {
+                FaultCommand::Add(rule) => {
+                    if self.add_rule(rule).is_err() {
+                        warn!("faults: too many rules");
+                    }
+                }
+                FaultCommand::Clear => {
+                    info!("faults: cleared");
+                    self.clear();
+                }
+                FaultCommand::Reload => {
+                    if let Err(e) = self.load_script().await {
+                        warn!("faults: can't load the script: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Opens a not ready or yank window of `ms` milliseconds
+    fn open_window(&mut self, fault: Fault) {
+        match fault {
+            Fault::NotReady(ms) => {
+                info!("faults: not ready for {} ms", ms);
+                self.not_ready_until = Some(Instant::now() + Duration::from_millis(ms as u64));
+            }
+            Fault::Yank(ms) => {
+                info!("faults: medium removed for {} ms", ms);
+                self.yanked_until = Some(Instant::now() + Duration::from_millis(ms as u64));
+            }
+            _ => {}
+        }
+    }
+
+    /// The error to fail accesses with while a window is open, closing windows that have
+    /// ended
+    fn window_error(&mut self) -> Option<BlockDeviceError> {
+        let now = Instant::now();
+        for (until, error) in [
+            (&mut self.yanked_until, BlockDeviceError::MediumNotPresent),
+            (&mut self.not_ready_until, BlockDeviceError::BecomingReady),
+        ] {
+            match *until {
+                Some(end) if end > now => return Some(error),
+                Some(_) => {
+                    *until = None;
+                    self.changed = true;
+                }
+                None => {}
+            }
+        }
+        None
+    }
+
+    /// Applies the rules affecting an access to `count` blocks from `lba`, failing it or
+    /// returning what it's affected by once it goes ahead
+    async fn inject(
+        &mut self,
+        operation: Operation,
+        lba: u32,
+        count: u32,
+    ) -> Result<Injection, BlockDeviceError> {
+        self.handle_commands().await;
+        if let Some(error) = self.window_error() {
+            return Err(error);
+        }
+
+        let accesses = self.accesses;
+        self.accesses = self.accesses.saturating_add(1);
+
+        let mut delay = 0;
+        let mut error = None;
+        let mut flip = None;
+        let mut window = None;
+        for rule in self.rules.iter_mut().flatten() {
+            if !rule.affects(operation, lba, count, accesses) {
+                continue;
+            }
+            if let Some(times) = &mut rule.times {
+                *times -= 1;
+            }
+
+            match rule.fault {
+                Fault::Fail(e) => {
+                    error = error.or(Some(e.unwrap_or(match operation {
+                        Operation::Read => BlockDeviceError::ReadError,
+                        Operation::Write => BlockDeviceError::WriteError,
+                    })))
+                }
+                Fault::Delay(ms) => delay += ms as u64,
+                Fault::FlipBit(bit) => {
+                    let block = rule.lbas.map_or(lba, |(start, _)| start.max(lba));
+                    flip = flip.or(Some((block, bit)));
+                }
+                fault @ (Fault::NotReady(_) | Fault::Yank(_)) => window = window.or(Some(fault)),
+            }
+        }
+
+        if delay > 0 {
+            Timer::after_millis(delay).await;
+        }
+        if let Some(fault) = window {
+            self.open_window(fault);
+            if let Some(error) = self.window_error() {
+                return Err(error);
+            }
+        }
+        match error {
+            Some(e) if !e.is_recovered() => {
+                warn!("faults: failing {} of lba {} with {}", operation, lba, e);
+                Err(e)
+            }
+            recovered => Ok(Injection { flip, recovered }),
+        }
+    }
+}
+
+impl<B: BlockDevice> FaultBlockDevice<B> {
+    /// Reads `blocks` from the base, with their protection information into `protection` if
+    /// it's given
+    async fn read_injected(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        protection: Option<&mut [u8]>,
+    ) -> Result<(), BlockDeviceError> {
+        let count = (blocks.len() / BLOCK_SIZE) as u32;
+        let injection = self.inject(Operation::Read, lba, count).await?;
+
+        let result = match protection {
+            None => self.base.read_blocks(lba, blocks).await,
+            Some(protection) => {
+                self.base
+                    .read_blocks_protected(lba, blocks, protection)
+                    .await
+            }
+        };
+        if matches!(result, Err(e) if !e.is_recovered()) {
+            return result;
+        }
+        if let Some((block, bit)) = injection.flip {
+            let offset = (block - lba) as usize * BLOCK_SIZE;
+            flip_bit(&mut blocks[offset..offset + BLOCK_SIZE], bit);
+        }
+        injection.recovered.map_or(result, Err)
+    }
+
+    async fn write_base(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        write: Write<'_>,
+    ) -> Result<(), BlockDeviceError> {
+        match write {
+            Write::Blocks => self.base.write_blocks(lba, blocks).await,
+            Write::ForceUnitAccess => self.base.write_blocks_fua(lba, blocks).await,
+            Write::Protected(protection) => {
+                self.base
+                    .write_blocks_protected(lba, blocks, protection)
+                    .await
+            }
+        }
+    }
+
+    async fn write_injected(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        write: Write<'_>,
+    ) -> Result<(), BlockDeviceError> {
+        let count = (blocks.len() / BLOCK_SIZE) as u32;
+        let injection = self.inject(Operation::Write, lba, count).await?;
+
+        let result = match injection.flip {
+            None => self.write_base(lba, blocks, write).await,
+            // the flipped block is written on its own, from a copy
+            Some((block, bit)) => {
+                let index = (block - lba) as usize;
+                let (before, rest) = blocks.split_at(index * BLOCK_SIZE);
+                let (flipped, after) = rest.split_at(BLOCK_SIZE);
+                let mut copy = [0u8; BLOCK_SIZE];
+                copy.copy_from_slice(flipped);
+                flip_bit(&mut copy, bit);
+                let writes = match write {
+                    Write::Protected(protection) => {
+                        let (before_pi, rest) =
+                            protection.split_at(index * PROTECTION_INFORMATION_BYTES);
+                        let (flipped_pi, after_pi) = rest.split_at(PROTECTION_INFORMATION_BYTES);
+                        [
+                            Write::Protected(before_pi),
+                            Write::Protected(flipped_pi),
+                            Write::Protected(after_pi),
+                        ]
+                    }
+                    write => [write; 3],
+                };
+
+                let mut result = Ok(());
+                for ((lba, part), write) in [(lba, before), (block, &copy[..]), (block + 1, after)]
+                    .into_iter()
+                    .zip(writes)
+                {
+                    if part.is_empty() {
+                        continue;
+                    }
+                    match self.write_base(lba, part, write).await {
+                        Err(e) if e.is_recovered() => result = Err(e),
+                        r => r?,
+                    }
+                }
+                result
+            }
+        };
+        if matches!(result, Err(e) if !e.is_recovered()) {
+            return result;
+        }
+        injection.recovered.map_or(result, Err)
+    }
+}
+
+fn flip_bit(block: &mut [u8], bit: u16) {
+    block[bit as usize / 8] ^= 1 << (bit % 8);
+}
+
+/// Reads up to `script.len()` bytes of [`SCRIPT_FILE`] from the root directory of the FAT12 or
+/// FAT16 filesystem on `base`. Returns the number of bytes read
+async fn read_script<B: BlockDevice>(
+    base: &mut B,
+    script: &mut [u8],
+) -> Result<usize, BlockDeviceError> {
+    let volume = Volume::open(base).await?;
+    let file = volume
+        .find(base, SCRIPT_FILE)
+        .await?
+        .ok_or(BlockDeviceError::MediumNotPresent)?;
+
+    let len = (file.size as usize).min(script.len());
+    let cluster_bytes = volume.cluster_blocks() as usize * BLOCK_SIZE;
+    let mut cluster = Some(file.first_cluster);
+    let mut block = [0u8; BLOCK_SIZE];
+    for (i, chunk) in script[..len].chunks_mut(BLOCK_SIZE).enumerate() {
+        let Some(current) = cluster else {
+            // the chain ended before the file did
+            return Ok(i * BLOCK_SIZE);
+        };
+        let block_in_cluster = (i * BLOCK_SIZE % cluster_bytes / BLOCK_SIZE) as u32;
+        match base
+            .read_blocks(volume.cluster_lba(current) + block_in_cluster, &mut block)
+            .await
+        {
+            Err(e) if !e.is_recovered() => return Err(e),
+            _ => {}
+        }
+        chunk.copy_from_slice(&block[..chunk.len()]);
+        if ((i + 1) * BLOCK_SIZE).is_multiple_of(cluster_bytes) {
+            cluster = volume.next_cluster(base, current).await?;
+        }
+    }
+    info!("faults: read {} bytes of script", len);
+    Ok(len)
+}
+
+impl<B: BlockDevice> Wrapper for FaultBlockDevice<B> {
+    type Base = B;
+
+    fn base(&self) -> &B {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut B {
+        &mut self.base
+    }
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.read_injected(lba, block, None).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_injected(lba, block, Write::Blocks).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.read_injected(lba, blocks, None).await
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_injected(lba, blocks, Write::Blocks).await
+    }
+
+    async fn write_blocks_fua(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_injected(lba, blocks, Write::ForceUnitAccess)
+            .await
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        if !self.script_loaded {
+            self.script_loaded = true;
+            match self.load_script().await {
+                Ok(()) => info!("faults: loaded the script"),
+                Err(_) => info!("faults: no script"),
+            }
+        }
+        self.handle_commands().await;
+
+        // windows that aren't tied to accesses can open without one
+        if self.not_ready_until.is_none() && self.yanked_until.is_none() {
+            let accesses = self.accesses;
+            let window = self.rules.iter_mut().flatten().find(|rule| {
+                matches!(rule.fault, Fault::NotReady(_) | Fault::Yank(_))
+                    && rule.lbas.is_none()
+                    && rule.operation.is_none()
+                    && rule.times != Some(0)
+                    && accesses >= rule.after
+            });
+            if let Some(rule) = window {
+                if let Some(times) = &mut rule.times {
+                    *times -= 1;
+                }
+                let fault = rule.fault;
+                self.open_window(fault);
+            }
+        }
+
+        match self.window_error() {
+            Some(BlockDeviceError::MediumNotPresent) => return MediaStatus::Absent,
+            Some(_) => return MediaStatus::BecomingReady,
+            None => {}
+        }
+        match self.base.media_status().await {
+            MediaStatus::Present if core::mem::take(&mut self.changed) => MediaStatus::Changed,
+            status => status,
+        }
+    }
+
+    async fn unmap(&mut self, lba: u32, count: u32) -> Result<(), BlockDeviceError> {
+        // a flipped bit has no data to go in
+        let injection = self.inject(Operation::Write, lba, count).await?;
+        let result = self.base.unmap(lba, count).await;
+        if matches!(result, Err(e) if !e.is_recovered()) {
+            return result;
+        }
+        injection.recovered.map_or(result, Err)
+    }
+
+    async fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        self.handle_commands().await;
+        match self.window_error() {
+            Some(error) => Err(error),
+            None => self.base.flush().await,
+        }
+    }
+
+    async fn read_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        protection: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.read_injected(lba, blocks, Some(protection)).await
+    }
+
+    async fn write_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.write_injected(lba, blocks, Write::Protected(protection))
+            .await
+    }
+}
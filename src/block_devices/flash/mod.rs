@@ -0,0 +1,510 @@
+//! A wear-levelled flash translation layer (FTL) presenting 512 byte sectors on top of NOR
+//! flash with larger (typically 4KiB) erase blocks.
+//!
+//! Every erase block is split into 512 byte slots. Slot 0 holds the block header (erase count)
+//! followed by one tag per data slot, the remaining slots hold sector data:
+//!
+//! ```text
+//! | header | tag 1 | tag 2 | ... | tag 7 | (unused) | data 1 | data 2 | ... | data 7 |
+//! ```
+//!
+//! Sectors are never rewritten in place. A write programs the data into the next free slot,
+//! then programs the slot's tag (lba, sequence number, crcs). The tag with the highest sequence
+//! number for an lba wins, so the mapping is rebuilt from the tags on mount and a write that is
+//! interrupted by a power cut leaves the previous copy of the sector in place. Nothing but the
+//! tags ever needs updating, so there is no mapping table on flash that could be torn.
+//!
+//! Stale copies are reclaimed by garbage collection once the free blocks run low, picking the
+//! block with the fewest valid sectors. Free blocks are handed out least-worn first and blocks
+//! holding cold data are relocated to worn blocks so their erase counts keep up with the rest.
+//!
+//! Writing carries on in a block that was in use at mount, after the last slot that isn't
+//! erased, so a power cut during garbage collection doesn't lose the free block it was
+//! relocating into.
+
+use defmt::{debug, info, warn};
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::scsi::{BlockDevice, BlockDeviceError};
+
+pub const SECTOR_SIZE: usize = 512;
+
+const HEADER_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+const HEADER_MAGIC: u32 = 0x4654_4c31; // "FTL1"
+
+const UNMAPPED: u16 = u16::MAX;
+
+/// Garbage collection keeps more than this many blocks free whenever the active block fills up,
+/// so that the collection and the wear levelling after it always have an erased block to
+/// relocate valid sectors into. Mount makes up for a power cut that left fewer
+const RESERVED_BLOCKS: usize = 1;
+
+/// Cold data is moved once the most worn free block is this many erases ahead of its block
+const WEAR_LEVELLING_THRESHOLD: u32 = 32;
+
+const CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// Number of sectors a flash region of `erase_blocks` blocks of `erase_size` bytes can hold.
+///
+/// Two blocks plus ~6% of the region are kept back so that garbage collection always finds a
+/// block with stale sectors in it.
+pub const fn sector_count(erase_blocks: usize, erase_size: usize) -> usize {
+    let spare = 2 + erase_blocks / 16;
+    if erase_blocks <= spare {
+        return 0;
+    }
+    (erase_blocks - spare) * (erase_size / SECTOR_SIZE - 1)
+}
+
+#[derive(Clone, Copy, Default)]
+struct EraseBlock {
+    erase_count: u32,
+    /// Number of slots holding the current copy of a sector
+    valid: u8,
+    /// Number of data slots used since the last erase, whether valid, stale or torn
+    used: u8,
+    /// Set once the block has been erased and had its header programmed
+    formatted: bool,
+}
+
+/// A [`BlockDevice`] storing `SECTORS` sectors in `BLOCKS` erase blocks of `flash`, starting
+/// at `offset`. Use [`sector_count`] to work out how many sectors fit.
+pub struct FlashBlockDevice<F: NorFlash, const BLOCKS: usize, const SECTORS: usize> {
+    flash: F,
+    offset: u32,
+    /// Physical slot holding each sector, or `UNMAPPED` if it was never written
+    map: [u16; SECTORS],
+    blocks: [EraseBlock; BLOCKS],
+    /// The block new sectors are being written to
+    active: Option<usize>,
+    sequence: u32,
+}
+
+impl<F: NorFlash, const BLOCKS: usize, const SECTORS: usize> FlashBlockDevice<F, BLOCKS, SECTORS> {
+    const SLOTS: usize = F::ERASE_SIZE / SECTOR_SIZE;
+    const DATA_SLOTS: usize = Self::SLOTS - 1;
+
+    /// Scans the tags in the flash region and rebuilds the sector mapping. Blocks that were
+    /// never formatted (or were torn by a power cut during an erase) are erased lazily.
+    pub fn mount(flash: F, offset: u32) -> Result<Self, BlockDeviceError> {
+        assert!(F::ERASE_SIZE % SECTOR_SIZE == 0 && Self::SLOTS >= 2);
+        assert!(HEADER_SIZE + TAG_SIZE * Self::DATA_SLOTS <= SECTOR_SIZE);
+        assert!(TAG_SIZE.is_multiple_of(F::WRITE_SIZE) && TAG_SIZE.is_multiple_of(F::READ_SIZE));
+        assert!((offset as usize).is_multiple_of(F::ERASE_SIZE));
+        assert!(offset as usize + BLOCKS * F::ERASE_SIZE <= flash.capacity());
+        assert!(BLOCKS * Self::SLOTS < UNMAPPED as usize);
+        assert!(SECTORS <= sector_count(BLOCKS, F::ERASE_SIZE));
+
+        let mut device = Self {
+            flash,
+            offset,
+            map: [UNMAPPED; SECTORS],
+            blocks: [EraseBlock::default(); BLOCKS],
+            active: None,
+            sequence: 0,
+        };
+
+        let mut max_erase_count = 0;
+        let mut header = [0u8; SECTOR_SIZE];
+        for block in 0..BLOCKS {
+            device.read(device.slot_address(block, 0), &mut header)?;
+
+            let Some(erase_count) = parse_header(&header[..HEADER_SIZE]) else {
+                // unknown contents, erased before use
+                continue;
+            };
+            max_erase_count = max_erase_count.max(erase_count);
+            device.blocks[block].erase_count = erase_count;
+            device.blocks[block].formatted = true;
+
+            let mut used = 0;
+            for slot in 1..Self::SLOTS {
+                let tag = &header[tag_range(slot)];
+                if is_erased(tag) {
+                    continue;
+                }
+                used = slot;
+
+                let Some(tag) = Tag::parse(tag) else {
+                    debug!("ftl: torn tag in block {} slot {}", block, slot);
+                    continue;
+                };
+                device.sequence = device.sequence.max(tag.sequence.wrapping_add(1));
+                if tag.lba as usize >= SECTORS {
+                    continue;
+                }
+                device.mount_sector(tag, (block * Self::SLOTS + slot) as u16)?;
+            }
+
+            // a data slot may have been programmed before a power cut stopped its tag
+            // from being written, only the slots after the last one that was are reused
+            for slot in (used + 1..Self::SLOTS).rev() {
+                if !device.slot_erased(block, slot)? {
+                    used = slot;
+                    break;
+                }
+            }
+            device.blocks[block].used = used as u8;
+        }
+
+        for block in device.blocks.iter_mut().filter(|b| !b.formatted) {
+            block.erase_count = max_erase_count;
+        }
+
+        // carry on in the block with the most room left, which a garbage collection cut short
+        // by the power was relocating into if the free blocks ran out
+        device.active = device
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.used > 0 && (b.used as usize) < Self::DATA_SLOTS)
+            .min_by_key(|(_, b)| b.used)
+            .map(|(block, _)| block);
+        while device.free_blocks() < RESERVED_BLOCKS {
+            if let Err(e) = device.collect_garbage() {
+                warn!("ftl: couldn't free a block at mount: {}", e);
+                break;
+            }
+        }
+
+        info!(
+            "ftl: mounted {} sectors, {} free blocks, sequence {}",
+            SECTORS,
+            device.free_blocks(),
+            device.sequence
+        );
+
+        Ok(device)
+    }
+
+    fn mount_sector(&mut self, tag: Tag, slot: u16) -> Result<(), BlockDeviceError> {
+        let current = self.map[tag.lba as usize];
+        if current != UNMAPPED {
+            let current_tag = self.read_tag(current)?;
+            if current_tag.is_some_and(|t| t.sequence > tag.sequence) {
+                return Ok(());
+            }
+            self.blocks[self.block_of(current)].valid -= 1;
+        }
+        self.map[tag.lba as usize] = slot;
+        self.blocks[self.block_of(slot)].valid += 1;
+        Ok(())
+    }
+
+    fn block_of(&self, slot: u16) -> usize {
+        slot as usize / Self::SLOTS
+    }
+
+    fn slot_address(&self, block: usize, slot: usize) -> u32 {
+        self.offset + (block * F::ERASE_SIZE + slot * SECTOR_SIZE) as u32
+    }
+
+    fn tag_address(&self, slot: u16) -> u32 {
+        let (block, slot) = (self.block_of(slot), slot as usize % Self::SLOTS);
+        self.slot_address(block, 0) + tag_range(slot).start as u32
+    }
+
+    fn data_address(&self, slot: u16) -> u32 {
+        let (block, slot) = (self.block_of(slot), slot as usize % Self::SLOTS);
+        self.slot_address(block, slot)
+    }
+
+    fn read(&mut self, address: u32, buf: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.flash.read(address, buf).map_err(|_| {
+            warn!("ftl: read failed at {:#x}", address);
+            BlockDeviceError::ReadError
+        })
+    }
+
+    fn program(&mut self, address: u32, buf: &[u8]) -> Result<(), BlockDeviceError> {
+        self.flash.write(address, buf).map_err(|_| {
+            warn!("ftl: program failed at {:#x}", address);
+            BlockDeviceError::WriteError
+        })
+    }
+
+    fn read_tag(&mut self, slot: u16) -> Result<Option<Tag>, BlockDeviceError> {
+        let mut tag = [0u8; TAG_SIZE];
+        self.read(self.tag_address(slot), &mut tag)?;
+        Ok(Tag::parse(&tag))
+    }
+
+    fn slot_erased(&mut self, block: usize, slot: usize) -> Result<bool, BlockDeviceError> {
+        let mut buf = [0u8; SECTOR_SIZE];
+        self.read(self.slot_address(block, slot), &mut buf)?;
+        Ok(is_erased(&buf))
+    }
+
+    fn free_blocks(&self) -> usize {
+        self.blocks.iter().filter(|b| b.used == 0).count()
+    }
+
+    /// Erases `block` and programs a fresh header, leaving it empty and ready for data
+    fn erase(&mut self, block: usize) -> Result<(), BlockDeviceError> {
+        let address = self.slot_address(block, 0);
+        self.flash
+            .erase(address, address + F::ERASE_SIZE as u32)
+            .map_err(|_| {
+                warn!("ftl: erase failed at {:#x}", address);
+                BlockDeviceError::WriteError
+            })?;
+
+        let erase_count = self.blocks[block].erase_count + 1;
+        self.blocks[block] = EraseBlock {
+            erase_count,
+            valid: 0,
+            used: 0,
+            formatted: false,
+        };
+
+        let mut header = [0xffu8; HEADER_SIZE];
+        header[0..4].copy_from_slice(&HEADER_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&erase_count.to_le_bytes());
+        let crc = CRC.checksum(&header[..12]);
+        header[12..16].copy_from_slice(&crc.to_le_bytes());
+        self.program(address, &header)?;
+
+        self.blocks[block].formatted = true;
+        Ok(())
+    }
+
+    fn active_full(&self) -> bool {
+        self.active
+            .is_none_or(|block| self.blocks[block].used as usize >= Self::DATA_SLOTS)
+    }
+
+    /// Takes a free block and makes it the active block, once the last one is full. New data
+    /// goes to the least worn block, cold data being moved by wear levelling goes to the most
+    /// worn one
+    fn open_block(&mut self, most_worn: bool) -> Result<usize, BlockDeviceError> {
+        let free = self.blocks.iter().enumerate().filter(|(_, b)| b.used == 0);
+        let block = if most_worn {
+            free.max_by_key(|(_, b)| b.erase_count)
+        } else {
+            free.min_by_key(|(_, b)| b.erase_count)
+        };
+        let Some((block, _)) = block else {
+            return Err(BlockDeviceError::WriteError);
+        };
+
+        if !self.blocks[block].formatted {
+            self.erase(block)?;
+        }
+        self.active = Some(block);
+        Ok(block)
+    }
+
+    /// Returns the next free data slot for a sector written by the host. Each time the active
+    /// block fills up, garbage is collected until there are blocks to spare and cold data is
+    /// moved if it's due, before another block is opened
+    fn next_slot(&mut self) -> Result<u16, BlockDeviceError> {
+        if self.active_full() {
+            while self.free_blocks() <= RESERVED_BLOCKS {
+                self.collect_garbage()?;
+            }
+        }
+        if self.active_full() {
+            self.level_wear()?;
+        }
+        self.take_slot()
+    }
+
+    /// Returns the next data slot of the active block, opening the least worn free block once
+    /// it's full
+    fn take_slot(&mut self) -> Result<u16, BlockDeviceError> {
+        let block = match self.active {
+            Some(block) if !self.active_full() => block,
+            _ => self.open_block(false)?,
+        };
+        let used = self.blocks[block].used as usize;
+        self.blocks[block].used += 1;
+        Ok((block * Self::SLOTS + used + 1) as u16)
+    }
+
+    /// Programs `data` into the (erased) `slot` as the current copy of `lba`
+    fn program_sector(&mut self, slot: u16, lba: u32, data: &[u8]) -> Result<(), BlockDeviceError> {
+        self.program(self.data_address(slot), data)?;
+
+        let tag = Tag {
+            lba,
+            sequence: self.sequence,
+            data_crc: CRC.checksum(data),
+        };
+        self.program(self.tag_address(slot), &tag.to_bytes())?;
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let previous = core::mem::replace(&mut self.map[lba as usize], slot);
+        if previous != UNMAPPED {
+            self.blocks[self.block_of(previous)].valid -= 1;
+        }
+        self.blocks[self.block_of(slot)].valid += 1;
+        Ok(())
+    }
+
+    /// Copies the valid sectors out of `block` into the active block (opening a new one as
+    /// required) and erases it
+    fn relocate(&mut self, block: usize) -> Result<(), BlockDeviceError> {
+        let mut header = [0u8; SECTOR_SIZE];
+        self.read(self.slot_address(block, 0), &mut header)?;
+
+        let mut data = [0u8; SECTOR_SIZE];
+        for slot in 1..Self::SLOTS {
+            let Some(tag) = Tag::parse(&header[tag_range(slot)]) else {
+                continue;
+            };
+            let source = (block * Self::SLOTS + slot) as u16;
+            if tag.lba as usize >= SECTORS || self.map[tag.lba as usize] != source {
+                continue;
+            }
+
+            self.read(self.data_address(source), &mut data)?;
+            let destination = self.take_slot()?;
+            self.program_sector(destination, tag.lba, &data)?;
+        }
+
+        self.erase(block)
+    }
+
+    /// Relocates the valid sectors out of the block with the fewest and erases it
+    fn collect_garbage(&mut self) -> Result<(), BlockDeviceError> {
+        // fewest valid sectors first, least worn on a tie
+        let victim = self
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(block, b)| b.used > 0 && Some(*block) != self.active)
+            .min_by_key(|(_, b)| (b.valid, b.erase_count))
+            .map(|(block, b)| (block, b.valid as usize));
+
+        let Some((victim, valid)) = victim.filter(|(_, valid)| *valid < Self::DATA_SLOTS) else {
+            warn!("ftl: no space left to collect");
+            return Err(BlockDeviceError::WriteError);
+        };
+        debug!("ftl: collecting block {} ({} valid)", victim, valid);
+
+        self.relocate(victim)
+    }
+
+    /// Moves the data out of the least worn block once the most worn free block is too far
+    /// ahead of it, so that blocks holding data that never changes share the wear. The most
+    /// worn block takes the data and becomes the active block
+    fn level_wear(&mut self) -> Result<(), BlockDeviceError> {
+        let most_worn = self
+            .blocks
+            .iter()
+            .filter(|b| b.used == 0)
+            .map(|b| b.erase_count)
+            .max();
+        let coldest = self
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(block, b)| b.used > 0 && Some(*block) != self.active)
+            .min_by_key(|(_, b)| b.erase_count)
+            .map(|(block, b)| (block, b.erase_count));
+
+        match (most_worn, coldest) {
+            (Some(max), Some((block, erase_count)))
+                if max.saturating_sub(erase_count) > WEAR_LEVELLING_THRESHOLD =>
+            {
+                debug!(
+                    "ftl: levelling block {} ({} erases, max {})",
+                    block, erase_count, max
+                );
+                self.open_block(true)?;
+                self.relocate(block)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<F: NorFlash, const BLOCKS: usize, const SECTORS: usize> BlockDevice
+    for FlashBlockDevice<F, BLOCKS, SECTORS>
+{
+    const BLOCK_BYTES: usize = SECTOR_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        assert_eq!(Self::BLOCK_BYTES, block.len());
+
+        let slot = *self
+            .map
+            .get(lba as usize)
+            .ok_or(BlockDeviceError::InvalidAddress)?;
+        if slot == UNMAPPED {
+            block.fill(0);
+            return Ok(());
+        }
+
+        self.read(self.data_address(slot), block)?;
+        match self.read_tag(slot)? {
+            Some(tag) if tag.data_crc == CRC.checksum(block) => Ok(()),
+            _ => {
+                warn!("ftl: crc mismatch reading lba {}", lba);
+                Err(BlockDeviceError::ReadError)
+            }
+        }
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        assert_eq!(Self::BLOCK_BYTES, block.len());
+
+        if lba as usize >= SECTORS {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+
+        let slot = self.next_slot()?;
+        self.program_sector(slot, lba, block)
+    }
+
+    fn block_count(&self) -> u32 {
+        SECTORS as u32
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Tag {
+    lba: u32,
+    sequence: u32,
+    data_crc: u32,
+}
+
+impl Tag {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let word = |i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+        if is_erased(bytes) || CRC.checksum(&bytes[..12]) != word(3) {
+            return None;
+        }
+        Some(Self {
+            lba: word(0),
+            sequence: word(1),
+            data_crc: word(2),
+        })
+    }
+
+    fn to_bytes(self) -> [u8; TAG_SIZE] {
+        let mut bytes = [0u8; TAG_SIZE];
+        bytes[0..4].copy_from_slice(&self.lba.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.data_crc.to_le_bytes());
+        let crc = CRC.checksum(&bytes[..12]);
+        bytes[12..16].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+}
+
+/// Returns the erase count from a valid block header
+fn parse_header(bytes: &[u8]) -> Option<u32> {
+    let word = |i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+    (word(0) == HEADER_MAGIC && CRC.checksum(&bytes[..12]) == word(3)).then(|| word(1))
+}
+
+fn tag_range(slot: usize) -> core::ops::Range<usize> {
+    let start = HEADER_SIZE + (slot - 1) * TAG_SIZE;
+    start..start + TAG_SIZE
+}
+
+fn is_erased(bytes: &[u8]) -> bool {
+    bytes.iter().all(|b| *b == 0xff)
+}
@@ -0,0 +1,704 @@
+//! The layout of a ghost FAT volume: where its boot sector, FATs, directories and files go,
+//! and the contents of every block that isn't file data. Nothing is stored, each block is
+//! worked out from the table of files when it's read. It doesn't depend on the rest of the
+//! firmware, so build.rs uses it too to make the disk image from a directory in the repo.
+//!
+//! ```text
+//! | MBR | gap | boot sector | FAT | FAT copy | root directory | clusters ... |
+//! ```
+//!
+//! The MBR has a single partition, starting at the partition LBA and filling the rest of the
+//! disk, and is left out if the partition LBA is 0. The FAT type follows from the number of
+//! clusters, which are as small as the size of the volume allows: FAT12 below 4085 clusters
+//! and FAT16 up to 65524. Asking for FAT12 makes the clusters bigger until there are few
+//! enough of them.
+//!
+//! The clusters of the directories and files are allocated in one contiguous run each, in the
+//! order of the table, and the rest of the volume is free. Names that aren't already a valid
+//! 8.3 name in upper case get long file name entries and a generated `NAME~N.EXT` short name.
+
+pub const BLOCK_SIZE: usize = 512;
+
+const RESERVED_BLOCKS: u32 = 1;
+const FATS: u32 = 2;
+const MIN_ROOT_ENTRIES: u32 = 512;
+const DIR_ENTRY_BYTES: usize = 32;
+const DIR_ENTRIES_PER_BLOCK: u32 = (BLOCK_SIZE / DIR_ENTRY_BYTES) as u32;
+const FIRST_CLUSTER: u32 = 2;
+/// Filesystems with fewer clusters are FAT12
+const FAT16_MIN_CLUSTERS: u32 = 4085;
+/// Filesystems with more clusters are FAT32
+const FAT16_MAX_CLUSTERS: u32 = 65524;
+const MAX_CLUSTER_BLOCKS: u32 = 128;
+const MEDIA_DESCRIPTOR: u8 = 0xf8;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// The label of a volume without one, which has no label entry in its root directory
+const NO_LABEL: &[u8; 11] = b"NO NAME    ";
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+const LFN_LAST: u8 = 0x40;
+const LFN_UNITS: usize = 13;
+const MAX_NAME_UNITS: usize = 255;
+/// Every entry is dated 2024-01-01 00:00
+const DATE: u16 = ((2024 - 1980) << 9) | (1 << 5) | 1;
+
+/// An entry in the table of files
+pub trait Node {
+    /// The long name, without the path
+    fn name(&self) -> &str;
+    /// The directory the entry is in, as an index into the table, `None` for the root
+    fn parent(&self) -> Option<usize>;
+    /// The size of a file, `None` for a directory
+    fn size(&self) -> Option<u32>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayoutError {
+    /// The directories and files don't fit on the volume
+    TooSmall,
+    /// The volume has too many clusters for FAT16, or for FAT12 if that was asked for
+    TooLarge,
+    /// The label isn't up to 11 upper case characters allowed in a short name, or spaces
+    InvalidLabel,
+    /// The entry's parent isn't a directory earlier in the table
+    InvalidParent(usize),
+    /// The entry's name is empty, too long or has a character FAT doesn't allow
+    InvalidName(usize),
+    /// The entry has the same name as an earlier one in its directory
+    DuplicateName(usize),
+}
+
+/// What a block holds, see [`Layout::read`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Block {
+    /// The block was filled in
+    Filled,
+    /// The block holds `len` bytes of the file at `node` from `offset`, followed by zeros.
+    /// The block was zeroed
+    File {
+        node: usize,
+        offset: u32,
+        len: usize,
+    },
+}
+
+/// Where a directory or file is stored
+#[derive(Clone, Copy)]
+struct Extent {
+    node: usize,
+    first_cluster: u32,
+    clusters: u32,
+}
+
+pub struct Layout {
+    partition_lba: u32,
+    blocks: u32,
+    fat_type: FatType,
+    cluster_blocks: u32,
+    clusters: u32,
+    fat_blocks: u32,
+    root_entries: u32,
+    label: [u8; 11],
+    volume_id: u32,
+}
+
+fn put_u16(block: &mut [u8], offset: usize, value: u16) {
+    block[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(block: &mut [u8], offset: usize, value: u32) {
+    block[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Whether `c` may be in a short name, other than letters and digits
+fn short_name_symbol(c: char) -> bool {
+    "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// The CHS address of `lba` in an MBR, with the usual 255 heads of 63 sectors and the
+/// largest address for blocks beyond it
+pub fn chs(lba: u32) -> [u8; 3] {
+    let cylinder = lba / (255 * 63);
+    if cylinder > 1023 {
+        return [0xfe, 0xff, 0xff];
+    }
+    let head = (lba / 63) % 255;
+    let sector = lba % 63 + 1;
+    [
+        head as u8,
+        ((cylinder >> 2) & 0xc0) as u8 | sector as u8,
+        cylinder as u8,
+    ]
+}
+
+/// The UTF-16 units of a long name
+fn units(name: &str) -> impl Iterator<Item = u16> + '_ {
+    name.encode_utf16()
+}
+
+/// The short name for `name` and whether it needs long name entries. `tail` numbers the
+/// generated short names with the same basis in a directory, from 1
+fn short_name(name: &str, tail: u32) -> ([u8; 11], bool) {
+    let mut short = [b' '; 11];
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    };
+
+    let valid = |part: &str, max: usize| {
+        part.len() <= max
+            && part
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || short_name_symbol(c))
+    };
+    if !base.is_empty() && valid(base, 8) && valid(ext, 3) {
+        short[..base.len()].copy_from_slice(base.as_bytes());
+        short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+        return (short, false);
+    }
+
+    // spaces and dots are dropped, anything else that isn't allowed becomes an underscore
+    let basis = |part: &str, out: &mut [u8]| {
+        let mut len = 0;
+        for c in part.chars().filter(|&c| c != ' ' && c != '.') {
+            if len == out.len() {
+                break;
+            }
+            out[len] = match c.to_ascii_uppercase() {
+                c if c.is_ascii_alphanumeric() || short_name_symbol(c) => c as u8,
+                _ => b'_',
+            };
+            len += 1;
+        }
+        len
+    };
+    let base_len = basis(base, &mut short[..8]).max(1);
+    if short[0] == b' ' {
+        short[0] = b'_';
+    }
+    basis(ext, &mut short[8..]);
+
+    let mut digits = [0u8; 10];
+    let mut n = tail;
+    let mut tail_len = 0;
+    while n > 0 || tail_len == 0 {
+        digits[tail_len] = b'0' + (n % 10) as u8;
+        n /= 10;
+        tail_len += 1;
+    }
+    let at = base_len.min(8 - 1 - tail_len);
+    short[at] = b'~';
+    for (i, &digit) in digits[..tail_len].iter().rev().enumerate() {
+        short[at + 1 + i] = digit;
+    }
+    short[at + 1 + tail_len..8].fill(b' ');
+    (short, true)
+}
+
+/// The checksum of a short name stored in its long name entries
+fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && units(name).count() <= MAX_NAME_UNITS
+        && !name
+            .chars()
+            .any(|c| c < ' ' || "\"*/:<>?\\|\x7f".contains(c))
+}
+
+/// Whether two names are the same, which FAT doesn't tell apart by case
+fn same_name(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .eq(b.chars().flat_map(char::to_lowercase))
+}
+
+/// Directory entries taken by `name`, its long name entries then its short name entry
+fn entry_slots(name: &str) -> u32 {
+    match short_name(name, 1) {
+        (_, true) => units(name).count().div_ceil(LFN_UNITS) as u32 + 1,
+        (_, false) => 1,
+    }
+}
+
+impl Layout {
+    /// Lays out the `nodes` on a disk of `block_count` blocks, with the volume starting at
+    /// `partition_lba` behind an MBR or, if it's 0, filling the disk. The FAT type is picked
+    /// from the size of the volume unless `fat_type` asks for one
+    pub fn new<N: Node>(
+        nodes: &[N],
+        block_count: u32,
+        partition_lba: u32,
+        label: &str,
+        volume_id: u32,
+        fat_type: Option<FatType>,
+    ) -> Result<Self, LayoutError> {
+        for (index, node) in nodes.iter().enumerate() {
+            if !valid_name(node.name()) {
+                return Err(LayoutError::InvalidName(index));
+            }
+            if let Some(parent) = node.parent() {
+                if parent >= index || nodes[parent].size().is_some() {
+                    return Err(LayoutError::InvalidParent(index));
+                }
+            }
+            let duplicate = nodes[..index].iter().any(|other| {
+                other.parent() == node.parent() && same_name(other.name(), node.name())
+            });
+            if duplicate {
+                return Err(LayoutError::DuplicateName(index));
+            }
+        }
+
+        let label_valid = label.len() <= 11
+            && !label.starts_with(' ')
+            && label.chars().all(|c| {
+                c == ' ' || c.is_ascii_uppercase() || c.is_ascii_digit() || short_name_symbol(c)
+            });
+        if !label_valid {
+            return Err(LayoutError::InvalidLabel);
+        }
+        let mut padded_label = *NO_LABEL;
+        if !label.is_empty() {
+            padded_label = [b' '; 11];
+            padded_label[..label.len()].copy_from_slice(label.as_bytes());
+        }
+
+        // the label and the entries of the top level
+        let root_slots = 1 + Self::dir_slots(nodes, None);
+        let root_entries = root_slots
+            .next_multiple_of(DIR_ENTRIES_PER_BLOCK)
+            .max(MIN_ROOT_ENTRIES);
+        if root_entries > u16::MAX as u32 {
+            return Err(LayoutError::TooSmall);
+        }
+
+        let blocks = block_count
+            .checked_sub(partition_lba)
+            .ok_or(LayoutError::TooSmall)?;
+        let mut layout = Self {
+            partition_lba,
+            blocks,
+            fat_type: FatType::Fat12,
+            cluster_blocks: 1,
+            clusters: 0,
+            fat_blocks: 1,
+            root_entries,
+            label: padded_label,
+            volume_id,
+        };
+        layout.size_fats(fat_type)?;
+
+        let used: u32 = layout.extents(nodes).map(|extent| extent.clusters).sum();
+        if used > layout.clusters {
+            return Err(LayoutError::TooSmall);
+        }
+        Ok(layout)
+    }
+
+    /// Picks the smallest clusters that keep the volume within FAT16, or within `fat_type`,
+    /// and the FAT size
+    fn size_fats(&mut self, fat_type: Option<FatType>) -> Result<(), LayoutError> {
+        let root_blocks = self.root_blocks();
+        let mut cluster_blocks = 1;
+        while cluster_blocks <= MAX_CLUSTER_BLOCKS {
+            // fewer clusters need a smaller FAT, which leaves room for more clusters, so
+            // this grows the FAT until it's big enough for what's left
+            let mut fat_blocks = 1;
+            let clusters = loop {
+                let data_blocks = self
+                    .blocks
+                    .checked_sub(RESERVED_BLOCKS + FATS * fat_blocks + root_blocks)
+                    .ok_or(LayoutError::TooSmall)?;
+                let clusters = data_blocks / cluster_blocks;
+                let bits = if clusters < FAT16_MIN_CLUSTERS {
+                    12
+                } else {
+                    16
+                };
+                let needed = ((clusters + FIRST_CLUSTER) * bits)
+                    .div_ceil(8)
+                    .div_ceil(BLOCK_SIZE as u32);
+                if needed <= fat_blocks {
+                    break clusters;
+                }
+                fat_blocks = needed;
+            };
+
+            if clusters == 0 {
+                return Err(LayoutError::TooSmall);
+            }
+            let fits = if clusters < FAT16_MIN_CLUSTERS {
+                FatType::Fat12
+            } else {
+                FatType::Fat16
+            };
+            if fat_type == Some(FatType::Fat16) && fits == FatType::Fat12 {
+                // bigger clusters only make fewer of them
+                return Err(LayoutError::TooSmall);
+            }
+            if clusters <= FAT16_MAX_CLUSTERS && fat_type.unwrap_or(fits) == fits {
+                self.cluster_blocks = cluster_blocks;
+                self.clusters = clusters;
+                self.fat_blocks = fat_blocks;
+                self.fat_type = fits;
+                return Ok(());
+            }
+            cluster_blocks *= 2;
+        }
+        Err(LayoutError::TooLarge)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// The size of the disk, MBR included
+    pub fn block_count(&self) -> u32 {
+        self.partition_lba + self.blocks
+    }
+
+    fn cluster_bytes(&self) -> u32 {
+        self.cluster_blocks * BLOCK_SIZE as u32
+    }
+
+    fn root_blocks(&self) -> u32 {
+        self.root_entries / DIR_ENTRIES_PER_BLOCK
+    }
+
+    fn fat_lba(&self) -> u32 {
+        RESERVED_BLOCKS
+    }
+
+    fn root_lba(&self) -> u32 {
+        self.fat_lba() + FATS * self.fat_blocks
+    }
+
+    fn data_lba(&self) -> u32 {
+        self.root_lba() + self.root_blocks()
+    }
+
+    /// Directory entries in the directory `dir` (`None` for the root), other than the
+    /// label, `.` and `..`
+    fn dir_slots<N: Node>(nodes: &[N], dir: Option<usize>) -> u32 {
+        nodes
+            .iter()
+            .filter(|node| node.parent() == dir)
+            .map(|node| entry_slots(node.name()))
+            .sum()
+    }
+
+    /// The clusters of each directory and file in the table, in order
+    fn extents<'n, N: Node>(&self, nodes: &'n [N]) -> impl Iterator<Item = Extent> + 'n {
+        let cluster_bytes = self.cluster_bytes();
+        let mut next = FIRST_CLUSTER;
+        nodes.iter().enumerate().map(move |(node, n)| {
+            let bytes = match n.size() {
+                Some(size) => size,
+                None => (2 + Self::dir_slots(nodes, Some(node))) * DIR_ENTRY_BYTES as u32,
+            };
+            let clusters = bytes.div_ceil(cluster_bytes);
+            let first_cluster = if clusters == 0 { 0 } else { next };
+            next += clusters;
+            Extent {
+                node,
+                first_cluster,
+                clusters,
+            }
+        })
+    }
+
+    fn first_cluster<N: Node>(&self, nodes: &[N], node: Option<usize>) -> u32 {
+        match node {
+            Some(node) => self.extents(nodes).nth(node).unwrap().first_cluster,
+            None => 0,
+        }
+    }
+
+    /// Fills `block` with block `lba` of the disk, or says which file it holds
+    pub fn read<N: Node>(&self, nodes: &[N], lba: u32, block: &mut [u8]) -> Block {
+        block.fill(0);
+        let Some(lba) = lba.checked_sub(self.partition_lba) else {
+            if lba == 0 {
+                self.mbr(block);
+            }
+            return Block::Filled;
+        };
+
+        if lba == 0 {
+            self.boot_sector(block);
+        } else if (self.fat_lba()..self.root_lba()).contains(&lba) {
+            self.fat(nodes, (lba - self.fat_lba()) % self.fat_blocks, block);
+        } else if (self.root_lba()..self.data_lba()).contains(&lba) {
+            self.dir(nodes, None, lba - self.root_lba(), block);
+        } else if lba >= self.data_lba() {
+            let cluster = FIRST_CLUSTER + (lba - self.data_lba()) / self.cluster_blocks;
+            let extent = self.extents(nodes).find(|extent| {
+                (extent.first_cluster..extent.first_cluster + extent.clusters).contains(&cluster)
+            });
+            if let Some(extent) = extent {
+                let offset = (lba - self.data_lba()) * BLOCK_SIZE as u32
+                    - (extent.first_cluster - FIRST_CLUSTER) * self.cluster_bytes();
+                match nodes[extent.node].size() {
+                    None => {
+                        let dir_block = offset / BLOCK_SIZE as u32;
+                        self.dir(nodes, Some(extent.node), dir_block, block);
+                    }
+                    Some(size) if offset < size => {
+                        return Block::File {
+                            node: extent.node,
+                            offset,
+                            len: ((size - offset) as usize).min(BLOCK_SIZE),
+                        };
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        Block::Filled
+    }
+
+    /// The MBR system id of the partition holding the volume
+    pub fn system_id(&self) -> u8 {
+        match self.fat_type {
+            FatType::Fat12 => 0x01,
+            FatType::Fat16 if self.blocks < 65536 => 0x04,
+            FatType::Fat16 => 0x06,
+        }
+    }
+
+    fn mbr(&self, block: &mut [u8]) {
+        let system_id = self.system_id();
+        let last = self.partition_lba + self.blocks - 1;
+        put_u32(block, 440, self.volume_id);
+        let entry = &mut block[446..462];
+        entry[1..4].copy_from_slice(&chs(self.partition_lba));
+        entry[4] = system_id;
+        entry[5..8].copy_from_slice(&chs(last));
+        put_u32(entry, 8, self.partition_lba);
+        put_u32(entry, 12, self.blocks);
+        block[510..].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    fn boot_sector(&self, block: &mut [u8]) {
+        block[0..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+        block[3..11].copy_from_slice(b"MSWIN4.1");
+        put_u16(block, 11, BLOCK_SIZE as u16);
+        block[13] = self.cluster_blocks as u8;
+        put_u16(block, 14, RESERVED_BLOCKS as u16);
+        block[16] = FATS as u8;
+        put_u16(block, 17, self.root_entries as u16);
+        if self.blocks < 65536 {
+            put_u16(block, 19, self.blocks as u16);
+        } else {
+            put_u32(block, 32, self.blocks);
+        }
+        block[21] = MEDIA_DESCRIPTOR;
+        put_u16(block, 22, self.fat_blocks as u16);
+        put_u16(block, 24, 63);
+        put_u16(block, 26, 255);
+        put_u32(block, 28, self.partition_lba);
+        // drive number, extended boot signature
+        block[36] = 0x80;
+        block[38] = 0x29;
+        put_u32(block, 39, self.volume_id);
+        block[43..54].copy_from_slice(&self.label);
+        block[54..62].copy_from_slice(match self.fat_type {
+            FatType::Fat12 => b"FAT12   ",
+            FatType::Fat16 => b"FAT16   ",
+        });
+        // not bootable, halt
+        block[62..65].copy_from_slice(&[0xf4, 0xeb, 0xfd]);
+        block[510..].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    /// Fills `block` with block `fat_block` of a FAT
+    fn fat<N: Node>(&self, nodes: &[N], fat_block: u32, block: &mut [u8]) {
+        // the entries that have a byte in the block, FAT12 entries straddle blocks
+        let start = fat_block * BLOCK_SIZE as u32;
+        let (first, last) = match self.fat_type {
+            FatType::Fat12 => (start * 2 / 3, (start + BLOCK_SIZE as u32) * 2 / 3),
+            FatType::Fat16 => (start / 2, (start + BLOCK_SIZE as u32) / 2 - 1),
+        };
+        let mut entries = [0u16; BLOCK_SIZE * 2 / 3 + 2];
+        let end_of_chain = match self.fat_type {
+            FatType::Fat12 => 0x0fff,
+            FatType::Fat16 => 0xffff,
+        };
+        let mut set = |cluster: u32, value: u16| {
+            if (first..=last).contains(&cluster) {
+                entries[(cluster - first) as usize] = value;
+            }
+        };
+
+        set(0, end_of_chain & 0xff00 | MEDIA_DESCRIPTOR as u16);
+        set(1, end_of_chain);
+        for extent in self.extents(nodes) {
+            let end = extent.first_cluster + extent.clusters;
+            if extent.clusters == 0 || end <= first {
+                continue;
+            }
+            if extent.first_cluster > last {
+                break;
+            }
+            for cluster in extent.first_cluster.max(first)..end.min(last + 1) {
+                let next = if cluster + 1 == end {
+                    end_of_chain
+                } else {
+                    cluster as u16 + 1
+                };
+                set(cluster, next);
+            }
+        }
+
+        for (i, byte) in block.iter_mut().enumerate() {
+            let offset = start + i as u32;
+            *byte = match self.fat_type {
+                FatType::Fat16 => {
+                    let entry = entries[(offset / 2 - first) as usize];
+                    entry.to_le_bytes()[offset as usize % 2]
+                }
+                FatType::Fat12 => {
+                    // two entries in three bytes
+                    let pair = offset / 3 * 2;
+                    let entry = |cluster: u32| entries[(cluster - first) as usize];
+                    match offset % 3 {
+                        0 => entry(pair) as u8,
+                        1 => ((entry(pair) >> 8) & 0x0f) as u8 | (entry(pair + 1) << 4) as u8,
+                        _ => (entry(pair + 1) >> 4) as u8,
+                    }
+                }
+            };
+        }
+    }
+
+    /// Fills `block` with block `dir_block` of the directory `dir`, `None` for the root
+    fn dir<N: Node>(&self, nodes: &[N], dir: Option<usize>, dir_block: u32, block: &mut [u8]) {
+        let first_slot = dir_block * DIR_ENTRIES_PER_BLOCK;
+        let slots = first_slot..first_slot + DIR_ENTRIES_PER_BLOCK;
+        let mut put = |slot: u32, entry: &[u8; DIR_ENTRY_BYTES]| {
+            if slots.contains(&slot) {
+                let offset = (slot - first_slot) as usize * DIR_ENTRY_BYTES;
+                block[offset..offset + DIR_ENTRY_BYTES].copy_from_slice(entry);
+            }
+        };
+
+        let mut slot = 0;
+        match dir {
+            None if self.label != *NO_LABEL => {
+                put(0, &short_entry(&self.label, ATTR_VOLUME_ID, 0, 0));
+                slot = 1;
+            }
+            None => {}
+            Some(dir) => {
+                let this = self.first_cluster(nodes, Some(dir));
+                let parent = self.first_cluster(nodes, nodes[dir].parent());
+                put(0, &short_entry(b".          ", ATTR_DIRECTORY, this, 0));
+                put(1, &short_entry(b"..         ", ATTR_DIRECTORY, parent, 0));
+                slot = 2;
+            }
+        }
+
+        for (node, extent) in nodes.iter().zip(self.extents(nodes)) {
+            if node.parent() != dir {
+                continue;
+            }
+            let name = node.name();
+            let entries = entry_slots(name);
+            if slot + entries <= slots.start || slot >= slots.end {
+                slot += entries;
+                continue;
+            }
+
+            let short = self.short_name(nodes, extent.node);
+            let (attributes, size) = match node.size() {
+                Some(size) => (ATTR_ARCHIVE, size),
+                None => (ATTR_DIRECTORY, 0),
+            };
+            let checksum = lfn_checksum(&short);
+            let lfn_entries = entries - 1;
+            for i in 0..lfn_entries {
+                // the last part of the name comes first
+                let part = lfn_entries - i;
+                put(slot, &lfn_entry(name, part, part == lfn_entries, checksum));
+                slot += 1;
+            }
+            put(
+                slot,
+                &short_entry(&short, attributes, extent.first_cluster, size),
+            );
+            slot += 1;
+        }
+    }
+
+    /// The short name of `node`, numbered after the earlier entries in its directory with
+    /// the same basis
+    fn short_name<N: Node>(&self, nodes: &[N], node: usize) -> [u8; 11] {
+        let (basis, generated) = short_name(nodes[node].name(), 1);
+        if !generated {
+            return basis;
+        }
+        let parent = nodes[node].parent();
+        let tail = 1 + nodes[..node]
+            .iter()
+            .filter(|other| other.parent() == parent)
+            .filter(|other| short_name(other.name(), 1) == (basis, true))
+            .count() as u32;
+        short_name(nodes[node].name(), tail).0
+    }
+}
+
+fn short_entry(
+    name: &[u8; 11],
+    attributes: u8,
+    first_cluster: u32,
+    size: u32,
+) -> [u8; DIR_ENTRY_BYTES] {
+    let mut entry = [0u8; DIR_ENTRY_BYTES];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attributes;
+    if attributes != ATTR_VOLUME_ID {
+        // created, accessed and written
+        put_u16(&mut entry, 16, DATE);
+        put_u16(&mut entry, 18, DATE);
+    }
+    put_u16(&mut entry, 24, DATE);
+    put_u16(&mut entry, 26, first_cluster as u16);
+    put_u32(&mut entry, 28, size);
+    entry
+}
+
+/// The long name entry holding part `part` (from 1) of `name`
+fn lfn_entry(name: &str, part: u32, last: bool, checksum: u8) -> [u8; DIR_ENTRY_BYTES] {
+    const OFFSETS: [usize; LFN_UNITS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+    let mut entry = [0u8; DIR_ENTRY_BYTES];
+    entry[0] = part as u8 | if last { LFN_LAST } else { 0 };
+    entry[11] = ATTR_LONG_NAME;
+    entry[13] = checksum;
+
+    // the name is terminated by a 0 if there's room, then padded with 0xffff
+    let mut name_units = units(name)
+        .skip((part as usize - 1) * LFN_UNITS)
+        .chain(core::iter::once(0));
+    for offset in OFFSETS {
+        let unit = name_units.next().unwrap_or(0xffff);
+        put_u16(&mut entry, offset, unit);
+    }
+    entry
+}
@@ -0,0 +1,126 @@
+//! A read-only FAT volume made up on the fly from a table of files in the firmware, like the
+//! UF2 bootloader's drive. The MBR, boot sector, FATs and directories are worked out from
+//! the table as the host reads them (see [`layout`]) and file data comes straight from a
+//! static slice or a callback, so a large volume takes next to no RAM.
+//!
+//! Directories are entries of the table too, and the other entries point at the directory
+//! they're in by its index, which has to come before them:
+//!
+//! ```ignore
+//! static FILES: [GhostFile; 3] = [
+//!     GhostFile::new("README.TXT", None, Contents::Static(b"hello")),
+//!     GhostFile::new("Logs", None, Contents::Directory),
+//!     GhostFile::new("Boot log.txt", Some(1), Contents::Generated { size: 4096, read }),
+//! ];
+//! ```
+
+pub mod layout;
+
+use crate::scsi::{BlockDevice, BlockDeviceError};
+use layout::{Block, FatType, Layout, LayoutError, Node, BLOCK_SIZE};
+
+/// Where the volume starts, after the MBR
+pub const PARTITION_LBA: u32 = 1;
+
+pub enum Contents {
+    /// A file stored in the firmware
+    Static(&'static [u8]),
+    /// A file of `size` bytes made up as it's read: `read` fills the buffer with the file from
+    /// the given offset
+    Generated {
+        size: u32,
+        read: fn(u32, &mut [u8]),
+    },
+    Directory,
+}
+
+pub struct GhostFile {
+    name: &'static str,
+    parent: Option<usize>,
+    contents: Contents,
+}
+
+impl GhostFile {
+    /// An entry named `name`, which may be a long name, in the directory at index `parent` of
+    /// the table, or the root directory if it's `None`
+    pub const fn new(name: &'static str, parent: Option<usize>, contents: Contents) -> Self {
+        Self {
+            name,
+            parent,
+            contents,
+        }
+    }
+}
+
+impl Node for GhostFile {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn parent(&self) -> Option<usize> {
+        self.parent
+    }
+
+    fn size(&self) -> Option<u32> {
+        match self.contents {
+            Contents::Static(data) => Some(data.len() as u32),
+            Contents::Generated { size, .. } => Some(size),
+            Contents::Directory => None,
+        }
+    }
+}
+
+pub struct GhostFatBlockDevice {
+    files: &'static [GhostFile],
+    layout: Layout,
+}
+
+impl GhostFatBlockDevice {
+    /// A disk of `block_count` blocks with a volume holding `files`, labelled `label`
+    pub fn new(
+        files: &'static [GhostFile],
+        block_count: u32,
+        label: &str,
+        volume_id: u32,
+    ) -> Result<Self, LayoutError> {
+        let layout = Layout::new(files, block_count, PARTITION_LBA, label, volume_id, None)?;
+        Ok(Self { files, layout })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.layout.fat_type()
+    }
+}
+
+impl BlockDevice for GhostFatBlockDevice {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        if lba >= self.block_count() {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+        if let Block::File { node, offset, len } = self.layout.read(self.files, lba, block) {
+            match self.files[node].contents {
+                Contents::Static(data) => {
+                    let offset = offset as usize;
+                    block[..len].copy_from_slice(&data[offset..offset + len]);
+                }
+                Contents::Generated { read, .. } => read(offset, &mut block[..len]),
+                Contents::Directory => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_block(&mut self, _lba: u32, _block: &[u8]) -> Result<(), BlockDeviceError> {
+        Err(BlockDeviceError::WriteProtected)
+    }
+
+    fn block_count(&self) -> u32 {
+        self.layout.block_count()
+    }
+
+    fn write_protected(&self) -> bool {
+        true
+    }
+}
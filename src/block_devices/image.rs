@@ -0,0 +1,50 @@
+//! A read-only disk image held in memory, typically an `include_bytes!` image that stays in
+//! XIP flash rather than being copied to RAM.
+
+use super::BLOCK_SIZE;
+use crate::scsi::{BlockDevice, BlockDeviceError};
+
+pub struct ImageBlockDevice {
+    image: &'static [u8],
+}
+
+impl ImageBlockDevice {
+    /// `image` must be a whole number of blocks
+    pub const fn new(image: &'static [u8]) -> Self {
+        assert!(image.len().is_multiple_of(BLOCK_SIZE));
+        Self { image }
+    }
+
+    fn blocks(&self, lba: u32, len: usize) -> Result<&'static [u8], BlockDeviceError> {
+        let start = lba as usize * BLOCK_SIZE;
+        self.image
+            .get(start..start + len)
+            .ok_or(BlockDeviceError::InvalidAddress)
+    }
+}
+
+impl BlockDevice for ImageBlockDevice {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        block.copy_from_slice(self.blocks(lba, block.len())?);
+        Ok(())
+    }
+
+    async fn write_block(&mut self, _lba: u32, _block: &[u8]) -> Result<(), BlockDeviceError> {
+        Err(BlockDeviceError::WriteProtected)
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        blocks.copy_from_slice(self.blocks(lba, blocks.len())?);
+        Ok(())
+    }
+
+    fn block_count(&self) -> u32 {
+        (self.image.len() / BLOCK_SIZE) as u32
+    }
+
+    fn write_protected(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,324 @@
+//! Detects silent corruption of a base device by keeping a CRC of every block, checked on
+//! each read. A block that doesn't match its CRC fails with UNRECOVERED READ ERROR rather than
+//! returning bad data to the host.
+//!
+//! The CRCs are kept as T10 type 1 protection information (see
+//! [`PROTECTION_INFORMATION_BYTES`]) in a metadata region at the end of the base, 64 blocks to
+//! a metadata block. With `protection_information` enabled the device also advertises it to
+//! the host, which can then send and check it end to end with RDPROTECT/WRPROTECT.
+//!
+//! The reference tag is stored inverted, so no record is all zeros. Blocks whose protection
+//! information was never written (all zeros, as in RAM, or all ones, as in erased flash)
+//! aren't checked and are reported to the host with the escape values that turn its checks
+//! off, as are blocks the host unmapped. A power cut between writing a block and its metadata
+//! leaves a block that fails to read.
+
+use defmt::error;
+
+use super::BLOCK_SIZE;
+use crate::scsi::{
+    BlockDevice, BlockDeviceError, MediaStatus, Wrapper, GUARD_CRC, PROTECTION_INFORMATION_BYTES,
+};
+
+/// Protection information records per metadata block
+const PI_PER_BLOCK: u32 = (BLOCK_SIZE / PROTECTION_INFORMATION_BYTES) as u32;
+/// Application and reference tags that disable the host's checks
+const ESCAPE: [u8; PROTECTION_INFORMATION_BYTES] = [0xff; PROTECTION_INFORMATION_BYTES];
+const NO_METADATA: u32 = u32::MAX;
+
+/// Type 1 protection information for `block` at `lba`
+pub fn protection_information(
+    lba: u32,
+    block: &[u8],
+    application_tag: u16,
+) -> [u8; PROTECTION_INFORMATION_BYTES] {
+    let mut pi = [0; PROTECTION_INFORMATION_BYTES];
+    pi[0..2].copy_from_slice(&GUARD_CRC.checksum(block).to_be_bytes());
+    pi[2..4].copy_from_slice(&application_tag.to_be_bytes());
+    pi[4..8].copy_from_slice(&lba.to_be_bytes());
+    pi
+}
+
+fn unwritten(pi: &[u8]) -> bool {
+    pi.iter().all(|&byte| byte == 0) || pi == ESCAPE
+}
+
+/// Turns protection information into the record stored for it and back. The reference tag is
+/// inverted, so a block of zeros at LBA 0, whose guard is zero too, isn't taken as unwritten
+fn invert_reference_tag(pi: &mut [u8]) {
+    for byte in &mut pi[4..8] {
+        *byte = !*byte;
+    }
+}
+
+/// The blocks of a base of `total` blocks left for the host, leaving room for one metadata
+/// block per `PI_PER_BLOCK` blocks, rounded up
+fn host_blocks(total: u32) -> u32 {
+    (total as u64 * PI_PER_BLOCK as u64 / (PI_PER_BLOCK as u64 + 1)) as u32
+}
+
+pub struct IntegrityBlockDevice<B> {
+    base: B,
+    /// Blocks available to the host, the metadata region follows them
+    blocks: u32,
+    protection_information: bool,
+    /// The metadata block cached in `metadata`, `NO_METADATA` if none
+    metadata_lba: u32,
+    metadata: [u8; BLOCK_SIZE],
+    /// `metadata` has changes that haven't been written to the base
+    metadata_dirty: bool,
+}
+
+impl<B: BlockDevice> IntegrityBlockDevice<B> {
+    /// Checks `base`, advertising the protection information to the host if
+    /// `protection_information` is set
+    pub fn new(base: B, protection_information: bool) -> Self {
+        assert!(B::BLOCK_BYTES == BLOCK_SIZE);
+
+        Self {
+            blocks: host_blocks(base.block_count()),
+            base,
+            protection_information,
+            metadata_lba: NO_METADATA,
+            metadata: [0; BLOCK_SIZE],
+            metadata_dirty: false,
+        }
+    }
+
+    fn check_range(&self, lba: u32, count: usize) -> Result<(), BlockDeviceError> {
+        match (lba as u64).checked_add(count as u64) {
+            Some(end) if end <= self.blocks as u64 => Ok(()),
+            _ => Err(BlockDeviceError::InvalidAddress),
+        }
+    }
+
+    /// The cached metadata block holding the protection information of `lba`
+    async fn metadata(&mut self, lba: u32) -> Result<&mut [u8], BlockDeviceError> {
+        let metadata_lba = self.blocks + lba / PI_PER_BLOCK;
+        if self.metadata_lba != metadata_lba {
+            self.flush_metadata(false).await?;
+            self.metadata_lba = NO_METADATA;
+            match self.base.read_block(metadata_lba, &mut self.metadata).await {
+                Err(e) if !e.is_recovered() => return Err(e),
+                _ => self.metadata_lba = metadata_lba,
+            }
+        }
+
+        let offset = (lba % PI_PER_BLOCK) as usize * PROTECTION_INFORMATION_BYTES;
+        Ok(&mut self.metadata[offset..offset + PROTECTION_INFORMATION_BYTES])
+    }
+
+    /// Writes the cached metadata block back if it changed, with force unit access if `fua`
+    async fn flush_metadata(&mut self, fua: bool) -> Result<(), BlockDeviceError> {
+        if !self.metadata_dirty {
+            return Ok(());
+        }
+        let written = if fua {
+            self.base
+                .write_blocks_fua(self.metadata_lba, &self.metadata)
+                .await
+        } else {
+            self.base
+                .write_block(self.metadata_lba, &self.metadata)
+                .await
+        };
+        match written {
+            Err(e) if !e.is_recovered() => Err(e),
+            _ => {
+                self.metadata_dirty = false;
+                Ok(())
+            }
+        }
+    }
+
+    /// Reads and checks the blocks from `lba`, copying their protection information into
+    /// `protection` if given
+    async fn read_checked(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        mut protection: Option<&mut [u8]>,
+    ) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+
+        let result = self.base.read_blocks(lba, blocks).await;
+        if matches!(result, Err(e) if !e.is_recovered()) {
+            return result;
+        }
+
+        for (i, block) in blocks.chunks_exact(BLOCK_SIZE).enumerate() {
+            let lba = lba + i as u32;
+            let stored = self.metadata(lba).await?;
+
+            let pi = if unwritten(stored) {
+                ESCAPE
+            } else {
+                let mut pi: [u8; PROTECTION_INFORMATION_BYTES] = stored.try_into().unwrap();
+                invert_reference_tag(&mut pi);
+                let expected = protection_information(lba, block, 0);
+                // the application tag belongs to the host and isn't checked
+                if pi[0..2] != expected[0..2] || pi[4..8] != expected[4..8] {
+                    error!("integrity: block {} doesn't match its checksum", lba);
+                    return Err(BlockDeviceError::ReadError);
+                }
+                pi
+            };
+
+            if let Some(protection) = protection.as_deref_mut() {
+                protection
+                    [i * PROTECTION_INFORMATION_BYTES..(i + 1) * PROTECTION_INFORMATION_BYTES]
+                    .copy_from_slice(&pi);
+            }
+        }
+        result
+    }
+
+    /// Writes the blocks from `lba` followed by their protection information, computed
+    /// unless given in `protection`, all with force unit access if `fua`
+    async fn write_checked(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: Option<&[u8]>,
+        fua: bool,
+    ) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+
+        let result = if fua {
+            self.base.write_blocks_fua(lba, blocks).await
+        } else {
+            self.base.write_blocks(lba, blocks).await
+        };
+        if matches!(result, Err(e) if !e.is_recovered()) {
+            return result;
+        }
+
+        for (i, block) in blocks.chunks_exact(BLOCK_SIZE).enumerate() {
+            let mut pi = match protection {
+                Some(protection) => protection
+                    [i * PROTECTION_INFORMATION_BYTES..(i + 1) * PROTECTION_INFORMATION_BYTES]
+                    .try_into()
+                    .unwrap(),
+                None => protection_information(lba + i as u32, block, 0),
+            };
+            invert_reference_tag(&mut pi);
+            self.metadata(lba + i as u32).await?.copy_from_slice(&pi);
+            self.metadata_dirty = true;
+        }
+        self.flush_metadata(fua).await?;
+        result
+    }
+}
+
+impl<B: BlockDevice> Wrapper for IntegrityBlockDevice<B> {
+    type Base = B;
+
+    fn base(&self) -> &B {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut B {
+        &mut self.base
+    }
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.read_checked(lba, block, None).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_checked(lba, block, None, false).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.read_checked(lba, blocks, None).await
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_checked(lba, blocks, None, false).await
+    }
+
+    async fn write_blocks_fua(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_checked(lba, blocks, None, true).await
+    }
+
+    fn block_count(&self) -> u32 {
+        self.blocks
+    }
+
+    /// A new medium has its metadata region at its own end, and the cached metadata block
+    /// belongs to the old one
+    async fn media_status(&mut self) -> MediaStatus {
+        let status = self.base.media_status().await;
+        if matches!(status, MediaStatus::Changed) {
+            self.blocks = host_blocks(self.base.block_count());
+            self.metadata_lba = NO_METADATA;
+            self.metadata_dirty = false;
+        }
+        status
+    }
+
+    /// Unmapped blocks read as zeros, so their protection information is dropped with them
+    async fn unmap(&mut self, lba: u32, count: u32) -> Result<(), BlockDeviceError> {
+        if count == 0 {
+            return Ok(());
+        }
+        self.check_range(lba, count as usize)?;
+        self.base.unmap(lba, count).await?;
+        for lba in lba..lba + count {
+            self.metadata(lba).await?.fill(0);
+            self.metadata_dirty = true;
+        }
+        self.flush_metadata(false).await
+    }
+
+    async fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        self.flush_metadata(false).await?;
+        self.base.flush().await
+    }
+
+    fn protection_information(&self) -> bool {
+        self.protection_information
+    }
+
+    async fn read_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        protection: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.read_checked(lba, blocks, Some(protection)).await
+    }
+
+    async fn write_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.write_checked(lba, blocks, Some(protection), false)
+            .await
+    }
+
+    /// Leaves room in the base's transactions for the metadata blocks a write spans
+    fn transaction_blocks(&self) -> Option<u32> {
+        self.base
+            .transaction_blocks()
+            .map(|blocks| blocks.saturating_sub(blocks / PI_PER_BLOCK + 2))
+    }
+
+    /// Formats the base and drops every block's protection information, as the format
+    /// rewrote the blocks without it
+    async fn format(&mut self) -> Result<(), BlockDeviceError> {
+        self.base.format().await?;
+        self.metadata_lba = NO_METADATA;
+        self.metadata_dirty = false;
+        let zeros = [0u8; BLOCK_SIZE];
+        for metadata_lba in self.blocks..self.base.block_count() {
+            match self.base.write_block(metadata_lba, &zeros).await {
+                Err(e) if !e.is_recovered() => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
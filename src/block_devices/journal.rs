@@ -0,0 +1,422 @@
+//! Makes each write command of the host atomic on a persistent base, so a power cut in the
+//! middle of a write of several blocks leaves either all of them or none of them on the
+//! medium, rather than e.g. a FAT with only some of its blocks updated.
+//!
+//! The SCSI layer wraps every WRITE in a transaction (see [`BlockDevice::begin_transaction`]).
+//! The blocks written in a transaction go to a journal at the end of the base instead of
+//! their place: a header block followed by `BLOCKS` data blocks. Committing the transaction
+//! flushes the data blocks, then writes and flushes the header, which lists where each block
+//! belongs and has a CRC of them, then copies the blocks to their place and clears the
+//! header. The header reaching the medium is the commit point.
+//!
+//! Before the first access after boot or a medium change, a complete header whose blocks
+//! match its CRC is replayed, which just copies the blocks again if the power went while they
+//! were being copied, and anything else in the journal is discarded. Writes outside a
+//! transaction go straight to the base. A transaction holds at most `BLOCKS` blocks, and the
+//! SCSI layer splits longer writes into several, each atomic on its own.
+//!
+//! Protection information isn't journaled so it isn't passed through, and the base isn't
+//! formatted as it would be over the journal.
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use defmt::{info, warn};
+
+use super::BLOCK_SIZE;
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus, Wrapper};
+
+const MAGIC: &[u8; 4] = b"JRNL";
+/// The header is the magic, the number of blocks in the journal, the CRC of their CRCs and
+/// where each of them belongs, all little endian, with a CRC of it all in its last bytes
+const HEADER_LBAS: usize = 12;
+const HEADER_CRC: usize = BLOCK_SIZE - 4;
+/// The most blocks a header can list
+pub const MAX_BLOCKS: usize = (HEADER_CRC - HEADER_LBAS) / 4;
+
+const JOURNAL_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+fn u32_at(block: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap())
+}
+
+/// Keeps the first non recovered error of a sequence of operations, or else the last
+/// recovered one
+fn check(
+    result: Result<(), BlockDeviceError>,
+    recovered: &mut Result<(), BlockDeviceError>,
+) -> Result<(), BlockDeviceError> {
+    match result {
+        Err(e) if e.is_recovered() => {
+            *recovered = Err(e);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// A transaction in the journal, as listed by its header
+struct Header {
+    count: usize,
+    data_crc: u32,
+    lbas: [u32; MAX_BLOCKS],
+}
+
+impl Header {
+    fn to_block(&self) -> [u8; BLOCK_SIZE] {
+        let mut block = [0u8; BLOCK_SIZE];
+        block[0..4].copy_from_slice(MAGIC);
+        block[4..8].copy_from_slice(&(self.count as u32).to_le_bytes());
+        block[8..12].copy_from_slice(&self.data_crc.to_le_bytes());
+        for (i, lba) in self.lbas[..self.count].iter().enumerate() {
+            let offset = HEADER_LBAS + i * 4;
+            block[offset..offset + 4].copy_from_slice(&lba.to_le_bytes());
+        }
+        let crc = JOURNAL_CRC.checksum(&block[..HEADER_CRC]);
+        block[HEADER_CRC..].copy_from_slice(&crc.to_le_bytes());
+        block
+    }
+
+    /// The header in `block`, `None` if it isn't a complete one
+    fn from_block(block: &[u8]) -> Option<Self> {
+        let count = u32_at(block, 4) as usize;
+        if block[0..4] != MAGIC[..]
+            || u32_at(block, HEADER_CRC) != JOURNAL_CRC.checksum(&block[..HEADER_CRC])
+            || count > MAX_BLOCKS
+        {
+            return None;
+        }
+
+        let mut lbas = [0; MAX_BLOCKS];
+        for (i, lba) in lbas[..count].iter_mut().enumerate() {
+            *lba = u32_at(block, HEADER_LBAS + i * 4);
+        }
+        Some(Self {
+            count,
+            data_crc: u32_at(block, 8),
+            lbas,
+        })
+    }
+}
+
+/// Journals the writes of each transaction on `base` through `BLOCKS` blocks at its end
+pub struct JournalBlockDevice<B, const BLOCKS: usize> {
+    base: B,
+    /// The journal has been checked for a transaction to replay since boot
+    recovered: bool,
+    in_transaction: bool,
+    /// Where the blocks in the journal belong, in the order they were first written
+    lbas: [u32; BLOCKS],
+    /// The CRCs of the blocks in the journal
+    crcs: [u32; BLOCKS],
+    len: usize,
+}
+
+impl<B: BlockDevice, const BLOCKS: usize> JournalBlockDevice<B, BLOCKS> {
+    pub const fn new(base: B) -> Self {
+        assert!(B::BLOCK_BYTES == BLOCK_SIZE);
+        assert!(BLOCKS > 0 && BLOCKS <= MAX_BLOCKS);
+        Self {
+            base,
+            recovered: false,
+            in_transaction: false,
+            lbas: [0; BLOCKS],
+            crcs: [0; BLOCKS],
+            len: 0,
+        }
+    }
+
+    /// The header of the journal, the data blocks follow it
+    fn header_lba(&self) -> u32 {
+        Wrapper::block_count(self)
+    }
+
+    fn check_range(&self, lba: u32, count: usize) -> Result<(), BlockDeviceError> {
+        match (lba as u64).checked_add(count as u64) {
+            Some(end) if end <= Wrapper::block_count(self) as u64 => Ok(()),
+            _ => Err(BlockDeviceError::InvalidAddress),
+        }
+    }
+
+    /// The journal block holding `lba` in the current transaction, taking the next free one
+    /// if it isn't there yet
+    fn slot(&mut self, lba: u32) -> Result<usize, BlockDeviceError> {
+        if let Some(slot) = self.lbas[..self.len].iter().position(|&l| l == lba) {
+            return Ok(slot);
+        }
+        if self.len == BLOCKS {
+            warn!("journal: full, {} blocks in the transaction", BLOCKS);
+            return Err(BlockDeviceError::SpaceAllocationFailed);
+        }
+        self.lbas[self.len] = lba;
+        self.len += 1;
+        Ok(self.len - 1)
+    }
+
+    /// Writes `blocks` into the journal, consecutive journal blocks at once
+    async fn write_journal(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        let mut result = Ok(());
+        let count = blocks.len() / BLOCK_SIZE;
+        let mut i = 0;
+        while i < count {
+            let first = self.slot(lba + i as u32)?;
+            let mut end = i + 1;
+            while end < count && self.slot(lba + end as u32)? == first + end - i {
+                end += 1;
+            }
+
+            let run = &blocks[i * BLOCK_SIZE..end * BLOCK_SIZE];
+            for (slot, block) in (first..).zip(run.chunks_exact(BLOCK_SIZE)) {
+                self.crcs[slot] = JOURNAL_CRC.checksum(block);
+            }
+            let journal_lba = self.header_lba() + 1 + first as u32;
+            check(self.base.write_blocks(journal_lba, run).await, &mut result)?;
+            i = end;
+        }
+        result
+    }
+
+    /// The CRC of the CRCs of the blocks in the journal, in the order of its header
+    fn data_crc(crcs: &[u32]) -> u32 {
+        let mut digest = JOURNAL_CRC.digest();
+        for crc in crcs {
+            digest.update(&crc.to_le_bytes());
+        }
+        digest.finalize()
+    }
+
+    async fn commit(&mut self) -> Result<(), BlockDeviceError> {
+        let mut result = Ok(());
+        let header_lba = self.header_lba();
+
+        // the blocks have to be on the medium before the header that points at them
+        check(self.base.flush().await, &mut result)?;
+        let mut header = Header {
+            count: self.len,
+            data_crc: Self::data_crc(&self.crcs[..self.len]),
+            lbas: [0; MAX_BLOCKS],
+        };
+        header.lbas[..self.len].copy_from_slice(&self.lbas[..self.len]);
+        check(
+            self.base.write_block(header_lba, &header.to_block()).await,
+            &mut result,
+        )?;
+        check(self.base.flush().await, &mut result)?;
+
+        self.apply(&header).await?;
+        result
+    }
+
+    /// Copies the blocks in the journal listed by `header` to their place and clears the
+    /// header once they're on the medium
+    async fn apply(&mut self, header: &Header) -> Result<(), BlockDeviceError> {
+        let mut result = Ok(());
+        let mut block = [0u8; BLOCK_SIZE];
+        for (slot, &lba) in header.lbas[..header.count].iter().enumerate() {
+            let journal_lba = self.header_lba() + 1 + slot as u32;
+            check(
+                self.base.read_block(journal_lba, &mut block).await,
+                &mut result,
+            )?;
+            check(self.base.write_block(lba, &block).await, &mut result)?;
+        }
+        check(self.base.flush().await, &mut result)?;
+        self.clear().await?;
+        result
+    }
+
+    async fn clear(&mut self) -> Result<(), BlockDeviceError> {
+        let mut result = Ok(());
+        let header_lba = self.header_lba();
+        check(
+            self.base.write_block(header_lba, &[0; BLOCK_SIZE]).await,
+            &mut result,
+        )?;
+        check(self.base.flush().await, &mut result)?;
+        result
+    }
+
+    /// Replays a committed transaction left in the journal by a power cut, or discards an
+    /// incomplete one
+    async fn recover(&mut self) -> Result<(), BlockDeviceError> {
+        if self.recovered {
+            return Ok(());
+        }
+
+        let mut result = Ok(());
+        let mut block = [0u8; BLOCK_SIZE];
+        let header_lba = self.header_lba();
+        check(
+            self.base.read_block(header_lba, &mut block).await,
+            &mut result,
+        )?;
+
+        match Header::from_block(&block) {
+            Some(header)
+                if header.lbas[..header.count]
+                    .iter()
+                    .all(|&lba| lba < header_lba) =>
+            {
+                let mut crcs = [0; MAX_BLOCKS];
+                for (slot, crc) in crcs[..header.count].iter_mut().enumerate() {
+                    let journal_lba = header_lba + 1 + slot as u32;
+                    check(
+                        self.base.read_block(journal_lba, &mut block).await,
+                        &mut result,
+                    )?;
+                    *crc = JOURNAL_CRC.checksum(&block);
+                }
+
+                if Self::data_crc(&crcs[..header.count]) == header.data_crc {
+                    info!("journal: replaying {} blocks", header.count);
+                    check(self.apply(&header).await, &mut result)?;
+                } else {
+                    warn!("journal: discarding an incomplete transaction");
+                    check(self.clear().await, &mut result)?;
+                }
+            }
+            _ if block[0..4] == MAGIC[..] => {
+                warn!("journal: discarding a torn header");
+                check(self.clear().await, &mut result)?;
+            }
+            _ => {}
+        }
+
+        self.recovered = true;
+        result
+    }
+}
+
+impl<B: BlockDevice, const BLOCKS: usize> Wrapper for JournalBlockDevice<B, BLOCKS> {
+    type Base = B;
+
+    fn base(&self) -> &B {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut B {
+        &mut self.base
+    }
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        Wrapper::read_blocks(self, lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        Wrapper::write_blocks(self, lba, block).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+        let mut result = Ok(());
+        check(self.recover().await, &mut result)?;
+        check(self.base.read_blocks(lba, blocks).await, &mut result)?;
+
+        // the transaction's own writes aren't in place yet
+        let header_lba = self.header_lba();
+        for (block_lba, block) in (lba..).zip(blocks.chunks_exact_mut(BLOCK_SIZE)) {
+            if let Some(slot) = self.lbas[..self.len].iter().position(|&l| l == block_lba) {
+                let journal_lba = header_lba + 1 + slot as u32;
+                check(self.base.read_block(journal_lba, block).await, &mut result)?;
+            }
+        }
+        result
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+        let mut result = Ok(());
+        check(self.recover().await, &mut result)?;
+        if self.in_transaction {
+            check(self.write_journal(lba, blocks).await, &mut result)?;
+        } else {
+            check(self.base.write_blocks(lba, blocks).await, &mut result)?;
+        }
+        result
+    }
+
+    /// The transaction is flushed when it's committed
+    async fn write_blocks_fua(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        if self.in_transaction {
+            return Wrapper::write_blocks(self, lba, blocks).await;
+        }
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+        let mut result = Ok(());
+        check(self.recover().await, &mut result)?;
+        check(self.base.write_blocks_fua(lba, blocks).await, &mut result)?;
+        result
+    }
+
+    fn block_count(&self) -> u32 {
+        self.base.block_count().saturating_sub(1 + BLOCKS as u32)
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        let status = self.base.media_status().await;
+        // a new medium has a journal of its own
+        if status == MediaStatus::Changed {
+            self.recovered = false;
+        }
+        if matches!(status, MediaStatus::Present | MediaStatus::Changed) {
+            if let Err(e) = self.recover().await {
+                warn!("journal: recovery failed: {}", e);
+            }
+        }
+        status
+    }
+
+    async fn unmap(&mut self, lba: u32, count: u32) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, count as usize)?;
+        self.base.unmap(lba, count).await
+    }
+
+    fn protection_information(&self) -> bool {
+        false
+    }
+
+    async fn read_blocks_protected(
+        &mut self,
+        _lba: u32,
+        _blocks: &mut [u8],
+        _protection: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        Err(BlockDeviceError::Unsupported)
+    }
+
+    async fn write_blocks_protected(
+        &mut self,
+        _lba: u32,
+        _blocks: &[u8],
+        _protection: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        Err(BlockDeviceError::Unsupported)
+    }
+
+    fn transaction_blocks(&self) -> Option<u32> {
+        Some(BLOCKS as u32)
+    }
+
+    fn begin_transaction(&mut self) {
+        self.in_transaction = true;
+        self.len = 0;
+    }
+
+    async fn end_transaction(&mut self, commit: bool) -> Result<(), BlockDeviceError> {
+        self.in_transaction = false;
+        if !commit || self.len == 0 {
+            self.len = 0;
+            return Ok(());
+        }
+
+        let result = self.commit().await;
+        self.len = 0;
+        if matches!(result, Err(e) if !e.is_recovered()) {
+            // the header may have made it, check again before the next access
+            self.recovered = false;
+        }
+        result
+    }
+
+    /// The base would format over the journal
+    async fn format(&mut self) -> Result<(), BlockDeviceError> {
+        Err(BlockDeviceError::Unsupported)
+    }
+}
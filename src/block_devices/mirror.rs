@@ -0,0 +1,429 @@
+//! Mirrors a primary and a secondary device (RAID-1), such as internal flash and an SD card,
+//! so the disk survives either of them failing.
+//!
+//! Writes go to both members. Reads come from the primary and fall back to the secondary on
+//! errors, after which the block is rewritten on the primary from the good copy. A member
+//! that fails a write or loses its medium is dropped from the mirror, which carries on
+//! degraded. The host is told once with a FAILURE PREDICTION THRESHOLD EXCEEDED unit
+//! attention and the display shows the state of the mirror.
+//!
+//! A member that joins, or whose medium changes (e.g. a new card is inserted), is resynced from
+//! the other one in the background, a few blocks on every media poll, and is in sync once it's
+//! complete. The mirror is the size of the primary, a secondary smaller than that can't join it.
+
+use defmt::{error, info, warn, Format};
+
+use super::BLOCK_SIZE;
+use crate::display::{DisplayState, SIGNAL};
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus};
+
+/// Blocks copied to a resyncing member on each media poll
+pub const RESYNC_BLOCKS_PER_POLL: u32 = 32;
+
+/// Blocks copied at once while resyncing
+const RESYNC_CHUNK_BLOCKS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Member {
+    Primary,
+    Secondary,
+}
+
+impl Member {
+    fn other(self) -> Member {
+        match self {
+            Member::Primary => Member::Secondary,
+            Member::Secondary => Member::Primary,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+enum MemberState {
+    /// Hasn't reported a medium since the mirror was made, so its size isn't known yet
+    Pending,
+    InSync,
+    /// Blocks before `next` have been copied from the other member
+    Resyncing {
+        next: u32,
+    },
+    Failed,
+}
+
+/// The health of the mirror, as shown on the display
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum MirrorStatus {
+    InSync,
+    /// `member` has failed or is missing
+    Degraded(Member),
+    /// `member` is being resynced and is `percent` of the way through
+    Resyncing {
+        member: Member,
+        percent: u8,
+    },
+    /// Neither member is usable
+    Failed,
+}
+
+pub struct MirrorBlockDevice<P, S> {
+    primary: P,
+    secondary: S,
+    blocks: u32,
+    states: [MemberState; 2],
+    /// The mirror lost a member since the host was last told
+    report_degraded: bool,
+    /// Last status sent to the display
+    status: MirrorStatus,
+}
+
+impl<P: BlockDevice, S: BlockDevice> MirrorBlockDevice<P, S> {
+    /// Mirrors `primary` onto `secondary`. The secondary joins the mirror on the first media
+    /// poll that finds its medium, as a device such as an SD card only knows its size once it's
+    /// been initialised, and is resynced from the primary
+    pub fn new(primary: P, secondary: S) -> Self {
+        assert!(P::BLOCK_BYTES == BLOCK_SIZE && S::BLOCK_BYTES == BLOCK_SIZE);
+
+        let blocks = primary.block_count();
+        let mut mirror = Self {
+            primary,
+            secondary,
+            blocks,
+            states: [MemberState::InSync, MemberState::Pending],
+            report_degraded: false,
+            status: MirrorStatus::InSync,
+        };
+        mirror.update_status();
+        mirror
+    }
+
+    pub fn status(&self) -> MirrorStatus {
+        self.status
+    }
+
+    /// Copies the other member onto `member`, which rejoins the mirror once it's complete.
+    /// Does nothing if the other member isn't in sync
+    pub fn rebuild(&mut self, member: Member) {
+        if self.state(member.other()) != MemberState::InSync {
+            warn!(
+                "mirror: can't rebuild {}, the other member isn't in sync",
+                member
+            );
+            return;
+        }
+        info!("mirror: rebuilding {}", member);
+        self.set_state(member, MemberState::Resyncing { next: 0 });
+    }
+
+    /// Copies the next blocks to a resyncing member, if there is one. Called on every media
+    /// poll
+    pub async fn resync_step(&mut self) {
+        let Some((member, next)) =
+            [Member::Primary, Member::Secondary]
+                .into_iter()
+                .find_map(|member| match self.state(member) {
+                    MemberState::Resyncing { next } => Some((member, next)),
+                    _ => None,
+                })
+        else {
+            return;
+        };
+        if self.state(member.other()) != MemberState::InSync {
+            // nothing left to resync from
+            return;
+        }
+
+        let mut buf = [0u8; RESYNC_CHUNK_BLOCKS * BLOCK_SIZE];
+        let end = self.blocks.min(next.saturating_add(RESYNC_BLOCKS_PER_POLL));
+        let mut lba = next;
+        while lba < end {
+            let count = (end - lba).min(RESYNC_CHUNK_BLOCKS as u32);
+            let buf = &mut buf[..count as usize * BLOCK_SIZE];
+
+            match self.read_member(member.other(), lba, buf).await {
+                Err(e) if !e.is_recovered() => {
+                    // the data is gone from both members, carry on with the rest
+                    error!(
+                        "mirror: resync can't read {} at lba {}: {}",
+                        member.other(),
+                        lba,
+                        e
+                    );
+                }
+                _ => match self.write_member(member, lba, buf).await {
+                    Err(e) if !e.is_recovered() => {
+                        error!("mirror: resync write failed at lba {}: {}", lba, e);
+                        self.fail(member);
+                        return;
+                    }
+                    _ => {}
+                },
+            }
+            lba += count;
+        }
+
+        if lba == self.blocks {
+            info!("mirror: {} resynced", member);
+            self.set_state(member, MemberState::InSync);
+        } else {
+            self.set_state(member, MemberState::Resyncing { next: lba });
+        }
+    }
+
+    fn state(&self, member: Member) -> MemberState {
+        self.states[member as usize]
+    }
+
+    fn set_state(&mut self, member: Member, state: MemberState) {
+        self.states[member as usize] = state;
+        self.update_status();
+    }
+
+    /// Whether `member` is written to, i.e. it's in sync or being resynced
+    fn active(&self, member: Member) -> bool {
+        !matches!(
+            self.state(member),
+            MemberState::Pending | MemberState::Failed
+        )
+    }
+
+    /// Drops `member` from the mirror
+    fn fail(&mut self, member: Member) {
+        if self.state(member) == MemberState::Failed {
+            return;
+        }
+        warn!("mirror: {} failed, the mirror is degraded", member);
+        self.report_degraded = true;
+        self.set_state(member, MemberState::Failed);
+    }
+
+    /// Sends the status to the display if it changed
+    fn update_status(&mut self) {
+        let status = match self.states {
+            [MemberState::InSync, MemberState::InSync] => MirrorStatus::InSync,
+            [MemberState::InSync, MemberState::Failed | MemberState::Pending] => {
+                MirrorStatus::Degraded(Member::Secondary)
+            }
+            [MemberState::Failed | MemberState::Pending, MemberState::InSync] => {
+                MirrorStatus::Degraded(Member::Primary)
+            }
+            [MemberState::InSync, MemberState::Resyncing { next }] => MirrorStatus::Resyncing {
+                member: Member::Secondary,
+                percent: (next as u64 * 100 / self.blocks.max(1) as u64) as u8,
+            },
+            [MemberState::Resyncing { next }, MemberState::InSync] => MirrorStatus::Resyncing {
+                member: Member::Primary,
+                percent: (next as u64 * 100 / self.blocks.max(1) as u64) as u8,
+            },
+            _ => MirrorStatus::Failed,
+        };
+        if status != self.status {
+            self.status = status;
+            SIGNAL.signal(DisplayState::Mirror(status));
+        }
+    }
+
+    /// Whether `member` holds valid data for the `count` blocks from `lba`
+    fn readable(&self, member: Member, lba: u32, count: u32) -> bool {
+        match self.state(member) {
+            MemberState::InSync => true,
+            MemberState::Resyncing { next } => lba + count <= next,
+            MemberState::Pending | MemberState::Failed => false,
+        }
+    }
+
+    fn check_range(&self, lba: u32, count: usize) -> Result<(), BlockDeviceError> {
+        match (lba as u64).checked_add(count as u64) {
+            Some(end) if end <= self.blocks as u64 => Ok(()),
+            _ => Err(BlockDeviceError::InvalidAddress),
+        }
+    }
+
+    async fn read_member(
+        &mut self,
+        member: Member,
+        lba: u32,
+        blocks: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        match member {
+            Member::Primary => self.primary.read_blocks(lba, blocks).await,
+            Member::Secondary => self.secondary.read_blocks(lba, blocks).await,
+        }
+    }
+
+    async fn write_member(
+        &mut self,
+        member: Member,
+        lba: u32,
+        blocks: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        match member {
+            Member::Primary => self.primary.write_blocks(lba, blocks).await,
+            Member::Secondary => self.secondary.write_blocks(lba, blocks).await,
+        }
+    }
+
+    /// Polls the medium of `member`, dropping it from the mirror if it's gone and starting a
+    /// resync if it joined or changed
+    async fn poll_member(&mut self, member: Member) {
+        let (status, block_count) = match member {
+            Member::Primary => (
+                self.primary.media_status().await,
+                self.primary.block_count(),
+            ),
+            Member::Secondary => (
+                self.secondary.media_status().await,
+                self.secondary.block_count(),
+            ),
+        };
+
+        match status {
+            MediaStatus::Absent | MediaStatus::Locked => self.fail(member),
+            // only news for a member that hasn't joined yet
+            MediaStatus::Present if self.state(member) != MemberState::Pending => {}
+            MediaStatus::Changed | MediaStatus::Present if block_count < self.blocks => {
+                warn!("mirror: {} is too small to join the mirror", member);
+                self.fail(member);
+            }
+            // whatever it holds may be stale, as the mirror could have been written without it
+            MediaStatus::Changed | MediaStatus::Present
+                if self.state(member.other()) == MemberState::InSync =>
+            {
+                self.rebuild(member)
+            }
+            // the only copy left, whatever it holds
+            MediaStatus::Changed | MediaStatus::Present => {
+                warn!("mirror: {} joined without a member to resync from", member);
+                self.set_state(member, MemberState::InSync);
+            }
+            MediaStatus::BecomingReady => {}
+        }
+    }
+
+    /// Turns a successful operation into [`BlockDeviceError::Degraded`] if the mirror lost a
+    /// member since the host was last told
+    fn report(&mut self, result: Result<(), BlockDeviceError>) -> Result<(), BlockDeviceError> {
+        match result {
+            Ok(()) if core::mem::take(&mut self.report_degraded) => Err(BlockDeviceError::Degraded),
+            result => result,
+        }
+    }
+}
+
+impl<P: BlockDevice, S: BlockDevice> BlockDevice for MirrorBlockDevice<P, S> {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.read_blocks(lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_blocks(lba, block).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let count = blocks.len() / BLOCK_SIZE;
+        self.check_range(lba, count)?;
+
+        let mut error = BlockDeviceError::MediumNotPresent;
+        let mut failed = None;
+        for member in [Member::Primary, Member::Secondary] {
+            if !self.readable(member, lba, count as u32) {
+                continue;
+            }
+
+            match self.read_member(member, lba, blocks).await {
+                Err(e) if !e.is_recovered() => {
+                    warn!("mirror: {} read failed at lba {}: {}", member, lba, e);
+                    error = e;
+                    failed = Some((member, e));
+                }
+                result => {
+                    // a bad block is rewritten from the good copy, anything else drops the
+                    // member
+                    match failed {
+                        Some((member, BlockDeviceError::ReadError)) => {
+                            match self.write_member(member, lba, blocks).await {
+                                Err(e) if !e.is_recovered() => self.fail(member),
+                                _ => info!("mirror: repaired {} at lba {}", member, lba),
+                            }
+                        }
+                        Some((member, _)) => self.fail(member),
+                        None => {}
+                    }
+                    return self.report(result);
+                }
+            }
+        }
+        Err(error)
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+
+        // resyncing members are written too, the resync only copies blocks not yet reached
+        let mut result = Err(BlockDeviceError::MediumNotPresent);
+        let mut written = false;
+        for member in [Member::Primary, Member::Secondary] {
+            if !self.active(member) {
+                continue;
+            }
+
+            match self.write_member(member, lba, blocks).await {
+                Err(e) if !e.is_recovered() => {
+                    error!("mirror: {} write failed at lba {}: {}", member, lba, e);
+                    self.fail(member);
+                    if !written {
+                        result = Err(e);
+                    }
+                }
+                r => {
+                    if !written || r.is_err() {
+                        result = r;
+                    }
+                    written = true;
+                }
+            }
+        }
+        if !written {
+            return result;
+        }
+        self.report(result)
+    }
+
+    fn block_count(&self) -> u32 {
+        self.blocks
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        self.poll_member(Member::Primary).await;
+        self.poll_member(Member::Secondary).await;
+        self.resync_step().await;
+
+        if self.states.contains(&MemberState::InSync) {
+            MediaStatus::Present
+        } else {
+            MediaStatus::Absent
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        let mut result = Ok(());
+        if self.active(Member::Primary) {
+            match self.primary.flush().await {
+                Err(e) if !e.is_recovered() => self.fail(Member::Primary),
+                r => result = r,
+            }
+        }
+        if self.active(Member::Secondary) {
+            match self.secondary.flush().await {
+                Err(e) if !e.is_recovered() => self.fail(Member::Secondary),
+                Err(e) => result = Err(e),
+                Ok(()) => {}
+            }
+        }
+        if !self.states.contains(&MemberState::InSync) {
+            return Err(BlockDeviceError::MediumNotPresent);
+        }
+        self.report(result)
+    }
+}
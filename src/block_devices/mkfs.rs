@@ -0,0 +1,306 @@
+//! Makes a fresh filesystem on its base (see [`fat12_partition::format`]) when the disk has
+//! none, so a unit with blank flash or a new SD card is ready to use straight away:
+//! - when a medium is found, if the disk is blank or its FAT filesystem is corrupt. A disk
+//!   holding anything else, such as another filesystem or one fatfs can't mount, is left alone
+//! - when the host sends FORMAT UNIT
+//! - on the `format` command of the [control server](crate::server::mkfs), which signals
+//!   [`REQUEST`] for the next media poll
+//!
+//! The check and a format that wasn't asked for by the host are done between commands, when
+//! the host is idle (see [`BlockDevice::idle`]), a few blocks at a time so commands aren't
+//! held up for long. Meanwhile the medium is reported as becoming ready and transfers fail,
+//! and once a format is written the host is told the medium changed.
+//!
+//! [`fat12_partition::format`]: crate::fat12_partition::format
+
+use defmt::{error, info, warn, Debug2Format};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use embassy_time::Instant;
+
+use super::BLOCK_SIZE;
+use crate::fat12_partition::{self, read_partition, BlockDeviceIo, Format, IoError, PartitionType};
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus, Wrapper};
+
+/// Asks the device to format its base on the next media poll
+pub static REQUEST: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// Media descriptors in a boot sector are 0xf0 or 0xf8 to 0xff
+const MEDIA_DESCRIPTOR_MIN: u8 = 0xf0;
+/// Blocks of a format written each time the host is idle
+const FORMAT_STEP_BLOCKS: u32 = 16;
+
+// there's no heap to box the format in, and the device is made once
+#[allow(clippy::large_enum_variant)]
+enum State {
+    /// The base is checked for a filesystem once the host is idle
+    Unchecked,
+    /// The base is formatted once the host is idle
+    Requested,
+    /// A format is being written, a step each time the host is idle
+    Formatting(Format),
+    Ready,
+}
+
+pub struct MkfsBlockDevice<B: BlockDevice> {
+    base: B,
+    label: [u8; 11],
+    state: State,
+    /// The host is told the medium changed once it's ready
+    changed: bool,
+}
+
+impl<B: BlockDevice> MkfsBlockDevice<B> {
+    /// Formats `base` when needed, labelling the volume `label`, which is padded with spaces
+    pub const fn new(base: B, label: [u8; 11]) -> Self {
+        assert!(B::BLOCK_BYTES == BLOCK_SIZE);
+        Self {
+            base,
+            label,
+            state: State::Unchecked,
+            changed: false,
+        }
+    }
+
+    /// Fails transfers until the base is checked and any format is written
+    fn ready(&self) -> Result<(), BlockDeviceError> {
+        match self.state {
+            State::Ready => Ok(()),
+            _ => Err(BlockDeviceError::BecomingReady),
+        }
+    }
+
+    /// Whether the base is blank or has a FAT filesystem that's corrupt
+    async fn needs_format(&mut self) -> Result<bool, BlockDeviceError> {
+        let mut block = [0u8; BLOCK_SIZE];
+        match self.base.read_block(0, &mut block).await {
+            Err(e) if !e.is_recovered() => return Err(e),
+            _ => {}
+        }
+        // erased flash and new cards read as all ones or all zeros
+        if block.iter().all(|&byte| byte == block[0]) {
+            return Ok(true);
+        }
+
+        // a filesystem without a partition table starts with its boot sector
+        let boot_sector = u16::from_le_bytes([block[11], block[12]]) as usize == BLOCK_SIZE
+            && matches!(block[16], 1 | 2)
+            && block[21] >= MEDIA_DESCRIPTOR_MIN;
+        let blocks = self.base.block_count();
+        let (start, len) = if boot_sector {
+            (0, blocks)
+        } else if block[510..] == MBR_SIGNATURE {
+            let partition = read_partition(&block, 0);
+            if !PartitionType::Mbr(partition.p_type).may_be_fat() {
+                return Ok(false);
+            }
+            if partition.p_lba >= blocks {
+                return Ok(true);
+            }
+            let len = partition.p_size.min(blocks - partition.p_lba);
+            (partition.p_lba, len)
+        } else {
+            return Ok(false);
+        };
+
+        let mut disk = BlockDeviceIo::new(start, len);
+        let mounted = disk
+            .run(&mut self.base, |disk| {
+                fatfs::FileSystem::new(disk, fatfs::FsOptions::new()).map(drop)
+            })
+            .await;
+        match mounted {
+            Ok(()) => Ok(false),
+            Err(fatfs::Error::Io(IoError::Device(e))) => Err(e),
+            Err(fatfs::Error::CorruptedFileSystem) => {
+                warn!("mkfs: the filesystem is corrupt");
+                Ok(true)
+            }
+            // anything else may be a filesystem fatfs can't mount, that's left alone
+            Err(e) => {
+                warn!(
+                    "mkfs: can't mount the filesystem: {}",
+                    fat12_partition::error_name(&e)
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    /// Takes the next step of the check or format
+    async fn step(&mut self) {
+        match &mut self.state {
+            State::Unchecked => {
+                self.state = match self.needs_format().await {
+                    Ok(true) => {
+                        info!("mkfs: no filesystem, formatting");
+                        State::Requested
+                    }
+                    Ok(false) => State::Ready,
+                    Err(e) => {
+                        warn!("mkfs: can't check for a filesystem: {}", e);
+                        State::Ready
+                    }
+                }
+            }
+            State::Requested => {
+                self.state = match Format::new(&mut self.base, &self.label, volume_id()).await {
+                    Ok(format) => State::Formatting(format),
+                    Err(e) => {
+                        format_failed(e);
+                        State::Ready
+                    }
+                }
+            }
+            State::Formatting(format) => {
+                match format.write(&mut self.base, FORMAT_STEP_BLOCKS).await {
+                    Ok(false) => return,
+                    Ok(true) => info!("mkfs: formatted as {}", Debug2Format(&format.fat_type())),
+                    Err(e) => {
+                        format_failed(e);
+                    }
+                }
+                // what was on the medium is gone either way
+                self.state = State::Ready;
+                self.changed = true;
+            }
+            State::Ready => {}
+        }
+    }
+}
+
+/// A volume id that's different each time, from the time since boot
+fn volume_id() -> u32 {
+    Instant::now().as_ticks() as u32
+}
+
+/// Logs a failed format, returning the error to fail FORMAT UNIT with
+fn format_failed(e: fatfs::Error<IoError>) -> BlockDeviceError {
+    match e {
+        fatfs::Error::Io(IoError::Device(e)) => {
+            error!("mkfs: format failed: {}", e);
+            e
+        }
+        e => {
+            error!("mkfs: format failed: {}", fat12_partition::error_name(&e));
+            BlockDeviceError::Unsupported
+        }
+    }
+}
+
+impl<B: BlockDevice> Wrapper for MkfsBlockDevice<B> {
+    type Base = B;
+
+    fn base(&self) -> &B {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut B {
+        &mut self.base
+    }
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.ready()?;
+        self.base.read_block(lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.ready()?;
+        self.base.write_block(lba, block).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.ready()?;
+        self.base.read_blocks(lba, blocks).await
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.ready()?;
+        self.base.write_blocks(lba, blocks).await
+    }
+
+    async fn write_blocks_fua(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.ready()?;
+        self.base.write_blocks_fua(lba, blocks).await
+    }
+
+    async fn read_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        protection: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.ready()?;
+        self.base
+            .read_blocks_protected(lba, blocks, protection)
+            .await
+    }
+
+    async fn write_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.ready()?;
+        self.base
+            .write_blocks_protected(lba, blocks, protection)
+            .await
+    }
+
+    async fn unmap(&mut self, lba: u32, count: u32) -> Result<(), BlockDeviceError> {
+        self.ready()?;
+        self.base.unmap(lba, count).await
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        let status = self.base.media_status().await;
+        if status != MediaStatus::Present && status != MediaStatus::Changed {
+            return status;
+        }
+        // a new medium is checked like the first one, and any format of the old one dropped
+        if status == MediaStatus::Changed {
+            self.state = State::Unchecked;
+            self.changed = true;
+        }
+        if REQUEST.try_take().is_some() {
+            self.state = State::Requested;
+        }
+        match self.state {
+            State::Ready if core::mem::take(&mut self.changed) => MediaStatus::Changed,
+            State::Ready => MediaStatus::Present,
+            _ => MediaStatus::BecomingReady,
+        }
+    }
+
+    async fn format(&mut self) -> Result<(), BlockDeviceError> {
+        // the host waits for FORMAT UNIT, so it's formatted straight away
+        let result = fat12_partition::format(&mut self.base, &self.label, volume_id()).await;
+        self.state = State::Ready;
+        match result {
+            Ok(fat_type) => {
+                info!("mkfs: formatted as {}", Debug2Format(&fat_type));
+                Ok(())
+            }
+            Err(e) => Err(format_failed(e)),
+        }
+    }
+
+    fn idle_deadline(&self) -> Option<Instant> {
+        match self.state {
+            State::Ready => self.base.idle_deadline(),
+            _ => Some(Instant::MIN),
+        }
+    }
+
+    async fn idle(&mut self) {
+        self.step().await;
+        if self
+            .base
+            .idle_deadline()
+            .is_some_and(|due| Instant::now() >= due)
+        {
+            self.base.idle().await;
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! [`BlockDevice`](crate::scsi::BlockDevice) implementations other than the RAM disk, each
+//! built only with the disk or feature that uses it
+
+/// The block size of every device here
+pub const BLOCK_SIZE: usize = 512;
+
+#[cfg(feature = "cache")]
+pub mod cache;
+#[cfg(feature = "concat")]
+pub mod concat;
+#[cfg(feature = "encrypted")]
+pub mod encrypted;
+#[cfg(any(feature = "faults", feature = "trace", feature = "watch"))]
+pub(crate) mod fat;
+#[cfg(feature = "faults")]
+pub mod fault;
+#[cfg(any(disk = "flash", disk = "mirror"))]
+pub mod flash;
+#[cfg(disk = "ghost")]
+pub mod ghost;
+#[cfg(disk = "overlay")]
+pub mod image;
+#[cfg(feature = "integrity")]
+pub mod integrity;
+#[cfg(feature = "journal")]
+pub mod journal;
+#[cfg(disk = "mirror")]
+pub mod mirror;
+#[cfg(feature = "mkfs")]
+pub mod mkfs;
+#[cfg(disk = "overlay")]
+pub mod overlay;
+#[cfg(disk = "packed")]
+pub mod packed;
+#[cfg(feature = "partition")]
+pub mod partition;
+#[cfg(any(disk = "sd", disk = "mirror"))]
+pub mod sd;
+#[cfg(feature = "snapshots")]
+pub mod snapshot;
+#[cfg(disk = "sparse")]
+pub mod sparse;
+#[cfg(feature = "stripe")]
+pub mod stripe;
+#[cfg(feature = "trace")]
+pub mod trace;
+#[cfg(feature = "watch")]
+pub mod watch;
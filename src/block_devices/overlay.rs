@@ -0,0 +1,249 @@
+//! A copy-on-write overlay keeping the blocks written by the host in a small RAM delta on top
+//! of a base device that isn't written to until the delta is committed.
+//!
+//! Blocks that haven't been written are read straight from the base, so a read-only image in
+//! XIP flash only costs RAM for the blocks the host actually changes. Writing a block back to
+//! its base contents frees its slot. Once every slot is taken, writes to further blocks fail
+//! with SPACE ALLOCATION FAILED and nothing is written.
+//!
+//! [`OverlayBlockDevice::discard`] drops the delta, returning the drive to the base image (a
+//! factory reset), and [`OverlayBlockDevice::commit`] writes the delta to the base, unless
+//! the base is write-protected like the flash image. Other tasks (e.g. the network server)
+//! can request either through [`COMMANDS`], which is handled the next time the host polls for
+//! media. A discard is reported to the host as a media change.
+
+use defmt::{error, info};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+
+use super::BLOCK_SIZE;
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus, Provisioning, Wrapper};
+
+const FREE: u32 = u32::MAX;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum OverlayCommand {
+    /// Write the delta to the base device
+    Commit,
+    /// Drop the delta
+    Discard,
+}
+
+/// Requests for the overlay, handled on the next media poll
+pub static COMMANDS: Signal<ThreadModeRawMutex, OverlayCommand> = Signal::new();
+
+/// An overlay over `base` with room for `BLOCKS` modified blocks
+pub struct OverlayBlockDevice<B, const BLOCKS: usize> {
+    base: B,
+    /// The LBA held by each slot of the delta, `FREE` if unused
+    lbas: [u32; BLOCKS],
+    delta: [[u8; BLOCK_SIZE]; BLOCKS],
+    /// The contents changed under the host, to be reported as a media change
+    changed: bool,
+}
+
+impl<B: BlockDevice, const BLOCKS: usize> OverlayBlockDevice<B, BLOCKS> {
+    pub const fn new(base: B) -> Self {
+        assert!(B::BLOCK_BYTES == BLOCK_SIZE);
+        Self {
+            base,
+            lbas: [FREE; BLOCKS],
+            delta: [[0; BLOCK_SIZE]; BLOCKS],
+            changed: false,
+        }
+    }
+
+    /// Number of modified blocks held in RAM
+    pub fn delta_len(&self) -> usize {
+        self.lbas.iter().filter(|&&lba| lba != FREE).count()
+    }
+
+    /// Drops every modified block, so reads return the base contents again
+    pub fn discard(&mut self) {
+        info!("discarding {} modified blocks", self.delta_len());
+        self.lbas = [FREE; BLOCKS];
+        self.changed = true;
+    }
+
+    /// Writes every modified block to the base device. Blocks written before an error are
+    /// removed from the delta, the rest stay in it. A write-protected base fails straight
+    /// away, keeping the whole delta
+    pub async fn commit(&mut self) -> Result<(), BlockDeviceError> {
+        if self.base.write_protected() {
+            return Err(BlockDeviceError::WriteProtected);
+        }
+        info!("committing {} modified blocks", self.delta_len());
+        for slot in 0..BLOCKS {
+            let lba = self.lbas[slot];
+            if lba == FREE {
+                continue;
+            }
+            match self.base.write_block(lba, &self.delta[slot]).await {
+                Err(e) if !e.is_recovered() => return Err(e),
+                _ => self.lbas[slot] = FREE,
+            }
+        }
+        self.base.flush().await
+    }
+
+    /// The slot holding `lba`, which must be in range
+    fn slot(&self, lba: u32) -> Option<usize> {
+        self.lbas.iter().position(|&slot_lba| slot_lba == lba)
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        self.slot(FREE)
+    }
+
+    fn check_range(&self, lba: u32, count: usize) -> Result<(), BlockDeviceError> {
+        match (lba as u64).checked_add(count as u64) {
+            Some(end) if end <= self.base.block_count() as u64 => Ok(()),
+            _ => Err(BlockDeviceError::InvalidAddress),
+        }
+    }
+
+    async fn read(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        match self.slot(lba) {
+            Some(slot) => {
+                block.copy_from_slice(&self.delta[slot]);
+                Ok(())
+            }
+            None => self.base.read_block(lba, block).await,
+        }
+    }
+
+    async fn write(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        let mut base = [0u8; BLOCK_SIZE];
+        let unchanged = match self.base.read_block(lba, &mut base).await {
+            Ok(()) => base == block,
+            Err(e) => e.is_recovered() && base == block,
+        };
+
+        match (self.slot(lba), unchanged) {
+            (Some(slot), true) => self.lbas[slot] = FREE,
+            (Some(slot), false) => self.delta[slot].copy_from_slice(block),
+            (None, true) => {}
+            (None, false) => {
+                let slot = self
+                    .free_slot()
+                    .ok_or(BlockDeviceError::SpaceAllocationFailed)?;
+                self.lbas[slot] = lba;
+                self.delta[slot].copy_from_slice(block);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The delta is the medium the host sees, so everything that reads or writes blocks stays
+/// here. Writes don't reach the base, which can't hold protection information for them or
+/// make them part of a transaction
+impl<B: BlockDevice, const BLOCKS: usize> Wrapper for OverlayBlockDevice<B, BLOCKS> {
+    type Base = B;
+
+    fn base(&self) -> &B {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut B {
+        &mut self.base
+    }
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        Wrapper::read_blocks(self, lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        Wrapper::write_blocks(self, lba, block).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+        let mut result = Ok(());
+        for (i, block) in blocks.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            match self.read(lba + i as u32, block).await {
+                Err(e) if e.is_recovered() => result = Err(e),
+                r => r?,
+            }
+        }
+        result
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+        for (i, block) in blocks.chunks_exact(BLOCK_SIZE).enumerate() {
+            self.write(lba + i as u32, block).await?;
+        }
+        Ok(())
+    }
+
+    /// The delta isn't kept anywhere but RAM, so there's nothing more to wait for
+    async fn write_blocks_fua(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        Wrapper::write_blocks(self, lba, blocks).await
+    }
+
+    fn write_protected(&self) -> bool {
+        false
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        match COMMANDS.try_take() {
+            Some(OverlayCommand::Commit) => {
+                if let Err(e) = self.commit().await {
+                    error!("failed to commit the overlay: {}", e);
+                }
+            }
+            Some(OverlayCommand::Discard) => self.discard(),
+            None => {}
+        }
+
+        match self.base.media_status().await {
+            MediaStatus::Present if core::mem::take(&mut self.changed) => MediaStatus::Changed,
+            status => status,
+        }
+    }
+
+    fn provisioning(&self) -> Option<Provisioning> {
+        None
+    }
+
+    async fn unmap(&mut self, _lba: u32, _count: u32) -> Result<(), BlockDeviceError> {
+        Err(BlockDeviceError::Unsupported)
+    }
+
+    fn protection_information(&self) -> bool {
+        false
+    }
+
+    async fn read_blocks_protected(
+        &mut self,
+        _lba: u32,
+        _blocks: &mut [u8],
+        _protection: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        Err(BlockDeviceError::Unsupported)
+    }
+
+    async fn write_blocks_protected(
+        &mut self,
+        _lba: u32,
+        _blocks: &[u8],
+        _protection: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        Err(BlockDeviceError::Unsupported)
+    }
+
+    fn transaction_blocks(&self) -> Option<u32> {
+        None
+    }
+
+    fn begin_transaction(&mut self) {}
+
+    async fn end_transaction(&mut self, _commit: bool) -> Result<(), BlockDeviceError> {
+        Ok(())
+    }
+
+    /// Formatting would write the base under the delta
+    async fn format(&mut self) -> Result<(), BlockDeviceError> {
+        Err(BlockDeviceError::Unsupported)
+    }
+}
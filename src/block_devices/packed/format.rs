@@ -0,0 +1,159 @@
+//! The layout of packed disk images, shared by the packer in `build.rs` and
+//! [`PackedBlockDevice`](super::PackedBlockDevice). It has no dependencies so that the build
+//! script can include it as well.
+//!
+//! ```text
+//! | magic | block count | offset 0 | offset 1 | ... | offset n | block data ... |
+//! ```
+//!
+//! The header and the index of `block count + 1` offsets are little endian `u32`s. Block `i`
+//! is stored in the block data between offsets `i` and `i + 1`: nothing for a block of zeros,
+//! `BLOCK_SIZE` bytes for a block that didn't compress and the compressed block otherwise.
+//!
+//! A compressed block is a sequence of tokens. A token below 0x80 is followed by that many
+//! plus one literal bytes. A token of 0x80 or above is followed by a little endian `u16`
+//! offset and copies `(token & 0x7f) + MIN_MATCH` bytes from that far back in the block. The
+//! copy may overlap the bytes it produces, which makes runs matches with an offset of 1.
+
+pub const MAGIC: [u8; 4] = *b"PIMG";
+pub const BLOCK_SIZE: usize = 512;
+/// Magic and block count
+pub const HEADER_LEN: usize = 8;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 0x7f + MIN_MATCH;
+const MAX_LITERALS: usize = 0x80;
+const HASH_BITS: u32 = 10;
+
+/// Stores `block` in `out`, returning the number of bytes used
+pub fn compress(block: &[u8; BLOCK_SIZE], out: &mut [u8; BLOCK_SIZE]) -> usize {
+    if block.iter().all(|&byte| byte == 0) {
+        return 0;
+    }
+    match compress_into(block, out) {
+        Some(len) if len < BLOCK_SIZE => len,
+        _ => {
+            out.copy_from_slice(block);
+            BLOCK_SIZE
+        }
+    }
+}
+
+/// Greedy LZ77 with a hash table of the last position of each 3 byte sequence, plus runs.
+/// Gives up once the output is no smaller than the block
+fn compress_into(block: &[u8; BLOCK_SIZE], out: &mut [u8]) -> Option<usize> {
+    let mut table = [u16::MAX; 1 << HASH_BITS];
+    let mut len = 0;
+    let mut literals = 0;
+    let mut i = 0;
+
+    while i < BLOCK_SIZE {
+        let mut best = (0, 0);
+        if i > 0 {
+            best = (match_len(block, i - 1, i), 1);
+        }
+        if i + MIN_MATCH <= BLOCK_SIZE {
+            let hash = hash(&block[i..i + MIN_MATCH]);
+            let candidate = table[hash];
+            table[hash] = i as u16;
+            if candidate != u16::MAX {
+                let candidate = candidate as usize;
+                let candidate_len = match_len(block, candidate, i);
+                if candidate_len > best.0 {
+                    best = (candidate_len, i - candidate);
+                }
+            }
+        }
+
+        let (match_len, offset) = best;
+        if match_len < MIN_MATCH {
+            i += 1;
+            continue;
+        }
+
+        len = emit_literals(&block[literals..i], out, len)?;
+        let token = out.get_mut(len..len + 3)?;
+        token[0] = 0x80 | (match_len - MIN_MATCH) as u8;
+        token[1..].copy_from_slice(&(offset as u16).to_le_bytes());
+        len += 3;
+
+        i += match_len;
+        literals = i;
+    }
+
+    emit_literals(&block[literals..], out, len)
+}
+
+fn emit_literals(mut literals: &[u8], out: &mut [u8], mut len: usize) -> Option<usize> {
+    while !literals.is_empty() {
+        let count = literals.len().min(MAX_LITERALS);
+        let chunk = out.get_mut(len..len + 1 + count)?;
+        chunk[0] = (count - 1) as u8;
+        chunk[1..].copy_from_slice(&literals[..count]);
+        len += 1 + count;
+        literals = &literals[count..];
+    }
+    Some(len)
+}
+
+fn match_len(block: &[u8], from: usize, at: usize) -> usize {
+    let max = (block.len() - at).min(MAX_MATCH);
+    (0..max)
+        .take_while(|&k| block[from + k] == block[at + k])
+        .count()
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+    (value.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Restores a block stored by [`compress`]. Fails if `data` is corrupt
+pub fn decompress(data: &[u8], out: &mut [u8]) -> Result<(), ()> {
+    match data.len() {
+        0 => {
+            out.fill(0);
+            return Ok(());
+        }
+        BLOCK_SIZE => {
+            out.copy_from_slice(data);
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let mut input = 0;
+    let mut len = 0;
+    while input < data.len() {
+        let token = data[input] as usize;
+        input += 1;
+
+        if token < 0x80 {
+            let count = token + 1;
+            let literals = data.get(input..input + count).ok_or(())?;
+            out.get_mut(len..len + count)
+                .ok_or(())?
+                .copy_from_slice(literals);
+            input += count;
+            len += count;
+        } else {
+            let count = (token & 0x7f) + MIN_MATCH;
+            let offset = data.get(input..input + 2).ok_or(())?;
+            let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+            input += 2;
+            if offset == 0 || offset > len || len + count > out.len() {
+                return Err(());
+            }
+            // byte by byte, the copy can overlap what it writes
+            for k in len..len + count {
+                out[k] = out[k - offset];
+            }
+            len += count;
+        }
+    }
+
+    if len != out.len() {
+        return Err(());
+    }
+    Ok(())
+}
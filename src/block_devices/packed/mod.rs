@@ -0,0 +1,76 @@
+//! A read-only disk image compressed block by block, decompressed as the host reads it.
+//!
+//! Images are packed at build time by `build.rs` (see [`format`] for the layout), so large
+//! and mostly empty images take up far less flash than they would verbatim.
+
+pub mod format;
+
+use defmt::error;
+
+use crate::scsi::{BlockDevice, BlockDeviceError};
+use format::{BLOCK_SIZE, HEADER_LEN, MAGIC};
+
+pub struct PackedBlockDevice {
+    blocks: u32,
+    /// `blocks + 1` offsets into `data`
+    index: &'static [u8],
+    data: &'static [u8],
+}
+
+impl PackedBlockDevice {
+    /// Returns `None` if `packed` isn't a packed image
+    pub fn new(packed: &'static [u8]) -> Option<Self> {
+        let header = packed.get(..HEADER_LEN)?;
+        if header[..4] != MAGIC {
+            return None;
+        }
+        let blocks = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+        let index_len = (blocks as usize + 1) * 4;
+        let index = packed.get(HEADER_LEN..HEADER_LEN + index_len)?;
+        let data = &packed[HEADER_LEN + index_len..];
+
+        Some(Self {
+            blocks,
+            index,
+            data,
+        })
+    }
+
+    fn offset(&self, i: usize) -> usize {
+        let entry = &self.index[i * 4..i * 4 + 4];
+        u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) as usize
+    }
+}
+
+impl BlockDevice for PackedBlockDevice {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        if lba >= self.blocks {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+
+        let lba = lba as usize;
+        let stored = self
+            .data
+            .get(self.offset(lba)..self.offset(lba + 1))
+            .ok_or(BlockDeviceError::ReadError)?;
+        format::decompress(stored, block).map_err(|_| {
+            error!("packed block {} is corrupt", lba);
+            BlockDeviceError::ReadError
+        })
+    }
+
+    async fn write_block(&mut self, _lba: u32, _block: &[u8]) -> Result<(), BlockDeviceError> {
+        Err(BlockDeviceError::WriteProtected)
+    }
+
+    fn block_count(&self) -> u32 {
+        self.blocks
+    }
+
+    fn write_protected(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,161 @@
+//! A view of a single partition of a device as a disk of its own.
+//!
+//! LBA 0 of the view is the first block of the partition and accesses past its end fail with
+//! LOGICAL BLOCK ADDRESS OUT OF RANGE, so the host can't reach anything else on the device.
+//! This allows exposing each partition as its own LUN, or keeping a configuration partition
+//! away from the host.
+
+use super::BLOCK_SIZE;
+use crate::fat12_partition::read_partition;
+use crate::scsi::{
+    read_blocks_shifted, write_blocks_shifted, BlockDevice, BlockDeviceError, Provisioning, Wrapper,
+};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_PARTITIONS: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PartitionError {
+    /// The MBR couldn't be read from the device
+    BlockDevice(BlockDeviceError),
+    /// Block 0 doesn't end with the MBR signature
+    NoPartitionTable,
+    /// The index is past the 4 primary partitions, the entry is unused or it extends past the
+    /// end of the device
+    InvalidPartition,
+}
+
+pub struct PartitionBlockDevice<B> {
+    base: B,
+    start: u32,
+    blocks: u32,
+}
+
+impl<B: BlockDevice> PartitionBlockDevice<B> {
+    /// The primary partition `index` (0 to 3) of the MBR on `base`
+    pub async fn new(mut base: B, index: u8) -> Result<Self, PartitionError> {
+        assert!(B::BLOCK_BYTES == BLOCK_SIZE);
+
+        if index >= MBR_PARTITIONS {
+            return Err(PartitionError::InvalidPartition);
+        }
+
+        let mut mbr = [0u8; BLOCK_SIZE];
+        match base.read_block(0, &mut mbr).await {
+            Err(e) if !e.is_recovered() => return Err(PartitionError::BlockDevice(e)),
+            _ => {}
+        }
+        if mbr[510..] != MBR_SIGNATURE {
+            return Err(PartitionError::NoPartitionTable);
+        }
+
+        let partition = read_partition(&mbr, index);
+        if partition.p_type == 0 {
+            return Err(PartitionError::InvalidPartition);
+        }
+        Self::from_range(base, partition.p_lba, partition.p_size)
+            .ok_or(PartitionError::InvalidPartition)
+    }
+
+    /// The `blocks` blocks of `base` starting at `start`. Returns `None` if they don't fit on
+    /// the device
+    pub fn from_range(base: B, start: u32, blocks: u32) -> Option<Self> {
+        if blocks == 0 || start.checked_add(blocks)? > base.block_count() {
+            return None;
+        }
+        Some(Self {
+            base,
+            start,
+            blocks,
+        })
+    }
+
+    /// The first block of the partition on the underlying device
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    /// Translates `count` blocks from `lba` to an address on the underlying device
+    fn translate(&self, lba: u32, count: usize) -> Result<u32, BlockDeviceError> {
+        let end = lba as u64 + count as u64;
+        if count == 0 || end > self.blocks as u64 {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+        Ok(self.start + lba)
+    }
+}
+
+impl<B: BlockDevice> Wrapper for PartitionBlockDevice<B> {
+    type Base = B;
+
+    fn base(&self) -> &B {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut B {
+        &mut self.base
+    }
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let lba = self.translate(lba, 1)?;
+        self.base.read_block(lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        let lba = self.translate(lba, 1)?;
+        self.base.write_block(lba, block).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let lba = self.translate(lba, blocks.len() / BLOCK_SIZE)?;
+        self.base.read_blocks(lba, blocks).await
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        let lba = self.translate(lba, blocks.len() / BLOCK_SIZE)?;
+        self.base.write_blocks(lba, blocks).await
+    }
+
+    async fn write_blocks_fua(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        let lba = self.translate(lba, blocks.len() / BLOCK_SIZE)?;
+        self.base.write_blocks_fua(lba, blocks).await
+    }
+
+    fn block_count(&self) -> u32 {
+        self.blocks
+    }
+
+    /// The provisioning of the whole underlying device, the pool is shared by its partitions
+    fn provisioning(&self) -> Option<Provisioning> {
+        self.base.provisioning()
+    }
+
+    async fn unmap(&mut self, lba: u32, count: u32) -> Result<(), BlockDeviceError> {
+        if count == 0 {
+            return Ok(());
+        }
+        let lba = self.translate(lba, count as usize)?;
+        self.base.unmap(lba, count).await
+    }
+
+    /// The reference tags the host sees count from the start of the partition
+    async fn read_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        protection: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        let base_lba = self.translate(lba, blocks.len() / BLOCK_SIZE)?;
+        read_blocks_shifted(&mut self.base, base_lba, blocks, protection, self.start).await
+    }
+
+    async fn write_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        let base_lba = self.translate(lba, blocks.len() / BLOCK_SIZE)?;
+        write_blocks_shifted(&mut self.base, base_lba, blocks, protection, self.start).await
+    }
+}
@@ -0,0 +1,565 @@
+//! SD, SDHC and SDXC cards driven in SPI mode.
+//!
+//! The card is brought up lazily the first time the SCSI layer polls for media, so a card can
+//! be inserted and swapped at any time: the card detect switch going open drops the card, and
+//! the next card to be detected is initialised and reported as a new medium.
+//!
+//! CRCs are turned on for everything (CMD59), transfers of more than one block use the
+//! multiple block commands (CMD18/CMD25) and a transfer that fails on a CRC error is retried
+//! a few times before giving up. A card that stays busy longer than the limits in the
+//! simplified physical layer spec is reported as a timeout.
+//!
+//! The SPI clock must be at most 400kHz until the card has been initialised, after which it
+//! can go up to 25MHz. The bus traits can't change the clock, so the driver is given a
+//! function to do it.
+
+#[cfg(test)]
+pub mod sim;
+
+use defmt::{debug, info, warn};
+use embassy_time::{Duration, Instant};
+use embedded_hal_1::digital::{InputPin, OutputPin};
+use embedded_hal_async::spi::SpiBus;
+
+use super::BLOCK_SIZE;
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus};
+
+/// SPI clock while the card is initialised
+pub const INIT_FREQUENCY: u32 = 400_000;
+/// SPI clock for transfers, the limit for cards in SPI mode
+pub const TRANSFER_FREQUENCY: u32 = 25_000_000;
+
+/// Field replaceable unit code reported when the SPI bus or chip select fails
+pub const FRU_SPI_BUS: u8 = 1;
+/// Field replaceable unit code reported when the card doesn't follow the protocol
+pub const FRU_CARD: u8 = 2;
+
+const CMD_GO_IDLE_STATE: u8 = 0;
+const CMD_SEND_IF_COND: u8 = 8;
+const CMD_SEND_CSD: u8 = 9;
+const CMD_STOP_TRANSMISSION: u8 = 12;
+const CMD_SEND_STATUS: u8 = 13;
+const CMD_SET_BLOCKLEN: u8 = 16;
+const CMD_READ_SINGLE_BLOCK: u8 = 17;
+const CMD_READ_MULTIPLE_BLOCK: u8 = 18;
+const CMD_WRITE_BLOCK: u8 = 24;
+const CMD_WRITE_MULTIPLE_BLOCK: u8 = 25;
+const CMD_APP_CMD: u8 = 55;
+const CMD_READ_OCR: u8 = 58;
+const CMD_CRC_ON_OFF: u8 = 59;
+const ACMD_SD_SEND_OP_COND: u8 = 41;
+
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const R1_COM_CRC_ERROR: u8 = 0x08;
+const R1_ADDRESS_ERROR: u8 = 0x20;
+const R1_PARAMETER_ERROR: u8 = 0x40;
+
+/// Voltage range 2.7-3.6V and the check pattern
+const IF_COND_ARGUMENT: u32 = 0x1aa;
+/// Host supports high capacity cards
+const OCR_HCS: u32 = 1 << 30;
+/// Card capacity status, set for block addressed (SDHC/SDXC) cards
+const OCR_CCS: u32 = 1 << 30;
+
+const TOKEN_START_BLOCK: u8 = 0xfe;
+const TOKEN_START_MULTIPLE_WRITE: u8 = 0xfc;
+const TOKEN_STOP_TRAN: u8 = 0xfd;
+/// Data error tokens are `0000xxxx`, this bit flags an out of range address
+const TOKEN_ERROR_OUT_OF_RANGE: u8 = 0x08;
+
+const DATA_RESPONSE_MASK: u8 = 0x1f;
+const DATA_ACCEPTED: u8 = 0x05;
+const DATA_CRC_ERROR: u8 = 0x0b;
+const DATA_WRITE_ERROR: u8 = 0x0d;
+
+/// Bytes to wait for a command response (NCR is at most 8)
+const RESPONSE_BYTES: usize = 10;
+const INIT_TIMEOUT: Duration = Duration::from_secs(1);
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+const WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Attempts after the first one for transfers that fail on a CRC or media error
+const RETRIES: usize = 3;
+
+const CRC7: crc::Crc<u8> = crc::Crc::<u8>::new(&crc::CRC_7_MMC);
+const CRC16: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_XMODEM);
+
+#[derive(Clone, Copy, defmt::Format)]
+struct Card {
+    /// SDHC/SDXC cards are addressed in blocks, SDSC cards in bytes
+    high_capacity: bool,
+    blocks: u32,
+}
+
+impl Card {
+    fn address(&self, lba: u32) -> u32 {
+        if self.high_capacity {
+            lba
+        } else {
+            lba * BLOCK_SIZE as u32
+        }
+    }
+}
+
+/// An SD card on an SPI bus with a card detect switch that pulls `CD` low while a card is
+/// inserted
+pub struct SdCard<SPI, CS, CD> {
+    spi: SPI,
+    cs: CS,
+    card_detect: CD,
+    set_frequency: fn(&mut SPI, u32),
+    card: Option<Card>,
+}
+
+impl<SPI: SpiBus, CS: OutputPin, CD: InputPin> SdCard<SPI, CS, CD> {
+    /// `set_frequency` changes the SPI clock to the given frequency in Hz
+    pub fn new(spi: SPI, cs: CS, card_detect: CD, set_frequency: fn(&mut SPI, u32)) -> Self {
+        Self {
+            spi,
+            cs,
+            card_detect,
+            set_frequency,
+            card: None,
+        }
+    }
+
+    fn card_inserted(&mut self) -> bool {
+        // a switch that can't be read is treated as open
+        self.card_detect.is_low().unwrap_or(false)
+    }
+
+    async fn read(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let card = self.card.ok_or(BlockDeviceError::MediumNotPresent)?;
+        check_range(&card, lba, blocks.len())?;
+
+        let mut attempt = 0;
+        loop {
+            self.select()?;
+            let result = self.read_selected(&card, lba, blocks).await;
+            self.deselect().await;
+
+            match result {
+                Ok(()) if attempt > 0 => return Err(BlockDeviceError::RecoveredWithRetries),
+                Err(BlockDeviceError::ReadError) if attempt < RETRIES => {
+                    warn!("SD read of lba {} failed, retrying", lba);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn write(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        let card = self.card.ok_or(BlockDeviceError::MediumNotPresent)?;
+        check_range(&card, lba, blocks.len())?;
+
+        let mut attempt = 0;
+        loop {
+            self.select()?;
+            let result = self.write_selected(&card, lba, blocks).await;
+            self.deselect().await;
+
+            match result {
+                Ok(()) if attempt > 0 => return Err(BlockDeviceError::RecoveredWithRetries),
+                Err(BlockDeviceError::WriteError) if attempt < RETRIES => {
+                    warn!("SD write of lba {} failed, retrying", lba);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn init(&mut self) -> Result<Card, BlockDeviceError> {
+        (self.set_frequency)(&mut self.spi, INIT_FREQUENCY);
+
+        // at least 74 clocks with chip select high to put the card in native mode
+        self.cs.set_high().map_err(|_| bus_error())?;
+        self.spi.write(&[0xff; 10]).await.map_err(|_| bus_error())?;
+
+        self.select()?;
+        let result = self.init_selected().await;
+        self.deselect().await;
+
+        if result.is_ok() {
+            (self.set_frequency)(&mut self.spi, TRANSFER_FREQUENCY);
+        }
+        result
+    }
+
+    async fn init_selected(&mut self) -> Result<Card, BlockDeviceError> {
+        let mut r1 = 0xff;
+        for _ in 0..=RETRIES {
+            r1 = self.command(CMD_GO_IDLE_STATE, 0).await?;
+            if r1 == R1_IDLE {
+                break;
+            }
+        }
+        if r1 != R1_IDLE {
+            debug!("SD card didn't go idle: {:02x}", r1);
+            return Err(card_error());
+        }
+
+        let r1 = self.command(CMD_CRC_ON_OFF, 1).await?;
+        if r1 & !R1_IDLE != 0 {
+            return Err(card_error());
+        }
+
+        // only version 2.00 cards and later know CMD8, and only they can be high capacity
+        let r1 = self.command(CMD_SEND_IF_COND, IF_COND_ARGUMENT).await?;
+        let version_2 = r1 & R1_ILLEGAL_COMMAND == 0;
+        if version_2 {
+            let r7 = self.read_u32().await?;
+            if r7 & 0xfff != IF_COND_ARGUMENT {
+                debug!("SD card rejected the interface condition: {:08x}", r7);
+                return Err(card_error());
+            }
+        }
+
+        let deadline = Instant::now() + INIT_TIMEOUT;
+        let argument = if version_2 { OCR_HCS } else { 0 };
+        loop {
+            let r1 = self.app_command(ACMD_SD_SEND_OP_COND, argument).await?;
+            if r1 == 0 {
+                break;
+            }
+            if r1 != R1_IDLE {
+                return Err(card_error());
+            }
+            if Instant::now() > deadline {
+                return Err(BlockDeviceError::Timeout);
+            }
+        }
+
+        let high_capacity = if version_2 {
+            if self.command(CMD_READ_OCR, 0).await? != 0 {
+                return Err(card_error());
+            }
+            self.read_u32().await? & OCR_CCS != 0
+        } else {
+            false
+        };
+        if !high_capacity && self.command(CMD_SET_BLOCKLEN, BLOCK_SIZE as u32).await? != 0 {
+            return Err(card_error());
+        }
+
+        if self.command(CMD_SEND_CSD, 0).await? != 0 {
+            return Err(card_error());
+        }
+        let mut csd = [0u8; 16];
+        self.read_data(&mut csd).await?;
+
+        Ok(Card {
+            high_capacity,
+            blocks: csd_blocks(&csd),
+        })
+    }
+
+    async fn read_selected(
+        &mut self,
+        card: &Card,
+        lba: u32,
+        blocks: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        let address = card.address(lba);
+        if blocks.len() == BLOCK_SIZE {
+            let r1 = self.command(CMD_READ_SINGLE_BLOCK, address).await?;
+            check_r1(r1, BlockDeviceError::ReadError)?;
+            return self.read_data(blocks).await;
+        }
+
+        let r1 = self.command(CMD_READ_MULTIPLE_BLOCK, address).await?;
+        check_r1(r1, BlockDeviceError::ReadError)?;
+
+        let mut result = Ok(());
+        for block in blocks.chunks_exact_mut(BLOCK_SIZE) {
+            result = self.read_data(block).await;
+            if result.is_err() {
+                break;
+            }
+        }
+
+        // the card sends a stuff byte and then R1b
+        let r1 = self.command(CMD_STOP_TRANSMISSION, 0).await?;
+        self.wait_not_busy(READ_TIMEOUT).await?;
+        result.and(check_r1(r1, BlockDeviceError::ReadError))
+    }
+
+    async fn write_selected(
+        &mut self,
+        card: &Card,
+        lba: u32,
+        blocks: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        let address = card.address(lba);
+        if blocks.len() == BLOCK_SIZE {
+            let r1 = self.command(CMD_WRITE_BLOCK, address).await?;
+            check_r1(r1, BlockDeviceError::WriteError)?;
+            return self.write_data(TOKEN_START_BLOCK, blocks).await;
+        }
+
+        let r1 = self.command(CMD_WRITE_MULTIPLE_BLOCK, address).await?;
+        check_r1(r1, BlockDeviceError::WriteError)?;
+
+        let mut result = Ok(());
+        for block in blocks.chunks_exact(BLOCK_SIZE) {
+            result = self.write_data(TOKEN_START_MULTIPLE_WRITE, block).await;
+            if result.is_err() {
+                break;
+            }
+        }
+
+        self.spi
+            .write(&[TOKEN_STOP_TRAN, 0xff])
+            .await
+            .map_err(|_| bus_error())?;
+        self.wait_not_busy(WRITE_TIMEOUT).await?;
+        result?;
+
+        // errors in the middle of a multiple block write only show up in the status
+        let r1 = self.command(CMD_SEND_STATUS, 0).await?;
+        let r2 = self.exchange().await?;
+        check_r1(r1, BlockDeviceError::WriteError)?;
+        if r2 != 0 {
+            debug!("SD card status after write: {:02x}", r2);
+            return Err(BlockDeviceError::WriteError);
+        }
+        Ok(())
+    }
+
+    /// Sends a command and returns its R1 response
+    async fn command(&mut self, command: u8, argument: u32) -> Result<u8, BlockDeviceError> {
+        let mut frame = [0x40 | command, 0, 0, 0, 0, 0];
+        frame[1..5].copy_from_slice(&argument.to_be_bytes());
+        frame[5] = (CRC7.checksum(&frame[..5]) << 1) | 1;
+        self.spi.write(&frame).await.map_err(|_| bus_error())?;
+
+        if command == CMD_STOP_TRANSMISSION {
+            self.exchange().await?;
+        }
+
+        for _ in 0..RESPONSE_BYTES {
+            let r1 = self.exchange().await?;
+            if r1 & 0x80 == 0 {
+                return Ok(r1);
+            }
+        }
+        Err(BlockDeviceError::Timeout)
+    }
+
+    async fn app_command(&mut self, command: u8, argument: u32) -> Result<u8, BlockDeviceError> {
+        let r1 = self.command(CMD_APP_CMD, 0).await?;
+        if r1 & !R1_IDLE != 0 {
+            return Ok(r1);
+        }
+        self.command(command, argument).await
+    }
+
+    /// Reads a data block following its start token
+    async fn read_data(&mut self, buf: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let deadline = Instant::now() + READ_TIMEOUT;
+        let token = loop {
+            let token = self.exchange().await?;
+            if token != 0xff {
+                break token;
+            }
+            if Instant::now() > deadline {
+                return Err(BlockDeviceError::Timeout);
+            }
+        };
+        match token {
+            TOKEN_START_BLOCK => {}
+            token if token & TOKEN_ERROR_OUT_OF_RANGE != 0 && token & 0xf0 == 0 => {
+                return Err(BlockDeviceError::InvalidAddress)
+            }
+            token => {
+                debug!("SD data error token: {:02x}", token);
+                return Err(BlockDeviceError::ReadError);
+            }
+        }
+
+        buf.fill(0xff);
+        self.spi
+            .transfer_in_place(buf)
+            .await
+            .map_err(|_| bus_error())?;
+
+        let mut crc = [0xff; 2];
+        self.spi
+            .transfer_in_place(&mut crc)
+            .await
+            .map_err(|_| bus_error())?;
+        if u16::from_be_bytes(crc) != CRC16.checksum(buf) {
+            debug!("SD data CRC mismatch");
+            return Err(BlockDeviceError::ReadError);
+        }
+        Ok(())
+    }
+
+    /// Sends a data block and waits for the card to program it
+    async fn write_data(&mut self, token: u8, block: &[u8]) -> Result<(), BlockDeviceError> {
+        let crc = CRC16.checksum(block).to_be_bytes();
+        self.spi
+            .write(&[0xff, token])
+            .await
+            .map_err(|_| bus_error())?;
+        self.spi.write(block).await.map_err(|_| bus_error())?;
+        self.spi.write(&crc).await.map_err(|_| bus_error())?;
+
+        match self.exchange().await? & DATA_RESPONSE_MASK {
+            DATA_ACCEPTED => self.wait_not_busy(WRITE_TIMEOUT).await,
+            DATA_CRC_ERROR => {
+                debug!("SD card rejected the data CRC");
+                Err(BlockDeviceError::WriteError)
+            }
+            DATA_WRITE_ERROR => Err(BlockDeviceError::WriteError),
+            response => {
+                debug!("SD data response: {:02x}", response);
+                Err(card_error())
+            }
+        }
+    }
+
+    /// The card holds its output low while busy
+    async fn wait_not_busy(&mut self, timeout: Duration) -> Result<(), BlockDeviceError> {
+        let deadline = Instant::now() + timeout;
+        while self.exchange().await? != 0xff {
+            if Instant::now() > deadline {
+                return Err(BlockDeviceError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_u32(&mut self) -> Result<u32, BlockDeviceError> {
+        let mut buf = [0xff; 4];
+        self.spi
+            .transfer_in_place(&mut buf)
+            .await
+            .map_err(|_| bus_error())?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    async fn exchange(&mut self) -> Result<u8, BlockDeviceError> {
+        let mut buf = [0xff];
+        self.spi
+            .transfer_in_place(&mut buf)
+            .await
+            .map_err(|_| bus_error())?;
+        Ok(buf[0])
+    }
+
+    fn select(&mut self) -> Result<(), BlockDeviceError> {
+        self.cs.set_low().map_err(|_| bus_error())
+    }
+
+    async fn deselect(&mut self) {
+        // the card only releases its output on the clock edge after chip select goes high
+        let _ = self.cs.set_high();
+        let _ = self.exchange().await;
+    }
+}
+
+impl<SPI: SpiBus, CS: OutputPin, CD: InputPin> BlockDevice for SdCard<SPI, CS, CD> {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.read(lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write(lba, block).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.read(lba, blocks).await
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write(lba, blocks).await
+    }
+
+    fn block_count(&self) -> u32 {
+        self.card.map_or(0, |card| card.blocks)
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        if !self.card_inserted() {
+            if self.card.take().is_some() {
+                info!("SD card removed");
+            }
+            return MediaStatus::Absent;
+        }
+        if self.card.is_some() {
+            return MediaStatus::Present;
+        }
+
+        match self.init().await {
+            Ok(card) => {
+                info!("SD card initialised: {}", card);
+                self.card = Some(card);
+                MediaStatus::Changed
+            }
+            Err(e) => {
+                // try again on the next poll
+                warn!("SD card initialisation failed: {}", e);
+                MediaStatus::Absent
+            }
+        }
+    }
+}
+
+fn check_range(card: &Card, lba: u32, len: usize) -> Result<(), BlockDeviceError> {
+    let blocks = (len / BLOCK_SIZE) as u32;
+    match lba.checked_add(blocks) {
+        Some(end) if end <= card.blocks => Ok(()),
+        _ => Err(BlockDeviceError::InvalidAddress),
+    }
+}
+
+fn check_r1(r1: u8, error: BlockDeviceError) -> Result<(), BlockDeviceError> {
+    match r1 {
+        0 => Ok(()),
+        r1 if r1 & (R1_ADDRESS_ERROR | R1_PARAMETER_ERROR) != 0 => {
+            Err(BlockDeviceError::InvalidAddress)
+        }
+        r1 if r1 & R1_COM_CRC_ERROR != 0 => Err(error),
+        r1 => {
+            debug!("SD command failed: {:02x}", r1);
+            Err(card_error())
+        }
+    }
+}
+
+fn bus_error() -> BlockDeviceError {
+    BlockDeviceError::HardwareFailure { fru: FRU_SPI_BUS }
+}
+
+fn card_error() -> BlockDeviceError {
+    BlockDeviceError::HardwareFailure { fru: FRU_CARD }
+}
+
+/// Capacity in blocks from the CSD register
+fn csd_blocks(csd: &[u8; 16]) -> u32 {
+    // bit 127 is the top bit of the first byte
+    let bits = |msb: usize, lsb: usize| -> u64 {
+        (lsb..=msb).rev().fold(0, |value, bit| {
+            (value << 1) | ((csd[15 - bit / 8] >> (bit % 8)) & 1) as u64
+        })
+    };
+
+    let blocks = match bits(127, 126) {
+        // SDSC: (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) blocks of 2^READ_BL_LEN bytes
+        0 => {
+            let c_size = bits(73, 62);
+            let c_size_mult = bits(49, 47);
+            let read_bl_len = bits(83, 80);
+            ((c_size + 1) << (c_size_mult + 2 + read_bl_len)) / BLOCK_SIZE as u64
+        }
+        // SDHC/SDXC: (C_SIZE + 1) * 512KiB
+        _ => (bits(69, 48) + 1) * 1024,
+    };
+    // LBAs are 32 bits, anything beyond 2TiB is out of reach
+    blocks.min(u32::MAX as u64) as u32
+}
@@ -0,0 +1,440 @@
+//! An SD card answering on a simulated SPI bus, for exercising the driver off-target.
+//!
+//! The card behaves as a block addressed SDHC card, or a byte addressed version 1 SDSC card,
+//! holding the given image, which must be a multiple of 512KiB (the SDHC CSD capacity unit). It understands the commands the driver uses,
+//! checks command and data CRCs once they are turned on, streams blocks for CMD18 until
+//! CMD12 and accepts blocks for CMD25 until the stop token. Read CRCs can be corrupted and
+//! writes can be made to stall the card through [`SimulatedFaults`] to exercise the driver's
+//! retries and timeouts.
+
+use core::cell::Cell;
+use core::convert::Infallible;
+
+use embedded_hal_1::digital::{self, InputPin, OutputPin};
+use embedded_hal_1::spi;
+use embedded_hal_async::spi::SpiBus;
+
+use super::*;
+
+/// Busy bytes sent after a block has been programmed
+const PROGRAM_BUSY_BYTES: usize = 3;
+/// Polls of ACMD41 before the card leaves the idle state
+const INIT_POLLS: u8 = 2;
+/// Room for R1 followed by a full data block
+const OUTPUT_LEN: usize = 1024;
+
+/// A pin backed by a [`Cell`] that holds whether it's low. Serves as the card detect switch
+/// (set the cell while a card is inserted) and as a chip select
+pub struct SimulatedPin<'a>(pub &'a Cell<bool>);
+
+impl digital::ErrorType for SimulatedPin<'_> {
+    type Error = Infallible;
+}
+
+impl InputPin for SimulatedPin<'_> {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.0.get())
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.0.get())
+    }
+}
+
+impl OutputPin for SimulatedPin<'_> {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.set(true);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.set(false);
+        Ok(())
+    }
+}
+
+/// Faults to inject, shared with the test so they can be changed while the driver owns the card
+#[derive(Default)]
+pub struct SimulatedFaults {
+    /// Number of upcoming data blocks to send with a bad CRC
+    pub corrupt_reads: Cell<usize>,
+    /// While set the card stays busy after accepting a block
+    pub stalled: Cell<bool>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for a command
+    Ready,
+    /// Streaming blocks for CMD18
+    ReadingMultiple { lba: u32 },
+    /// Waiting for the start token of a block for CMD24/CMD25
+    AwaitingData { lba: u32, multiple: bool },
+    /// Receiving a block and its CRC
+    ReceivingData { lba: u32, multiple: bool },
+}
+
+pub struct SimulatedSdCard<'a> {
+    image: &'a mut [u8],
+    faults: &'a SimulatedFaults,
+    high_capacity: bool,
+    state: State,
+    idle: bool,
+    app_command: bool,
+    crc_enabled: bool,
+    init_polls: u8,
+    frame: [u8; 6],
+    frame_len: usize,
+    data: [u8; BLOCK_SIZE + 2],
+    data_len: usize,
+    output: [u8; OUTPUT_LEN],
+    output_start: usize,
+    output_end: usize,
+    commands: usize,
+}
+
+impl<'a> SimulatedSdCard<'a> {
+    /// An SDHC card
+    pub fn new(image: &'a mut [u8], faults: &'a SimulatedFaults) -> Self {
+        Self::with_capacity(image, faults, true)
+    }
+
+    /// An SDSC card that predates CMD8
+    pub fn new_standard_capacity(image: &'a mut [u8], faults: &'a SimulatedFaults) -> Self {
+        Self::with_capacity(image, faults, false)
+    }
+
+    fn with_capacity(
+        image: &'a mut [u8],
+        faults: &'a SimulatedFaults,
+        high_capacity: bool,
+    ) -> Self {
+        assert!(image.len().is_multiple_of(512 * 1024) && !image.is_empty());
+        Self {
+            image,
+            faults,
+            high_capacity,
+            state: State::Ready,
+            idle: false,
+            app_command: false,
+            crc_enabled: false,
+            init_polls: INIT_POLLS,
+            frame: [0; 6],
+            frame_len: 0,
+            data: [0; BLOCK_SIZE + 2],
+            data_len: 0,
+            output: [0; OUTPUT_LEN],
+            output_start: 0,
+            output_end: 0,
+            commands: 0,
+        }
+    }
+
+    /// Number of commands received since creation
+    pub fn commands(&self) -> usize {
+        self.commands
+    }
+
+    pub fn image(&self) -> &[u8] {
+        self.image
+    }
+
+    fn blocks(&self) -> u32 {
+        (self.image.len() / BLOCK_SIZE) as u32
+    }
+
+    /// Clocks one byte in each direction
+    fn exchange(&mut self, mosi: u8) -> u8 {
+        let miso = self.next_output();
+        self.receive(mosi);
+        miso
+    }
+
+    fn next_output(&mut self) -> u8 {
+        if self.output_start == self.output_end {
+            self.output_start = 0;
+            self.output_end = 0;
+            match self.state {
+                State::ReadingMultiple { lba } if lba < self.blocks() => {
+                    self.push_block(lba);
+                    self.state = State::ReadingMultiple { lba: lba + 1 };
+                }
+                State::Ready if self.faults.stalled.get() => return 0x00,
+                _ => return 0xff,
+            }
+        }
+        let byte = self.output[self.output_start];
+        self.output_start += 1;
+        byte
+    }
+
+    fn receive(&mut self, mosi: u8) {
+        match self.state {
+            State::AwaitingData { lba, multiple } => match mosi {
+                TOKEN_START_BLOCK if !multiple => self.start_data(lba, multiple),
+                TOKEN_START_MULTIPLE_WRITE if multiple => self.start_data(lba, multiple),
+                TOKEN_STOP_TRAN if multiple => {
+                    self.push(&[0x00; PROGRAM_BUSY_BYTES]);
+                    self.state = State::Ready;
+                }
+                _ => {}
+            },
+            State::ReceivingData { lba, multiple } => {
+                self.data[self.data_len] = mosi;
+                self.data_len += 1;
+                if self.data_len == self.data.len() {
+                    self.program(lba, multiple);
+                }
+            }
+            State::Ready | State::ReadingMultiple { .. } => {
+                // commands start with 01
+                if self.frame_len == 0 && mosi & 0xc0 != 0x40 {
+                    return;
+                }
+                self.frame[self.frame_len] = mosi;
+                self.frame_len += 1;
+                if self.frame_len == self.frame.len() {
+                    self.frame_len = 0;
+                    self.command();
+                }
+            }
+        }
+    }
+
+    fn start_data(&mut self, lba: u32, multiple: bool) {
+        self.data_len = 0;
+        self.state = State::ReceivingData { lba, multiple };
+    }
+
+    fn program(&mut self, lba: u32, multiple: bool) {
+        let (block, crc) = self.data.split_at(BLOCK_SIZE);
+        let crc_ok =
+            !self.crc_enabled || u16::from_be_bytes([crc[0], crc[1]]) == CRC16.checksum(block);
+
+        let next = if !crc_ok {
+            self.push(&[DATA_CRC_ERROR]);
+            lba
+        } else if lba >= self.blocks() {
+            self.push(&[DATA_WRITE_ERROR]);
+            lba
+        } else {
+            let offset = lba as usize * BLOCK_SIZE;
+            self.image[offset..offset + BLOCK_SIZE].copy_from_slice(block);
+            self.push(&[DATA_ACCEPTED]);
+            self.push(&[0x00; PROGRAM_BUSY_BYTES]);
+            lba + 1
+        };
+
+        self.state = if multiple {
+            State::AwaitingData {
+                lba: next,
+                multiple,
+            }
+        } else {
+            State::Ready
+        };
+    }
+
+    fn command(&mut self) {
+        self.commands += 1;
+        let frame = self.frame;
+        let command = frame[0] & 0x3f;
+        let argument = u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]);
+        let app_command = core::mem::take(&mut self.app_command);
+        // the block a read or write is for
+        let lba = if self.high_capacity {
+            argument
+        } else {
+            argument / BLOCK_SIZE as u32
+        };
+
+        // CMD0 and CMD8 are always checked, since the card starts out in SD mode
+        let crc_checked =
+            self.crc_enabled || matches!(command, CMD_GO_IDLE_STATE | CMD_SEND_IF_COND);
+        if crc_checked && frame[5] != (CRC7.checksum(&frame[..5]) << 1) | 1 {
+            self.push_r1(R1_COM_CRC_ERROR);
+            return;
+        }
+
+        match (app_command, command) {
+            (_, CMD_STOP_TRANSMISSION) => {
+                // drop the rest of the block being streamed
+                self.output_start = 0;
+                self.output_end = 0;
+                self.state = State::Ready;
+                self.push(&[0xff]);
+                self.push_r1(0);
+                self.push(&[0x00]);
+            }
+            (_, CMD_GO_IDLE_STATE) => {
+                self.idle = true;
+                self.crc_enabled = false;
+                self.init_polls = INIT_POLLS;
+                self.state = State::Ready;
+                self.push_r1(0);
+            }
+            (false, CMD_SEND_IF_COND) if !self.high_capacity => self.push_r1(R1_ILLEGAL_COMMAND),
+            (false, CMD_SEND_IF_COND) => {
+                self.push_r1(0);
+                self.push(&(argument & 0xfff).to_be_bytes());
+            }
+            (false, CMD_CRC_ON_OFF) => {
+                self.crc_enabled = argument & 1 != 0;
+                self.push_r1(0);
+            }
+            (false, CMD_APP_CMD) => {
+                self.app_command = true;
+                self.push_r1(0);
+            }
+            (true, ACMD_SD_SEND_OP_COND) => {
+                if self.init_polls > 0 {
+                    self.init_polls -= 1;
+                } else {
+                    self.idle = false;
+                }
+                self.push_r1(0);
+            }
+            (false, CMD_READ_OCR) => {
+                // power up status, CCS and 3.2-3.4V
+                let ocr = match (self.idle, self.high_capacity) {
+                    (true, _) => 0,
+                    (false, true) => 1 << 31 | OCR_CCS,
+                    (false, false) => 1 << 31,
+                } | 0x30_0000;
+                self.push_r1(0);
+                self.push(&u32::to_be_bytes(ocr));
+            }
+            _ if self.idle => self.push_r1(R1_ILLEGAL_COMMAND),
+            (false, CMD_SEND_CSD) => {
+                let mut csd = [0u8; 16];
+                if self.high_capacity {
+                    let c_size = self.blocks() / 1024 - 1;
+                    csd[0] = 0x40; // CSD version 2.0
+                    csd[5] = 0x59; // CCC and READ_BL_LEN = 9
+                    csd[7] = (c_size >> 16) as u8 & 0x3f;
+                    csd[8] = (c_size >> 8) as u8;
+                    csd[9] = c_size as u8;
+                } else {
+                    // CSD version 1.0, 1KiB blocks in units of 2^(C_SIZE_MULT + 2) = 256 of them
+                    let c_size = self.blocks() / 512 - 1;
+                    set_bits(&mut csd, 83, 80, 10);
+                    set_bits(&mut csd, 73, 62, c_size);
+                    set_bits(&mut csd, 49, 47, 6);
+                }
+                csd[15] = (CRC7.checksum(&csd[..15]) << 1) | 1;
+                self.push_r1(0);
+                self.push_data(&csd);
+            }
+            (false, CMD_SEND_STATUS) => {
+                self.push_r1(0);
+                self.push(&[0]);
+            }
+            (false, CMD_SET_BLOCKLEN) => self.push_r1(0),
+            (false, CMD_READ_SINGLE_BLOCK) if lba < self.blocks() => {
+                self.push_r1(0);
+                self.push_block(lba);
+            }
+            (false, CMD_READ_MULTIPLE_BLOCK) if lba < self.blocks() => {
+                self.push_r1(0);
+                self.state = State::ReadingMultiple { lba };
+            }
+            (false, CMD_WRITE_BLOCK | CMD_WRITE_MULTIPLE_BLOCK) if lba < self.blocks() => {
+                self.push_r1(0);
+                self.state = State::AwaitingData {
+                    lba,
+                    multiple: command == CMD_WRITE_MULTIPLE_BLOCK,
+                };
+            }
+            (
+                false,
+                CMD_READ_SINGLE_BLOCK
+                | CMD_READ_MULTIPLE_BLOCK
+                | CMD_WRITE_BLOCK
+                | CMD_WRITE_MULTIPLE_BLOCK,
+            ) => self.push_r1(R1_ADDRESS_ERROR),
+            _ => self.push_r1(R1_ILLEGAL_COMMAND),
+        }
+    }
+
+    fn push_r1(&mut self, r1: u8) {
+        let idle = if self.idle { R1_IDLE } else { 0 };
+        // one byte of NCR before the response
+        self.push(&[0xff, r1 | idle]);
+    }
+
+    fn push_block(&mut self, lba: u32) {
+        let offset = lba as usize * BLOCK_SIZE;
+        let mut block = [0u8; BLOCK_SIZE];
+        block.copy_from_slice(&self.image[offset..offset + BLOCK_SIZE]);
+        self.push_data(&block);
+    }
+
+    fn push_data(&mut self, data: &[u8]) {
+        let mut crc = CRC16.checksum(data);
+        let corrupt_reads = self.faults.corrupt_reads.get();
+        if corrupt_reads > 0 {
+            self.faults.corrupt_reads.set(corrupt_reads - 1);
+            crc = !crc;
+        }
+        self.push(&[0xff, TOKEN_START_BLOCK]);
+        self.push(data);
+        self.push(&crc.to_be_bytes());
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let end = self.output_end + bytes.len();
+        self.output[self.output_end..end].copy_from_slice(bytes);
+        self.output_end = end;
+    }
+}
+
+/// Sets bits `msb` down to `lsb` of a register sent most significant byte first
+fn set_bits(register: &mut [u8], msb: usize, lsb: usize, value: u32) {
+    for bit in lsb..=msb {
+        let byte = &mut register[register.len() - 1 - bit / 8];
+        if value >> (bit - lsb) & 1 != 0 {
+            *byte |= 1 << (bit % 8);
+        }
+    }
+}
+
+impl spi::ErrorType for SimulatedSdCard<'_> {
+    type Error = Infallible;
+}
+
+impl SpiBus for SimulatedSdCard<'_> {
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        for word in words {
+            *word = self.exchange(0xff);
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        for word in words {
+            self.exchange(*word);
+        }
+        Ok(())
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        for i in 0..read.len().max(write.len()) {
+            let miso = self.exchange(write.get(i).copied().unwrap_or(0xff));
+            if let Some(word) = read.get_mut(i) {
+                *word = miso;
+            }
+        }
+        Ok(())
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        for word in words {
+            *word = self.exchange(*word);
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,467 @@
+//! Point-in-time snapshots of a base device, which the drive can be rolled back to, so a demo
+//! volume the host has trashed can be put back without reflashing.
+//!
+//! Taking a snapshot copies nothing. Afterwards, the first write to each block copies its old
+//! contents into a small pool in RAM (copy-on-write), so a snapshot only costs the blocks
+//! changed since it was taken. Writing a block with its current contents doesn't use the
+//! pool. Unmapping blocks writes zeros over them as far as the snapshots are concerned, and
+//! the copies keep the protection information of the blocks if the base has any. Once the
+//! pool is full, writes that need a copy fail with SPACE ALLOCATION FAILED and nothing is
+//! written, until a snapshot is deleted or rolled back to.
+//!
+//! Rolling back writes the copies back to the base and drops every later snapshot. The
+//! medium is then ejected for [`EJECT_TIME`], so the host drops what it cached of the old
+//! contents, and comes back with MEDIUM MAY HAVE CHANGED to be mounted again.
+//!
+//! Snapshots are taken, deleted and rolled back to through the methods of
+//! [`SnapshotBlockDevice`], or by other tasks (e.g. the network server or a button) through
+//! [`COMMANDS`], which are handled the next time the host polls for media. [`list`] returns
+//! the current snapshots. There are none until one is taken, so the pool only fills once the
+//! drive has something to roll back to.
+//!
+//! Formatting the drive fails while there are snapshots, as it would change every block.
+
+use core::cell::RefCell;
+
+use defmt::{error, info, warn};
+use embassy_sync::{
+    blocking_mutex::{raw::ThreadModeRawMutex, Mutex},
+    channel::Channel,
+};
+use embassy_time::{Duration, Instant};
+
+use super::BLOCK_SIZE;
+use crate::scsi::{
+    BlockDevice, BlockDeviceError, MediaStatus, Wrapper, PROTECTION_INFORMATION_BYTES,
+};
+
+/// Taking a snapshot beyond this drops the oldest one
+pub const MAX_SNAPSHOTS: usize = 8;
+/// How long the medium is removed after a rollback, long enough for the host to notice
+pub const EJECT_TIME: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum SnapshotCommand {
+    Take,
+    Delete(u32),
+    Rollback(u32),
+    /// Roll back to the oldest snapshot
+    RollbackOldest,
+}
+
+/// Requests for the snapshots, handled on the next media poll
+pub static COMMANDS: Channel<ThreadModeRawMutex, SnapshotCommand, 4> = Channel::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct SnapshotInfo {
+    pub id: u32,
+    pub taken: Instant,
+    /// Blocks of the pool holding its copies
+    pub blocks: u32,
+}
+
+static LIST: Mutex<ThreadModeRawMutex, RefCell<[Option<SnapshotInfo>; MAX_SNAPSHOTS]>> =
+    Mutex::new(RefCell::new([None; MAX_SNAPSHOTS]));
+
+/// The snapshots as of the last change to them, oldest first
+pub fn list() -> [Option<SnapshotInfo>; MAX_SNAPSHOTS] {
+    LIST.lock(|list| *list.borrow())
+}
+
+/// The old contents of a block, from before a write after the snapshot was taken
+#[derive(Clone, Copy)]
+struct Preserved {
+    snapshot: u32,
+    lba: u32,
+    /// Of the block on a base with protection information
+    protection: [u8; PROTECTION_INFORMATION_BYTES],
+}
+
+/// Snapshots of `base` with a pool of `BLOCKS` blocks for the copies
+pub struct SnapshotBlockDevice<'p, B, const BLOCKS: usize> {
+    base: B,
+    /// Oldest first, the copies of new writes belong to the last one
+    snapshots: [Option<SnapshotInfo>; MAX_SNAPSHOTS],
+    next_id: u32,
+    copies: [Option<Preserved>; BLOCKS],
+    pool: &'p mut [[u8; BLOCK_SIZE]; BLOCKS],
+    ejected_until: Option<Instant>,
+    /// The contents changed under the host, to be reported as a media change
+    changed: bool,
+}
+
+impl<'p, B: BlockDevice, const BLOCKS: usize> SnapshotBlockDevice<'p, B, BLOCKS> {
+    /// The pool is borrowed rather than held, as it's usually too large for a task
+    pub const fn new(base: B, pool: &'p mut [[u8; BLOCK_SIZE]; BLOCKS]) -> Self {
+        assert!(B::BLOCK_BYTES == BLOCK_SIZE);
+        Self {
+            base,
+            snapshots: [None; MAX_SNAPSHOTS],
+            next_id: 0,
+            copies: [None; BLOCKS],
+            pool,
+            ejected_until: None,
+            changed: false,
+        }
+    }
+
+    /// Takes a snapshot of the current contents, returning its id
+    pub fn take(&mut self) -> u32 {
+        if self.snapshots.iter().all(Option::is_some) {
+            if let Some(oldest) = self.snapshots[0] {
+                warn!("snapshots: dropping snapshot {} for a new one", oldest.id);
+                self.delete(oldest.id);
+            }
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let snapshot = SnapshotInfo {
+            id,
+            taken: Instant::now(),
+            blocks: 0,
+        };
+        let len = self.len();
+        self.snapshots[len] = Some(snapshot);
+        info!("snapshots: took snapshot {}", id);
+        self.publish();
+        id
+    }
+
+    /// Deletes snapshot `id`, returning whether there was one. Its copies that older
+    /// snapshots still need are handed over to the one before it
+    pub fn delete(&mut self, id: u32) -> bool {
+        let Some(index) = self.index(id) else {
+            return false;
+        };
+        let previous = index.checked_sub(1).and_then(|i| self.snapshots[i]);
+
+        for slot in 0..BLOCKS {
+            let Some(copy) = self.copies[slot] else {
+                continue;
+            };
+            if copy.snapshot != id {
+                continue;
+            }
+            self.copies[slot] = match previous {
+                Some(previous) if self.copy_slot(previous.id, copy.lba).is_none() => {
+                    Some(Preserved {
+                        snapshot: previous.id,
+                        ..copy
+                    })
+                }
+                // the oldest snapshot, or the one before has an older copy of its own
+                _ => None,
+            };
+        }
+
+        self.snapshots.copy_within(index + 1.., index);
+        self.snapshots[MAX_SNAPSHOTS - 1] = None;
+        info!("snapshots: deleted snapshot {}", id);
+        self.publish();
+        true
+    }
+
+    /// Returns the drive to its contents when snapshot `id` was taken, dropping every later
+    /// snapshot, and ejects the medium. Blocks restored before an error stay restored and the
+    /// rollback can be tried again. Fails with `InvalidAddress` if there's no snapshot `id`
+    pub async fn rollback(&mut self, id: u32) -> Result<(), BlockDeviceError> {
+        let Some(index) = self.index(id) else {
+            return Err(BlockDeviceError::InvalidAddress);
+        };
+        info!("snapshots: rolling back to snapshot {}", id);
+
+        // newest first, so the oldest copy of a block is the one left on the base
+        for snapshot in self.snapshots[index..].iter().rev().flatten() {
+            for slot in 0..BLOCKS {
+                match self.copies[slot] {
+                    Some(copy) if copy.snapshot == snapshot.id => {
+                        let result = if self.base.protection_information() {
+                            self.base
+                                .write_blocks_protected(
+                                    copy.lba,
+                                    &self.pool[slot],
+                                    &copy.protection,
+                                )
+                                .await
+                        } else {
+                            self.base.write_block(copy.lba, &self.pool[slot]).await
+                        };
+                        match result {
+                            Err(e) if !e.is_recovered() => return Err(e),
+                            _ => {}
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for copy in &mut self.copies {
+            if copy.is_some_and(|copy| copy.snapshot >= id) {
+                *copy = None;
+            }
+        }
+        for snapshot in &mut self.snapshots[index + 1..] {
+            *snapshot = None;
+        }
+        self.ejected_until = Some(Instant::now() + EJECT_TIME);
+        self.publish();
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.snapshots.iter().flatten().count()
+    }
+
+    fn index(&self, id: u32) -> Option<usize> {
+        self.snapshots
+            .iter()
+            .position(|snapshot| snapshot.is_some_and(|snapshot| snapshot.id == id))
+    }
+
+    fn copy_slot(&self, snapshot: u32, lba: u32) -> Option<usize> {
+        self.copies
+            .iter()
+            .position(|copy| copy.is_some_and(|copy| copy.snapshot == snapshot && copy.lba == lba))
+    }
+
+    /// Updates the block counts and the list other tasks see
+    fn publish(&mut self) {
+        for snapshot in self.snapshots.iter_mut().flatten() {
+            let blocks = self
+                .copies
+                .iter()
+                .flatten()
+                .filter(|copy| copy.snapshot == snapshot.id)
+                .count();
+            snapshot.blocks = blocks as u32;
+        }
+        LIST.lock(|list| *list.borrow_mut() = self.snapshots);
+    }
+
+    /// Copies the contents of `lba` into the pool before `block` is written over them, if the
+    /// newest snapshot needs them. `protection` is what's written with the block, if the
+    /// caller has it
+    async fn copy_on_write(
+        &mut self,
+        lba: u32,
+        block: &[u8],
+        protection: Option<&[u8]>,
+    ) -> Result<(), BlockDeviceError> {
+        let Some(newest) = self.snapshots.iter().flatten().last() else {
+            return Ok(());
+        };
+        let newest = newest.id;
+        if self.copy_slot(newest, lba).is_some() {
+            return Ok(());
+        }
+
+        let mut old = [0u8; BLOCK_SIZE];
+        let mut old_protection = [0u8; PROTECTION_INFORMATION_BYTES];
+        let protected = self.base.protection_information();
+        let result = if protected {
+            self.base
+                .read_blocks_protected(lba, &mut old, &mut old_protection)
+                .await
+        } else {
+            self.base.read_block(lba, &mut old).await
+        };
+        match result {
+            Err(e) if !e.is_recovered() => return Err(e),
+            _ => {}
+        }
+        // the base makes up the protection information of blocks written without it
+        if old == block && (!protected || protection == Some(&old_protection[..])) {
+            return Ok(());
+        }
+
+        let slot = self
+            .copies
+            .iter()
+            .position(Option::is_none)
+            .ok_or(BlockDeviceError::SpaceAllocationFailed)?;
+        self.copies[slot] = Some(Preserved {
+            snapshot: newest,
+            lba,
+            protection: old_protection,
+        });
+        self.pool[slot] = old;
+        self.publish();
+        Ok(())
+    }
+
+    /// [`Self::copy_on_write`] for each of `blocks`, written from `lba`, checking they're on
+    /// the base first
+    async fn copy_blocks_on_write(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: Option<&[u8]>,
+    ) -> Result<(), BlockDeviceError> {
+        let count = (blocks.len() / BLOCK_SIZE) as u32;
+        match lba.checked_add(count) {
+            Some(end) if end <= self.base.block_count() => {}
+            _ => return Err(BlockDeviceError::InvalidAddress),
+        }
+        for (i, block) in blocks.chunks_exact(BLOCK_SIZE).enumerate() {
+            let protection = protection.map(|protection| {
+                &protection[i * PROTECTION_INFORMATION_BYTES..][..PROTECTION_INFORMATION_BYTES]
+            });
+            self.copy_on_write(lba + i as u32, block, protection)
+                .await?;
+        }
+        Ok(())
+    }
+
+    fn check_ejected(&self) -> Result<(), BlockDeviceError> {
+        match self.ejected_until {
+            Some(_) => Err(BlockDeviceError::MediumNotPresent),
+            None => Ok(()),
+        }
+    }
+
+    async fn handle_commands(&mut self) {
+        while let Ok(command) = COMMANDS.try_receive() {
+            info!("snapshots: {}", command);
+            match command {
+                SnapshotCommand::Take => {
+                    self.take();
+                }
+                SnapshotCommand::Delete(id) => {
+                    if !self.delete(id) {
+                        warn!("snapshots: no snapshot {}", id);
+                    }
+                }
+                SnapshotCommand::Rollback(id) => {
+                    if let Err(e) = self.rollback(id).await {
+                        error!("snapshots: failed to roll back to {}: {}", id, e);
+                    }
+                }
+                SnapshotCommand::RollbackOldest => match self.snapshots[0] {
+                    Some(oldest) => {
+                        if let Err(e) = self.rollback(oldest.id).await {
+                            error!("snapshots: failed to roll back to {}: {}", oldest.id, e);
+                        }
+                    }
+                    None => warn!("snapshots: nothing to roll back to"),
+                },
+            }
+        }
+    }
+}
+
+impl<B: BlockDevice, const BLOCKS: usize> Wrapper for SnapshotBlockDevice<'_, B, BLOCKS> {
+    type Base = B;
+
+    fn base(&self) -> &B {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut B {
+        &mut self.base
+    }
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.check_ejected()?;
+        self.base.read_block(lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.check_ejected()?;
+        self.copy_blocks_on_write(lba, block, None).await?;
+        self.base.write_block(lba, block).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.check_ejected()?;
+        self.base.read_blocks(lba, blocks).await
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.check_ejected()?;
+        self.copy_blocks_on_write(lba, blocks, None).await?;
+        self.base.write_blocks(lba, blocks).await
+    }
+
+    async fn write_blocks_fua(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.check_ejected()?;
+        self.copy_blocks_on_write(lba, blocks, None).await?;
+        self.base.write_blocks_fua(lba, blocks).await
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        self.handle_commands().await;
+
+        if let Some(until) = self.ejected_until {
+            if Instant::now() < until {
+                return MediaStatus::Absent;
+            }
+            self.ejected_until = None;
+            self.changed = true;
+        }
+
+        let status = self.base.media_status().await;
+        // the snapshots were of another medium
+        if matches!(status, MediaStatus::Absent | MediaStatus::Changed) && self.len() > 0 {
+            warn!("snapshots: the medium changed, dropping the snapshots");
+            self.snapshots = [None; MAX_SNAPSHOTS];
+            self.copies = [None; BLOCKS];
+            self.publish();
+        }
+        match status {
+            MediaStatus::Present if core::mem::take(&mut self.changed) => MediaStatus::Changed,
+            status => status,
+        }
+    }
+
+    async fn unmap(&mut self, lba: u32, count: u32) -> Result<(), BlockDeviceError> {
+        self.check_ejected()?;
+        if self.len() > 0 {
+            // unmapped blocks read as zeros
+            let zeros = [0u8; BLOCK_SIZE];
+            let end = lba
+                .checked_add(count)
+                .filter(|&end| end <= self.base.block_count())
+                .ok_or(BlockDeviceError::InvalidAddress)?;
+            for block_lba in lba..end {
+                self.copy_on_write(block_lba, &zeros, None).await?;
+            }
+        }
+        self.base.unmap(lba, count).await
+    }
+
+    async fn read_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        protection: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.check_ejected()?;
+        self.base
+            .read_blocks_protected(lba, blocks, protection)
+            .await
+    }
+
+    async fn write_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.check_ejected()?;
+        self.copy_blocks_on_write(lba, blocks, Some(protection))
+            .await?;
+        self.base
+            .write_blocks_protected(lba, blocks, protection)
+            .await
+    }
+
+    async fn format(&mut self) -> Result<(), BlockDeviceError> {
+        if self.len() > 0 {
+            warn!("snapshots: not formatting over the snapshots");
+            return Err(BlockDeviceError::Unsupported);
+        }
+        self.base.format().await
+    }
+}
@@ -0,0 +1,159 @@
+//! A thin provisioned RAM disk that advertises more blocks than it has RAM for.
+//!
+//! Blocks are taken from a fixed pool the first time they're written. Blocks that were never
+//! written read as zeros, and blocks the host unmaps or fills with zeros go back to the pool.
+//! Once the pool is exhausted, writes to blocks without storage fail with SPACE ALLOCATION
+//! FAILED. Going past the soft threshold is reported to the host as a unit attention, so it
+//! gets a chance to free space before that happens. It's reported once, and again only after
+//! the host has freed enough blocks to drop back below the threshold. The blocks used and
+//! left in the pool are in the Logical Block Provisioning log page (e.g. `sg_logs -p 0xc`).
+
+use defmt::warn;
+
+use super::BLOCK_SIZE;
+use crate::scsi::{BlockDevice, BlockDeviceError, Provisioning};
+
+/// Thresholds are reported in multiples of 2^3 blocks (4KiB)
+const THRESHOLD_EXPONENT: u8 = 3;
+
+/// `block_count` virtual blocks backed by a pool of `POOL` blocks of RAM
+pub struct SparseBlockDevice<const POOL: usize> {
+    block_count: u32,
+    /// Number of allocated blocks that triggers the soft threshold
+    soft_threshold: usize,
+    /// Whether the soft threshold has been reported since `used` last dropped below it
+    over_threshold: bool,
+    /// Allocated LBAs in ascending order, the first `used` entries are valid
+    lbas: [u32; POOL],
+    /// The pool slot of each entry of `lbas`
+    slots: [u16; POOL],
+    /// Stack of unallocated pool slots, the first `POOL - used` entries are valid
+    free: [u16; POOL],
+    used: usize,
+    pool: [[u8; BLOCK_SIZE]; POOL],
+}
+
+impl<const POOL: usize> SparseBlockDevice<POOL> {
+    /// `soft_threshold` is in blocks and rounded down to a multiple of 2^`THRESHOLD_EXPONENT`,
+    /// 0 turns it off
+    pub const fn new(block_count: u32, soft_threshold: usize) -> Self {
+        assert!(POOL <= u16::MAX as usize);
+
+        let mut free = [0; POOL];
+        let mut slot = 0;
+        while slot < POOL {
+            free[slot] = slot as u16;
+            slot += 1;
+        }
+
+        Self {
+            block_count,
+            soft_threshold: soft_threshold >> THRESHOLD_EXPONENT << THRESHOLD_EXPONENT,
+            over_threshold: false,
+            lbas: [0; POOL],
+            slots: [0; POOL],
+            free,
+            used: 0,
+            pool: [[0; BLOCK_SIZE]; POOL],
+        }
+    }
+
+    /// The index of `lba` in `lbas` if it's allocated, otherwise where it would go
+    fn find(&self, lba: u32) -> Result<usize, usize> {
+        self.lbas[..self.used].binary_search(&lba)
+    }
+
+    fn allocate(&mut self, index: usize, lba: u32) -> Result<u16, BlockDeviceError> {
+        if self.used == POOL {
+            warn!("sparse: pool exhausted writing lba {}", lba);
+            return Err(BlockDeviceError::SpaceAllocationFailed);
+        }
+
+        let slot = self.free[POOL - self.used - 1];
+        self.lbas.copy_within(index..self.used, index + 1);
+        self.slots.copy_within(index..self.used, index + 1);
+        self.lbas[index] = lba;
+        self.slots[index] = slot;
+        self.used += 1;
+        Ok(slot)
+    }
+
+    fn release(&mut self, index: usize) {
+        let slot = self.slots[index];
+        self.lbas.copy_within(index + 1..self.used, index);
+        self.slots.copy_within(index + 1..self.used, index);
+        self.used -= 1;
+        self.free[POOL - self.used - 1] = slot;
+        if self.used < self.soft_threshold {
+            self.over_threshold = false;
+        }
+    }
+}
+
+impl<const POOL: usize> BlockDevice for SparseBlockDevice<POOL> {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        if lba >= self.block_count {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+
+        match self.find(lba) {
+            Ok(index) => block.copy_from_slice(&self.pool[self.slots[index] as usize]),
+            Err(_) => block.fill(0),
+        }
+        Ok(())
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        if lba >= self.block_count {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+
+        let zeros = block.iter().all(|&byte| byte == 0);
+        match self.find(lba) {
+            Ok(index) if zeros => self.release(index),
+            Ok(index) => self.pool[self.slots[index] as usize].copy_from_slice(block),
+            Err(_) if zeros => {}
+            Err(index) => {
+                let slot = self.allocate(index, lba)?;
+                self.pool[slot as usize].copy_from_slice(block);
+
+                if self.soft_threshold > 0
+                    && self.used >= self.soft_threshold
+                    && !self.over_threshold
+                {
+                    self.over_threshold = true;
+                    return Err(BlockDeviceError::SoftThresholdReached);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn block_count(&self) -> u32 {
+        self.block_count
+    }
+
+    fn provisioning(&self) -> Option<Provisioning> {
+        Some(Provisioning {
+            used: self.used as u32,
+            available: (POOL - self.used) as u32,
+            threshold_exponent: THRESHOLD_EXPONENT,
+        })
+    }
+
+    async fn unmap(&mut self, lba: u32, count: u32) -> Result<(), BlockDeviceError> {
+        let end = lba as u64 + count as u64;
+        if end > self.block_count as u64 {
+            return Err(BlockDeviceError::InvalidAddress);
+        }
+
+        // releasing shifts the following LBAs down into `index`
+        let index = self.find(lba).unwrap_or_else(|index| index);
+        while index < self.used && (self.lbas[index] as u64) < end {
+            self.release(index);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+//! Stripes the blocks of a disk across several devices of the same type (RAID-0), such as a
+//! few SD cards, so they appear to the host as one larger disk.
+//!
+//! The disk is divided into stripes of a fixed number of blocks, which go to the members in
+//! turn: stripe 0 is at the start of member 0, stripe 1 at the start of member 1 and so on.
+//! Accesses spanning several stripes are split between the members. Every member
+//! contributes as many whole stripes as the smallest one holds, so the rest of the larger
+//! members is unused. There's no redundancy, losing any member loses the disk.
+//!
+//! As with [concatenation](super::concat), features are only offered if every member has
+//! them, and a transaction is committed by each member in turn.
+
+use embassy_time::Instant;
+
+use super::concat::{
+    combine_caching, combine_idle_deadlines, combine_provisioning, combine_results,
+    combine_transaction_blocks, combine_unlock, idle_due, MembersStatus,
+};
+use super::BLOCK_SIZE;
+use crate::scsi::{
+    read_blocks_shifted, write_blocks_shifted, BlockDevice, BlockDeviceError, Caching, MediaStatus,
+    Provisioning, PROTECTION_INFORMATION_BYTES,
+};
+
+pub struct StripeBlockDevice<B, const MEMBERS: usize> {
+    members: [B; MEMBERS],
+    stripe_blocks: u32,
+    status: MembersStatus,
+}
+
+impl<B: BlockDevice, const MEMBERS: usize> StripeBlockDevice<B, MEMBERS> {
+    /// Stripes across `members` in stripes of `stripe_blocks` blocks
+    pub fn new(members: [B; MEMBERS], stripe_blocks: u32) -> Self {
+        assert!(B::BLOCK_BYTES == BLOCK_SIZE);
+        assert!(MEMBERS > 0 && stripe_blocks > 0);
+        Self {
+            members,
+            stripe_blocks,
+            status: MembersStatus::default(),
+        }
+    }
+
+    fn check_range(&self, lba: u32, count: usize) -> Result<(), BlockDeviceError> {
+        match (lba as u64).checked_add(count as u64) {
+            Some(end) if end <= self.block_count() as u64 => Ok(()),
+            _ => Err(BlockDeviceError::InvalidAddress),
+        }
+    }
+
+    /// The member holding `lba`, its address on that member and the number of blocks left in
+    /// its stripe
+    fn locate(&self, lba: u32) -> (usize, u32, u32) {
+        let stripe = lba / self.stripe_blocks;
+        let offset = lba % self.stripe_blocks;
+        let member = stripe % MEMBERS as u32;
+        let member_lba = stripe / MEMBERS as u32 * self.stripe_blocks + offset;
+        (member as usize, member_lba, self.stripe_blocks - offset)
+    }
+}
+
+impl<B: BlockDevice, const MEMBERS: usize> BlockDevice for StripeBlockDevice<B, MEMBERS> {
+    const BLOCK_BYTES: usize = BLOCK_SIZE;
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.read_blocks(lba, block).await
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.write_blocks(lba, block).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+
+        let mut result = Ok(());
+        let mut lba = lba;
+        let mut blocks = blocks;
+        while !blocks.is_empty() {
+            let (member, member_lba, left) = self.locate(lba);
+            let count = (blocks.len() / BLOCK_SIZE).min(left as usize);
+            let (part, rest) = blocks.split_at_mut(count * BLOCK_SIZE);
+
+            let part = self.members[member].read_blocks(member_lba, part).await;
+            combine_results(&mut result, part)?;
+            lba += count as u32;
+            blocks = rest;
+        }
+        result
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+
+        let mut result = Ok(());
+        let mut lba = lba;
+        let mut blocks = blocks;
+        while !blocks.is_empty() {
+            let (member, member_lba, left) = self.locate(lba);
+            let count = (blocks.len() / BLOCK_SIZE).min(left as usize);
+            let (part, rest) = blocks.split_at(count * BLOCK_SIZE);
+
+            let part = self.members[member].write_blocks(member_lba, part).await;
+            combine_results(&mut result, part)?;
+            lba += count as u32;
+            blocks = rest;
+        }
+        result
+    }
+
+    async fn write_blocks_fua(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+
+        let mut result = Ok(());
+        let mut lba = lba;
+        let mut blocks = blocks;
+        while !blocks.is_empty() {
+            let (member, member_lba, left) = self.locate(lba);
+            let count = (blocks.len() / BLOCK_SIZE).min(left as usize);
+            let (part, rest) = blocks.split_at(count * BLOCK_SIZE);
+
+            let part = self.members[member]
+                .write_blocks_fua(member_lba, part)
+                .await;
+            combine_results(&mut result, part)?;
+            lba += count as u32;
+            blocks = rest;
+        }
+        result
+    }
+
+    fn block_count(&self) -> u32 {
+        let smallest = self
+            .members
+            .iter()
+            .map(|member| member.block_count())
+            .min()
+            .unwrap_or(0);
+        let stripes = (smallest / self.stripe_blocks) as u64 * MEMBERS as u64;
+        (stripes * self.stripe_blocks as u64).min(u32::MAX as u64) as u32
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        let mut statuses = [MediaStatus::Present; MEMBERS];
+        for (status, member) in statuses.iter_mut().zip(&mut self.members) {
+            *status = member.media_status().await;
+        }
+        self.status.combine(statuses)
+    }
+
+    async fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        // every member is flushed even if one fails
+        let mut result = Ok(());
+        let mut failed = None;
+        for member in &mut self.members {
+            if let Err(e) = combine_results(&mut result, member.flush().await) {
+                failed = Some(e);
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => result,
+        }
+    }
+
+    fn write_protected(&self) -> bool {
+        self.members.iter().any(|member| member.write_protected())
+    }
+
+    fn provisioning(&self) -> Option<Provisioning> {
+        combine_provisioning(self.members.iter().map(|member| member.provisioning()))
+    }
+
+    async fn unmap(&mut self, lba: u32, count: u32) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, count as usize)?;
+
+        let mut result = Ok(());
+        let mut lba = lba;
+        let mut count = count;
+        while count > 0 {
+            let (member, member_lba, left) = self.locate(lba);
+            let part_count = count.min(left);
+
+            let part = self.members[member].unmap(member_lba, part_count).await;
+            combine_results(&mut result, part)?;
+            lba += part_count;
+            count -= part_count;
+        }
+        result
+    }
+
+    async fn unlock(&mut self, key: &[u8]) -> Result<(), BlockDeviceError> {
+        let mut result = Err(BlockDeviceError::Unsupported);
+        for member in &mut self.members {
+            combine_unlock(&mut result, member.unlock(key).await)?;
+        }
+        result
+    }
+
+    fn lock(&mut self) {
+        for member in &mut self.members {
+            member.lock();
+        }
+    }
+
+    fn caching(&self) -> Option<Caching> {
+        combine_caching(self.members.iter().map(|member| member.caching()))
+    }
+
+    async fn set_caching(&mut self, caching: Caching) -> Result<(), BlockDeviceError> {
+        // every member is set even if one fails
+        let mut result = Ok(());
+        let mut failed = None;
+        for member in &mut self.members {
+            if let Err(e) = combine_results(&mut result, member.set_caching(caching).await) {
+                failed = Some(e);
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => result,
+        }
+    }
+
+    fn protection_information(&self) -> bool {
+        self.members
+            .iter()
+            .all(|member| member.protection_information())
+    }
+
+    /// The reference tags on each member count from its own start
+    async fn read_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        protection: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+
+        let mut result = Ok(());
+        let mut lba = lba;
+        let mut blocks = blocks;
+        let mut protection = protection;
+        while !blocks.is_empty() {
+            let (member, member_lba, left) = self.locate(lba);
+            let count = (blocks.len() / BLOCK_SIZE).min(left as usize);
+            let (part, rest) = blocks.split_at_mut(count * BLOCK_SIZE);
+            let (part_pi, rest_pi) = protection.split_at_mut(count * PROTECTION_INFORMATION_BYTES);
+
+            let offset = member_lba.wrapping_sub(lba);
+            let part =
+                read_blocks_shifted(&mut self.members[member], member_lba, part, part_pi, offset)
+                    .await;
+            combine_results(&mut result, part)?;
+            lba += count as u32;
+            blocks = rest;
+            protection = rest_pi;
+        }
+        result
+    }
+
+    async fn write_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.check_range(lba, blocks.len() / BLOCK_SIZE)?;
+
+        let mut result = Ok(());
+        let mut lba = lba;
+        let mut blocks = blocks;
+        let mut protection = protection;
+        while !blocks.is_empty() {
+            let (member, member_lba, left) = self.locate(lba);
+            let count = (blocks.len() / BLOCK_SIZE).min(left as usize);
+            let (part, rest) = blocks.split_at(count * BLOCK_SIZE);
+            let (part_pi, rest_pi) = protection.split_at(count * PROTECTION_INFORMATION_BYTES);
+
+            let offset = member_lba.wrapping_sub(lba);
+            let part =
+                write_blocks_shifted(&mut self.members[member], member_lba, part, part_pi, offset)
+                    .await;
+            combine_results(&mut result, part)?;
+            lba += count as u32;
+            blocks = rest;
+            protection = rest_pi;
+        }
+        result
+    }
+
+    fn transaction_blocks(&self) -> Option<u32> {
+        combine_transaction_blocks(
+            self.members
+                .iter()
+                .map(|member| member.transaction_blocks()),
+        )
+    }
+
+    fn begin_transaction(&mut self) {
+        for member in &mut self.members {
+            member.begin_transaction();
+        }
+    }
+
+    async fn end_transaction(&mut self, commit: bool) -> Result<(), BlockDeviceError> {
+        // every member is ended even if one fails, so none is left open
+        let mut result = Ok(());
+        let mut failed = None;
+        for member in &mut self.members {
+            if let Err(e) = combine_results(&mut result, member.end_transaction(commit).await) {
+                failed = Some(e);
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => result,
+        }
+    }
+
+    fn idle_deadline(&self) -> Option<Instant> {
+        combine_idle_deadlines(self.members.iter().map(|member| member.idle_deadline()))
+    }
+
+    async fn idle(&mut self) {
+        for member in &mut self.members {
+            if idle_due(member.idle_deadline()) {
+                member.idle().await;
+            }
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Shows the access trace of the SCSI layer (see [`crate::scsi::trace`]) in a read-only file
+//! on the drive itself, so it can be copied off without a network.
+//!
+//! Create [`TRACE_FILE`] in the root directory of the FAT12 or FAT16 filesystem, at least
+//! [`EXPORT_BYTES`] long (e.g. `truncate -s 3K TRACE.BIN`), and reads of its blocks return the
+//! trace as it is at the time of the read instead of what's stored in them. The host caches
+//! file contents, so it has to bypass or drop its cache to see a newer trace (e.g. `dd
+//! iflag=direct`).
+//!
+//! The blocks of the file are looked up when a medium is found, and again once the host is
+//! idle after it writes to the filesystem metadata, in case the file was deleted or moved;
+//! they're read as stored until then. Writing to the file itself stops the blocks being
+//! replaced until the medium changes, as they're likely to have been reused for another file
+//! by then.
+
+use defmt::{info, warn};
+use embassy_time::Instant;
+
+use super::fat::Volume;
+use super::BLOCK_SIZE;
+use crate::scsi::trace::{self, EXPORT_BYTES};
+use crate::scsi::{
+    BlockDevice, BlockDeviceError, MediaStatus, Wrapper, GUARD_CRC, PROTECTION_INFORMATION_BYTES,
+};
+
+/// The file holding the trace, as an 8.3 directory entry name
+pub const TRACE_FILE: &[u8; 11] = b"TRACE   BIN";
+const FILE_BLOCKS: usize = EXPORT_BYTES.div_ceil(BLOCK_SIZE);
+
+pub struct TraceFileBlockDevice<B> {
+    base: B,
+    /// The blocks of the file, in the order of the trace
+    file: [u32; FILE_BLOCKS],
+    file_len: usize,
+    /// Writes below this may change where the file is, it's everything if there's no
+    /// filesystem
+    metadata_end: u32,
+    /// `file` is up to date, otherwise it's looked up once the host is idle
+    looked_up: bool,
+    /// The file was written to and is left alone until the medium changes
+    disabled: bool,
+}
+
+impl<B: BlockDevice> TraceFileBlockDevice<B> {
+    pub fn new(base: B) -> Self {
+        assert!(B::BLOCK_BYTES == BLOCK_SIZE);
+        Self {
+            base,
+            file: [0; FILE_BLOCKS],
+            file_len: 0,
+            metadata_end: u32::MAX,
+            looked_up: false,
+            disabled: false,
+        }
+    }
+
+    /// Finds the blocks of [`TRACE_FILE`], following its cluster chain
+    async fn look_up(&mut self) -> Result<(), BlockDeviceError> {
+        self.looked_up = true;
+        self.file_len = 0;
+        self.metadata_end = u32::MAX;
+
+        let volume = Volume::open(&mut self.base).await?;
+        self.metadata_end = volume.root_dir().end;
+        let Some(file) = volume.find(&mut self.base, TRACE_FILE).await? else {
+            return Ok(());
+        };
+
+        let blocks = (file.size as usize).div_ceil(BLOCK_SIZE).min(FILE_BLOCKS);
+        // an empty file has no clusters
+        let mut cluster =
+            Some(file.first_cluster).filter(|&cluster| volume.is_data_cluster(cluster));
+        while let Some(current) = cluster {
+            let lba = volume.cluster_lba(current);
+            for block in 0..volume.cluster_blocks() {
+                if self.file_len == blocks {
+                    break;
+                }
+                self.file[self.file_len] = lba + block;
+                self.file_len += 1;
+            }
+            if self.file_len == blocks {
+                break;
+            }
+            cluster = volume.next_cluster(&mut self.base, current).await?;
+        }
+        info!("trace: {} blocks of the trace file found", self.file_len);
+        Ok(())
+    }
+
+    async fn look_up_file(&mut self) {
+        // a missing filesystem just means there's no file
+        let _ = self.look_up().await;
+    }
+
+    /// Replaces the blocks of the file among `blocks`, read from `lba`, with the trace, and
+    /// the guards of their protection information with ones that match
+    fn export(&self, lba: u32, blocks: &mut [u8], mut protection: Option<&mut [u8]>) {
+        if !self.looked_up || self.disabled {
+            return;
+        }
+        for (i, block) in blocks.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            let block_lba = lba + i as u32;
+            let Some(position) = self.file[..self.file_len]
+                .iter()
+                .position(|&file_lba| file_lba == block_lba)
+            else {
+                continue;
+            };
+            let len = trace::export(position * BLOCK_SIZE, block);
+            block[len..].fill(0);
+            if let Some(protection) = protection.as_deref_mut() {
+                let pi = &mut protection[i * PROTECTION_INFORMATION_BYTES..]
+                    [..PROTECTION_INFORMATION_BYTES];
+                // unless it's the escape value that turns the checks off
+                if pi[2..4] != [0xff; 2] {
+                    pi[0..2].copy_from_slice(&GUARD_CRC.checksum(block).to_be_bytes());
+                }
+            }
+        }
+    }
+
+    /// Notes a write or unmap of `count` blocks from `lba`
+    fn written(&mut self, lba: u32, count: u32) {
+        let end = lba.saturating_add(count);
+        if lba < self.metadata_end {
+            self.looked_up = false;
+        }
+        let file = &self.file[..self.file_len];
+        if !self.disabled && file.iter().any(|block| (lba..end).contains(block)) {
+            warn!("trace: the trace file was written, leaving it alone");
+            self.disabled = true;
+        }
+    }
+}
+
+impl<B: BlockDevice> Wrapper for TraceFileBlockDevice<B> {
+    type Base = B;
+
+    fn base(&self) -> &B {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut B {
+        &mut self.base
+    }
+
+    async fn read_block(&mut self, lba: u32, block: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let result = self.base.read_block(lba, block).await;
+        if !matches!(&result, Err(e) if !e.is_recovered()) {
+            self.export(lba, block, None);
+        }
+        result
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.written(lba, 1);
+        self.base.write_block(lba, block).await
+    }
+
+    async fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), BlockDeviceError> {
+        let result = self.base.read_blocks(lba, blocks).await;
+        if !matches!(&result, Err(e) if !e.is_recovered()) {
+            self.export(lba, blocks, None);
+        }
+        result
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.written(lba, (blocks.len() / BLOCK_SIZE) as u32);
+        self.base.write_blocks(lba, blocks).await
+    }
+
+    async fn write_blocks_fua(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.written(lba, (blocks.len() / BLOCK_SIZE) as u32);
+        self.base.write_blocks_fua(lba, blocks).await
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        let status = self.base.media_status().await;
+        match status {
+            MediaStatus::Present => {}
+            MediaStatus::Changed => {
+                self.looked_up = false;
+                self.disabled = false;
+            }
+            status => {
+                self.looked_up = false;
+                self.disabled = false;
+                return status;
+            }
+        }
+        if !self.looked_up {
+            self.look_up_file().await;
+        }
+        status
+    }
+
+    async fn unmap(&mut self, lba: u32, count: u32) -> Result<(), BlockDeviceError> {
+        self.written(lba, count);
+        self.base.unmap(lba, count).await
+    }
+
+    async fn read_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        protection: &mut [u8],
+    ) -> Result<(), BlockDeviceError> {
+        let result = self
+            .base
+            .read_blocks_protected(lba, blocks, protection)
+            .await;
+        if !matches!(&result, Err(e) if !e.is_recovered()) {
+            self.export(lba, blocks, Some(protection));
+        }
+        result
+    }
+
+    async fn write_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.written(lba, (blocks.len() / BLOCK_SIZE) as u32);
+        self.base
+            .write_blocks_protected(lba, blocks, protection)
+            .await
+    }
+
+    async fn format(&mut self) -> Result<(), BlockDeviceError> {
+        self.looked_up = false;
+        self.disabled = false;
+        self.base.format().await
+    }
+
+    fn idle_deadline(&self) -> Option<Instant> {
+        if self.looked_up {
+            self.base.idle_deadline()
+        } else {
+            Some(Instant::MIN)
+        }
+    }
+
+    async fn idle(&mut self) {
+        if !self.looked_up {
+            self.look_up_file().await;
+        }
+        if self
+            .base
+            .idle_deadline()
+            .is_some_and(|due| Instant::now() >= due)
+        {
+            self.base.idle().await;
+        }
+    }
+}
@@ -0,0 +1,522 @@
+//! Watches the FAT12 or FAT16 filesystem on its base for the changes the host makes, and
+//! publishes them on [`EVENTS`] as [`FsEvent`]s, for the display, the network server and the
+//! application to subscribe to.
+//!
+//! Writes only note which parts of the filesystem they touched: the blocks ahead of the FAT,
+//! the FAT, the root directory or runs of data clusters. Once the host has stopped writing for
+//! [`IDLE`] (see [`BlockDevice::idle`]), or when it flushes (e.g. with SYNCHRONIZE CACHE), the
+//! directories with changed blocks are read again and compared with what was there before. Files whose cluster chains have changed blocks are modified, even
+//! if their directory entries aren't. Changes ahead of the FAT, such as a new partition table
+//! or boot sector, and more runs of clusters than are kept, have the whole filesystem read
+//! again instead.
+//!
+//! Files are known by their 8.3 names. Up to `ENTRIES` files and directories are tracked, and
+//! changes to any beyond that aren't reported. The filesystem found when the device starts,
+//! and after the medium changes, is read without publishing events.
+
+use core::ops::Range;
+
+use defmt::{info, warn, Format};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, pubsub::PubSubChannel};
+use embassy_time::{Duration, Instant};
+
+use super::fat::{DirEntry, Volume};
+use super::BLOCK_SIZE;
+use crate::display::{DisplayState, SIGNAL};
+use crate::scsi::{BlockDevice, BlockDeviceError, MediaStatus, Wrapper};
+
+/// How long the host has to stop writing before its changes are looked at
+pub const IDLE: Duration = Duration::from_secs(1);
+/// Events kept for subscribers that haven't caught up, beyond which they miss the oldest
+pub const EVENT_CAPACITY: usize = 16;
+/// The most tasks that can subscribe to [`EVENTS`]
+pub const SUBSCRIBERS: usize = 4;
+/// Paths longer than this are cut short
+pub const PATH_BYTES: usize = 64;
+/// Runs of changed clusters noted before the whole filesystem is read again instead
+const DIRTY_RUNS: usize = 8;
+/// The label of a volume without one
+const NO_LABEL: [u8; 11] = *b"NO NAME    ";
+const NO_RUN: Range<u32> = 0..0;
+
+/// The changes to the filesystem, published once the host is idle
+pub static EVENTS: PubSubChannel<ThreadModeRawMutex, FsEvent, EVENT_CAPACITY, SUBSCRIBERS, 0> =
+    PubSubChannel::new();
+
+/// The path of a file or directory from the root directory: its 8.3 names separated by `/`,
+/// ending in `/` for a directory
+#[derive(Clone, PartialEq, Eq)]
+pub struct Path {
+    bytes: [u8; PATH_BYTES],
+    len: usize,
+}
+
+impl Path {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; PATH_BYTES],
+            len: 0,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ASCII is pushed
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.len == PATH_BYTES {
+                return;
+            }
+            self.bytes[self.len] = if byte.is_ascii() { byte } else { b'?' };
+            self.len += 1;
+        }
+    }
+
+    /// Appends an 8.3 directory entry name, as `NAME.EXT`
+    fn push_name(&mut self, name: &[u8; 11]) {
+        let trim = |part: &[u8]| {
+            let padding = part.iter().rev().take_while(|&&byte| byte == b' ').count();
+            part.len() - padding
+        };
+        let (base, extension) = name.split_at(8);
+        self.push(&base[..trim(base)]);
+        if trim(extension) > 0 {
+            self.push(b".");
+            self.push(&extension[..trim(extension)]);
+        }
+    }
+}
+
+impl core::fmt::Debug for Path {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Format for Path {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=str}", self.as_str())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Format)]
+pub enum FsEvent {
+    Created(Path),
+    /// The contents, size or time of a file changed
+    Modified(Path),
+    Deleted(Path),
+    /// The volume label changed, to this one padded with spaces
+    LabelChanged([u8; 11]),
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    name: [u8; 11],
+    /// The directory holding the node, `None` for the root directory
+    parent: Option<u16>,
+    dir: bool,
+    first_cluster: u32,
+    size: u32,
+    modified: u32,
+    /// A directory to read again
+    stale: bool,
+    /// Found when its directory was read again
+    seen: bool,
+    /// Already reported as modified by this scan
+    reported: bool,
+}
+
+fn publish(event: FsEvent) {
+    info!("watch: {}", event);
+    EVENTS.immediate_publisher().publish_immediate(event);
+}
+
+/// The node at `index` and the directories holding it, from the innermost
+fn ancestors(nodes: &[Option<Node>], index: usize) -> impl Iterator<Item = &Node> {
+    let first = nodes[index].as_ref();
+    let parent = |node: &Node| {
+        node.parent
+            .and_then(|parent| nodes[parent as usize].as_ref())
+    };
+    core::iter::successors(first, move |node| parent(node)).take(nodes.len())
+}
+
+fn path(nodes: &[Option<Node>], index: usize) -> Path {
+    let mut path = Path::new();
+    let depth = ancestors(nodes, index).count();
+    for level in (0..depth).rev() {
+        let node = ancestors(nodes, index).nth(level).unwrap();
+        path.push_name(&node.name);
+        if node.dir {
+            path.push(b"/");
+        }
+    }
+    path
+}
+
+/// Forgets the node at `index`, and everything in it if it's a directory
+fn remove(nodes: &mut [Option<Node>], index: usize, report: bool) {
+    let in_dir =
+        |node: &Option<Node>| matches!(node, Some(node) if node.parent == Some(index as u16));
+    while let Some(child) = nodes.iter().position(in_dir) {
+        remove(nodes, child, report);
+    }
+    if report {
+        publish(FsEvent::Deleted(path(nodes, index)));
+    }
+    nodes[index] = None;
+}
+
+/// Compares `entry`, found in the directory `dir`, with what was there before
+fn update(nodes: &mut [Option<Node>], dir: Option<u16>, entry: DirEntry, report: bool) {
+    let same_name = |node: &Option<Node>| {
+        node.is_some_and(|node| node.parent == dir && node.name == entry.name)
+    };
+    if let Some(index) = nodes.iter().position(same_name) {
+        let node = nodes[index].as_mut().unwrap();
+        if node.dir == entry.is_dir() {
+            node.seen = true;
+            let before = (node.first_cluster, node.size, node.modified);
+            if before == (entry.first_cluster, entry.size, entry.modified) {
+                return;
+            }
+            node.first_cluster = entry.first_cluster;
+            node.size = entry.size;
+            node.modified = entry.modified;
+            if node.dir {
+                node.stale = true;
+            } else if !core::mem::replace(&mut node.reported, true) && report {
+                publish(FsEvent::Modified(path(nodes, index)));
+            }
+            return;
+        }
+        // a file replaced by a directory or the other way round
+        remove(nodes, index, report);
+    }
+
+    let Some(index) = nodes.iter().position(Option::is_none) else {
+        warn!("watch: too many files to track");
+        return;
+    };
+    nodes[index] = Some(Node {
+        name: entry.name,
+        parent: dir,
+        dir: entry.is_dir(),
+        first_cluster: entry.first_cluster,
+        size: entry.size,
+        modified: entry.modified,
+        stale: entry.is_dir(),
+        seen: true,
+        reported: true,
+    });
+    if report {
+        publish(FsEvent::Created(path(nodes, index)));
+    }
+}
+
+pub struct WatchBlockDevice<B, const ENTRIES: usize> {
+    base: B,
+    volume: Option<Volume>,
+    nodes: [Option<Node>; ENTRIES],
+    label: [u8; 11],
+    /// Whether changes are published, once what's on the disk is known
+    loaded: bool,
+    /// The whole filesystem has to be read again
+    full: bool,
+    /// The FAT changed, and with it the free space
+    fat: bool,
+    /// The root directory changed
+    root: bool,
+    /// Runs of changed clusters
+    runs: [Range<u32>; DIRTY_RUNS],
+    /// When to look at the changes the host made, if there are any
+    due: Option<Instant>,
+}
+
+impl<B: BlockDevice, const ENTRIES: usize> WatchBlockDevice<B, ENTRIES> {
+    /// Watches the filesystem on `base`, which is read as soon as the host is idle
+    pub const fn new(base: B) -> Self {
+        assert!(B::BLOCK_BYTES == BLOCK_SIZE);
+        assert!(ENTRIES <= u16::MAX as usize);
+        Self {
+            base,
+            volume: None,
+            nodes: [None; ENTRIES],
+            label: NO_LABEL,
+            loaded: false,
+            full: true,
+            fat: false,
+            root: false,
+            runs: [NO_RUN; DIRTY_RUNS],
+            due: Some(Instant::MIN),
+        }
+    }
+
+    /// Notes that the host wrote `blocks` blocks from `lba`
+    fn note(&mut self, lba: u32, blocks: usize) {
+        self.due = Some(Instant::now() + IDLE);
+        let Some(volume) = self.volume else {
+            self.full = true;
+            return;
+        };
+        let blocks = lba..lba.saturating_add(blocks as u32);
+        let overlaps = |range: Range<u32>| blocks.start < range.end && range.start < blocks.end;
+        if blocks.start < volume.fat().start {
+            self.full = true;
+        }
+        self.fat |= overlaps(volume.fat());
+        self.root |= overlaps(volume.root_dir());
+
+        let clusters = volume.clusters_in(blocks);
+        if clusters.is_empty() {
+            return;
+        }
+        let touches = |run: &&mut Range<u32>| {
+            run.start < run.end && run.start <= clusters.end && clusters.start <= run.end
+        };
+        if let Some(run) = self.runs.iter_mut().find(touches) {
+            run.start = run.start.min(clusters.start);
+            run.end = run.end.max(clusters.end);
+        } else if let Some(run) = self.runs.iter_mut().find(|run| run.start == run.end) {
+            *run = clusters;
+        } else {
+            self.full = true;
+        }
+    }
+
+    /// Whether the chain from `first_cluster` has changed clusters
+    async fn chain_changed(
+        &mut self,
+        volume: &Volume,
+        first_cluster: u32,
+    ) -> Result<bool, BlockDeviceError> {
+        let mut cluster = Some(first_cluster);
+        for _ in 0..volume.clusters() {
+            let Some(current) = cluster else {
+                break;
+            };
+            if self.runs.iter().any(|run| run.contains(&current)) {
+                return Ok(true);
+            }
+            cluster = volume.next_cluster(&mut self.base, current).await?;
+        }
+        Ok(false)
+    }
+
+    /// Reads the directory `dir` (the root directory if `None`) again, returning the volume
+    /// label if it's the root directory and has one
+    async fn read_dir(
+        &mut self,
+        volume: &Volume,
+        dir: Option<u16>,
+    ) -> Result<Option<[u8; 11]>, BlockDeviceError> {
+        let first_cluster = dir.map(|index| {
+            let node = self.nodes[index as usize].as_mut().unwrap();
+            node.stale = false;
+            node.first_cluster
+        });
+        for node in self.nodes.iter_mut().flatten() {
+            if node.parent == dir {
+                node.seen = false;
+            }
+        }
+
+        let nodes = &mut self.nodes;
+        let report = self.loaded;
+        let mut label = None;
+        volume
+            .read_dir(&mut self.base, first_cluster, |entry| {
+                if !entry.is_label() {
+                    update(nodes, dir, entry, report);
+                } else if dir.is_none() {
+                    label = Some(entry.name);
+                }
+            })
+            .await?;
+
+        let gone =
+            |node: &Option<Node>| matches!(node, Some(node) if node.parent == dir && !node.seen);
+        while let Some(index) = nodes.iter().position(gone) {
+            remove(nodes, index, report);
+        }
+        Ok(label)
+    }
+
+    /// Looks at what changed since the last scan
+    async fn scan(&mut self) -> Result<(), BlockDeviceError> {
+        self.due = None;
+        let reopen = core::mem::take(&mut self.full) || self.volume.is_none();
+        if reopen {
+            self.volume = match Volume::open(&mut self.base).await {
+                Ok(volume) => Some(volume),
+                Err(BlockDeviceError::MediumNotPresent) => None,
+                Err(e) => return Err(e),
+            };
+        }
+        let Some(volume) = self.volume else {
+            for index in 0..ENTRIES {
+                if matches!(self.nodes[index], Some(node) if node.parent.is_none()) {
+                    remove(&mut self.nodes, index, self.loaded);
+                }
+            }
+            self.runs = [NO_RUN; DIRTY_RUNS];
+            self.loaded = true;
+            SIGNAL.signal(DisplayState::FileSystem([0; 11], 0));
+            return Ok(());
+        };
+
+        for index in 0..ENTRIES {
+            let Some(node) = self.nodes[index] else {
+                continue;
+            };
+            let stale =
+                node.dir && (reopen || self.chain_changed(&volume, node.first_cluster).await?);
+            let node = self.nodes[index].as_mut().unwrap();
+            node.stale = stale;
+            node.reported = false;
+        }
+
+        let mut label_changed = false;
+        if core::mem::take(&mut self.root) || reopen {
+            let label = self.read_dir(&volume, None).await?.unwrap_or(NO_LABEL);
+            if label != self.label {
+                self.label = label;
+                label_changed = true;
+                if self.loaded {
+                    publish(FsEvent::LabelChanged(label));
+                }
+            }
+        }
+        let stale = |node: &Option<Node>| matches!(node, Some(node) if node.stale);
+        while let Some(index) = self.nodes.iter().position(stale) {
+            self.read_dir(&volume, Some(index as u16)).await?;
+        }
+
+        // files written in place, without their directory entries changing
+        if !reopen && self.runs.iter().any(|run| !run.is_empty()) {
+            for index in 0..ENTRIES {
+                let Some(node) = self.nodes[index] else {
+                    continue;
+                };
+                if node.dir || node.reported {
+                    continue;
+                }
+                if self.chain_changed(&volume, node.first_cluster).await? && self.loaded {
+                    publish(FsEvent::Modified(path(&self.nodes, index)));
+                }
+            }
+        }
+        self.runs = [NO_RUN; DIRTY_RUNS];
+
+        if core::mem::take(&mut self.fat) || label_changed || reopen {
+            let free = volume.free_clusters(&mut self.base).await?;
+            let cluster_bytes = volume.cluster_blocks() * BLOCK_SIZE as u32;
+            SIGNAL.signal(DisplayState::FileSystem(
+                self.label,
+                free.saturating_mul(cluster_bytes),
+            ));
+        }
+        self.loaded = true;
+        Ok(())
+    }
+
+    async fn look(&mut self) {
+        if let Err(e) = self.scan().await {
+            // looked at again after the next write or medium change
+            warn!("watch: can't read the filesystem: {}", e);
+            self.full = true;
+        }
+    }
+}
+
+impl<B: BlockDevice, const ENTRIES: usize> Wrapper for WatchBlockDevice<B, ENTRIES> {
+    type Base = B;
+
+    fn base(&self) -> &B {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut B {
+        &mut self.base
+    }
+
+    async fn write_block(&mut self, lba: u32, block: &[u8]) -> Result<(), BlockDeviceError> {
+        self.note(lba, 1);
+        self.base.write_block(lba, block).await
+    }
+
+    async fn write_blocks(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.note(lba, blocks.len() / BLOCK_SIZE);
+        self.base.write_blocks(lba, blocks).await
+    }
+
+    async fn write_blocks_fua(&mut self, lba: u32, blocks: &[u8]) -> Result<(), BlockDeviceError> {
+        self.note(lba, blocks.len() / BLOCK_SIZE);
+        self.base.write_blocks_fua(lba, blocks).await
+    }
+
+    async fn media_status(&mut self) -> MediaStatus {
+        let status = self.base.media_status().await;
+        match status {
+            MediaStatus::Present => {}
+            // a new medium is read like the first one
+            MediaStatus::Changed => {
+                self.loaded = false;
+                self.full = true;
+                self.due = Some(Instant::MIN);
+            }
+            status => return status,
+        }
+        status
+    }
+
+    async fn unmap(&mut self, lba: u32, count: u32) -> Result<(), BlockDeviceError> {
+        self.note(lba, count as usize);
+        self.base.unmap(lba, count).await
+    }
+
+    async fn flush(&mut self) -> Result<(), BlockDeviceError> {
+        let result = self.base.flush().await;
+        if self.due.is_some() {
+            self.look().await;
+        }
+        result
+    }
+
+    async fn write_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: &[u8],
+    ) -> Result<(), BlockDeviceError> {
+        self.note(lba, blocks.len() / BLOCK_SIZE);
+        self.base
+            .write_blocks_protected(lba, blocks, protection)
+            .await
+    }
+
+    async fn format(&mut self) -> Result<(), BlockDeviceError> {
+        self.full = true;
+        self.due = Some(Instant::MIN);
+        self.base.format().await
+    }
+
+    fn idle_deadline(&self) -> Option<Instant> {
+        match (self.due, self.base.idle_deadline()) {
+            (Some(due), Some(base)) => Some(due.min(base)),
+            (due, base) => due.or(base),
+        }
+    }
+
+    async fn idle(&mut self) {
+        let now = Instant::now();
+        if self.due.is_some_and(|due| now >= due) {
+            self.look().await;
+        }
+        if self.base.idle_deadline().is_some_and(|due| now >= due) {
+            self.base.idle().await;
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use core::future::Future;
 
 use defmt::warn;
+use embassy_futures::select::{select3, Either3};
 use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_time::{Instant, Timer};
 use embassy_usb::driver::Driver;
 use embedded_io_async::{Read, ReadExactError, Write};
 
@@ -42,6 +44,12 @@ pub trait Handler {
         &mut self,
         cb: &CommandBlock,
     ) -> impl Future<Output = Result<(), CommandError>>;
+    /// Called when the bus is suspended between commands, the host may cut the power next
+    fn suspended(&mut self) -> impl Future<Output = ()>;
+    /// When [`Handler::idle`] is next due, `None` if it isn't
+    fn idle_deadline(&self) -> Option<Instant>;
+    /// Called between commands once the idle deadline has passed
+    fn idle(&mut self) -> impl Future<Output = ()>;
 }
 
 pub struct BulkOnlyTransport<'d, D: Driver<'d>, M: RawMutex> {
@@ -57,16 +65,38 @@ impl<'d, D: Driver<'d>, M: RawMutex> BulkOnlyTransport<'d, D, M> {
         loop {
             // TODO: the error handling is non-existent here
             let mut buf = [0u8; CBW_LEN];
-            match self.endpoints.read_exact(&mut buf).await {
-                Ok(()) => {}
-                Err(ReadExactError::Other(e)) => {
+            let suspend_signal = self.endpoints.suspend_signal();
+            let deadline = handler.idle_deadline();
+            let idle = async {
+                match deadline {
+                    Some(deadline) => Timer::at(deadline).await,
+                    None => core::future::pending().await,
+                }
+            };
+            match select3(
+                self.endpoints.read_exact(&mut buf),
+                suspend_signal.wait(),
+                idle,
+            )
+            .await
+            {
+                Either3::First(Ok(())) => {}
+                Either3::First(Err(ReadExactError::Other(e))) => {
                     warn!("Transport error reading CBW {}", e);
                     continue;
                 }
-                Err(ReadExactError::UnexpectedEof) => {
+                Either3::First(Err(ReadExactError::UnexpectedEof)) => {
                     warn!("Unexpected EOF reading CBW");
                     continue;
                 }
+                Either3::Second(()) => {
+                    handler.suspended().await;
+                    continue;
+                }
+                Either3::Third(()) => {
+                    handler.idle().await;
+                    continue;
+                }
             };
             let cbw = CommandBlockWrapper::from_le_bytes(&buf).unwrap();
             let cb = CommandBlock {
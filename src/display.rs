@@ -0,0 +1,14 @@
+//! What the screen shows, signalled by the tasks that find it out
+
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+
+#[cfg(disk = "mirror")]
+use crate::block_devices::mirror::MirrorStatus;
+
+pub enum DisplayState {
+    Address([u8; 4]),
+    FileSystem([u8; 11], u32),
+    #[cfg(disk = "mirror")]
+    Mirror(MirrorStatus),
+}
+pub static SIGNAL: Signal<ThreadModeRawMutex, DisplayState> = Signal::new();
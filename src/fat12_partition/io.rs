@@ -0,0 +1,371 @@
+//! A range of blocks of a [`BlockDevice`] as the storage of a [`fatfs`] filesystem, so the
+//! firmware can work with the filesystem on any disk rather than only the RAM disk.
+//!
+//! fatfs is synchronous and block devices are async, so fatfs never waits for the device.
+//! It works on the blocks the adapter holds: blocks read from the device, and the blocks and
+//! runs of zeros fatfs has written, which are kept until they're written back. An operation
+//! that needs a block the adapter doesn't hold fails with [`IoError::Miss`], and
+//! [`BlockDeviceIo::run`] reads the block and runs the operation again from the start until it
+//! completes. [`BlockDeviceIo::write_back`] then writes what it wrote to the device, a few
+//! blocks at a time if need be. The device is only ever awaited, so the rest of the firmware
+//! carries on meanwhile.
+//!
+//! The adapter holds few blocks, which suits operations that touch few of them, such as
+//! mounting or formatting a filesystem: the FATs and root directory a format zeroes are kept
+//! as runs rather than blocks. An operation that needs more fails with [`IoError::Full`].
+
+use crate::scsi::{BlockDevice, BlockDeviceError};
+
+pub const BLOCK_SIZE: usize = 512;
+/// How many blocks read from the device are held
+const READ_LINES: usize = 4;
+/// How many written blocks are held. Formatting a FAT32 volume writes 8
+const WRITTEN_LINES: usize = 10;
+/// How many runs of zeros are held
+const ZERO_RUNS: usize = 4;
+/// Blocks of zeros written back at once
+const ZERO_CHUNK_BLOCKS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum IoError {
+    /// The device failed
+    Device(BlockDeviceError),
+    /// A read ended at the end of the volume
+    UnexpectedEof,
+    /// A write ended at the end of the volume
+    WriteZero,
+    /// A seek to before the start or past the end of the volume, or to a block the device
+    /// can't address
+    InvalidSeek,
+    /// The block at this LBA has to be read from the device first, see [`BlockDeviceIo::run`]
+    Miss(u32),
+    /// The operation read or wrote more blocks than the adapter can hold
+    Full,
+}
+
+impl fatfs::IoError for IoError {
+    fn is_interrupted(&self) -> bool {
+        false
+    }
+
+    fn new_unexpected_eof_error() -> Self {
+        IoError::UnexpectedEof
+    }
+
+    fn new_write_zero_error() -> Self {
+        IoError::WriteZero
+    }
+}
+
+/// The name of a fatfs error, for logging
+pub fn error_name<T>(e: &fatfs::Error<T>) -> &'static str {
+    match e {
+        fatfs::Error::Io(_) => "Io",
+        fatfs::Error::UnexpectedEof => "UnexpectedEof",
+        fatfs::Error::WriteZero => "WriteZero",
+        fatfs::Error::InvalidInput => "InvalidInput",
+        fatfs::Error::NotFound => "NotFound",
+        fatfs::Error::AlreadyExists => "AlreadyExists",
+        fatfs::Error::DirectoryIsNotEmpty => "DirectoryIsNotEmpty",
+        fatfs::Error::CorruptedFileSystem => "CorruptedFileSystem",
+        fatfs::Error::NotEnoughSpace => "NotEnoughSpace",
+        fatfs::Error::InvalidFileNameLength => "InvalidFileNameLength",
+        fatfs::Error::UnsupportedFileNameCharacter => "UnsupportedFileNameCharacter",
+        _ => "Unknown",
+    }
+}
+
+/// Fails on errors that aren't recovered
+pub(super) fn check(result: Result<(), BlockDeviceError>) -> Result<(), IoError> {
+    match result {
+        Err(e) if !e.is_recovered() => Err(IoError::Device(e)),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Line {
+    lba: Option<u32>,
+    data: [u8; BLOCK_SIZE],
+}
+
+impl Line {
+    const EMPTY: Self = Self {
+        lba: None,
+        data: [0; BLOCK_SIZE],
+    };
+}
+
+/// The blocks from `start` up to `end`, written as zeros
+#[derive(Clone, Copy)]
+struct Zeros {
+    start: u32,
+    end: u32,
+}
+
+impl Zeros {
+    const EMPTY: Self = Self { start: 0, end: 0 };
+}
+
+/// Where the adapter holds the contents of a block
+#[derive(Clone, Copy)]
+enum Held {
+    Written(usize),
+    Zeros,
+    Read(usize),
+}
+
+/// The `blocks` blocks of a device from `start`, read and written at any byte position
+pub struct BlockDeviceIo {
+    start: u32,
+    blocks: u32,
+    position: u64,
+    /// Blocks read from the device, kept for the operations run again
+    read: [Line; READ_LINES],
+    /// Blocks written, until they're written back
+    written: [Line; WRITTEN_LINES],
+    /// Runs of blocks written as zeros, until they're written back
+    zeros: [Zeros; ZERO_RUNS],
+}
+
+impl BlockDeviceIo {
+    pub const fn new(start: u32, blocks: u32) -> Self {
+        Self {
+            start,
+            blocks,
+            position: 0,
+            read: [Line::EMPTY; READ_LINES],
+            written: [Line::EMPTY; WRITTEN_LINES],
+            zeros: [Zeros::EMPTY; ZERO_RUNS],
+        }
+    }
+
+    /// Runs `operation` from the start of the volume until it no longer misses a block,
+    /// reading each block it misses from `device`. What an operation that missed a block wrote
+    /// is dropped, so each run starts from the same blocks
+    pub async fn run<B: BlockDevice, T>(
+        &mut self,
+        device: &mut B,
+        mut operation: impl FnMut(&mut Self) -> Result<T, fatfs::Error<IoError>>,
+    ) -> Result<T, fatfs::Error<IoError>> {
+        assert!(B::BLOCK_BYTES == BLOCK_SIZE);
+        loop {
+            self.position = 0;
+            match operation(self) {
+                Err(fatfs::Error::Io(IoError::Miss(lba))) => {
+                    self.written = [Line::EMPTY; WRITTEN_LINES];
+                    self.zeros = [Zeros::EMPTY; ZERO_RUNS];
+                    self.fetch(device, lba).await?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Writes up to `max_blocks` of the blocks written so far to `device`, flushing it once
+    /// they're all written. Returns whether they are
+    pub async fn write_back<B: BlockDevice>(
+        &mut self,
+        device: &mut B,
+        max_blocks: u32,
+    ) -> Result<bool, IoError> {
+        assert!(B::BLOCK_BYTES == BLOCK_SIZE);
+        // the copies read before may be out of date once blocks are written
+        self.read = [Line::EMPTY; READ_LINES];
+
+        // the runs first, blocks written over them since take their place
+        let zeros = [0; ZERO_CHUNK_BLOCKS * BLOCK_SIZE];
+        let mut left = max_blocks;
+        for run in &mut self.zeros {
+            while run.start < run.end {
+                if left == 0 {
+                    return Ok(false);
+                }
+                let count = (run.end - run.start)
+                    .min(ZERO_CHUNK_BLOCKS as u32)
+                    .min(left);
+                check(
+                    device
+                        .write_blocks(run.start, &zeros[..count as usize * BLOCK_SIZE])
+                        .await,
+                )?;
+                run.start += count;
+                left -= count;
+            }
+        }
+        for line in &mut self.written {
+            let Some(lba) = line.lba else {
+                continue;
+            };
+            if left == 0 {
+                return Ok(false);
+            }
+            check(device.write_block(lba, &line.data).await)?;
+            line.lba = None;
+            left -= 1;
+        }
+        check(device.flush().await)?;
+        Ok(true)
+    }
+
+    async fn fetch<B: BlockDevice>(&mut self, device: &mut B, lba: u32) -> Result<(), IoError> {
+        let line = self
+            .read
+            .iter_mut()
+            .find(|line| line.lba.is_none())
+            .ok_or(IoError::Full)?;
+        check(device.read_block(lba, &mut line.data).await)?;
+        line.lba = Some(lba);
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.blocks as u64 * BLOCK_SIZE as u64
+    }
+
+    /// The block at the position, and the offset of the position in it
+    fn block(&self) -> Result<(u32, usize), IoError> {
+        let lba = u32::try_from(self.position / BLOCK_SIZE as u64)
+            .ok()
+            .and_then(|block| self.start.checked_add(block))
+            .ok_or(IoError::InvalidSeek)?;
+        Ok((lba, (self.position % BLOCK_SIZE as u64) as usize))
+    }
+
+    /// How many of `len` bytes from the position are in the volume
+    fn available(&self, len: usize) -> usize {
+        (len as u64).min(self.len().saturating_sub(self.position)) as usize
+    }
+
+    /// Where the latest contents of `lba` are, if they're held
+    fn held(&self, lba: u32) -> Option<Held> {
+        if let Some(index) = self.written.iter().position(|line| line.lba == Some(lba)) {
+            return Some(Held::Written(index));
+        }
+        if self
+            .zeros
+            .iter()
+            .any(|run| (run.start..run.end).contains(&lba))
+        {
+            return Some(Held::Zeros);
+        }
+        self.read
+            .iter()
+            .position(|line| line.lba == Some(lba))
+            .map(Held::Read)
+    }
+
+    /// Adds `lba` to the runs of zeros, extending the run it follows if there is one
+    fn add_zeros(&mut self, lba: u32) -> Result<(), IoError> {
+        if let Some(run) = self
+            .zeros
+            .iter_mut()
+            .find(|run| run.start < run.end && run.end == lba)
+        {
+            run.end += 1;
+            return Ok(());
+        }
+        let run = self
+            .zeros
+            .iter_mut()
+            .find(|run| run.start == run.end)
+            .ok_or(IoError::Full)?;
+        *run = Zeros {
+            start: lba,
+            end: lba + 1,
+        };
+        Ok(())
+    }
+}
+
+impl fatfs::IoBase for BlockDeviceIo {
+    type Error = IoError;
+}
+
+impl fatfs::Read for BlockDeviceIo {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let len = self.available(buf.len());
+        if len == 0 {
+            return Ok(0);
+        }
+        let (lba, offset) = self.block()?;
+        let read = len.min(BLOCK_SIZE - offset);
+        let buf = &mut buf[..read];
+        match self.held(lba).ok_or(IoError::Miss(lba))? {
+            Held::Written(index) => {
+                buf.copy_from_slice(&self.written[index].data[offset..][..read])
+            }
+            Held::Zeros => buf.fill(0),
+            Held::Read(index) => buf.copy_from_slice(&self.read[index].data[offset..][..read]),
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl fatfs::Write for BlockDeviceIo {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        let len = self.available(buf.len());
+        if len == 0 {
+            return Ok(0);
+        }
+        let (lba, offset) = self.block()?;
+        let written = len.min(BLOCK_SIZE - offset);
+        let buf = &buf[..written];
+        let whole = written == BLOCK_SIZE;
+
+        let index = match self.held(lba) {
+            Some(Held::Written(index)) => index,
+            // as fatfs zeroes the FATs and directories a block at a time
+            Some(Held::Zeros) if whole && buf.iter().all(|&byte| byte == 0) => {
+                self.position += written as u64;
+                return Ok(written);
+            }
+            Some(Held::Read(_)) | None if whole && buf.iter().all(|&byte| byte == 0) => {
+                self.add_zeros(lba)?;
+                self.position += written as u64;
+                return Ok(written);
+            }
+            held => {
+                // the rest of a partly written block is kept
+                let data = match held {
+                    Some(Held::Read(index)) => self.read[index].data,
+                    None if !whole => return Err(IoError::Miss(lba)),
+                    _ => [0; BLOCK_SIZE],
+                };
+                let index = self
+                    .written
+                    .iter()
+                    .position(|line| line.lba.is_none())
+                    .ok_or(IoError::Full)?;
+                self.written[index] = Line {
+                    lba: Some(lba),
+                    data,
+                };
+                index
+            }
+        };
+        self.written[index].data[offset..offset + written].copy_from_slice(buf);
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    /// Nothing reaches the device before [`BlockDeviceIo::write_back`]
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+
+impl fatfs::Seek for BlockDeviceIo {
+    fn seek(&mut self, pos: fatfs::SeekFrom) -> Result<u64, IoError> {
+        let position = match pos {
+            fatfs::SeekFrom::Start(offset) => Some(offset),
+            fatfs::SeekFrom::End(offset) => self.len().checked_add_signed(offset),
+            fatfs::SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position
+            .filter(|&position| position <= self.len())
+            .ok_or(IoError::InvalidSeek)?;
+        Ok(self.position)
+    }
+}
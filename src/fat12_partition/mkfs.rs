@@ -0,0 +1,210 @@
+//! Makes a fresh filesystem on a disk: an MBR with one partition filling it, holding a FAT
+//! volume made by [`fatfs::format_volume`], which picks FAT12, FAT16 or FAT32 from its size.
+//!
+//! The volume is laid out in memory (see [`BlockDeviceIo`]) before anything is written, and
+//! [`Format`] then writes it a few blocks at a time, so a format can be spread between other
+//! work. The partition table is cleared first and only written once the volume is complete,
+//! so a format that's cut short leaves a blank disk rather than a broken filesystem.
+
+use fatfs::{FatType, FormatVolumeOptions, Read as _, Seek as _, SeekFrom, Write as _};
+
+use super::io::{check, BlockDeviceIo, IoError, BLOCK_SIZE};
+use crate::scsi::BlockDevice;
+
+/// Where the partition starts, right after the MBR
+const PARTITION_LBA: u32 = 1;
+/// Where the partition starts on disks of at least `ALIGNED_MIN_BLOCKS`: on a 1 MiB boundary,
+/// like other partitioning tools, which lines the volume up with the erase blocks of SD cards
+const ALIGNED_PARTITION_LBA: u32 = 2048;
+const ALIGNED_MIN_BLOCKS: u32 = 64 * 1024 * 1024 / BLOCK_SIZE as u32;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// Filesystems with fewer clusters are FAT12
+const FAT16_MIN_CLUSTERS: u32 = 4085;
+/// Filesystems with fewer clusters are FAT16
+const FAT32_MIN_CLUSTERS: u32 = 65525;
+const DIR_ENTRY_BYTES: u32 = 32;
+
+pub type Error = fatfs::Error<IoError>;
+
+fn u16_at(block: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([block[offset], block[offset + 1]])
+}
+
+fn u32_at(block: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap())
+}
+
+fn put_u32(block: &mut [u8], offset: usize, value: u32) {
+    block[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// The CHS address of `lba` in an MBR, with the usual 255 heads of 63 sectors and the
+/// largest address for blocks beyond it
+fn chs(lba: u32) -> [u8; 3] {
+    let cylinder = lba / (255 * 63);
+    if cylinder > 1023 {
+        return [0xfe, 0xff, 0xff];
+    }
+    let head = (lba / 63) % 255;
+    let sector = lba % 63 + 1;
+    [
+        head as u8,
+        ((cylinder >> 2) & 0xc0) as u8 | sector as u8,
+        cylinder as u8,
+    ]
+}
+
+/// The FAT type of the volume starting with `boot_sector`, which follows from its number of
+/// clusters
+fn fat_type(boot_sector: &[u8]) -> FatType {
+    let cluster_blocks = boot_sector[13] as u32;
+    let reserved_blocks = u16_at(boot_sector, 14) as u32;
+    let fats = boot_sector[16] as u32;
+    let root_blocks =
+        (u16_at(boot_sector, 17) as u32 * DIR_ENTRY_BYTES).div_ceil(BLOCK_SIZE as u32);
+    let blocks = match u16_at(boot_sector, 19) {
+        0 => u32_at(boot_sector, 32),
+        blocks => blocks as u32,
+    };
+    let fat_blocks = match u16_at(boot_sector, 22) {
+        0 => u32_at(boot_sector, 36),
+        fat_blocks => fat_blocks as u32,
+    };
+    let data_blocks = blocks.saturating_sub(reserved_blocks + fats * fat_blocks + root_blocks);
+    match data_blocks / cluster_blocks.max(1) {
+        clusters if clusters < FAT16_MIN_CLUSTERS => FatType::Fat12,
+        clusters if clusters < FAT32_MIN_CLUSTERS => FatType::Fat16,
+        _ => FatType::Fat32,
+    }
+}
+
+/// What's left to write of a format
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Step {
+    ClearMbr,
+    Volume,
+    Mbr,
+    Done,
+}
+
+/// A fresh filesystem laid out for a disk, which replaces whatever is on it once it's written
+pub struct Format {
+    fat_type: FatType,
+    volume: BlockDeviceIo,
+    mbr: [u8; BLOCK_SIZE],
+    step: Step,
+}
+
+impl Format {
+    /// Lays out an empty filesystem labelled `label` for `device`, without writing anything
+    pub async fn new<B: BlockDevice>(
+        device: &mut B,
+        label: &[u8; 11],
+        volume_id: u32,
+    ) -> Result<Self, Error> {
+        let blocks = device.block_count();
+        let partition_lba = if blocks >= ALIGNED_MIN_BLOCKS {
+            ALIGNED_PARTITION_LBA
+        } else {
+            PARTITION_LBA
+        };
+        let partition_blocks = blocks
+            .checked_sub(partition_lba)
+            .ok_or(Error::InvalidInput)?;
+
+        let mut volume = BlockDeviceIo::new(partition_lba, partition_blocks);
+        let fat_type = volume
+            .run(device, |volume| {
+                let options = FormatVolumeOptions::new()
+                    .bytes_per_sector(BLOCK_SIZE as u16)
+                    .total_sectors(partition_blocks)
+                    .volume_id(volume_id)
+                    .volume_label(*label);
+                fatfs::format_volume(&mut *volume, options)?;
+
+                // fatfs doesn't know the volume is in a partition, so the blocks before it
+                // (the hidden sectors) are fixed up in the boot sector, and in its backup on
+                // FAT32
+                let mut boot_sector = [0; BLOCK_SIZE];
+                volume.seek(SeekFrom::Start(0))?;
+                volume.read_exact(&mut boot_sector)?;
+                put_u32(&mut boot_sector, 28, partition_lba);
+                volume.seek(SeekFrom::Start(0))?;
+                volume.write_all(&boot_sector)?;
+                let fat_type = fat_type(&boot_sector);
+                if let FatType::Fat32 = fat_type {
+                    let backup = u16_at(&boot_sector, 50) as u64;
+                    volume.seek(SeekFrom::Start(backup * BLOCK_SIZE as u64))?;
+                    volume.write_all(&boot_sector)?;
+                }
+                Ok(fat_type)
+            })
+            .await?;
+
+        let system_id = match fat_type {
+            FatType::Fat12 => 0x01,
+            FatType::Fat16 if partition_blocks < 65536 => 0x04,
+            FatType::Fat16 => 0x06,
+            FatType::Fat32 => 0x0c,
+        };
+        let mut mbr = [0; BLOCK_SIZE];
+        put_u32(&mut mbr, 440, volume_id);
+        let entry = &mut mbr[446..462];
+        entry[1..4].copy_from_slice(&chs(partition_lba));
+        entry[4] = system_id;
+        entry[5..8].copy_from_slice(&chs(blocks - 1));
+        put_u32(entry, 8, partition_lba);
+        put_u32(entry, 12, partition_blocks);
+        mbr[510..].copy_from_slice(&MBR_SIGNATURE);
+
+        Ok(Self {
+            fat_type,
+            volume,
+            mbr,
+            step: Step::ClearMbr,
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// Writes up to `max_blocks` more of the filesystem to `device`, which has to be the one
+    /// it was laid out for. Returns whether it's all written
+    pub async fn write<B: BlockDevice>(
+        &mut self,
+        device: &mut B,
+        max_blocks: u32,
+    ) -> Result<bool, Error> {
+        match self.step {
+            Step::ClearMbr => {
+                check(device.write_block(0, &[0; BLOCK_SIZE]).await)?;
+                self.step = Step::Volume;
+            }
+            Step::Volume => {
+                if self.volume.write_back(device, max_blocks).await? {
+                    self.step = Step::Mbr;
+                }
+            }
+            Step::Mbr => {
+                check(device.write_block(0, &self.mbr).await)?;
+                check(device.flush().await)?;
+                self.step = Step::Done;
+            }
+            Step::Done => {}
+        }
+        Ok(self.step == Step::Done)
+    }
+}
+
+/// Replaces whatever is on `device` with an empty filesystem labelled `label` straight away,
+/// returning its FAT type
+pub async fn format<B: BlockDevice>(
+    device: &mut B,
+    label: &[u8; 11],
+    volume_id: u32,
+) -> Result<FatType, Error> {
+    let mut format = Format::new(device, label, volume_id).await?;
+    while !format.write(device, u32::MAX).await? {}
+    Ok(format.fat_type())
+}
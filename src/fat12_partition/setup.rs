@@ -1,9 +1,9 @@
-use crate::storage::Storage;
+use crate::storage::{Storage, BLOCKS, BLOCK_SIZE};
 
-static FS_DUMP: &[u8; 102400] = include_bytes!("../../dumps/linux_partitioned.dump");
+/// The disk image made by build.rs from the `IMAGE_DIR` directory
+pub const FS_IMAGE: &[u8; BLOCKS as usize * BLOCK_SIZE] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/disk_image.bin"));
 
 pub fn init(storage: &mut Storage) {
-    let bytes = storage.as_bytes_mut();
-
-    bytes[..FS_DUMP.len()].copy_from_slice(FS_DUMP);
+    storage.as_bytes_mut().copy_from_slice(FS_IMAGE);
 }
@@ -0,0 +1,413 @@
+//! Partition table parsing for MBR disks, including logical partitions in the EBR chain of an
+//! extended partition, and GPT disks behind a protective MBR.
+//!
+//! The GPT header and partition entry array are checked against their CRC32s. If the primary
+//! header at LBA 1 is damaged, the backup header in the last block of the disk is used instead.
+//!
+//! The disk is read a block at a time through its [`BlockDevice`], whose blocks have to be
+//! [`BLOCK_SIZE`] bytes.
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use defmt::{warn, Format};
+
+use crate::block_devices::BLOCK_SIZE;
+use crate::scsi::{BlockDevice, BlockDeviceError};
+
+/// Partitions beyond this are ignored
+pub const MAX_PARTITIONS: usize = 16;
+
+const MBR_ENTRIES: usize = 446;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_PROTECTIVE: u8 = 0xee;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// Guards against loops in a corrupt EBR chain
+const MAX_LOGICAL_PARTITIONS: usize = 64;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+const GPT_NAME_UNITS: usize = 36;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// A GPT GUID as stored on disk, the first three fields are little endian
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const UNUSED: Guid = Guid([0; 16]);
+    /// C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+    pub const EFI_SYSTEM: Guid = Guid([
+        0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9,
+        0x3b,
+    ]);
+    /// EBD0A0A2-B9E5-4433-87C0-68B6B72699C7, used by Windows and macOS for FAT and exFAT
+    pub const BASIC_DATA: Guid = Guid([
+        0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44, 0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99,
+        0xc7,
+    ]);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum PartitionType {
+    /// The system ID of an MBR entry
+    Mbr(u8),
+    /// The partition type GUID of a GPT entry
+    Gpt(Guid),
+}
+
+impl PartitionType {
+    /// Whether the partition could hold a FAT filesystem
+    pub fn may_be_fat(&self) -> bool {
+        match self {
+            PartitionType::Mbr(system_id) => {
+                matches!(system_id, 0x01 | 0x04 | 0x06 | 0x0b | 0x0c | 0x0e)
+            }
+            PartitionType::Gpt(guid) => *guid == Guid::BASIC_DATA || *guid == Guid::EFI_SYSTEM,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub struct DiskPartition {
+    pub partition_type: PartitionType,
+    /// First block of the partition
+    pub lba: u32,
+    /// Number of blocks in the partition
+    pub blocks: u32,
+    /// The MBR boot indicator, always false for GPT
+    pub bootable: bool,
+    /// The UTF-16 partition name of a GPT entry, zero padded. All zeros for MBR
+    pub name: [u16; GPT_NAME_UNITS],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum PartitionScheme {
+    Mbr,
+    Gpt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum PartitionTableError {
+    /// Block 0 doesn't end with the MBR signature
+    NoPartitionTable,
+    /// Neither the primary nor the backup GPT header and entry array are valid
+    InvalidGpt,
+    /// A partition or EBR lies outside of the disk
+    OutOfRange,
+    /// The disk couldn't be read
+    Io(BlockDeviceError),
+}
+
+/// The partitions found on a disk, in on-disk order with MBR logical partitions after the
+/// primary ones
+#[derive(Clone, Debug, Format)]
+pub struct PartitionTable {
+    pub scheme: PartitionScheme,
+    partitions: [Option<DiskPartition>; MAX_PARTITIONS],
+    len: usize,
+}
+
+impl PartitionTable {
+    fn new(scheme: PartitionScheme) -> Self {
+        Self {
+            scheme,
+            partitions: [None; MAX_PARTITIONS],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, partition: DiskPartition) {
+        if self.len == MAX_PARTITIONS {
+            warn!(
+                "more than {} partitions, ignoring {}",
+                MAX_PARTITIONS, partition
+            );
+            return;
+        }
+        self.partitions[self.len] = Some(partition);
+        self.len += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&DiskPartition> {
+        self.partitions.get(index)?.as_ref()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DiskPartition> {
+        self.partitions.iter().flatten()
+    }
+}
+
+/// Reads the partition table of `disk`
+pub async fn read_partitions<B: BlockDevice>(
+    disk: &mut B,
+) -> Result<PartitionTable, PartitionTableError> {
+    let disk_blocks = disk.block_count();
+    let mut mbr = [0; BLOCK_SIZE];
+    block(disk, 0, &mut mbr).await?;
+    if mbr[510..] != MBR_SIGNATURE {
+        return Err(PartitionTableError::NoPartitionTable);
+    }
+
+    if (0..4).any(|index| mbr_entry(&mbr, index).0 == MBR_PROTECTIVE) {
+        return read_gpt(disk, disk_blocks).await;
+    }
+
+    let mut table = PartitionTable::new(PartitionScheme::Mbr);
+    let mut extended_lba = None;
+    for index in 0..4 {
+        let (system_id, lba, blocks, bootable) = mbr_entry(&mbr, index);
+        match system_id {
+            0 => {}
+            _ if MBR_EXTENDED.contains(&system_id) => extended_lba = Some(lba),
+            _ => table.push(mbr_partition(
+                disk_blocks,
+                system_id,
+                lba,
+                blocks,
+                bootable,
+            )?),
+        }
+    }
+    // the logical partitions go after all of the primary ones
+    if let Some(extended_lba) = extended_lba {
+        read_ebr_chain(disk, disk_blocks, extended_lba, &mut table).await?;
+    }
+    Ok(table)
+}
+
+/// Follows the EBR chain of the extended partition at `extended_lba`. Each EBR describes one
+/// logical partition relative to itself and the next EBR relative to the extended partition
+async fn read_ebr_chain<B: BlockDevice>(
+    disk: &mut B,
+    disk_blocks: u32,
+    extended_lba: u32,
+    table: &mut PartitionTable,
+) -> Result<(), PartitionTableError> {
+    let mut ebr_lba = extended_lba;
+    let mut ebr = [0; BLOCK_SIZE];
+    for _ in 0..MAX_LOGICAL_PARTITIONS {
+        block(disk, ebr_lba, &mut ebr).await?;
+        if ebr[510..] != MBR_SIGNATURE {
+            warn!("EBR at {} has no signature", ebr_lba);
+            return Ok(());
+        }
+
+        let (system_id, lba, blocks, bootable) = mbr_entry(&ebr, 0);
+        if system_id != 0 {
+            let lba = ebr_lba
+                .checked_add(lba)
+                .ok_or(PartitionTableError::OutOfRange)?;
+            table.push(mbr_partition(
+                disk_blocks,
+                system_id,
+                lba,
+                blocks,
+                bootable,
+            )?);
+        }
+
+        let (next_id, next_lba, _, _) = mbr_entry(&ebr, 1);
+        if !MBR_EXTENDED.contains(&next_id) {
+            return Ok(());
+        }
+        let next_lba = extended_lba
+            .checked_add(next_lba)
+            .ok_or(PartitionTableError::OutOfRange)?;
+        if next_lba <= ebr_lba {
+            warn!("EBR at {} links backwards to {}", ebr_lba, next_lba);
+            return Ok(());
+        }
+        ebr_lba = next_lba;
+    }
+    warn!("EBR chain longer than {} entries", MAX_LOGICAL_PARTITIONS);
+    Ok(())
+}
+
+async fn read_gpt<B: BlockDevice>(
+    disk: &mut B,
+    disk_blocks: u32,
+) -> Result<PartitionTable, PartitionTableError> {
+    if let Some(table) = read_gpt_header(disk, disk_blocks, 1).await {
+        return table;
+    }
+    warn!("primary GPT is invalid, trying the backup");
+    let last_lba = disk_blocks.saturating_sub(1);
+    let table = read_gpt_header(disk, disk_blocks, last_lba).await;
+    table.ok_or(PartitionTableError::InvalidGpt)?
+}
+
+/// Reads the GPT whose header is at `header_lba`. Returns `None` if the header or entry array
+/// fail validation
+async fn read_gpt_header<B: BlockDevice>(
+    disk: &mut B,
+    disk_blocks: u32,
+    header_lba: u32,
+) -> Option<Result<PartitionTable, PartitionTableError>> {
+    let mut header = [0; BLOCK_SIZE];
+    match block(disk, header_lba, &mut header).await {
+        Ok(()) => {}
+        Err(PartitionTableError::OutOfRange) => return None,
+        Err(e) => return Some(Err(e)),
+    }
+    if &header[..8] != GPT_SIGNATURE {
+        return None;
+    }
+
+    let header_size = le_u32(&header[12..]) as usize;
+    if !(GPT_MIN_HEADER_SIZE..=BLOCK_SIZE).contains(&header_size) {
+        return None;
+    }
+    let mut digest = CRC32.digest();
+    digest.update(&header[..16]);
+    digest.update(&[0; 4]);
+    digest.update(&header[20..header_size]);
+    if digest.finalize() != le_u32(&header[16..]) || le_u64(&header[24..]) != header_lba as u64 {
+        return None;
+    }
+
+    let entries_lba = u32::try_from(le_u64(&header[72..])).ok()?;
+    let entry_count = le_u32(&header[80..]) as usize;
+    let entry_size = le_u32(&header[84..]) as usize;
+    if entry_size < GPT_MIN_ENTRY_SIZE || !entry_size.is_multiple_of(GPT_MIN_ENTRY_SIZE) {
+        return None;
+    }
+    let start = entries_lba as u64 * BLOCK_SIZE as u64;
+    let len = entry_count.checked_mul(entry_size)? as u64;
+    if start + len > disk_blocks as u64 * BLOCK_SIZE as u64 {
+        return None;
+    }
+    // the entry array can span many blocks, so it's checked as it's read and then read again
+    // an entry at a time
+    let mut digest = CRC32.digest();
+    let mut chunk = [0; BLOCK_SIZE];
+    let mut read = 0;
+    while read < len {
+        let n = (len - read).min(BLOCK_SIZE as u64) as usize;
+        if let Err(e) = read_at(disk, start + read, &mut chunk[..n]).await {
+            return Some(Err(e));
+        }
+        digest.update(&chunk[..n]);
+        read += n as u64;
+    }
+    if digest.finalize() != le_u32(&header[88..]) {
+        return None;
+    }
+
+    let mut table = PartitionTable::new(PartitionScheme::Gpt);
+    let mut entry = [0; GPT_MIN_ENTRY_SIZE];
+    for index in 0..entry_count {
+        let offset = start + (index * entry_size) as u64;
+        if let Err(e) = read_at(disk, offset, &mut entry).await {
+            return Some(Err(e));
+        }
+        let partition_type = Guid(entry[..16].try_into().unwrap());
+        if partition_type == Guid::UNUSED {
+            continue;
+        }
+
+        let first = le_u64(&entry[32..]);
+        let last = le_u64(&entry[40..]);
+        let (Ok(lba), Ok(last)) = (u32::try_from(first), u32::try_from(last)) else {
+            return Some(Err(PartitionTableError::OutOfRange));
+        };
+        if last < lba || last >= disk_blocks {
+            return Some(Err(PartitionTableError::OutOfRange));
+        }
+
+        let mut name = [0; GPT_NAME_UNITS];
+        for (unit, bytes) in name.iter_mut().zip(entry[56..128].chunks_exact(2)) {
+            *unit = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        table.push(DiskPartition {
+            partition_type: PartitionType::Gpt(partition_type),
+            lba,
+            blocks: last - lba + 1,
+            bootable: false,
+            name,
+        });
+    }
+    Some(Ok(table))
+}
+
+fn mbr_partition(
+    disk_blocks: u32,
+    system_id: u8,
+    lba: u32,
+    blocks: u32,
+    bootable: bool,
+) -> Result<DiskPartition, PartitionTableError> {
+    if lba as u64 + blocks as u64 > disk_blocks as u64 {
+        return Err(PartitionTableError::OutOfRange);
+    }
+    Ok(DiskPartition {
+        partition_type: PartitionType::Mbr(system_id),
+        lba,
+        blocks,
+        bootable,
+        name: [0; GPT_NAME_UNITS],
+    })
+}
+
+/// System ID, first LBA, block count and boot indicator of entry `index` of an MBR or EBR
+fn mbr_entry(block: &[u8], index: usize) -> (u8, u32, u32, bool) {
+    let entry = &block[MBR_ENTRIES + 16 * index..];
+    (
+        entry[4],
+        le_u32(&entry[8..]),
+        le_u32(&entry[12..]),
+        entry[0] == 0x80,
+    )
+}
+
+/// Reads `buf.len()` bytes of `disk` from byte `offset`
+async fn read_at<B: BlockDevice>(
+    disk: &mut B,
+    offset: u64,
+    buf: &mut [u8],
+) -> Result<(), PartitionTableError> {
+    let mut data = [0; BLOCK_SIZE];
+    let mut done = 0;
+    while done < buf.len() {
+        let position = offset + done as u64;
+        let lba = u32::try_from(position / BLOCK_SIZE as u64)
+            .map_err(|_| PartitionTableError::OutOfRange)?;
+        block(disk, lba, &mut data).await?;
+        let start = (position % BLOCK_SIZE as u64) as usize;
+        let n = (BLOCK_SIZE - start).min(buf.len() - done);
+        buf[done..done + n].copy_from_slice(&data[start..start + n]);
+        done += n;
+    }
+    Ok(())
+}
+
+/// Reads block `lba` of `disk`, ignoring recovered errors
+async fn block<B: BlockDevice>(
+    disk: &mut B,
+    lba: u32,
+    block: &mut [u8; BLOCK_SIZE],
+) -> Result<(), PartitionTableError> {
+    if lba >= disk.block_count() {
+        return Err(PartitionTableError::OutOfRange);
+    }
+    match disk.read_block(lba, block).await {
+        Err(e) if !e.is_recovered() => Err(PartitionTableError::Io(e)),
+        _ => Ok(()),
+    }
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
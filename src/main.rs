@@ -2,27 +2,30 @@
 #![no_main]
 
 use assign_resources::assign_resources;
-use defmt::info;
 use defmt_rtt as _;
 use embassy_executor::Spawner;
 use embassy_rp::peripherals;
 use embassy_rp::usb::Driver;
-use embassy_sync::{
-    blocking_mutex::raw::{NoopRawMutex, ThreadModeRawMutex},
-    signal::Signal,
-};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_usb::{Builder, Config};
 use panic_probe as _;
 
 mod scsi;
+#[cfg(disk = "ram")]
 use scsi::{BlockDevice, BlockDeviceError};
 mod usb_mass_storage;
 use usb_mass_storage::UsbMassStorage;
 mod bulk_only_transport;
 
+mod block_devices;
+mod display;
+
+#[cfg_attr(not(disk = "ram"), allow(dead_code))]
 mod storage;
+#[cfg(disk = "ram")]
 use storage::Storage;
 
+#[cfg_attr(not(disk = "ram"), allow(dead_code))]
 mod fat12_partition;
 mod screen;
 mod server;
@@ -32,16 +35,27 @@ use pico_usb_mass_storage as lib;
 #[cfg(feature = "wifi")]
 mod wifi;
 
+#[cfg(disk = "ram")]
 static mut STORAGE: Storage = Storage::new();
 
 const USB_PACKET_SIZE: u16 = 64; // 8,16,32,64
 const MAX_LUN: u8 = 0; // max 0x0F
-
-pub enum DisplayState {
-    Address([u8; 4]),
-    FileSystem([u8; 11], u32),
-}
-pub static SIGNAL: Signal<ThreadModeRawMutex, DisplayState> = Signal::new();
+/// Blocks held by the write-back cache, each taking 512 bytes of the main task
+#[cfg(feature = "cache")]
+const CACHE_LINES: usize = 8;
+/// The most blocks a write is atomic for, each journaled in a block of the disk. Longer write
+/// commands are applied in parts this long
+#[cfg(feature = "journal")]
+const JOURNAL_BLOCKS: usize = 32;
+/// Blocks the host can change before writes fail, until a snapshot is deleted or rolled back to
+#[cfg(feature = "snapshots")]
+const SNAPSHOT_BLOCKS: usize = 32;
+/// How long the snapshot button is held to take a snapshot
+#[cfg(feature = "snapshots")]
+const SNAPSHOT_HOLD: embassy_time::Duration = embassy_time::Duration::from_secs(2);
+/// Files and directories whose changes are published, each taking 32 bytes of the main task
+#[cfg(feature = "watch")]
+const WATCH_ENTRIES: usize = 64;
 
 assign_resources! {
     wifi: Wifi {
@@ -59,11 +73,28 @@ assign_resources! {
         sda: PIN_0,
         scl: PIN_1,
         i2c: I2C0
+    },
+    disk: Disk {
+        flash: FLASH
+    },
+    card: Card {
+        spi: SPI1,
+        clk: PIN_10,
+        mosi: PIN_11,
+        miso: PIN_12,
+        cs: PIN_13,
+        detect: PIN_14,
+        tx_dma: DMA_CH1,
+        rx_dma: DMA_CH2
+    },
+    button: Button {
+        pin: PIN_15
     }
 }
 
 #[embassy_executor::main]
 async fn main(#[allow(unused_variables)] spawner: Spawner) {
+    #[cfg(disk = "ram")]
     #[allow(static_mut_refs)]
     fat12_partition::init(unsafe { &mut STORAGE });
 
@@ -72,6 +103,10 @@ async fn main(#[allow(unused_variables)] spawner: Spawner) {
     let wifi = r.wifi;
     let usb = r.usb.usb;
     let display = r.display;
+    #[cfg(any(disk = "flash", disk = "mirror"))]
+    let disk = r.disk;
+    #[cfg(any(disk = "sd", disk = "mirror"))]
+    let card = r.card;
     let driver = Driver::new(usb, lib::Irqs);
 
     let mut config = Config::new(0xabcd, 0xabcd);
@@ -114,7 +149,35 @@ async fn main(#[allow(unused_variables)] spawner: Spawner) {
 
         //let mut blinky = Blinky::build(fw, clm, pwr, spi, spawner).await;
         //let server = server::echo::Server::new();
-        let server = server::okay::Server::new();
+        #[cfg(all(
+            feature = "statistics",
+            not(any(
+                feature = "encrypted",
+                feature = "faults",
+                feature = "mkfs",
+                feature = "snapshots",
+                disk = "overlay"
+            ))
+        ))]
+        let server = server::statistics::Server::new();
+        #[cfg(not(any(
+            feature = "statistics",
+            feature = "encrypted",
+            feature = "faults",
+            feature = "mkfs",
+            feature = "snapshots",
+            disk = "overlay"
+        )))]
+        compile_error!("wifi needs a server: enable statistics or a feature with commands");
+        // takes the commands of every feature that has them, see `server::control`
+        #[cfg(any(
+            feature = "encrypted",
+            feature = "faults",
+            feature = "mkfs",
+            feature = "snapshots",
+            disk = "overlay"
+        ))]
+        let server = server::control::Server::new();
         wifi::server::Server::build(fw, clm, pwr, spi, spawner, server).await
     };
 
@@ -143,14 +206,106 @@ async fn main(#[allow(unused_variables)] spawner: Spawner) {
     let product_id = b"100k of trunc   ";
     let product_revision = b"1.24";
 
-    let mut block_device = InMemoryBlockDevice;
+    #[cfg(disk = "ram")]
+    let block_device = &mut InMemoryBlockDevice;
+    #[cfg(disk = "flash")]
+    let block_device = flash_disk(disk.flash);
+    #[cfg(disk = "sd")]
+    let block_device = sd_card(card);
+    #[cfg(disk = "overlay")]
+    let block_device = overlay_disk();
+    #[cfg(disk = "packed")]
+    let block_device = packed_disk();
+    #[cfg(disk = "sparse")]
+    let block_device = sparse_disk();
+    #[cfg(disk = "ghost")]
+    let block_device = ghost_disk();
+    // flash mirrored onto the SD card, which is resynced whenever a card is inserted
+    #[cfg(disk = "mirror")]
+    let mut mirror =
+        block_devices::mirror::MirrorBlockDevice::new(flash_disk(disk.flash), sd_card(card));
+    #[cfg(disk = "mirror")]
+    let block_device = &mut mirror;
+
+    // starts locked, see `block_devices::encrypted` for the ways to unlock it
+    #[cfg(all(
+        feature = "encrypted",
+        not(all(feature = "partition", any(disk = "flash", disk = "mirror")))
+    ))]
+    let mut encrypted = block_devices::encrypted::EncryptedBlockDevice::new(block_device);
+    // with `partition`, unlocked at boot by a key in the first block of the flash disk
+    #[cfg(all(
+        feature = "encrypted",
+        feature = "partition",
+        any(disk = "flash", disk = "mirror")
+    ))]
+    let mut encrypted = provisioned_disk(block_device).await;
+    #[cfg(feature = "encrypted")]
+    let block_device = &mut encrypted;
+
+    // checksums every block, with the metadata taking the end of the disk
+    #[cfg(feature = "integrity")]
+    let mut integrity = block_devices::integrity::IntegrityBlockDevice::new(block_device, true);
+    #[cfg(feature = "integrity")]
+    let block_device = &mut integrity;
+
+    // write-back cache, flushed on SYNCHRONIZE CACHE, eject and USB suspend
+    #[cfg(feature = "cache")]
+    let mut cache = block_devices::cache::CacheBlockDevice::<_, CACHE_LINES>::new(block_device);
+    #[cfg(feature = "cache")]
+    let block_device = &mut cache;
+
+    // makes each write command atomic, with the journal taking the end of the disk
+    #[cfg(feature = "journal")]
+    let mut journal =
+        block_devices::journal::JournalBlockDevice::<_, JOURNAL_BLOCKS>::new(block_device);
+    #[cfg(feature = "journal")]
+    let block_device = &mut journal;
+
+    // taken and rolled back to with the button, none at boot as the pool would fill with
+    // every change the host makes
+    #[cfg(feature = "snapshots")]
+    let mut snapshots = {
+        static POOL: static_cell::ConstStaticCell<
+            [[u8; block_devices::BLOCK_SIZE]; SNAPSHOT_BLOCKS],
+        > = static_cell::ConstStaticCell::new([[0; block_devices::BLOCK_SIZE]; SNAPSHOT_BLOCKS]);
+        block_devices::snapshot::SnapshotBlockDevice::new(block_device, POOL.take())
+    };
+    #[cfg(feature = "snapshots")]
+    let block_device = &mut snapshots;
+    #[cfg(feature = "snapshots")]
+    defmt::unwrap!(spawner.spawn(snapshot_button(r.button)));
+
+    // scripted over the network or from FAULTS.TXT on the drive, see `block_devices::fault`
+    #[cfg(feature = "faults")]
+    let mut faults = block_devices::fault::FaultBlockDevice::new(block_device);
+    #[cfg(feature = "faults")]
+    let block_device = &mut faults;
+
+    // reads of TRACE.BIN on the drive return the access trace, see `scsi::trace`
+    #[cfg(feature = "trace")]
+    let mut trace_file = block_devices::trace::TraceFileBlockDevice::new(block_device);
+    #[cfg(feature = "trace")]
+    let block_device = &mut trace_file;
+
+    // formats a blank disk while the host is idle, see `block_devices::mkfs`
+    #[cfg(feature = "mkfs")]
+    let mut mkfs = block_devices::mkfs::MkfsBlockDevice::new(block_device, *b"PICO       ");
+    #[cfg(feature = "mkfs")]
+    let block_device = &mut mkfs;
+
+    // publishes the changes the host makes to the filesystem, see `block_devices::watch`
+    #[cfg(feature = "watch")]
+    let mut watch = block_devices::watch::WatchBlockDevice::<_, WATCH_ENTRIES>::new(block_device);
+    #[cfg(feature = "watch")]
+    let block_device = &mut watch;
 
     let mut usb_mass_storage = UsbMassStorage::<'_, '_, _, _, NoopRawMutex>::new(
         &mut usb_mass_storage_state,
         &mut builder,
         USB_PACKET_SIZE,
         MAX_LUN,
-        &mut block_device,
+        block_device,
         vendor_id,
         product_id,
         product_revision,
@@ -173,8 +328,245 @@ async fn main(#[allow(unused_variables)] spawner: Spawner) {
     }
 }
 
+#[cfg(any(disk = "flash", disk = "mirror"))]
+type FlashDisk = block_devices::flash::FlashBlockDevice<
+    embassy_rp::flash::Flash<
+        'static,
+        peripherals::FLASH,
+        embassy_rp::flash::Blocking,
+        { storage::flash_layout::FLASH_SIZE },
+    >,
+    { storage::flash_layout::DISK_FLASH_LENGTH as usize / embassy_rp::flash::ERASE_SIZE },
+    {
+        block_devices::flash::sector_count(
+            storage::flash_layout::DISK_FLASH_LENGTH as usize / embassy_rp::flash::ERASE_SIZE,
+            embassy_rp::flash::ERASE_SIZE,
+        )
+    },
+>;
+
+/// Mounts the flash disk in the region reserved by build.rs (see `DISK_SIZE`)
+#[cfg(any(disk = "flash", disk = "mirror"))]
+fn flash_disk(flash: peripherals::FLASH) -> &'static mut FlashDisk {
+    use storage::flash_layout::DISK_FLASH_OFFSET;
+
+    static FLASH_DISK: static_cell::StaticCell<FlashDisk> = static_cell::StaticCell::new();
+
+    let flash = embassy_rp::flash::Flash::new_blocking(flash);
+    let disk = defmt::unwrap!(FlashDisk::mount(flash, DISK_FLASH_OFFSET));
+    FLASH_DISK.init(disk)
+}
+
+/// The encrypted disk after the first block of `disk`, which is kept from the host for a key
+/// that unlocks the disk at boot (see `block_devices::encrypted::provisioned_key`). The key is
+/// provisioned by writing its block to LBA 0 from a build without `encrypted`
+#[cfg(all(
+    feature = "encrypted",
+    feature = "partition",
+    any(disk = "flash", disk = "mirror")
+))]
+async fn provisioned_disk<B: scsi::BlockDevice>(
+    mut disk: B,
+) -> block_devices::encrypted::EncryptedBlockDevice<block_devices::partition::PartitionBlockDevice<B>>
+{
+    use block_devices::encrypted::{provisioned_key, EncryptedBlockDevice};
+    use block_devices::partition::PartitionBlockDevice;
+    use scsi::BlockDevice as _;
+    use zeroize::Zeroize as _;
+
+    let key = {
+        let mut provisioning = defmt::unwrap!(PartitionBlockDevice::from_range(&mut disk, 0, 1));
+        provisioned_key(&mut provisioning).await
+    };
+    let blocks = disk.block_count();
+    let disk = defmt::unwrap!(PartitionBlockDevice::from_range(disk, 1, blocks - 1));
+    let mut encrypted = EncryptedBlockDevice::new(disk);
+    if let Ok(mut key) = key {
+        if let Err(e) = encrypted.unlock(&key).await {
+            defmt::warn!("provisioned key: {}", e);
+        }
+        key.zeroize();
+    }
+    encrypted
+}
+
+#[cfg(any(disk = "sd", disk = "mirror"))]
+type SdCard = block_devices::sd::SdCard<
+    embassy_rp::spi::Spi<'static, peripherals::SPI1, embassy_rp::spi::Async>,
+    embassy_rp::gpio::Output<'static, peripherals::PIN_13>,
+    embassy_rp::gpio::Input<'static, peripherals::PIN_14>,
+>;
+
+/// An SD card socket on SPI1 with its card detect switch to ground
+#[cfg(any(disk = "sd", disk = "mirror"))]
+fn sd_card(card: Card) -> &'static mut SdCard {
+    use embassy_rp::gpio::{Input, Level, Output, Pull};
+    use embassy_rp::spi::{Config, Spi};
+
+    static SD_CARD: static_cell::StaticCell<SdCard> = static_cell::StaticCell::new();
+
+    let mut config = Config::default();
+    config.frequency = block_devices::sd::INIT_FREQUENCY;
+    let spi = Spi::new(
+        card.spi,
+        card.clk,
+        card.mosi,
+        card.miso,
+        card.tx_dma,
+        card.rx_dma,
+        config,
+    );
+    let cs = Output::new(card.cs, Level::High);
+    let detect = Input::new(card.detect, Pull::Up);
+
+    SD_CARD.init(SdCard::new(spi, cs, detect, |spi, frequency| {
+        spi.set_frequency(frequency)
+    }))
+}
+
+/// Blocks of the image the host can modify before writes fail, 64KiB, most of the default
+/// 100KiB image
+#[cfg(disk = "overlay")]
+const OVERLAY_BLOCKS: usize = 128;
+
+#[cfg(disk = "overlay")]
+type OverlayDisk = block_devices::overlay::OverlayBlockDevice<
+    block_devices::image::ImageBlockDevice,
+    OVERLAY_BLOCKS,
+>;
+
+/// The disk image, read from flash with the host's changes kept in RAM
+#[cfg(disk = "overlay")]
+fn overlay_disk() -> &'static mut OverlayDisk {
+    static OVERLAY_DISK: static_cell::ConstStaticCell<OverlayDisk> =
+        static_cell::ConstStaticCell::new(OverlayDisk::new(
+            block_devices::image::ImageBlockDevice::new(fat12_partition::FS_IMAGE),
+        ));
+
+    OVERLAY_DISK.take()
+}
+
+/// The image packed by build.rs (see `PACKED_IMAGE`)
+#[cfg(disk = "packed")]
+fn packed_disk() -> &'static mut block_devices::packed::PackedBlockDevice {
+    use block_devices::packed::PackedBlockDevice;
+
+    static PACKED_IMAGE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/packed_image.bin"));
+    static PACKED_DISK: static_cell::StaticCell<PackedBlockDevice> = static_cell::StaticCell::new();
+
+    let disk = defmt::unwrap!(PackedBlockDevice::new(PACKED_IMAGE));
+    PACKED_DISK.init(disk)
+}
+
+/// A 1GiB drive backed by 96KiB of RAM
+#[cfg(disk = "sparse")]
+type SparseDisk = block_devices::sparse::SparseBlockDevice<192>;
+
+#[cfg(disk = "sparse")]
+fn sparse_disk() -> &'static mut SparseDisk {
+    const BLOCKS: u32 = 1024 * 1024 * 1024 / 512;
+    const SOFT_THRESHOLD: usize = 160;
+
+    static SPARSE_DISK: static_cell::ConstStaticCell<SparseDisk> =
+        static_cell::ConstStaticCell::new(SparseDisk::new(BLOCKS, SOFT_THRESHOLD));
+
+    SPARSE_DISK.take()
+}
+
+/// A 128MiB read-only drive made up from a table of files, see `block_devices::ghost`
+#[cfg(disk = "ghost")]
+fn ghost_disk() -> &'static mut block_devices::ghost::GhostFatBlockDevice {
+    use block_devices::ghost::{Contents, GhostFatBlockDevice, GhostFile};
+
+    const BLOCKS: u32 = 128 * 1024 * 1024 / 512;
+    const PATTERN_SIZE: u32 = 64 * 1024 * 1024;
+    const UPTIME_SIZE: u32 = 20;
+    const README: &[u8] = b"This drive is made up by the firmware as it's read.\r\n";
+
+    /// Each byte is its offset modulo 251, so the pattern doesn't line up with the blocks
+    fn pattern(offset: u32, buf: &mut [u8]) {
+        for (byte_offset, byte) in (offset..).zip(buf) {
+            *byte = (byte_offset % 251) as u8;
+        }
+    }
+
+    /// The seconds since boot when the file was read
+    fn uptime(offset: u32, buf: &mut [u8]) {
+        let mut text = [b' '; UPTIME_SIZE as usize];
+        text[UPTIME_SIZE as usize - 3..].copy_from_slice(b" s\n");
+        let mut seconds = embassy_time::Instant::now().as_secs();
+        for digit in text[..UPTIME_SIZE as usize - 3].iter_mut().rev() {
+            *digit = b'0' + (seconds % 10) as u8;
+            seconds /= 10;
+            if seconds == 0 {
+                break;
+            }
+        }
+        let offset = offset as usize;
+        buf.copy_from_slice(&text[offset..offset + buf.len()]);
+    }
+
+    static FILES: [GhostFile; 4] = [
+        GhostFile::new("README.TXT", None, Contents::Static(README)),
+        GhostFile::new("Pico", None, Contents::Directory),
+        GhostFile::new(
+            "Uptime.txt",
+            Some(1),
+            Contents::Generated {
+                size: UPTIME_SIZE,
+                read: uptime,
+            },
+        ),
+        GhostFile::new(
+            "Test pattern.bin",
+            None,
+            Contents::Generated {
+                size: PATTERN_SIZE,
+                read: pattern,
+            },
+        ),
+    ];
+    static GHOST_DISK: static_cell::StaticCell<GhostFatBlockDevice> =
+        static_cell::StaticCell::new();
+
+    match GhostFatBlockDevice::new(&FILES, BLOCKS, "PICO GHOST", 0x9c05_7fa7) {
+        Ok(disk) => GHOST_DISK.init(disk),
+        Err(e) => defmt::panic!("ghost FAT: {}", defmt::Debug2Format(&e)),
+    }
+}
+
+/// A push button to ground. A press rolls the drive back to the oldest snapshot, holding it
+/// for [`SNAPSHOT_HOLD`] takes a snapshot instead
+#[cfg(feature = "snapshots")]
+#[embassy_executor::task]
+async fn snapshot_button(button: Button) -> ! {
+    use block_devices::snapshot::{SnapshotCommand, COMMANDS};
+    use embassy_futures::select::{select, Either};
+    use embassy_rp::gpio::{Input, Pull};
+    use embassy_time::Timer;
+
+    let mut pin = Input::new(button.pin, Pull::Up);
+    loop {
+        pin.wait_for_falling_edge().await;
+        // debounce
+        Timer::after_millis(20).await;
+        if pin.is_high() {
+            continue;
+        }
+
+        let command = match select(pin.wait_for_high(), Timer::after(SNAPSHOT_HOLD)).await {
+            Either::First(()) => SnapshotCommand::RollbackOldest,
+            Either::Second(()) => SnapshotCommand::Take,
+        };
+        COMMANDS.send(command).await;
+        pin.wait_for_high().await;
+    }
+}
+
+#[cfg(disk = "ram")]
 struct InMemoryBlockDevice;
 
+#[cfg(disk = "ram")]
 impl BlockDevice for InMemoryBlockDevice {
     const BLOCK_BYTES: usize = storage::BLOCK_SIZE;
 
@@ -198,22 +590,10 @@ impl BlockDevice for InMemoryBlockDevice {
         let block = storage.block_mut(lba);
         block.as_bytes_mut().copy_from_slice(input);
 
-        #[allow(static_mut_refs)]
-        fat12_partition::log_fs(
-            storage.as_bytes_mut(),
-            storage::BLOCKS as _,
-            storage::BLOCK_SIZE as _,
-        );
-
-        for id in 0..4 {
-            let partition = fat12_partition::read_partition(storage.as_bytes_mut(), id);
-            info!("partition {}: {}", id, partition);
-        }
-
         Ok(())
     }
 
     fn block_count(&self) -> u32 {
-        storage::BLOCKS - 1
+        storage::BLOCKS
     }
 }
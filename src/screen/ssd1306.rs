@@ -1,5 +1,6 @@
 use core::fmt::Write;
 
+use embassy_futures::select::{select, Either};
 use embassy_rp::i2c::{self, Blocking, Config, I2c};
 use embassy_rp::peripherals::{I2C0, PIN_0, PIN_1};
 use embassy_time::Timer;
@@ -7,12 +8,19 @@ use ssd1306::mode::{DisplayConfig, TerminalMode};
 use ssd1306::prelude::{DisplayRotation, I2CInterface};
 use ssd1306::{size::DisplaySize128x32, I2CDisplayInterface, Ssd1306};
 
-use crate::SIGNAL;
+#[cfg(disk = "mirror")]
+use crate::block_devices::mirror::{Member, MirrorStatus};
+use crate::display::{DisplayState, SIGNAL};
+#[cfg(feature = "statistics")]
+use crate::scsi::statistics;
 
 pub struct Screen<'a> {
     address: [u8; 4],
     label: [u8; 11],
     freespace: u32,
+    /// Only shown for a mirrored disk
+    #[cfg(disk = "mirror")]
+    mirror: Option<MirrorStatus>,
     display: Ssd1306<I2CInterface<I2c<'a, I2C0, Blocking>>, DisplaySize128x32, TerminalMode>,
 }
 
@@ -32,20 +40,32 @@ impl<'a> Screen<'a> {
             address: [0; 4],
             label: [0; 11],
             freespace: 0,
+            #[cfg(disk = "mirror")]
+            mirror: None,
             display,
         }
     }
     pub async fn run(&mut self) -> ! {
         Timer::after_secs(5).await;
         loop {
-            match SIGNAL.wait().await {
-                crate::DisplayState::Address(address) => {
+            // redrawn every second for the throughput
+            let state = match select(SIGNAL.wait(), Timer::after_secs(1)).await {
+                Either::First(state) => Some(state),
+                Either::Second(()) => None,
+            };
+            match state {
+                None => {}
+                Some(DisplayState::Address(address)) => {
                     self.address.copy_from_slice(&address);
                 }
-                crate::DisplayState::FileSystem(label, freespace) => {
+                Some(DisplayState::FileSystem(label, freespace)) => {
                     self.label.copy_from_slice(&label);
                     self.freespace = freespace;
                 }
+                #[cfg(disk = "mirror")]
+                Some(DisplayState::Mirror(status)) => {
+                    self.mirror = Some(status);
+                }
             }
             self.display.clear().unwrap();
             let _ = writeln!(
@@ -58,7 +78,55 @@ impl<'a> Screen<'a> {
             });
             //let _ = write!(self.display, "Free: ");
             super::human_bytes::write(&mut self.display, self.freespace);
+            if !self.write_mirror() {
+                #[cfg(feature = "statistics")]
+                self.write_throughput();
+            }
             Timer::after_millis(100).await;
         }
     }
+
+    /// Writes the throughput of the first LUN on the last line
+    #[cfg(feature = "statistics")]
+    fn write_throughput(&mut self) {
+        if let Some(statistics) = statistics::snapshot(0) {
+            let _ = writeln!(self.display);
+            let _ = write!(self.display, "I/O ");
+            super::human_bytes::write(&mut self.display, statistics.bytes_per_second());
+            let _ = write!(self.display, "/s");
+        }
+    }
+
+    /// Writes the mirror's status on the last line, in place of the throughput, once it's known
+    #[cfg(disk = "mirror")]
+    fn write_mirror(&mut self) -> bool {
+        let Some(status) = self.mirror else {
+            return false;
+        };
+        let member = |member| match member {
+            Member::Primary => "flash",
+            Member::Secondary => "card",
+        };
+        let _ = writeln!(self.display);
+        let _ = match status {
+            MirrorStatus::InSync => write!(self.display, "Mirror OK"),
+            MirrorStatus::Degraded(failed) => {
+                write!(self.display, "DEGRADED: {}", member(failed))
+            }
+            MirrorStatus::Resyncing {
+                member: to,
+                percent,
+            } => {
+                write!(self.display, "Sync {} {}%", member(to), percent)
+            }
+            MirrorStatus::Failed => write!(self.display, "MIRROR FAILED"),
+        };
+        true
+    }
+
+    /// Only a mirrored disk has a status to show
+    #[cfg(not(disk = "mirror"))]
+    fn write_mirror(&mut self) -> bool {
+        false
+    }
 }
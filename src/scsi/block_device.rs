@@ -1,13 +1,123 @@
 use core::future::Future;
 
+use crc::{Crc, CRC_16_T10_DIF};
+
+use embassy_time::Instant;
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum BlockDeviceError {
     /// Error during writing; most likely value read back after write was wrong
     WriteError,
 
+    /// Data could not be read back from the medium, even after retrying
+    ReadError,
+
     /// Address is invalid or out of range
     InvalidAddress,
+
+    /// The medium can't be accessed right now and the cause is unknown
+    NotReady,
+
+    /// The medium is spinning up/initialising and will be accessible shortly
+    BecomingReady,
+
+    /// There is no medium loaded (e.g. the card has been removed from the socket)
+    MediumNotPresent,
+
+    /// The medium refuses writes (e.g. a write-protect switch or a read-only image)
+    WriteProtected,
+
+    /// There is no room left to store the written data (e.g. a full RAM delta or
+    /// provisioning pool). Nothing was written
+    SpaceAllocationFailed,
+
+    /// The hardware behind the device has failed. `fru` identifies the failing
+    /// component (field replaceable unit) in a vendor specific way, 0 if unknown
+    HardwareFailure { fru: u8 },
+
+    /// The underlying hardware didn't respond in time
+    Timeout,
+
+    /// The operation succeeded but only after retrying. The data is valid and the
+    /// command completes successfully, the error is only reported in the sense data
+    RecoveredWithRetries,
+
+    /// The medium is locked and can't be accessed until it's unlocked with the right key
+    AccessNotAuthorized,
+
+    /// The device doesn't support the requested operation
+    Unsupported,
+
+    /// The write succeeded but took the allocated blocks of a thin provisioned device past
+    /// its soft threshold. The host is told with a unit attention on its next command
+    SoftThresholdReached,
+
+    /// The operation succeeded but a redundant device (e.g. a mirror) lost one of its members
+    /// and carries on without it. The host is told with a unit attention on its next command
+    Degraded,
+}
+
+impl BlockDeviceError {
+    /// Returns true if the operation that produced this error actually completed
+    pub fn is_recovered(&self) -> bool {
+        matches!(
+            self,
+            BlockDeviceError::RecoveredWithRetries
+                | BlockDeviceError::SoftThresholdReached
+                | BlockDeviceError::Degraded
+        )
+    }
+}
+
+/// Whether a medium is loaded, as reported by [`BlockDevice::media_status`]
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MediaStatus {
+    /// A medium is loaded and hasn't changed since the last poll
+    Present,
+
+    /// No medium is loaded
+    Absent,
+
+    /// A medium is loaded but is locked, see [`BlockDevice::unlock`]
+    Locked,
+
+    /// A medium is loaded but can't be accessed yet (e.g. while it spins up)
+    BecomingReady,
+
+    /// The medium was swapped or resized since the last poll. `block_count` already
+    /// reflects the new medium
+    Changed,
+}
+
+/// Bytes of T10 protection information per block: a CRC16 guard over the block, an
+/// application tag and a reference tag (the LBA for type 1 protection), all big endian
+pub const PROTECTION_INFORMATION_BYTES: usize = 8;
+
+/// The CRC16 guard of protection information, over the block
+pub const GUARD_CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_T10_DIF);
+
+/// Block usage of a thin provisioned device, see [`BlockDevice::provisioning`]. Reported to the
+/// host in the Logical Block Provisioning log page
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Provisioning {
+    /// Blocks backed by storage
+    pub used: u32,
+    /// Blocks that can still be allocated
+    pub available: u32,
+    /// Thresholds are set in multiples of 2^`threshold_exponent` blocks
+    pub threshold_exponent: u8,
+}
+
+/// Settings of a device with a volatile cache, see [`BlockDevice::caching`]. These are the WCE
+/// and RCD bits of the SCSI caching mode page
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Caching {
+    /// Writes complete once they're in the cache and reach the medium later
+    pub write_cache_enabled: bool,
+    /// Reads always come from the medium and nothing is read ahead
+    pub read_cache_disable: bool,
 }
 
 pub trait BlockDevice {
@@ -29,6 +139,570 @@ pub trait BlockDevice {
         block: &[u8],
     ) -> impl Future<Output = Result<(), BlockDeviceError>>;
 
-    /// Get the maxium valid lba (logical block address)
+    /// Read the consecutive blocks starting at `lba` into `blocks`, whose length is a multiple
+    /// of `BLOCK_BYTES`. Devices that transfer several blocks faster than one at a time
+    /// should override this
+    fn read_blocks(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async move {
+            let mut result = Ok(());
+            for (i, block) in blocks.chunks_exact_mut(Self::BLOCK_BYTES).enumerate() {
+                match self.read_block(lba + i as u32, block).await {
+                    Err(e) if e.is_recovered() => result = Err(e),
+                    r => r?,
+                }
+            }
+            result
+        }
+    }
+
+    /// Write `blocks`, whose length is a multiple of `BLOCK_BYTES`, to the consecutive blocks
+    /// starting at `lba`. Devices that transfer several blocks faster than one at a time
+    /// should override this
+    fn write_blocks(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async move {
+            let mut result = Ok(());
+            for (i, block) in blocks.chunks_exact(Self::BLOCK_BYTES).enumerate() {
+                match self.write_block(lba + i as u32, block).await {
+                    Err(e) if e.is_recovered() => result = Err(e),
+                    r => r?,
+                }
+            }
+            result
+        }
+    }
+
+    /// Write `blocks` like [`BlockDevice::write_blocks`], only completing once they're on the
+    /// medium rather than in a volatile cache (force unit access). Defaults to writing the
+    /// blocks and then flushing the whole device
+    fn write_blocks_fua(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async move {
+            let result = self.write_blocks(lba, blocks).await;
+            if matches!(result, Err(e) if !e.is_recovered()) {
+                return result;
+            }
+            match self.flush().await {
+                Err(e) if !e.is_recovered() => Err(e),
+                _ => result,
+            }
+        }
+    }
+
+    /// Get the number of blocks on the current medium (i.e. the maximum valid lba + 1).
+    /// This may change at runtime, in which case `media_status` must report
+    /// [`MediaStatus::Changed`] on the next poll
     fn block_count(&self) -> u32;
+
+    /// Whether writes to the medium always fail with [`BlockDeviceError::WriteProtected`],
+    /// which the host is told in the WP bit of MODE SENSE so it mounts the medium read-only.
+    /// `false` by default
+    fn write_protected(&self) -> bool {
+        false
+    }
+
+    /// Report whether the medium is loaded or has changed. Polled by the SCSI layer on
+    /// TEST UNIT READY and READ CAPACITY. Devices with fixed media can rely on the default
+    fn media_status(&mut self) -> impl Future<Output = MediaStatus> {
+        async { MediaStatus::Present }
+    }
+
+    /// Thin provisioning state, `None` (the default) for devices with every block backed by
+    /// storage. Thin provisioned devices are advertised to the host, which then releases
+    /// blocks it no longer needs with [`BlockDevice::unmap`]
+    fn provisioning(&self) -> Option<Provisioning> {
+        None
+    }
+
+    /// Release the `count` blocks starting at `lba`, which read as zeros afterwards. Only
+    /// called on devices that report [`BlockDevice::provisioning`]
+    fn unmap(
+        &mut self,
+        _lba: u32,
+        _count: u32,
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async { Ok(()) }
+    }
+
+    /// Unlock a locked medium with `key`. A wrong key fails with
+    /// [`BlockDeviceError::AccessNotAuthorized`], devices without locking with
+    /// [`BlockDeviceError::Unsupported`] (the default)
+    fn unlock(&mut self, _key: &[u8]) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async { Err(BlockDeviceError::Unsupported) }
+    }
+
+    /// Lock the medium until it's unlocked again. Does nothing on devices without locking
+    fn lock(&mut self) {}
+
+    /// Write anything held in a volatile cache to the medium. Called on SYNCHRONIZE CACHE,
+    /// when the unit is stopped or ejected and when the USB bus is suspended. Devices without
+    /// a cache can rely on the default
+    fn flush(&mut self) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async { Ok(()) }
+    }
+
+    /// The cache settings, `None` (the default) for devices without a cache
+    fn caching(&self) -> Option<Caching> {
+        None
+    }
+
+    /// Change the cache settings, as asked by the host with MODE SELECT. Devices without a
+    /// cache fail with [`BlockDeviceError::Unsupported`] (the default)
+    fn set_caching(
+        &mut self,
+        _caching: Caching,
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async { Err(BlockDeviceError::Unsupported) }
+    }
+
+    /// Whether the device stores T10 type 1 protection information for each block, which
+    /// the host can then transfer along with the data. `false` by default
+    fn protection_information(&self) -> bool {
+        false
+    }
+
+    /// Read the blocks starting at `lba` into `blocks` like [`BlockDevice::read_blocks`], and
+    /// their protection information into `protection`, `PROTECTION_INFORMATION_BYTES` per
+    /// block. Only called on devices that report [`BlockDevice::protection_information`]
+    fn read_blocks_protected(
+        &mut self,
+        _lba: u32,
+        _blocks: &mut [u8],
+        _protection: &mut [u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async { Err(BlockDeviceError::Unsupported) }
+    }
+
+    /// Write `blocks` starting at `lba` like [`BlockDevice::write_blocks`], storing the
+    /// protection information in `protection` with them. Only called on devices that report
+    /// [`BlockDevice::protection_information`]
+    fn write_blocks_protected(
+        &mut self,
+        _lba: u32,
+        _blocks: &[u8],
+        _protection: &[u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async { Err(BlockDeviceError::Unsupported) }
+    }
+
+    /// The most blocks a transaction can write, `None` (the default) for devices without
+    /// transactions. The SCSI layer advertises this as the maximum transfer length, and splits
+    /// longer writes into several transactions
+    fn transaction_blocks(&self) -> Option<u32> {
+        None
+    }
+
+    /// Start a transaction: the writes until [`BlockDevice::end_transaction`] either all
+    /// reach the medium or none of them do, even across a power cut. The SCSI layer wraps
+    /// each WRITE command in one, or each part of one longer than
+    /// [`BlockDevice::transaction_blocks`]. Does nothing on devices without transactions
+    fn begin_transaction(&mut self) {}
+
+    /// Finish the transaction, applying its writes if `commit` is set and dropping them
+    /// otherwise. The writes are on the medium once a commit completes
+    fn end_transaction(
+        &mut self,
+        _commit: bool,
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async { Ok(()) }
+    }
+
+    /// Replace the contents of the medium with an empty filesystem, as asked by the host with
+    /// FORMAT UNIT. Devices that can't fail with [`BlockDeviceError::Unsupported`] (the
+    /// default)
+    fn format(&mut self) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        async { Err(BlockDeviceError::Unsupported) }
+    }
+
+    /// When the device next has work of its own to do, `None` (the default) if it has none.
+    /// Once that time comes without a command arriving first, the SCSI layer calls
+    /// [`BlockDevice::idle`]
+    fn idle_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Do the work that's due, between commands. It has to move [`BlockDevice::idle_deadline`]
+    /// on, or it's called again straight away. Does nothing by default
+    fn idle(&mut self) -> impl Future<Output = ()> {
+        async {}
+    }
+}
+
+/// A device that wraps another one, its base, and only changes some of what it does, such as
+/// a cache or a partition. It overrides the methods it changes and the rest are forwarded to
+/// the base, including methods added to [`BlockDevice`] later. Every wrapper is a
+/// [`BlockDevice`].
+///
+/// A wrapper that changes the blocks read or written has to override every method that
+/// transfers them: the single and multiple block ones, the FUA write and the protected ones.
+/// Methods added to [`BlockDevice`] have to be added here and to the [`BlockDevice`]
+/// implementation below too, or wrappers fall back on the [`BlockDevice`] default
+pub trait Wrapper {
+    type Base: BlockDevice;
+
+    fn base(&self) -> &Self::Base;
+
+    fn base_mut(&mut self) -> &mut Self::Base;
+
+    fn read_block(
+        &mut self,
+        lba: u32,
+        block: &mut [u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        self.base_mut().read_block(lba, block)
+    }
+
+    fn write_block(
+        &mut self,
+        lba: u32,
+        block: &[u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        self.base_mut().write_block(lba, block)
+    }
+
+    fn read_blocks(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        self.base_mut().read_blocks(lba, blocks)
+    }
+
+    fn write_blocks(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        self.base_mut().write_blocks(lba, blocks)
+    }
+
+    fn write_blocks_fua(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        self.base_mut().write_blocks_fua(lba, blocks)
+    }
+
+    fn block_count(&self) -> u32 {
+        self.base().block_count()
+    }
+
+    fn write_protected(&self) -> bool {
+        self.base().write_protected()
+    }
+
+    fn media_status(&mut self) -> impl Future<Output = MediaStatus> {
+        self.base_mut().media_status()
+    }
+
+    fn provisioning(&self) -> Option<Provisioning> {
+        self.base().provisioning()
+    }
+
+    fn unmap(
+        &mut self,
+        lba: u32,
+        count: u32,
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        self.base_mut().unmap(lba, count)
+    }
+
+    fn unlock(&mut self, key: &[u8]) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        self.base_mut().unlock(key)
+    }
+
+    fn lock(&mut self) {
+        self.base_mut().lock()
+    }
+
+    fn flush(&mut self) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        self.base_mut().flush()
+    }
+
+    fn caching(&self) -> Option<Caching> {
+        self.base().caching()
+    }
+
+    fn set_caching(
+        &mut self,
+        caching: Caching,
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        self.base_mut().set_caching(caching)
+    }
+
+    fn protection_information(&self) -> bool {
+        self.base().protection_information()
+    }
+
+    fn read_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        protection: &mut [u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        self.base_mut()
+            .read_blocks_protected(lba, blocks, protection)
+    }
+
+    fn write_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: &[u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        self.base_mut()
+            .write_blocks_protected(lba, blocks, protection)
+    }
+
+    fn transaction_blocks(&self) -> Option<u32> {
+        self.base().transaction_blocks()
+    }
+
+    fn begin_transaction(&mut self) {
+        self.base_mut().begin_transaction()
+    }
+
+    fn end_transaction(
+        &mut self,
+        commit: bool,
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        self.base_mut().end_transaction(commit)
+    }
+
+    fn format(&mut self) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        self.base_mut().format()
+    }
+
+    fn idle_deadline(&self) -> Option<Instant> {
+        self.base().idle_deadline()
+    }
+
+    fn idle(&mut self) -> impl Future<Output = ()> {
+        self.base_mut().idle()
+    }
+}
+
+impl<W: Wrapper> BlockDevice for W {
+    const BLOCK_BYTES: usize = W::Base::BLOCK_BYTES;
+
+    fn read_block(
+        &mut self,
+        lba: u32,
+        block: &mut [u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        Wrapper::read_block(self, lba, block)
+    }
+
+    fn write_block(
+        &mut self,
+        lba: u32,
+        block: &[u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        Wrapper::write_block(self, lba, block)
+    }
+
+    fn read_blocks(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        Wrapper::read_blocks(self, lba, blocks)
+    }
+
+    fn write_blocks(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        Wrapper::write_blocks(self, lba, blocks)
+    }
+
+    fn write_blocks_fua(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        Wrapper::write_blocks_fua(self, lba, blocks)
+    }
+
+    fn block_count(&self) -> u32 {
+        Wrapper::block_count(self)
+    }
+
+    fn write_protected(&self) -> bool {
+        Wrapper::write_protected(self)
+    }
+
+    fn media_status(&mut self) -> impl Future<Output = MediaStatus> {
+        Wrapper::media_status(self)
+    }
+
+    fn provisioning(&self) -> Option<Provisioning> {
+        Wrapper::provisioning(self)
+    }
+
+    fn unmap(
+        &mut self,
+        lba: u32,
+        count: u32,
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        Wrapper::unmap(self, lba, count)
+    }
+
+    fn unlock(&mut self, key: &[u8]) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        Wrapper::unlock(self, key)
+    }
+
+    fn lock(&mut self) {
+        Wrapper::lock(self)
+    }
+
+    fn flush(&mut self) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        Wrapper::flush(self)
+    }
+
+    fn caching(&self) -> Option<Caching> {
+        Wrapper::caching(self)
+    }
+
+    fn set_caching(
+        &mut self,
+        caching: Caching,
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        Wrapper::set_caching(self, caching)
+    }
+
+    fn protection_information(&self) -> bool {
+        Wrapper::protection_information(self)
+    }
+
+    fn read_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &mut [u8],
+        protection: &mut [u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        Wrapper::read_blocks_protected(self, lba, blocks, protection)
+    }
+
+    fn write_blocks_protected(
+        &mut self,
+        lba: u32,
+        blocks: &[u8],
+        protection: &[u8],
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        Wrapper::write_blocks_protected(self, lba, blocks, protection)
+    }
+
+    fn transaction_blocks(&self) -> Option<u32> {
+        Wrapper::transaction_blocks(self)
+    }
+
+    fn begin_transaction(&mut self) {
+        Wrapper::begin_transaction(self)
+    }
+
+    fn end_transaction(
+        &mut self,
+        commit: bool,
+    ) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        Wrapper::end_transaction(self, commit)
+    }
+
+    fn format(&mut self) -> impl Future<Output = Result<(), BlockDeviceError>> {
+        Wrapper::format(self)
+    }
+
+    fn idle_deadline(&self) -> Option<Instant> {
+        Wrapper::idle_deadline(self)
+    }
+
+    fn idle(&mut self) -> impl Future<Output = ()> {
+        Wrapper::idle(self)
+    }
+}
+
+/// Lets devices that wrap another device, such as partitions and overlays, borrow it rather
+/// than own it
+impl<T: BlockDevice> Wrapper for &mut T {
+    type Base = T;
+
+    fn base(&self) -> &T {
+        self
+    }
+
+    fn base_mut(&mut self) -> &mut T {
+        self
+    }
+}
+
+/// Reads blocks and their protection information from `lba` on `device`, whose LBAs are
+/// `offset` blocks on from the ones the host sees, moving the reference tags back by `offset`
+pub(crate) async fn read_blocks_shifted<B: BlockDevice>(
+    device: &mut B,
+    lba: u32,
+    blocks: &mut [u8],
+    protection: &mut [u8],
+    offset: u32,
+) -> Result<(), BlockDeviceError> {
+    let result = device.read_blocks_protected(lba, blocks, protection).await;
+    if matches!(result, Err(e) if !e.is_recovered()) {
+        return result;
+    }
+    for pi in protection.chunks_exact_mut(PROTECTION_INFORMATION_BYTES) {
+        shift_reference_tag(pi, offset.wrapping_neg());
+    }
+    result
+}
+
+/// Writes blocks and their protection information to `lba` on `device`, whose LBAs are
+/// `offset` blocks on from the ones the host sees, moving the reference tags on by `offset`
+pub(crate) async fn write_blocks_shifted<B: BlockDevice>(
+    device: &mut B,
+    mut lba: u32,
+    blocks: &[u8],
+    protection: &[u8],
+    offset: u32,
+) -> Result<(), BlockDeviceError> {
+    let mut result = Ok(());
+    // the protection information of a few blocks at a time
+    let mut shifted = [0; 64 * PROTECTION_INFORMATION_BYTES];
+    let records = shifted.len() / PROTECTION_INFORMATION_BYTES;
+    for (blocks, protection) in blocks
+        .chunks(records * B::BLOCK_BYTES)
+        .zip(protection.chunks(records * PROTECTION_INFORMATION_BYTES))
+    {
+        let shifted = &mut shifted[..protection.len()];
+        shifted.copy_from_slice(protection);
+        for pi in shifted.chunks_exact_mut(PROTECTION_INFORMATION_BYTES) {
+            shift_reference_tag(pi, offset);
+        }
+        match device.write_blocks_protected(lba, blocks, shifted).await {
+            Err(e) if e.is_recovered() => result = Err(e),
+            r => r?,
+        }
+        lba += (blocks.len() / B::BLOCK_BYTES) as u32;
+    }
+    result
+}
+
+/// Adds `offset` to the reference tag of the protection information `pi`, which type 1
+/// protection sets to the LBA, unless it's the escape value that turns the host's checks off
+fn shift_reference_tag(pi: &mut [u8], offset: u32) {
+    if pi[2..4] == [0xff; 2] {
+        return;
+    }
+    let tag = u32::from_be_bytes([pi[4], pi[5], pi[6], pi[7]]);
+    pi[4..8].copy_from_slice(&tag.wrapping_add(offset).to_be_bytes());
 }
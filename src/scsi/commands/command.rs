@@ -6,7 +6,8 @@ use crate::scsi::{commands::*, enums::*, Error};
 pub enum Command {
     Inquiry(#[defmt(Debug2Format)] InquiryCommand),
     TestUnitReady(#[defmt(Debug2Format)] TestUnitReadyCommand),
-    ReadCapacity(#[defmt(Debug2Format)] ReadCapacity10Command), // FIXME: ReadCapacity16
+    ReadCapacity(#[defmt(Debug2Format)] ReadCapacity10Command),
+    ReadCapacity16(#[defmt(Debug2Format)] ReadCapacity16Command),
     ModeSense(#[defmt(Debug2Format)] ModeSenseXCommand),
     PreventAllowMediumRemoval(#[defmt(Debug2Format)] PreventAllowMediumRemovalCommand),
     RequestSense(#[defmt(Debug2Format)] RequestSenseCommand),
@@ -20,6 +21,9 @@ pub enum Command {
     ReadFormatCapacities(#[defmt(Debug2Format)] ReadFormatCapacitiesCommand),
     Verify(#[defmt(Debug2Format)] Verify10Command), // FIXME: Verify16?
     SynchronizeCache(#[defmt(Debug2Format)] SynchronizeCache10Command), // FIXME: SynchronizeCache16?
+    Unmap(#[defmt(Debug2Format)] UnmapCommand),
+    LogSense(#[defmt(Debug2Format)] LogSenseCommand),
+    Unlock(#[defmt(Debug2Format)] UnlockCommand),
 }
 
 impl Command {
@@ -34,6 +38,13 @@ impl Command {
             OpCode::Read10 => Ok(Command::Read((overlay::<Read10Command>(cbw)?).into())),
             OpCode::Read12 => Ok(Command::Read((overlay::<Read12Command>(cbw)?).into())),
             OpCode::ReadCapacity10 => Ok(Command::ReadCapacity(overlay(cbw)?)),
+            OpCode::ServiceActionIn16 => {
+                let command: ReadCapacity16Command = overlay(cbw)?;
+                if command.service_action() != ReadCapacity16Command::SERVICE_ACTION {
+                    return Err(Error::UnhandledOpCode);
+                }
+                Ok(Command::ReadCapacity16(command))
+            }
             OpCode::ReadFormatCapacities => Ok(Command::ReadFormatCapacities(overlay(cbw)?)),
             OpCode::Inquiry => Ok(Command::Inquiry(overlay(cbw)?)),
             OpCode::TestUnitReady => Ok(Command::TestUnitReady(overlay(cbw)?)),
@@ -43,8 +54,12 @@ impl Command {
             OpCode::ModeSense10 => Ok(Command::ModeSense(
                 (overlay::<ModeSense10Command>(cbw)?).into(),
             )),
-            OpCode::ModeSelect6 => Ok(Command::ModeSelect(overlay(cbw)?)),
-            OpCode::ModeSelect10 => Ok(Command::ModeSelect(overlay(cbw)?)),
+            OpCode::ModeSelect6 => Ok(Command::ModeSelect(
+                (overlay::<ModeSelect6Command>(cbw)?).into(),
+            )),
+            OpCode::ModeSelect10 => Ok(Command::ModeSelect(
+                (overlay::<ModeSelect10Command>(cbw)?).into(),
+            )),
             OpCode::PreventAllowMediumRemoval => {
                 Ok(Command::PreventAllowMediumRemoval(overlay(cbw)?))
             }
@@ -58,6 +73,9 @@ impl Command {
             OpCode::StartStopUnit => Ok(Command::StartStopUnit(overlay(cbw)?)),
             OpCode::Verify10 => Ok(Command::Verify(overlay(cbw)?)),
             OpCode::SynchronizeCache10 => Ok(Command::SynchronizeCache(overlay(cbw)?)),
+            OpCode::Unmap => Ok(Command::Unmap(overlay(cbw)?)),
+            OpCode::LogSense => Ok(Command::LogSense(overlay(cbw)?)),
+            OpCode::VendorUnlock => Ok(Command::Unlock(overlay(cbw)?)),
             _ => Err(Error::UnhandledOpCode),
         }
     }
@@ -0,0 +1,34 @@
+use overlay_macro::overlay;
+
+use crate::scsi::commands::Control;
+
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct LogSenseCommand {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub op_code: u8,
+
+    /// SP, asks for the parameters to be saved
+    #[overlay(bytes=1..=1, bits=0..=0)]
+    pub save_parameters: bool,
+
+    /// PC, threshold or cumulative values, current or default
+    #[overlay(bytes=2..=2, bits=6..=7)]
+    pub page_control: u8,
+
+    #[overlay(bytes=2..=2, bits=0..=5)]
+    pub page_code: u8,
+
+    #[overlay(bytes=3..=3, bits=0..=7)]
+    pub subpage_code: u8,
+
+    /// Only parameters with this code or above are returned
+    #[overlay(bytes=5..=6)]
+    pub parameter_pointer: u16,
+
+    #[overlay(bytes=7..=8)]
+    pub allocation_length: u16,
+
+    #[overlay(bytes=9..=9, nested)]
+    pub control: Control,
+}
@@ -13,6 +13,9 @@ pub use command_length::*;
 mod inquiry;
 pub use inquiry::*;
 
+mod log_sense;
+pub use log_sense::*;
+
 mod mode_select;
 pub use mode_select::*;
 
@@ -52,6 +55,12 @@ pub use synchronize_cache::*;
 mod test_unit_ready;
 pub use test_unit_ready::*;
 
+mod unlock;
+pub use unlock::*;
+
+mod unmap;
+pub use unmap::*;
+
 mod verify;
 pub use verify::*;
 
@@ -73,7 +73,6 @@ impl Default for ModeParameterHeader10 {
 impl ModeParameterHeader10 {
     /// Increase the relevant length fields to indicate the provided page follows this header
     /// can be called multiple times but be aware of the max length allocated by CBW
-    #[allow(dead_code)]
     pub fn increase_length_for_page(&mut self, page_code: PageCode) {
         self.set_mode_data_length(
             self.mode_data_length()
@@ -117,12 +116,25 @@ pub struct CachingModePage {
 
     #[overlay(bytes=2..=2, bits=0..=0)]
     pub read_cache_disable: bool,
+
+    #[overlay(bytes=12..=12, bits=5..=5)]
+    pub disable_read_ahead: bool,
+
+    #[overlay(bytes=13..=13, bits=0..=7)]
+    pub number_of_cache_segments: u8,
+
+    #[overlay(bytes=14..=15)]
+    pub cache_segment_size: u16,
+
+    /// Pads the page to its full length
+    #[overlay(bytes=16..=19)]
+    pub reserved: u32,
 }
 impl Default for CachingModePage {
     fn default() -> Self {
         let mut mode = Self::new();
         mode.set_page_code(PageCode::CachingModePage);
-        mode.set_page_length(Self::BYTE_LEN as u8);
+        mode.set_page_length(Self::BYTE_LEN as u8 - 2);
         mode.set_write_cache_enabled(false);
         mode.set_read_cache_disable(true);
         mode
@@ -1,11 +1,14 @@
 use overlay_macro::overlay;
 
-use crate::scsi::commands::Control;
+use crate::scsi::commands::{CommandLength, Control};
 
-#[overlay]
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub struct ModeSelectXCommand {
-    // TBD
+    /// Decides the format of the mode parameter header that starts the parameter list
+    pub command_length: CommandLength,
+    pub page_format: bool,
+    pub save_pages: bool,
+    pub parameter_list_length: u16,
 }
 
 #[overlay]
@@ -27,8 +30,13 @@ pub struct ModeSelect6Command {
     pub control: Control,
 }
 impl From<ModeSelect6Command> for ModeSelectXCommand {
-    fn from(_m: ModeSelect6Command) -> Self {
-        todo!()
+    fn from(m: ModeSelect6Command) -> Self {
+        Self {
+            command_length: CommandLength::C6,
+            page_format: m.page_format(),
+            save_pages: m.save_pages(),
+            parameter_list_length: m.parameter_list_length().into(),
+        }
     }
 }
 
@@ -51,7 +59,12 @@ pub struct ModeSelect10Command {
     pub control: Control,
 }
 impl From<ModeSelect10Command> for ModeSelectXCommand {
-    fn from(_m: ModeSelect10Command) -> Self {
-        todo!()
+    fn from(m: ModeSelect10Command) -> Self {
+        Self {
+            command_length: CommandLength::C10,
+            page_format: m.page_format(),
+            save_pages: m.save_pages(),
+            parameter_list_length: m.parameter_list_length(),
+        }
     }
 }
@@ -22,6 +22,8 @@ B) the default values.
 pub struct ModeSenseXCommand {
     pub command_length: CommandLength,
     pub page_control: PageControl,
+    pub page_code: u8,
+    pub allocation_length: u16,
 }
 
 #[overlay]
@@ -53,6 +55,8 @@ impl From<ModeSense6Command> for ModeSenseXCommand {
         Self {
             command_length: CommandLength::C6,
             page_control: m.page_control().unwrap(), // FIXME: error handling here and below
+            page_code: m.page_code(),
+            allocation_length: m.allocation_length().into(),
         }
     }
 }
@@ -89,6 +93,8 @@ impl From<ModeSense10Command> for ModeSenseXCommand {
         Self {
             command_length: CommandLength::C10,
             page_control: m.page_control().unwrap(),
+            page_code: m.page_code(),
+            allocation_length: m.allocation_length(),
         }
     }
 }
@@ -5,6 +5,8 @@ use overlay_macro::overlay;
 pub struct ReadXCommand {
     pub lba: u32,
     pub transfer_length: u32,
+    /// RDPROTECT, 0 for commands without the field
+    pub protect: u8,
 }
 
 #[overlay]
@@ -28,6 +30,7 @@ impl From<Read6Command> for ReadXCommand {
         Self {
             lba: r.lba(),
             transfer_length: r.transfer_length().into(),
+            protect: 0,
         }
     }
 }
@@ -68,6 +71,7 @@ impl From<Read10Command> for ReadXCommand {
         Self {
             lba: r.lba(),
             transfer_length: r.transfer_length().into(),
+            protect: r.rd_protect(),
         }
     }
 }
@@ -108,6 +112,7 @@ impl From<Read12Command> for ReadXCommand {
         Self {
             lba: r.lba(),
             transfer_length: r.transfer_length(),
+            protect: r.rd_protect(),
         }
     }
 }
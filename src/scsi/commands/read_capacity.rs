@@ -14,3 +14,24 @@ pub struct ReadCapacity10Command {
     #[overlay(bytes=9..=9, nested)]
     pub control: Control,
 }
+
+/// READ CAPACITY (16), the READ CAPACITY service action of SERVICE ACTION IN (16)
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct ReadCapacity16Command {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub op_code: u8,
+
+    #[overlay(bytes=1..=1, bits=0..=4)]
+    pub service_action: u8,
+
+    #[overlay(bytes=10..=13)]
+    pub allocation_length: u32,
+
+    #[overlay(bytes=15..=15, nested)]
+    pub control: Control,
+}
+
+impl ReadCapacity16Command {
+    pub const SERVICE_ACTION: u8 = 0x10;
+}
@@ -0,0 +1,19 @@
+use overlay_macro::overlay;
+
+use crate::scsi::commands::Control;
+
+/// Vendor specific: unlocks the medium with the key in the parameter list, or locks it when
+/// the parameter list is empty
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct UnlockCommand {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub op_code: u8,
+
+    /// Length in bytes of the key that follows
+    #[overlay(bytes=7..=8)]
+    pub parameter_list_length: u16,
+
+    #[overlay(bytes=9..=9, nested)]
+    pub control: Control,
+}
@@ -0,0 +1,23 @@
+use overlay_macro::overlay;
+
+use crate::scsi::commands::Control;
+
+#[overlay]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct UnmapCommand {
+    #[overlay(bytes=0..=0, bits=0..=7)]
+    pub op_code: u8,
+
+    #[overlay(bytes=1..=1, bits=0..=0)]
+    pub anchor: bool,
+
+    #[overlay(bytes=6..=6, bits=0..=4)]
+    pub group_number: u8,
+
+    /// Length in bytes of the parameter list (header and block descriptors) that follows
+    #[overlay(bytes=7..=8)]
+    pub parameter_list_length: u16,
+
+    #[overlay(bytes=9..=9, nested)]
+    pub control: Control,
+}
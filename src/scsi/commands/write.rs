@@ -6,6 +6,10 @@ use crate::scsi::commands::Control;
 pub struct WriteXCommand {
     pub lba: u32,
     pub transfer_length: u32,
+    /// WRPROTECT, 0 for commands without the field
+    pub protect: u8,
+    /// Force unit access: the blocks must reach the medium before the command completes
+    pub fua: bool,
 }
 
 #[overlay]
@@ -28,6 +32,8 @@ impl From<Write6Command> for WriteXCommand {
         Self {
             lba: w.lba(),
             transfer_length: w.transfer_length().into(),
+            protect: 0,
+            fua: false,
         }
     }
 }
@@ -67,6 +73,8 @@ impl From<Write10Command> for WriteXCommand {
         Self {
             lba: w.lba(),
             transfer_length: w.transfer_length().into(),
+            protect: w.wr_protect(),
+            fua: w.fua(),
         }
     }
 }
@@ -106,6 +114,8 @@ impl From<Write12Command> for WriteXCommand {
         Self {
             lba: w.lba(),
             transfer_length: w.transfer_length(),
+            protect: w.wr_protect(),
+            fua: w.fua(),
         }
     }
 }
@@ -18,6 +18,44 @@ pub enum AdditionalSenseCode {
     EraseFailure,
     /// ASC 0x21, ASCQ: 0x0 - LOGICAL BLOCK ADDRESS OUT OF RANGE
     LogicalBlockAddressOutOfRange,
+    /// ASC 0x11, ASCQ: 0x0 - UNRECOVERED READ ERROR
+    UnrecoveredReadError,
+    /// ASC 0x4, ASCQ: 0x0 - LOGICAL UNIT NOT READY, CAUSE NOT REPORTABLE
+    LogicalUnitNotReadyCauseNotReportable,
+    /// ASC 0x4, ASCQ: 0x1 - LOGICAL UNIT IS IN PROCESS OF BECOMING READY
+    LogicalUnitIsInProcessOfBecomingReady,
+    /// ASC 0x3A, ASCQ: 0x0 - MEDIUM NOT PRESENT
+    MediumNotPresent,
+    /// ASC 0x27, ASCQ: 0x0 - WRITE PROTECTED
+    WriteProtected,
+    /// ASC 0x44, ASCQ: 0x0 - INTERNAL TARGET FAILURE
+    InternalTargetFailure,
+    /// ASC 0x8, ASCQ: 0x1 - LOGICAL UNIT COMMUNICATION TIME-OUT
+    LogicalUnitCommunicationTimeOut,
+    /// ASC 0x17, ASCQ: 0x1 - RECOVERED DATA WITH RETRIES
+    RecoveredDataWithRetries,
+    /// ASC 0x28, ASCQ: 0x0 - NOT READY TO READY CHANGE, MEDIUM MAY HAVE CHANGED
+    MediumMayHaveChanged,
+    /// ASC 0x2A, ASCQ: 0x9 - CAPACITY DATA HAS CHANGED
+    CapacityDataHasChanged,
+    /// ASC 0x27, ASCQ: 0x7 - SPACE ALLOCATION FAILED WRITE PROTECT
+    SpaceAllocationFailedWriteProtect,
+    /// ASC 0x38, ASCQ: 0x7 - THIN PROVISIONING SOFT THRESHOLD REACHED
+    ThinProvisioningSoftThresholdReached,
+    /// ASC 0x74, ASCQ: 0x71 - LOGICAL UNIT ACCESS NOT AUTHORIZED
+    LogicalUnitAccessNotAuthorized,
+    /// ASC 0x10, ASCQ: 0x1 - LOGICAL BLOCK GUARD CHECK FAILED
+    LogicalBlockGuardCheckFailed,
+    /// ASC 0x10, ASCQ: 0x3 - LOGICAL BLOCK REFERENCE TAG CHECK FAILED
+    LogicalBlockReferenceTagCheckFailed,
+    /// ASC 0x26, ASCQ: 0x0 - INVALID FIELD IN PARAMETER LIST
+    InvalidFieldInParameterList,
+    /// ASC 0x1A, ASCQ: 0x0 - PARAMETER LIST LENGTH ERROR
+    ParameterListLengthError,
+    /// ASC 0x39, ASCQ: 0x0 - SAVING PARAMETERS NOT SUPPORTED
+    SavingParametersNotSupported,
+    /// ASC 0x5D, ASCQ: 0x0 - FAILURE PREDICTION THRESHOLD EXCEEDED
+    FailurePredictionThresholdExceeded,
 }
 
 #[allow(dead_code)]
@@ -32,6 +70,25 @@ impl AdditionalSenseCode {
             AdditionalSenseCode::WriteError => 12,
             AdditionalSenseCode::EraseFailure => 81,
             AdditionalSenseCode::LogicalBlockAddressOutOfRange => 33,
+            AdditionalSenseCode::UnrecoveredReadError => 17,
+            AdditionalSenseCode::LogicalUnitNotReadyCauseNotReportable => 4,
+            AdditionalSenseCode::LogicalUnitIsInProcessOfBecomingReady => 4,
+            AdditionalSenseCode::MediumNotPresent => 58,
+            AdditionalSenseCode::WriteProtected => 39,
+            AdditionalSenseCode::InternalTargetFailure => 68,
+            AdditionalSenseCode::LogicalUnitCommunicationTimeOut => 8,
+            AdditionalSenseCode::RecoveredDataWithRetries => 23,
+            AdditionalSenseCode::MediumMayHaveChanged => 40,
+            AdditionalSenseCode::CapacityDataHasChanged => 42,
+            AdditionalSenseCode::SpaceAllocationFailedWriteProtect => 39,
+            AdditionalSenseCode::ThinProvisioningSoftThresholdReached => 56,
+            AdditionalSenseCode::LogicalUnitAccessNotAuthorized => 116,
+            AdditionalSenseCode::LogicalBlockGuardCheckFailed => 16,
+            AdditionalSenseCode::LogicalBlockReferenceTagCheckFailed => 16,
+            AdditionalSenseCode::InvalidFieldInParameterList => 38,
+            AdditionalSenseCode::ParameterListLengthError => 26,
+            AdditionalSenseCode::SavingParametersNotSupported => 57,
+            AdditionalSenseCode::FailurePredictionThresholdExceeded => 93,
         }
     }
     /// Returns the ASCQ code for this variant
@@ -44,6 +101,25 @@ impl AdditionalSenseCode {
             AdditionalSenseCode::WriteError => 0,
             AdditionalSenseCode::EraseFailure => 0,
             AdditionalSenseCode::LogicalBlockAddressOutOfRange => 0,
+            AdditionalSenseCode::UnrecoveredReadError => 0,
+            AdditionalSenseCode::LogicalUnitNotReadyCauseNotReportable => 0,
+            AdditionalSenseCode::LogicalUnitIsInProcessOfBecomingReady => 1,
+            AdditionalSenseCode::MediumNotPresent => 0,
+            AdditionalSenseCode::WriteProtected => 0,
+            AdditionalSenseCode::InternalTargetFailure => 0,
+            AdditionalSenseCode::LogicalUnitCommunicationTimeOut => 1,
+            AdditionalSenseCode::RecoveredDataWithRetries => 1,
+            AdditionalSenseCode::MediumMayHaveChanged => 0,
+            AdditionalSenseCode::CapacityDataHasChanged => 9,
+            AdditionalSenseCode::SpaceAllocationFailedWriteProtect => 7,
+            AdditionalSenseCode::ThinProvisioningSoftThresholdReached => 7,
+            AdditionalSenseCode::LogicalUnitAccessNotAuthorized => 113,
+            AdditionalSenseCode::LogicalBlockGuardCheckFailed => 1,
+            AdditionalSenseCode::LogicalBlockReferenceTagCheckFailed => 3,
+            AdditionalSenseCode::InvalidFieldInParameterList => 0,
+            AdditionalSenseCode::ParameterListLengthError => 0,
+            AdditionalSenseCode::SavingParametersNotSupported => 0,
+            AdditionalSenseCode::FailurePredictionThresholdExceeded => 0,
         }
     }
     /// Returns the ASCQ code for this variant
@@ -56,6 +132,25 @@ impl AdditionalSenseCode {
             (12, 0) => Some(AdditionalSenseCode::WriteError),
             (81, 0) => Some(AdditionalSenseCode::EraseFailure),
             (33, 0) => Some(AdditionalSenseCode::LogicalBlockAddressOutOfRange),
+            (17, 0) => Some(AdditionalSenseCode::UnrecoveredReadError),
+            (4, 0) => Some(AdditionalSenseCode::LogicalUnitNotReadyCauseNotReportable),
+            (4, 1) => Some(AdditionalSenseCode::LogicalUnitIsInProcessOfBecomingReady),
+            (58, 0) => Some(AdditionalSenseCode::MediumNotPresent),
+            (39, 0) => Some(AdditionalSenseCode::WriteProtected),
+            (68, 0) => Some(AdditionalSenseCode::InternalTargetFailure),
+            (8, 1) => Some(AdditionalSenseCode::LogicalUnitCommunicationTimeOut),
+            (23, 1) => Some(AdditionalSenseCode::RecoveredDataWithRetries),
+            (40, 0) => Some(AdditionalSenseCode::MediumMayHaveChanged),
+            (42, 9) => Some(AdditionalSenseCode::CapacityDataHasChanged),
+            (39, 7) => Some(AdditionalSenseCode::SpaceAllocationFailedWriteProtect),
+            (56, 7) => Some(AdditionalSenseCode::ThinProvisioningSoftThresholdReached),
+            (116, 113) => Some(AdditionalSenseCode::LogicalUnitAccessNotAuthorized),
+            (16, 1) => Some(AdditionalSenseCode::LogicalBlockGuardCheckFailed),
+            (16, 3) => Some(AdditionalSenseCode::LogicalBlockReferenceTagCheckFailed),
+            (38, 0) => Some(AdditionalSenseCode::InvalidFieldInParameterList),
+            (26, 0) => Some(AdditionalSenseCode::ParameterListLengthError),
+            (57, 0) => Some(AdditionalSenseCode::SavingParametersNotSupported),
+            (93, 0) => Some(AdditionalSenseCode::FailurePredictionThresholdExceeded),
             _ => None,
         }
     }
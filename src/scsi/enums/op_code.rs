@@ -26,7 +26,13 @@ pub enum OpCode {
     Verify10 = 0x2F,
     SynchronizeCache10 = 0x35,
     ReadTocPmaAtip = 0x43,
+    Unmap = 0x42,
+    LogSense = 0x4D,
     ModeSelect10 = 0x55,
+    ServiceActionIn16 = 0x9E,
     Read12 = 0xA8,
     Write12 = 0xAA,
+
+    /// Vendor specific, see [`UnlockCommand`](crate::scsi::commands::UnlockCommand)
+    VendorUnlock = 0xC0,
 }
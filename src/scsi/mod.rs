@@ -1,7 +1,10 @@
-use defmt::{debug, error, info};
+use defmt::{debug, error, info, warn};
 use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_time::Instant;
 use embassy_usb::driver::Driver;
 use embedded_io_async::ReadExactError;
+use num_enum::TryFromPrimitive;
+use overlay::Overlay;
 
 use crate::{
     bulk_only_transport::{self, BulkOnlyTransport, CommandBlock, CommandError},
@@ -25,11 +28,71 @@ mod responses;
 mod error;
 use error::Error;
 
+#[cfg(feature = "statistics")]
+pub mod statistics;
+#[cfg(feature = "statistics")]
+use statistics::{CommandClass, CommandRecord};
+#[cfg(feature = "trace")]
+pub mod trace;
+
 use self::{
     commands::Command,
     responses::{InquiryResponse, RequestSenseResponse},
 };
 
+const LOG_SUPPORTED_PAGES: u8 = 0x00;
+#[cfg(feature = "statistics")]
+const LOG_WRITE_ERROR_COUNTER: u8 = 0x02;
+#[cfg(feature = "statistics")]
+const LOG_READ_ERROR_COUNTER: u8 = 0x03;
+#[cfg(feature = "statistics")]
+const LOG_GENERAL_STATISTICS: u8 = 0x19;
+const LOG_LOGICAL_BLOCK_PROVISIONING: u8 = 0x0c;
+/// Room for the longest log page, general statistics and performance
+const LOG_PAGE_BYTES: usize = 96;
+/// The DS bit of a log page header, its parameters can't be saved
+const LOG_DISABLE_SAVE: u8 = 0x80;
+/// Parameter control byte of a counter
+#[cfg(feature = "statistics")]
+const LOG_PARAMETER_COUNTER: u8 = 0x00;
+/// Parameter control byte of a binary format list
+const LOG_PARAMETER_LIST: u8 = 0x03;
+/// Scope of a provisioning resource count, the resources are dedicated to the logical unit
+const LOG_RESOURCE_DEDICATED: u8 = 0b01;
+
+const VPD_SUPPORTED_PAGES: u8 = 0x00;
+const VPD_BLOCK_LIMITS: u8 = 0xb0;
+const VPD_LOGICAL_BLOCK_PROVISIONING: u8 = 0xb2;
+
+const UNMAP_HEADER_BYTES: usize = 8;
+const UNMAP_DESCRIPTOR_BYTES: usize = 16;
+/// Room for the header and 16 block descriptors
+const UNMAP_PARAMETER_LIST_BYTES: usize = UNMAP_HEADER_BYTES + 16 * UNMAP_DESCRIPTOR_BYTES;
+
+/// Longest key accepted by the vendor specific UNLOCK command
+const UNLOCK_KEY_MAX_BYTES: usize = 64;
+
+/// Largest USB packet handled when transferring blocks with protection information
+const MAX_PACKET_SIZE: usize = 64;
+
+/// Where the sense key is in fixed format sense data
+#[cfg(feature = "statistics")]
+const SENSE_KEY_BYTE: usize = 2;
+#[cfg(feature = "statistics")]
+const SENSE_KEY_MASK: u8 = 0x0f;
+
+/// Asks MODE SENSE for every page
+const MODE_PAGES_ALL: u8 = 0x3f;
+const MODE_PAGE_CACHING: u8 = PageCode::CachingModePage as u8;
+const MODE_PAGE_CODE_MASK: u8 = 0x3f;
+/// Room for the longer header, a long LBA block descriptor and the caching mode page
+const MODE_SELECT_PARAMETER_LIST_BYTES: usize = 64;
+/// What devices without a cache report, matching the default caching mode page
+const NO_CACHE: Caching = Caching {
+    write_cache_enabled: false,
+    read_cache_disable: true,
+};
+
 pub struct Scsi<'d, 'bd, B: Driver<'d>, BD: BlockDevice, M: RawMutex> {
     transport: BulkOnlyTransport<'d, B, M>,
     inquiry_response: InquiryResponse,
@@ -68,6 +131,7 @@ impl<'d, 'bd, B: Driver<'d>, BD: BlockDevice, M: RawMutex> Scsi<'d, 'bd, B, BD,
         inquiry_response.set_product_revision_level(product_revision_level);
 
         inquiry_response.set_version(SpcVersion::Spc2); // we are compliant (???)
+        inquiry_response.set_protect(block_device.protection_information());
 
         Self {
             transport: BulkOnlyTransport::new(endpoints),
@@ -80,6 +144,9 @@ impl<'d, 'bd, B: Driver<'d>, BD: BlockDevice, M: RawMutex> Scsi<'d, 'bd, B, BD,
 
     pub async fn run(&mut self) -> ! {
         let mut handler = BulkHandler {
+            block_count: self.block_device.block_count(),
+            medium_present: true,
+            unit_attention: None,
             block_device: self.block_device,
             inquiry_response: &self.inquiry_response,
             request_sense_response: &mut self.request_sense_response,
@@ -91,6 +158,12 @@ impl<'d, 'bd, B: Driver<'d>, BD: BlockDevice, M: RawMutex> Scsi<'d, 'bd, B, BD,
 
 struct BulkHandler<'scsi, BD> {
     block_device: &'scsi mut BD,
+    /// Capacity last reported to the host, used to tell a resize from a media change
+    block_count: u32,
+    /// Whether the medium was present at the last poll
+    medium_present: bool,
+    /// Unit attention to report on the next command
+    unit_attention: Option<AdditionalSenseCode>,
     inquiry_response: &'scsi InquiryResponse,
     request_sense_response: &'scsi mut RequestSenseResponse,
     packet_size: u16,
@@ -102,46 +175,256 @@ impl<'scsi, BD: BlockDevice> bulk_only_transport::Handler for BulkHandler<'scsi,
         cb: &CommandBlock<'_>,
         reader: &mut impl embedded_io_async::Read<Error = TransportError>,
     ) -> Result<(), CommandError> {
-        let command = Command::extract_from_cbw(cb).map_err(|e| {
-            error!("scsi (from-host) couldn't parse command");
-            self.set_sense_from_error(e);
-            CommandError::Invalid
-        })?;
+        #[cfg(feature = "statistics")]
+        let started = Instant::now();
+        let command = self.parse_command(cb, "from-host");
+        let result = match command {
+            Some(command) => self.handle_from_host(command, reader).await,
+            None => Err(CommandError::Invalid),
+        };
+        #[cfg(feature = "statistics")]
+        self.record_statistics(cb, command, started, &result);
+        result
+    }
+
+    async fn data_transfer_to_host(
+        &mut self,
+        cb: &CommandBlock<'_>,
+        writer: &mut impl embedded_io_async::Write<Error = TransportError>,
+    ) -> Result<(), CommandError> {
+        #[cfg(feature = "statistics")]
+        let started = Instant::now();
+        let command = self.parse_command(cb, "to-host");
+        let result = match command {
+            Some(command) => self.handle_to_host(cb, command, writer).await,
+            None => Err(CommandError::Invalid),
+        };
+        #[cfg(feature = "statistics")]
+        self.record_statistics(cb, command, started, &result);
+        result
+    }
+
+    async fn no_data_transfer(&mut self, cb: &CommandBlock<'_>) -> Result<(), CommandError> {
+        #[cfg(feature = "statistics")]
+        let started = Instant::now();
+        let command = self.parse_command(cb, "no-data");
+        let result = match command {
+            Some(command) => self.handle_no_data(command).await,
+            None => Err(CommandError::Invalid),
+        };
+        #[cfg(feature = "statistics")]
+        self.record_statistics(cb, command, started, &result);
+        result
+    }
+
+    async fn suspended(&mut self) {
+        // there's no command to report a failure against
+        match self.block_device.flush().await {
+            Err(e) if !e.is_recovered() => error!("scsi: flush on suspend failed: {}", e),
+            _ => debug!("scsi: flushed on suspend"),
+        }
+    }
+
+    fn idle_deadline(&self) -> Option<Instant> {
+        self.block_device.idle_deadline()
+    }
+
+    async fn idle(&mut self) {
+        self.block_device.idle().await
+    }
+}
+
+impl<BD: BlockDevice> BulkHandler<'_, BD> {
+    /// Parses the command in `cb`, which transfers data in `direction`. Returns `None`, with
+    /// the sense data set, if it's invalid
+    fn parse_command(&mut self, cb: &CommandBlock<'_>, direction: &str) -> Option<Command> {
+        match Command::extract_from_cbw(cb) {
+            Ok(command) => Some(command),
+            Err(e) => {
+                error!("scsi ({}) couldn't parse command", direction);
+                self.set_sense_from_error(e);
+                None
+            }
+        }
+    }
+
+    async fn handle_from_host(
+        &mut self,
+        command: Command,
+        reader: &mut impl embedded_io_async::Read<Error = TransportError>,
+    ) -> Result<(), CommandError> {
         info!("scsi from-host command: {}", command);
+        self.clear_stale_sense(&command);
+        self.check_unit_attention(&command)?;
 
         match command {
             Command::Write(WriteXCommand {
                 lba: lba_start,
                 transfer_length,
+                protect,
+                fua,
             }) => {
-                let lba_end = lba_start + transfer_length - 1;
-
-                for lba in lba_start..=lba_end {
-                    let mut buf = [0u8; 2048];
-                    assert!(buf.len() >= BD::BLOCK_BYTES); // TODO: almighty hack
-                    let buf = &mut buf[0..BD::BLOCK_BYTES];
-
-                    reader.read_exact(buf).await.map_err(|e| match e {
-                        ReadExactError::UnexpectedEof => {
-                            error!("Unexpected EOF reading block to write to device");
-                            self.set_sense(
-                                SenseKey::IllegalRequest,
-                                AdditionalSenseCode::InvalidCommandOperationCode,
-                            );
-                            CommandError::Failed
-                        }
-                        ReadExactError::Other(e) => CommandError::TransportError(e),
-                    })?;
-
-                    self.block_device.write_block(lba, buf).await.map_err(|e| {
-                        error!("block device error: {}", e);
-                        self.set_sense_from_blockdev_error(e);
+                self.check_lba_range(lba_start, transfer_length)?;
+                #[cfg(feature = "trace")]
+                trace::record(
+                    trace::TraceOp::Write,
+                    lba_start,
+                    transfer_length,
+                    self.block_device.block_count(),
+                );
+                if self.check_protect(protect)? {
+                    self.write_protected(reader, lba_start, transfer_length, protect)
+                        .await?;
+                    if fua {
+                        self.flush_cache().await?;
+                    }
+                    return Ok(());
+                }
+
+                // the whole command is applied or none of it, on devices with transactions,
+                // unless it's longer than a transaction and goes in several
+                let max_blocks = self.block_device.transaction_blocks().unwrap_or(u32::MAX);
+                let lba_end = lba_start + transfer_length;
+                let mut lba = lba_start;
+                while lba < lba_end {
+                    let blocks = max_blocks.max(1).min(lba_end - lba);
+                    self.block_device.begin_transaction();
+                    let result = self.write_from_host(reader, lba, blocks, fua).await;
+                    let end = self.block_device.end_transaction(result.is_ok()).await;
+                    result?;
+                    self.check_blockdev_result(end, lba)?;
+                    lba += blocks;
+                }
+                Ok(())
+            }
+            Command::Unmap(unmap) => {
+                if self.block_device.provisioning().is_none() {
+                    error!("scsi: unmap on a fully provisioned device");
+                    self.set_sense(
+                        SenseKey::IllegalRequest,
+                        AdditionalSenseCode::InvalidCommandOperationCode,
+                    );
+                    return Err(CommandError::Failed);
+                }
+
+                let mut buf = [0u8; UNMAP_PARAMETER_LIST_BYTES];
+                let len = unmap.parameter_list_length() as usize;
+                if len > buf.len() {
+                    error!("scsi: unmap parameter list too long: {}", len);
+                    self.set_sense(
+                        SenseKey::IllegalRequest,
+                        AdditionalSenseCode::InvalidFieldInCdb,
+                    );
+                    return Err(CommandError::Failed);
+                }
+                let buf = &mut buf[..len];
+                reader.read_exact(buf).await.map_err(|e| match e {
+                    ReadExactError::UnexpectedEof => {
+                        error!("Unexpected EOF reading unmap parameter list");
+                        self.set_sense(
+                            SenseKey::IllegalRequest,
+                            AdditionalSenseCode::InvalidFieldInCdb,
+                        );
                         CommandError::Failed
-                    })?;
+                    }
+                    ReadExactError::Other(e) => CommandError::TransportError(e),
+                })?;
+
+                // an empty parameter list unmaps nothing
+                if len < UNMAP_HEADER_BYTES {
+                    return Ok(());
+                }
+                let descriptors_len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+                let descriptors = buf[UNMAP_HEADER_BYTES..]
+                    .chunks_exact(UNMAP_DESCRIPTOR_BYTES)
+                    .take(descriptors_len / UNMAP_DESCRIPTOR_BYTES);
+                for descriptor in descriptors {
+                    let mut lba = [0u8; 8];
+                    lba.copy_from_slice(&descriptor[0..8]);
+                    let lba = u64::from_be_bytes(lba);
+                    let count = u32::from_be_bytes([
+                        descriptor[8],
+                        descriptor[9],
+                        descriptor[10],
+                        descriptor[11],
+                    ]);
+
+                    let lba = u32::try_from(lba).unwrap_or(u32::MAX);
+                    self.check_lba_range(lba, count)?;
+                    let result = self.block_device.unmap(lba, count).await;
+                    self.check_blockdev_result(result, lba)?;
                 }
 
                 Ok(())
             }
+            Command::ModeSelect(mode_select) => {
+                if mode_select.save_pages {
+                    error!("scsi: mode select can't save pages");
+                    self.set_sense(
+                        SenseKey::IllegalRequest,
+                        AdditionalSenseCode::SavingParametersNotSupported,
+                    );
+                    return Err(CommandError::Failed);
+                }
+
+                let mut buf = [0u8; MODE_SELECT_PARAMETER_LIST_BYTES];
+                let len = mode_select.parameter_list_length as usize;
+                if len > buf.len() {
+                    error!("scsi: mode select parameter list too long: {}", len);
+                    self.set_sense(
+                        SenseKey::IllegalRequest,
+                        AdditionalSenseCode::ParameterListLengthError,
+                    );
+                    return Err(CommandError::Failed);
+                }
+                let buf = &mut buf[..len];
+                reader.read_exact(buf).await.map_err(|e| match e {
+                    ReadExactError::UnexpectedEof => {
+                        error!("Unexpected EOF reading mode select parameter list");
+                        self.set_sense(
+                            SenseKey::IllegalRequest,
+                            AdditionalSenseCode::ParameterListLengthError,
+                        );
+                        CommandError::Failed
+                    }
+                    ReadExactError::Other(e) => CommandError::TransportError(e),
+                })?;
+
+                self.mode_select(mode_select.command_length, buf).await
+            }
+            Command::Unlock(unlock) => {
+                let mut key = [0u8; UNLOCK_KEY_MAX_BYTES];
+                let len = unlock.parameter_list_length() as usize;
+                if len > key.len() {
+                    error!("scsi: unlock key too long: {}", len);
+                    self.set_sense(
+                        SenseKey::IllegalRequest,
+                        AdditionalSenseCode::InvalidFieldInCdb,
+                    );
+                    return Err(CommandError::Failed);
+                }
+                let key = &mut key[..len];
+                reader.read_exact(key).await.map_err(|e| match e {
+                    ReadExactError::UnexpectedEof => {
+                        error!("Unexpected EOF reading unlock key");
+                        self.set_sense(
+                            SenseKey::IllegalRequest,
+                            AdditionalSenseCode::InvalidFieldInCdb,
+                        );
+                        CommandError::Failed
+                    }
+                    ReadExactError::Other(e) => CommandError::TransportError(e),
+                })?;
+
+                match self.block_device.unlock(key).await {
+                    Ok(()) => Ok(()),
+                    Err(e) => {
+                        error!("scsi: unlock failed: {}", e);
+                        self.set_sense_from_blockdev_error(e, None);
+                        Err(CommandError::Failed)
+                    }
+                }
+            }
             _ => {
                 error!("invalid from-host command");
                 self.set_sense_invalid_dir();
@@ -149,22 +432,22 @@ impl<'scsi, BD: BlockDevice> bulk_only_transport::Handler for BulkHandler<'scsi,
             }
         }
     }
-    async fn data_transfer_to_host(
+    async fn handle_to_host(
         &mut self,
         cb: &CommandBlock<'_>,
+        command: Command,
         writer: &mut impl embedded_io_async::Write<Error = TransportError>,
     ) -> Result<(), CommandError> {
-        let command = Command::extract_from_cbw(cb).map_err(|e| {
-            error!("scsi (to-host) couldn't parse command");
-            self.set_sense_from_error(e);
-            CommandError::Invalid
-        })?;
         info!("scsi to-host command: {}", command);
+        self.clear_stale_sense(&command);
+        self.check_unit_attention(&command)?;
 
         match command {
             Command::ReadCapacity(_read_capacity10) => {
+                self.check_media().await?;
+
                 // TODO: support read_capacity16 etc
-                let max_lba = self.block_device.block_count();
+                let max_lba = self.block_count.saturating_sub(1);
                 let block_size = BD::BLOCK_BYTES as u32;
                 let mut cap = ReadCapacity10Response::new();
 
@@ -180,12 +463,51 @@ impl<'scsi, BD: BlockDevice> bulk_only_transport::Handler for BulkHandler<'scsi,
                 //let _ = &mut data[8..12].copy_from_slice(&u32::to_be_bytes(BLOCK_SIZE));
             }
 
+            Command::ReadCapacity16(read_capacity16) => {
+                self.check_media().await?;
+
+                let max_lba = self.block_count.saturating_sub(1) as u64;
+                let block_size = BD::BLOCK_BYTES as u32;
+
+                let mut response = [0u8; 32];
+                response[0..8].copy_from_slice(&max_lba.to_be_bytes());
+                response[8..12].copy_from_slice(&block_size.to_be_bytes());
+                if self.block_device.protection_information() {
+                    // P_TYPE 000b (type 1), PROT_EN
+                    response[12] = 0b0000_0001;
+                }
+                if self.block_device.provisioning().is_some() {
+                    // LBPME: logical block provisioning management enabled
+                    // LBPRZ: unmapped blocks read as zeros
+                    response[14] = 0b1100_0000;
+                }
+
+                let len = response
+                    .len()
+                    .min(read_capacity16.allocation_length() as usize);
+                writer.write_all(&response[..len]).await?;
+                Ok(())
+            }
+
             Command::Read(ReadXCommand {
                 lba: lba_start,
                 transfer_length,
+                protect,
             }) => {
                 // transfer_length == number of blocks to read
-                let lba_end = lba_start + transfer_length - 1;
+                self.check_lba_range(lba_start, transfer_length)?;
+                #[cfg(feature = "trace")]
+                trace::record(
+                    trace::TraceOp::Read,
+                    lba_start,
+                    transfer_length,
+                    self.block_device.block_count(),
+                );
+                if self.check_protect(protect)? {
+                    return self
+                        .read_protected(writer, lba_start, transfer_length)
+                        .await;
+                }
 
                 // FIXME: what if block_size isn't a multiple of packet_size?
                 assert!(
@@ -195,26 +517,74 @@ impl<'scsi, BD: BlockDevice> bulk_only_transport::Handler for BulkHandler<'scsi,
 
                 let mut buf = [0u8; 2048];
                 assert!(buf.len() >= BD::BLOCK_BYTES); // TODO: almighty hack
-                let buf = &mut buf[0..BD::BLOCK_BYTES];
+                let blocks_per_chunk = (buf.len() / BD::BLOCK_BYTES) as u32;
 
-                for lba in lba_start..=lba_end {
-                    self.block_device.read_block(lba, buf).await.map_err(|e| {
-                        error!("block device error: {}", e);
-                        self.set_sense_from_blockdev_error(e);
-                        CommandError::Failed
-                    })?;
+                let lba_end = lba_start + transfer_length;
+                let mut lba = lba_start;
+                while lba < lba_end {
+                    let blocks = blocks_per_chunk.min(lba_end - lba);
+                    let buf = &mut buf[0..blocks as usize * BD::BLOCK_BYTES];
+
+                    // errors are reported against the first block of the chunk
+                    let result = self.block_device.read_blocks(lba, buf).await;
+                    self.check_blockdev_result(result, lba)?;
 
                     for offset in (0..buf.len()).step_by(self.packet_size as usize) {
                         writer
                             .write_all(&buf[offset..offset + self.packet_size as usize])
                             .await?;
                     }
+
+                    lba += blocks;
+                }
+
+                Ok(())
+            }
+            Command::Inquiry(inquiry) if inquiry.enable_vital_product_data() => {
+                let mut buf = [0u8; 64];
+                let Some(len) = self.vital_product_data(inquiry.page_code(), &mut buf) else {
+                    error!("scsi: unsupported VPD page {:02x}", inquiry.page_code());
+                    self.set_sense(
+                        SenseKey::IllegalRequest,
+                        AdditionalSenseCode::InvalidFieldInCdb,
+                    );
+                    return Err(CommandError::Failed);
+                };
+
+                let len = len.min(inquiry.allocation_length() as usize);
+                writer.write_all(&buf[..len]).await?;
+                Ok(())
+            }
+            Command::LogSense(log_sense) => {
+                if log_sense.save_parameters() || log_sense.subpage_code() != 0 {
+                    error!("scsi: log sense can't save parameters or return subpages");
+                    self.set_sense(
+                        SenseKey::IllegalRequest,
+                        AdditionalSenseCode::InvalidFieldInCdb,
+                    );
+                    return Err(CommandError::Failed);
                 }
 
+                let mut buf = [0u8; LOG_PAGE_BYTES];
+                let Some(len) = self.log_page(
+                    cb.lun,
+                    log_sense.page_code(),
+                    log_sense.parameter_pointer(),
+                    &mut buf,
+                ) else {
+                    error!("scsi: unsupported log page {:02x}", log_sense.page_code());
+                    self.set_sense(
+                        SenseKey::IllegalRequest,
+                        AdditionalSenseCode::InvalidFieldInCdb,
+                    );
+                    return Err(CommandError::Failed);
+                };
+
+                let len = len.min(log_sense.allocation_length() as usize);
+                writer.write_all(&buf[..len]).await?;
                 Ok(())
             }
             Command::Inquiry { .. } => {
-                // FIXME - VPD page should specify maximum transfer_length for read/write
                 let buf = &self.inquiry_response.as_bytes()[..InquiryResponse::MINIMUM_SIZE];
 
                 writer.write_all(buf).await?;
@@ -225,46 +595,35 @@ impl<'scsi, BD: BlockDevice> bulk_only_transport::Handler for BulkHandler<'scsi,
                 writer
                     .write_all(self.request_sense_response.as_bytes())
                     .await?;
+                // it's been reported now
+                self.reset_sense();
                 Ok(())
             }
-            Command::ModeSense(ModeSenseXCommand {
-                command_length: CommandLength::C6, // FIXME: handle other mode senses
-                page_control: PageControl::CurrentValues,
-            }) => {
-                let data = [
-                    0x03, // number of bytes that follow
-                    0x00, // the media type is SBC
-                    0x00, // not write-protected, no cache-control bytes support
-                    0x00, // no mode-parameter block descriptors
-                ];
-                writer.write_all(&data).await?;
-                Ok(())
-
-                /*
-                 * FIXME
-                let mut header = ModeParameterHeader6::default();
-                header.increase_length_for_page(PageCode::CachingModePage);
-
-                // Default is both caches disabled
-                let cache_page = CachingModePage::default();
+            Command::ModeSense(mode_sense) => {
+                if mode_sense.page_control == PageControl::SavedValues {
+                    error!("scsi: mode sense of saved values");
+                    self.set_sense(
+                        SenseKey::IllegalRequest,
+                        AdditionalSenseCode::SavingParametersNotSupported,
+                    );
+                    return Err(CommandError::Failed);
+                }
 
-                let mut buf = [0u8; ModeParameterHeader6::BYTES + CachingModePage::BYTES];
+                let mut buf = [0u8; ModeParameterHeader10::BYTE_LEN + CachingModePage::BYTE_LEN];
+                let len = self.mode_sense(mode_sense, &mut buf);
 
-                header.pack(&mut buf[..ModeParameterHeader6::BYTES]).unwrap();
-                cache_page.pack(&mut buf[ModeParameterHeader6::BYTES..]).unwrap();
-                // FIXME?: original modesense6 response only had 4 bytes, none of this cache_page
-                writer.write_all(&buf).await?;
+                let len = len.min(mode_sense.allocation_length as usize);
+                writer.write_all(&buf[..len]).await?;
                 Ok(())
-                */
             }
-            Command::ModeSense(_) => todo!(),
             Command::ReadFormatCapacities(ReadFormatCapacitiesCommand { .. }) => {
-                let max_lba = self.block_device.block_count();
+                // the capacity last polled, the same as READ CAPACITY reports
+                let block_count = self.block_count;
                 let block_size = BD::BLOCK_BYTES as u32;
 
                 let mut response = [0u8; 12];
                 response[3] = 0x08; // capacity list length
-                response[4..8].copy_from_slice(max_lba.to_be_bytes().as_slice());
+                response[4..8].copy_from_slice(block_count.to_be_bytes().as_slice());
                 response[8] = 0x02; // formatted media
                 response[9..12].copy_from_slice(&block_size.to_be_bytes().as_slice()[1..]); // block size
 
@@ -278,33 +637,40 @@ impl<'scsi, BD: BlockDevice> bulk_only_transport::Handler for BulkHandler<'scsi,
             }
         }
     }
-    async fn no_data_transfer(&mut self, cb: &CommandBlock<'_>) -> Result<(), CommandError> {
-        let command = Command::extract_from_cbw(cb).map_err(|e| {
-            error!("scsi (no-data) couldn't parse command");
-            self.set_sense_from_error(e);
-            CommandError::Invalid
-        })?;
+    async fn handle_no_data(&mut self, command: Command) -> Result<(), CommandError> {
         debug!("scsi no-data command: {}", command);
+        self.clear_stale_sense(&command);
+        self.check_unit_attention(&command)?;
 
         match command {
             Command::PreventAllowMediumRemoval(PreventAllowMediumRemovalCommand { .. }) => {
                 // TODO: pass up a level?
                 Ok(())
             }
-            Command::TestUnitReady(_) => {
-                // TODO: after enough errors apparently the host will keep sending TUR
-                // requests and nothing else. There may be additional data in the
-                // request that indicates we should respond CommandError and prepare
-                // sense response data with more info
+            Command::TestUnitReady(_) => self.check_media().await,
+            Command::StartStopUnit(start_stop_unit) => {
+                // stopping the unit, which ejecting it implies, writes back the cache
+                if !start_stop_unit.start() && !start_stop_unit.no_flush() {
+                    self.flush_cache().await?;
+                }
+                Ok(())
+            }
+            // the whole cache is written back, not just the blocks asked for
+            Command::SynchronizeCache(_) => self.flush_cache().await,
+            // a mode select without a parameter list changes nothing
+            Command::ModeSelect(_) => Ok(()),
+            // an unlock without a key
+            Command::Unlock(_) => {
+                self.flush_cache().await?;
+                self.block_device.lock();
                 Ok(())
             }
-            Command::StartStopUnit(StartStopUnitCommand { .. }) => Ok(()),
-            Command::Format(_)
-            | Command::ModeSelect(_)
-            | Command::ReportLuns(_)
-            | Command::SendDiagnostic(_)
-            | Command::SynchronizeCache(_)
-            | Command::Verify(_) => {
+            // FORMAT UNIT with a parameter list comes from the host and isn't supported
+            Command::Format(_) => {
+                let result = self.block_device.format().await;
+                self.check_blockdev_result(result, 0)
+            }
+            Command::ReportLuns(_) | Command::SendDiagnostic(_) | Command::Verify(_) => {
                 unimplemented!();
             }
             _ => {
@@ -314,40 +680,796 @@ impl<'scsi, BD: BlockDevice> bulk_only_transport::Handler for BulkHandler<'scsi,
             }
         }
     }
+
+    /// Counts the command in `cb`, received at `started`, in the statistics of its LUN.
+    /// `command` is the command parsed from it, `None` if it was invalid
+    #[cfg(feature = "statistics")]
+    fn record_statistics(
+        &self,
+        cb: &CommandBlock<'_>,
+        command: Option<Command>,
+        started: Instant,
+        result: &Result<(), CommandError>,
+    ) {
+        let (class, blocks) = match command {
+            Some(Command::Read(read)) => (CommandClass::Read, read.transfer_length),
+            Some(Command::Write(write)) => (CommandClass::Write, write.transfer_length),
+            _ => (CommandClass::Other, 0),
+        };
+        let blocks = if result.is_ok() { blocks } else { 0 };
+        let sense_key = match result {
+            Err(CommandError::Failed | CommandError::Invalid) => {
+                Some(self.request_sense_response.as_bytes()[SENSE_KEY_BYTE] & SENSE_KEY_MASK)
+            }
+            _ => None,
+        };
+
+        statistics::record(
+            cb.lun,
+            CommandRecord {
+                op_code: cb.bytes[0],
+                class,
+                blocks,
+                bytes: blocks as u64 * BD::BLOCK_BYTES as u64,
+                sense_key,
+                transport_error: matches!(result, Err(CommandError::TransportError(_))),
+                started,
+            },
+        );
+    }
+
+    /// Fills `buf` with the mode parameter header for `command` followed by the caching mode
+    /// page if it was asked for, returning their length. Other pages aren't supported and
+    /// only get the header
+    fn mode_sense(&self, command: ModeSenseXCommand, buf: &mut [u8]) -> usize {
+        let caching = self.block_device.caching();
+        let write_protected = self.block_device.write_protected();
+        let include_page = matches!(command.page_code, MODE_PAGES_ALL | MODE_PAGE_CACHING);
+
+        let header_len = match command.command_length {
+            CommandLength::C6 => {
+                let mut header = ModeParameterHeader6::default();
+                let parameter = header.device_specific_parameter_mut();
+                parameter.set_write_protect(write_protected);
+                parameter.set_disable_page_out_and_force_unit_access_available(caching.is_some());
+                if include_page {
+                    header.increase_length_for_page(PageCode::CachingModePage);
+                }
+                buf[..ModeParameterHeader6::BYTE_LEN].copy_from_slice(header.as_bytes());
+                ModeParameterHeader6::BYTE_LEN
+            }
+            CommandLength::C10 => {
+                let mut header = ModeParameterHeader10::default();
+                let parameter = header.device_specific_parameter_mut();
+                parameter.set_write_protect(write_protected);
+                parameter.set_disable_page_out_and_force_unit_access_available(caching.is_some());
+                if include_page {
+                    header.increase_length_for_page(PageCode::CachingModePage);
+                }
+                buf[..ModeParameterHeader10::BYTE_LEN].copy_from_slice(header.as_bytes());
+                ModeParameterHeader10::BYTE_LEN
+            }
+        };
+        if !include_page {
+            return header_len;
+        }
+
+        let mut page = CachingModePage::default();
+        match command.page_control {
+            PageControl::ChangeableValues => {
+                page.set_write_cache_enabled(caching.is_some());
+                page.set_read_cache_disable(caching.is_some());
+            }
+            // the settings the device started with aren't known, so defaults are the
+            // current values
+            _ => {
+                let caching = caching.unwrap_or(NO_CACHE);
+                page.set_write_cache_enabled(caching.write_cache_enabled);
+                page.set_read_cache_disable(caching.read_cache_disable);
+            }
+        }
+        buf[header_len..header_len + CachingModePage::BYTE_LEN].copy_from_slice(page.as_bytes());
+        header_len + CachingModePage::BYTE_LEN
+    }
+
+    /// Applies the mode pages in the MODE SELECT `parameters`. Block descriptors are
+    /// ignored, the only page supported is the caching mode page
+    async fn mode_select(
+        &mut self,
+        command_length: CommandLength,
+        parameters: &[u8],
+    ) -> Result<(), CommandError> {
+        if parameters.is_empty() {
+            return Ok(());
+        }
+        let pages_start = match command_length {
+            CommandLength::C6 => ModeParameterHeader6::overlay(parameters).map(|header| {
+                ModeParameterHeader6::BYTE_LEN + header.block_descriptor_length() as usize
+            }),
+            CommandLength::C10 => ModeParameterHeader10::overlay(parameters).map(|header| {
+                ModeParameterHeader10::BYTE_LEN + header.block_descriptor_length() as usize
+            }),
+        };
+        let Some(mut pages) = pages_start.ok().and_then(|start| parameters.get(start..)) else {
+            error!("scsi: mode select header doesn't fit the parameter list");
+            self.set_sense_invalid_parameter_list();
+            return Err(CommandError::Failed);
+        };
+
+        while !pages.is_empty() {
+            let page_len = 2 + pages.get(1).copied().unwrap_or(0) as usize;
+            let Some(page) = pages.get(..page_len) else {
+                error!("scsi: mode page doesn't fit the parameter list");
+                self.set_sense_invalid_parameter_list();
+                return Err(CommandError::Failed);
+            };
+
+            match PageCode::try_from_primitive(page[0] & MODE_PAGE_CODE_MASK) {
+                Ok(PageCode::CachingModePage) if page_len == CachingModePage::BYTE_LEN => {
+                    let page = CachingModePage::overlay(page).unwrap();
+                    self.set_caching(Caching {
+                        write_cache_enabled: page.write_cache_enabled(),
+                        read_cache_disable: page.read_cache_disable(),
+                    })
+                    .await?;
+                }
+                _ => {
+                    error!("scsi: mode select of unsupported page {:02x}", page[0]);
+                    self.set_sense_invalid_parameter_list();
+                    return Err(CommandError::Failed);
+                }
+            }
+            pages = &pages[page_len..];
+        }
+
+        Ok(())
+    }
+
+    /// Changes the block device's cache settings. Devices without a cache only accept the
+    /// settings they report
+    async fn set_caching(&mut self, caching: Caching) -> Result<(), CommandError> {
+        if self.block_device.caching().unwrap_or(NO_CACHE) == caching {
+            return Ok(());
+        }
+        match self.block_device.set_caching(caching).await {
+            Err(BlockDeviceError::Unsupported) => {
+                error!(
+                    "scsi: the block device can't change its cache to {}",
+                    caching
+                );
+                self.set_sense_invalid_parameter_list();
+                Err(CommandError::Failed)
+            }
+            Err(e) if !e.is_recovered() => {
+                error!("scsi: changing the cache failed: {}", e);
+                self.set_sense_from_blockdev_error(e, None);
+                Err(CommandError::Failed)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes back anything the block device has cached
+    async fn flush_cache(&mut self) -> Result<(), CommandError> {
+        match self.block_device.flush().await {
+            Err(e) if !e.is_recovered() => {
+                error!("scsi: flushing the cache failed: {}", e);
+                self.set_sense_from_blockdev_error(e, None);
+                Err(CommandError::Failed)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fills `buf` with the log page `page_code` of `lun`, leaving out parameters below
+    /// `parameter_pointer`. Returns the length of the page, or `None` if it isn't supported.
+    /// Only the cumulative values of the statistics are kept, they're returned whatever the
+    /// page control asks for
+    fn log_page(
+        &self,
+        lun: u8,
+        page_code: u8,
+        parameter_pointer: u16,
+        buf: &mut [u8; LOG_PAGE_BYTES],
+    ) -> Option<usize> {
+        #[cfg(feature = "statistics")]
+        let statistics = statistics::snapshot(lun);
+        #[cfg(not(feature = "statistics"))]
+        let _ = lun;
+        let provisioning = self.block_device.provisioning();
+        buf[0] = LOG_DISABLE_SAVE | page_code;
+
+        let mut len = 4;
+        let mut parameter = |code: u16, control: u8, values: &[u64]| {
+            if code < parameter_pointer {
+                return;
+            }
+            buf[len..len + 2].copy_from_slice(&code.to_be_bytes());
+            buf[len + 2] = control;
+            buf[len + 3] = (values.len() * 8) as u8;
+            len += 4;
+            for value in values {
+                buf[len..len + 8].copy_from_slice(&value.to_be_bytes());
+                len += 8;
+            }
+        };
+
+        match page_code {
+            LOG_SUPPORTED_PAGES => {
+                let mut pages = [LOG_SUPPORTED_PAGES; 5];
+                let mut count = 1;
+                #[cfg(feature = "statistics")]
+                if statistics.is_some() {
+                    pages[1] = LOG_WRITE_ERROR_COUNTER;
+                    pages[2] = LOG_READ_ERROR_COUNTER;
+                    count = 3;
+                }
+                if provisioning.is_some() {
+                    pages[count] = LOG_LOGICAL_BLOCK_PROVISIONING;
+                    count += 1;
+                }
+                #[cfg(feature = "statistics")]
+                if statistics.is_some() {
+                    pages[count] = LOG_GENERAL_STATISTICS;
+                    count += 1;
+                }
+                buf[4..4 + count].copy_from_slice(&pages[..count]);
+                len += count;
+            }
+            LOG_LOGICAL_BLOCK_PROVISIONING => {
+                let provisioning = provisioning?;
+                // resource counts are in threshold sets of 2^threshold_exponent blocks,
+                // followed by their scope
+                let resource_count = |blocks: u32| {
+                    let count = blocks >> provisioning.threshold_exponent;
+                    ((count as u64) << 32) | ((LOG_RESOURCE_DEDICATED as u64) << 24)
+                };
+                // available and used LBA mapping resource counts
+                parameter(
+                    0x0001,
+                    LOG_PARAMETER_LIST,
+                    &[resource_count(provisioning.available)],
+                );
+                parameter(
+                    0x0002,
+                    LOG_PARAMETER_LIST,
+                    &[resource_count(provisioning.used)],
+                );
+            }
+            #[cfg(feature = "statistics")]
+            LOG_WRITE_ERROR_COUNTER | LOG_READ_ERROR_COUNTER => {
+                let statistics = statistics?;
+                let (class, bytes) = if page_code == LOG_WRITE_ERROR_COUNTER {
+                    (CommandClass::Write, statistics.bytes_written)
+                } else {
+                    (CommandClass::Read, statistics.bytes_read)
+                };
+                // total bytes processed
+                parameter(0x0005, LOG_PARAMETER_COUNTER, &[bytes]);
+                // total uncorrected errors
+                let failures = statistics.failures(class) as u64;
+                parameter(0x0006, LOG_PARAMETER_COUNTER, &[failures]);
+            }
+            #[cfg(feature = "statistics")]
+            LOG_GENERAL_STATISTICS => {
+                let statistics = statistics?;
+                let reads = statistics.latency(CommandClass::Read);
+                let writes = statistics.latency(CommandClass::Write);
+                // processing intervals are in microseconds, see the time interval parameter
+                parameter(
+                    0x0001,
+                    LOG_PARAMETER_LIST,
+                    &[
+                        reads.count() as u64,
+                        writes.count() as u64,
+                        statistics.blocks_written,
+                        statistics.blocks_read,
+                        reads.total_micros,
+                        writes.total_micros,
+                        reads.count() as u64 + writes.count() as u64,
+                        reads.total_micros + writes.total_micros,
+                    ],
+                );
+                // time interval: an exponent of -6 and an integer of 1
+                let time_interval = ((-6i32 as u32 as u64) << 32) | 1;
+                parameter(0x0003, LOG_PARAMETER_LIST, &[time_interval]);
+            }
+            _ => return None,
+        }
+
+        buf[2..4].copy_from_slice(&((len - 4) as u16).to_be_bytes());
+        Some(len)
+    }
+
+    /// Fills `buf` with the vital product data page `page_code`, returning its length, or
+    /// `None` if the page isn't supported
+    fn vital_product_data(&self, page_code: u8, buf: &mut [u8; 64]) -> Option<usize> {
+        let provisioning = self.block_device.provisioning();
+        let peripheral_device_type = self.inquiry_response.as_bytes()[0];
+        buf[0] = peripheral_device_type;
+        buf[1] = page_code;
+
+        let page_length = match page_code {
+            VPD_SUPPORTED_PAGES => {
+                buf[4] = VPD_SUPPORTED_PAGES;
+                buf[5] = VPD_BLOCK_LIMITS;
+                if provisioning.is_some() {
+                    buf[6] = VPD_LOGICAL_BLOCK_PROVISIONING;
+                    3
+                } else {
+                    2
+                }
+            }
+            VPD_BLOCK_LIMITS => {
+                if let Some(max) = self.block_device.transaction_blocks() {
+                    // maximum transfer length
+                    buf[8..12].copy_from_slice(&max.to_be_bytes());
+                }
+                if provisioning.is_some() {
+                    // maximum unmap lba count, unlimited
+                    buf[20..24].copy_from_slice(&u32::MAX.to_be_bytes());
+                    // maximum unmap block descriptor count
+                    let descriptors =
+                        (UNMAP_PARAMETER_LIST_BYTES - UNMAP_HEADER_BYTES) / UNMAP_DESCRIPTOR_BYTES;
+                    buf[24..28].copy_from_slice(&(descriptors as u32).to_be_bytes());
+                    // optimal unmap granularity
+                    buf[28..32].copy_from_slice(&1u32.to_be_bytes());
+                }
+                0x3c
+            }
+            VPD_LOGICAL_BLOCK_PROVISIONING => {
+                let provisioning = provisioning?;
+                buf[4] = provisioning.threshold_exponent;
+                // LBPU: UNMAP is supported, LBPRZ: unmapped blocks read as zeros
+                buf[5] = 0b1000_0100;
+                // provisioning type: thin
+                buf[6] = 0b010;
+                4
+            }
+            _ => return None,
+        };
+
+        buf[2..4].copy_from_slice(&(page_length as u16).to_be_bytes());
+        Some(4 + page_length)
+    }
+
+    /// Polls the block device for media changes. A missing medium fails the command with
+    /// NOT READY, a changed medium fails it once with the appropriate UNIT ATTENTION so the
+    /// host re-reads the capacity and drops its caches
+    async fn check_media(&mut self) -> Result<(), CommandError> {
+        let status = self.block_device.media_status().await;
+        debug!("scsi: media status {}", status);
+
+        match status {
+            MediaStatus::Absent => {
+                self.medium_present = false;
+                self.set_sense(SenseKey::NotReady, AdditionalSenseCode::MediumNotPresent);
+                Err(CommandError::Failed)
+            }
+            MediaStatus::Locked => {
+                self.medium_present = false;
+                self.set_sense(
+                    SenseKey::NotReady,
+                    AdditionalSenseCode::LogicalUnitAccessNotAuthorized,
+                );
+                Err(CommandError::Failed)
+            }
+            MediaStatus::BecomingReady => {
+                self.set_sense(
+                    SenseKey::NotReady,
+                    AdditionalSenseCode::LogicalUnitIsInProcessOfBecomingReady,
+                );
+                Err(CommandError::Failed)
+            }
+            MediaStatus::Present if self.medium_present => Ok(()),
+            // a medium that reappears is a change, even if the device didn't say so
+            MediaStatus::Present | MediaStatus::Changed => {
+                self.medium_present = true;
+
+                let block_count = self.block_device.block_count();
+                let code = if block_count != self.block_count {
+                    AdditionalSenseCode::CapacityDataHasChanged
+                } else {
+                    AdditionalSenseCode::MediumMayHaveChanged
+                };
+                self.block_count = block_count;
+
+                self.set_sense(SenseKey::UnitAttention, code);
+                Err(CommandError::Failed)
+            }
+        }
+    }
+
+    /// Checks the RDPROTECT/WRPROTECT field of a read or write, returning whether protection
+    /// information is transferred with the blocks
+    fn check_protect(&mut self, protect: u8) -> Result<bool, CommandError> {
+        match protect {
+            0 => Ok(false),
+            1..=5 if self.block_device.protection_information() => Ok(true),
+            _ => {
+                error!("scsi: unsupported protect field {}", protect);
+                self.set_sense(
+                    SenseKey::IllegalRequest,
+                    AdditionalSenseCode::InvalidFieldInCdb,
+                );
+                Err(CommandError::Failed)
+            }
+        }
+    }
+
+    /// Writes the blocks sent by the host a chunk at a time
+    async fn write_from_host(
+        &mut self,
+        reader: &mut impl embedded_io_async::Read<Error = TransportError>,
+        lba_start: u32,
+        transfer_length: u32,
+        fua: bool,
+    ) -> Result<(), CommandError> {
+        let mut buf = [0u8; 2048];
+        assert!(buf.len() >= BD::BLOCK_BYTES); // TODO: almighty hack
+        let blocks_per_chunk = (buf.len() / BD::BLOCK_BYTES) as u32;
+
+        let lba_end = lba_start + transfer_length;
+        let mut lba = lba_start;
+        while lba < lba_end {
+            let blocks = blocks_per_chunk.min(lba_end - lba);
+            let buf = &mut buf[0..blocks as usize * BD::BLOCK_BYTES];
+
+            reader.read_exact(buf).await.map_err(|e| match e {
+                ReadExactError::UnexpectedEof => {
+                    error!("Unexpected EOF reading block to write to device");
+                    self.set_sense(
+                        SenseKey::IllegalRequest,
+                        AdditionalSenseCode::InvalidCommandOperationCode,
+                    );
+                    CommandError::Failed
+                }
+                ReadExactError::Other(e) => CommandError::TransportError(e),
+            })?;
+
+            // errors are reported against the first block of the chunk
+            let result = if fua {
+                self.block_device.write_blocks_fua(lba, buf).await
+            } else {
+                self.block_device.write_blocks(lba, buf).await
+            };
+            self.check_blockdev_result(result, lba)?;
+
+            lba += blocks;
+        }
+
+        Ok(())
+    }
+
+    /// Writes blocks sent by the host each followed by its protection information, which is
+    /// checked as `wr_protect` asks before it's stored with the block
+    async fn write_protected(
+        &mut self,
+        reader: &mut impl embedded_io_async::Read<Error = TransportError>,
+        lba_start: u32,
+        transfer_length: u32,
+        wr_protect: u8,
+    ) -> Result<(), CommandError> {
+        let mut unit = [0u8; 2048];
+        let unit_len = BD::BLOCK_BYTES + PROTECTION_INFORMATION_BYTES;
+        assert!(unit.len() >= unit_len); // TODO: almighty hack
+        let unit = &mut unit[..unit_len];
+
+        // units don't line up with packets, and the endpoint can only read whole packets
+        let packet_size = self.packet_size as usize;
+        assert!(packet_size <= MAX_PACKET_SIZE);
+        let mut packet = [0u8; MAX_PACKET_SIZE];
+        let (mut start, mut end) = (0, 0);
+
+        for lba in lba_start..lba_start + transfer_length {
+            let mut filled = 0;
+            while filled < unit_len {
+                if start == end {
+                    start = 0;
+                    end = reader.read(&mut packet[..packet_size]).await?;
+                    if end == 0 {
+                        error!("Unexpected EOF reading block to write to device");
+                        self.set_sense(
+                            SenseKey::IllegalRequest,
+                            AdditionalSenseCode::InvalidCommandOperationCode,
+                        );
+                        return Err(CommandError::Failed);
+                    }
+                }
+                let len = (end - start).min(unit_len - filled);
+                unit[filled..filled + len].copy_from_slice(&packet[start..start + len]);
+                filled += len;
+                start += len;
+            }
+
+            let (block, protection) = unit.split_at(BD::BLOCK_BYTES);
+            self.check_protection_information(lba, block, protection, wr_protect)?;
+            let result = self
+                .block_device
+                .write_blocks_protected(lba, block, protection)
+                .await;
+            self.check_blockdev_result(result, lba)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends blocks to the host each followed by its protection information
+    async fn read_protected(
+        &mut self,
+        writer: &mut impl embedded_io_async::Write<Error = TransportError>,
+        lba_start: u32,
+        transfer_length: u32,
+    ) -> Result<(), CommandError> {
+        let mut unit = [0u8; 2048];
+        let unit_len = BD::BLOCK_BYTES + PROTECTION_INFORMATION_BYTES;
+        assert!(unit.len() >= unit_len); // TODO: almighty hack
+        let unit = &mut unit[..unit_len];
+
+        // units don't line up with packets, so they're sent a packet at a time
+        let packet_size = self.packet_size as usize;
+        assert!(packet_size <= MAX_PACKET_SIZE);
+        let mut packet = [0u8; MAX_PACKET_SIZE];
+        let mut len = 0;
+
+        for lba in lba_start..lba_start + transfer_length {
+            let (block, protection) = unit.split_at_mut(BD::BLOCK_BYTES);
+            let result = self
+                .block_device
+                .read_blocks_protected(lba, block, protection)
+                .await;
+            self.check_blockdev_result(result, lba)?;
+
+            let mut sent = 0;
+            while sent < unit_len {
+                let count = (packet_size - len).min(unit_len - sent);
+                packet[len..len + count].copy_from_slice(&unit[sent..sent + count]);
+                len += count;
+                sent += count;
+                if len == packet_size {
+                    writer.write_all(&packet[..len]).await?;
+                    len = 0;
+                }
+            }
+        }
+        if len > 0 {
+            writer.write_all(&packet[..len]).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the type 1 protection information the host sent with `block` as `wr_protect`
+    /// asks. The application tag is the host's and isn't checked, unless it's the escape
+    /// value that turns checking off
+    fn check_protection_information(
+        &mut self,
+        lba: u32,
+        block: &[u8],
+        protection: &[u8],
+        wr_protect: u8,
+    ) -> Result<(), CommandError> {
+        if protection[2..4] == [0xff, 0xff] {
+            return Ok(());
+        }
+
+        let check_guard = matches!(wr_protect, 1 | 4 | 5);
+        let check_reference_tag = matches!(wr_protect, 1 | 2 | 5);
+        let code = if check_guard && protection[0..2] != GUARD_CRC.checksum(block).to_be_bytes() {
+            AdditionalSenseCode::LogicalBlockGuardCheckFailed
+        } else if check_reference_tag && protection[4..8] != lba.to_be_bytes() {
+            AdditionalSenseCode::LogicalBlockReferenceTagCheckFailed
+        } else {
+            return Ok(());
+        };
+
+        error!("scsi: protection information check failed at lba {}", lba);
+        self.set_sense(SenseKey::AbortedCommand, code);
+        self.set_sense_lba(lba);
+        Err(CommandError::Failed)
+    }
+
+    /// Checks that the `transfer_length` blocks starting at `lba` are within the capacity last
+    /// reported to the host
+    fn check_lba_range(&mut self, lba: u32, transfer_length: u32) -> Result<(), CommandError> {
+        match lba.checked_add(transfer_length) {
+            Some(end) if end <= self.block_count => Ok(()),
+            _ => {
+                error!("scsi: lba {} + {} out of range", lba, transfer_length);
+                self.set_sense(
+                    SenseKey::IllegalRequest,
+                    AdditionalSenseCode::LogicalBlockAddressOutOfRange,
+                );
+                Err(CommandError::Failed)
+            }
+        }
+    }
 }
 
 impl<BD> BulkHandler<'_, BD> {
     fn set_sense(&mut self, key: SenseKey, code: AdditionalSenseCode) {
+        self.write_sense(key, code);
+        info!("sense: set to {}, {}", key, code);
+    }
+
+    /// Sets the sense data to say nothing's wrong
+    fn reset_sense(&mut self) {
+        self.write_sense(
+            SenseKey::NoSense,
+            AdditionalSenseCode::NoAdditionalSenseInformation,
+        );
+    }
+
+    fn write_sense(&mut self, key: SenseKey, code: AdditionalSenseCode) {
         self.request_sense_response.set_sense_key(key);
         self.request_sense_response.set_additional_sense_code(code);
+        self.request_sense_response.set_valid(false);
+        self.request_sense_response.set_information(0);
+        self.request_sense_response
+            .set_field_replaceable_unit_code(0);
+    }
 
-        info!("sense: set to {}, {}", key, code);
+    /// Report `lba` in the INFORMATION field of the current sense data
+    fn set_sense_lba(&mut self, lba: u32) {
+        self.request_sense_response.set_valid(true);
+        self.request_sense_response.set_information(lba);
     }
 
     fn set_sense_from_error(&mut self, e: Error) {
-        self.set_sense(
-            SenseKey::IllegalRequest,
-            match e {
-                Error::UnhandledOpCode => AdditionalSenseCode::InvalidCommandOperationCode,
-                Error::InsufficientDataForCommand => AdditionalSenseCode::InvalidPacketSize,
-                Error::BlockDeviceError(_) => AdditionalSenseCode::WriteError,
-            },
-        );
+        match e {
+            Error::UnhandledOpCode => self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::InvalidCommandOperationCode,
+            ),
+            Error::InsufficientDataForCommand => self.set_sense(
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::InvalidPacketSize,
+            ),
+            Error::BlockDeviceError(e) => self.set_sense_from_blockdev_error(e, None),
+        }
     }
 
-    fn set_sense_from_blockdev_error(&mut self, e: BlockDeviceError) {
-        match e {
+    /// Sets the sense data for a block device error. `lba` is the address being accessed
+    /// when the error occurred, if known.
+    fn set_sense_from_blockdev_error(&mut self, e: BlockDeviceError, lba: Option<u32>) {
+        let (key, code) = match e {
             BlockDeviceError::WriteError => {
-                self.set_sense(
-                    SenseKey::HardwareError, // or SenseKey::MediumError
-                    AdditionalSenseCode::WriteError,
-                );
+                (SenseKey::MediumError, AdditionalSenseCode::WriteError)
             }
-            BlockDeviceError::InvalidAddress => {
-                self.set_sense(
-                    SenseKey::IllegalRequest,
-                    AdditionalSenseCode::LogicalBlockAddressOutOfRange,
-                );
+            BlockDeviceError::ReadError => (
+                SenseKey::MediumError,
+                AdditionalSenseCode::UnrecoveredReadError,
+            ),
+            BlockDeviceError::InvalidAddress => (
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::LogicalBlockAddressOutOfRange,
+            ),
+            BlockDeviceError::NotReady => (
+                SenseKey::NotReady,
+                AdditionalSenseCode::LogicalUnitNotReadyCauseNotReportable,
+            ),
+            BlockDeviceError::BecomingReady => (
+                SenseKey::NotReady,
+                AdditionalSenseCode::LogicalUnitIsInProcessOfBecomingReady,
+            ),
+            BlockDeviceError::MediumNotPresent => {
+                (SenseKey::NotReady, AdditionalSenseCode::MediumNotPresent)
+            }
+            BlockDeviceError::WriteProtected => {
+                (SenseKey::DataProtect, AdditionalSenseCode::WriteProtected)
+            }
+            BlockDeviceError::SpaceAllocationFailed => (
+                SenseKey::DataProtect,
+                AdditionalSenseCode::SpaceAllocationFailedWriteProtect,
+            ),
+            BlockDeviceError::HardwareFailure { .. } => (
+                SenseKey::HardwareError,
+                AdditionalSenseCode::InternalTargetFailure,
+            ),
+            BlockDeviceError::Timeout => (
+                SenseKey::AbortedCommand,
+                AdditionalSenseCode::LogicalUnitCommunicationTimeOut,
+            ),
+            BlockDeviceError::AccessNotAuthorized => (
+                SenseKey::NotReady,
+                AdditionalSenseCode::LogicalUnitAccessNotAuthorized,
+            ),
+            BlockDeviceError::Unsupported => (
+                SenseKey::IllegalRequest,
+                AdditionalSenseCode::InvalidCommandOperationCode,
+            ),
+            BlockDeviceError::RecoveredWithRetries => (
+                SenseKey::RecoveredError,
+                AdditionalSenseCode::RecoveredDataWithRetries,
+            ),
+            BlockDeviceError::SoftThresholdReached => (
+                SenseKey::UnitAttention,
+                AdditionalSenseCode::ThinProvisioningSoftThresholdReached,
+            ),
+            BlockDeviceError::Degraded => (
+                SenseKey::UnitAttention,
+                AdditionalSenseCode::FailurePredictionThresholdExceeded,
+            ),
+        };
+        self.set_sense(key, code);
+
+        if let BlockDeviceError::HardwareFailure { fru } = e {
+            self.request_sense_response
+                .set_field_replaceable_unit_code(fru);
+        }
+
+        // Only errors tied to the medium contents identify a failing block
+        if let (
+            BlockDeviceError::WriteError
+            | BlockDeviceError::ReadError
+            | BlockDeviceError::HardwareFailure { .. }
+            | BlockDeviceError::RecoveredWithRetries,
+            Some(lba),
+        ) = (e, lba)
+        {
+            self.set_sense_lba(lba);
+        }
+    }
+
+    /// Turns the result of a block device operation on `lba` into a command result, setting
+    /// the sense data on error. Recovered errors are recorded but don't fail the command.
+    fn check_blockdev_result(
+        &mut self,
+        result: Result<(), BlockDeviceError>,
+        lba: u32,
+    ) -> Result<(), CommandError> {
+        match result {
+            Ok(()) => Ok(()),
+            Err(BlockDeviceError::SoftThresholdReached) => {
+                warn!("block device soft threshold reached at lba {}", lba);
+                self.unit_attention =
+                    Some(AdditionalSenseCode::ThinProvisioningSoftThresholdReached);
+                Ok(())
+            }
+            Err(BlockDeviceError::Degraded) => {
+                warn!("block device degraded at lba {}", lba);
+                self.unit_attention = Some(AdditionalSenseCode::FailurePredictionThresholdExceeded);
+                Ok(())
+            }
+            Err(e) if e.is_recovered() => {
+                warn!("block device recovered error at lba {}: {}", lba, e);
+                self.set_sense_from_blockdev_error(e, Some(lba));
+                Ok(())
+            }
+            Err(e) => {
+                error!("block device error at lba {}: {}", lba, e);
+                self.set_sense_from_blockdev_error(e, Some(lba));
+                Err(CommandError::Failed)
+            }
+        }
+    }
+
+    /// Clears the sense data of the last command, which is only kept for a REQUEST SENSE straight
+    /// after it, so a recovered error isn't reported against every command that follows
+    fn clear_stale_sense(&mut self, command: &Command) {
+        if !matches!(command, Command::RequestSense(_)) {
+            self.reset_sense();
+        }
+    }
+
+    /// Reports a pending unit attention by failing `command`. INQUIRY and REPORT LUNS
+    /// aren't affected and REQUEST SENSE returns the unit attention instead of failing
+    fn check_unit_attention(&mut self, command: &Command) -> Result<(), CommandError> {
+        let Some(code) = self.unit_attention else {
+            return Ok(());
+        };
+
+        match command {
+            Command::Inquiry(_) | Command::ReportLuns(_) => Ok(()),
+            Command::RequestSense(_) => {
+                self.unit_attention = None;
+                self.set_sense(SenseKey::UnitAttention, code);
+                Ok(())
+            }
+            _ => {
+                self.unit_attention = None;
+                self.set_sense(SenseKey::UnitAttention, code);
+                Err(CommandError::Failed)
             }
         }
     }
@@ -358,4 +1480,11 @@ impl<BD> BulkHandler<'_, BD> {
             AdditionalSenseCode::InvalidCommandOperationCode,
         );
     }
+
+    fn set_sense_invalid_parameter_list(&mut self) {
+        self.set_sense(
+            SenseKey::IllegalRequest,
+            AdditionalSenseCode::InvalidFieldInParameterList,
+        );
+    }
 }
@@ -0,0 +1,214 @@
+//! I/O statistics for each LUN, gathered by the SCSI layer as it handles commands: commands
+//! by op code, failures by sense key, blocks transferred, throughput and latency histograms.
+//!
+//! Other tasks (e.g. the display and the network server) read them with [`snapshot`], the
+//! host with LOG SENSE.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+
+/// LUNs with statistics, commands to other LUNs aren't counted
+pub const LUNS: usize = crate::MAX_LUN as usize + 1;
+pub const LATENCY_BUCKETS: usize = 16;
+/// The upper bound of the first latency bucket, each bucket after it covers twice the range of
+/// the one before and the last one has no upper bound
+pub const FIRST_BUCKET_MICROS: u64 = 64;
+
+/// Throughput is measured over windows of this length
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum CommandClass {
+    Read,
+    Write,
+    /// Every other command
+    Other,
+}
+
+/// Command latencies, from when the command is received to when its status is about to be
+/// sent
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct Histogram {
+    pub buckets: [u32; LATENCY_BUCKETS],
+    pub total_micros: u64,
+    pub max_micros: u32,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [0; LATENCY_BUCKETS],
+            total_micros: 0,
+            max_micros: 0,
+        }
+    }
+
+    /// The latency below which commands land in `bucket`, `None` for the last one
+    pub fn bucket_limit_micros(bucket: usize) -> Option<u64> {
+        (bucket < LATENCY_BUCKETS - 1).then(|| FIRST_BUCKET_MICROS << bucket)
+    }
+
+    pub fn count(&self) -> u32 {
+        self.buckets.iter().sum()
+    }
+
+    pub fn mean_micros(&self) -> u64 {
+        self.total_micros / (self.count().max(1) as u64)
+    }
+
+    fn record(&mut self, micros: u64) {
+        let bucket = (u64::BITS - (micros / FIRST_BUCKET_MICROS).leading_zeros()) as usize;
+        let bucket = &mut self.buckets[bucket.min(LATENCY_BUCKETS - 1)];
+        *bucket = bucket.saturating_add(1);
+        self.total_micros = self.total_micros.saturating_add(micros);
+        self.max_micros = self.max_micros.max(micros.min(u32::MAX as u64) as u32);
+    }
+}
+
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct Statistics {
+    /// Commands received, by op code
+    pub commands: [u32; 256],
+    /// Commands that failed, by the sense key they reported
+    pub errors: [u32; 16],
+    /// Commands abandoned because of a USB error
+    pub transport_errors: u32,
+    /// Commands that failed or were abandoned, by [`CommandClass`]
+    pub failures: [u32; 3],
+    pub blocks_read: u64,
+    pub blocks_written: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Latencies by [`CommandClass`]
+    pub latency: [Histogram; 3],
+    /// Throughput over the last complete window
+    bytes_per_second: u32,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl Statistics {
+    const fn new() -> Self {
+        Self {
+            commands: [0; 256],
+            errors: [0; 16],
+            transport_errors: 0,
+            failures: [0; 3],
+            blocks_read: 0,
+            blocks_written: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            latency: [Histogram::new(); 3],
+            bytes_per_second: 0,
+            window_start: Instant::from_ticks(0),
+            window_bytes: 0,
+        }
+    }
+
+    pub fn latency(&self, class: CommandClass) -> &Histogram {
+        &self.latency[class as usize]
+    }
+
+    pub fn failures(&self, class: CommandClass) -> u32 {
+        self.failures[class as usize]
+    }
+
+    pub fn total_commands(&self) -> u32 {
+        self.commands.iter().sum()
+    }
+
+    pub fn total_errors(&self) -> u32 {
+        self.errors.iter().sum()
+    }
+
+    /// Bytes read and written per second, measured over the last second. Zero once the host
+    /// has stopped transferring
+    pub fn bytes_per_second(&self) -> u32 {
+        if Instant::now() - self.window_start > THROUGHPUT_WINDOW * 2 {
+            0
+        } else {
+            self.bytes_per_second
+        }
+    }
+
+    fn record_bytes(&mut self, now: Instant, bytes: u64) {
+        let elapsed = now - self.window_start;
+        if elapsed >= THROUGHPUT_WINDOW {
+            // a window without transfers in it ends with the next transfer
+            self.bytes_per_second = if elapsed < THROUGHPUT_WINDOW * 2 {
+                (self.window_bytes * 1000 / elapsed.as_millis()).min(u32::MAX as u64) as u32
+            } else {
+                0
+            };
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+        self.window_bytes += bytes;
+    }
+}
+
+/// What a command did, as recorded by the SCSI layer
+pub(crate) struct CommandRecord {
+    pub op_code: u8,
+    pub class: CommandClass,
+    /// Blocks transferred, 0 if the command failed
+    pub blocks: u32,
+    pub bytes: u64,
+    /// The sense key of a failed command, `None` if it succeeded or the USB transfer failed
+    pub sense_key: Option<u8>,
+    pub transport_error: bool,
+    pub started: Instant,
+}
+
+static STATISTICS: [Mutex<ThreadModeRawMutex, RefCell<Statistics>>; LUNS] =
+    [const { Mutex::new(RefCell::new(Statistics::new())) }; LUNS];
+
+/// A copy of the statistics of `lun`, `None` if it isn't one of the [`LUNS`]
+pub fn snapshot(lun: u8) -> Option<Statistics> {
+    let statistics = STATISTICS.get(lun as usize)?;
+    Some(statistics.lock(|statistics| *statistics.borrow()))
+}
+
+pub(crate) fn record(lun: u8, record: CommandRecord) {
+    let Some(statistics) = STATISTICS.get(lun as usize) else {
+        return;
+    };
+    let now = Instant::now();
+    statistics.lock(|statistics| {
+        let mut statistics = statistics.borrow_mut();
+        let statistics = &mut *statistics;
+
+        let count = &mut statistics.commands[record.op_code as usize];
+        *count = count.saturating_add(1);
+        if let Some(sense_key) = record.sense_key {
+            let count = &mut statistics.errors[sense_key as usize & 0x0f];
+            *count = count.saturating_add(1);
+        }
+        if record.transport_error {
+            statistics.transport_errors = statistics.transport_errors.saturating_add(1);
+        }
+        if record.sense_key.is_some() || record.transport_error {
+            let count = &mut statistics.failures[record.class as usize];
+            *count = count.saturating_add(1);
+        }
+
+        match record.class {
+            CommandClass::Read => {
+                statistics.blocks_read += record.blocks as u64;
+                statistics.bytes_read += record.bytes;
+            }
+            CommandClass::Write => {
+                statistics.blocks_written += record.blocks as u64;
+                statistics.bytes_written += record.bytes;
+            }
+            CommandClass::Other => {}
+        }
+        if record.bytes > 0 {
+            statistics.record_bytes(now, record.bytes);
+        }
+
+        statistics.latency[record.class as usize].record((now - record.started).as_micros());
+    });
+}
@@ -0,0 +1,198 @@
+//! Traces the reads and writes of the host, to see which blocks it actually touches when
+//! tuning FTL and cache policies, e.g. how often it rewrites the FAT compared to the data
+//! clusters.
+//!
+//! Every access is counted in a heatmap of the disk, which splits it into [`HEATMAP_REGIONS`]
+//! regions of the same size, and the last [`TRACE_RECORDS`] are kept in a ring buffer. Both
+//! are exported as one binary dump by [`export`], over the network by the statistics server
+//! (`GET /trace`, cleared with `DELETE /trace`) and as a read-only file on the drive (see
+//! [`TraceFileBlockDevice`](crate::block_devices::trace::TraceFileBlockDevice)).
+//!
+//! The dump is [`EXPORT_BYTES`] long, with every value little endian:
+//!
+//! | offset | length | |
+//! |---|---|---|
+//! | 0 | 4 | `LBAT` |
+//! | 4 | 4 | blocks on the disk |
+//! | 8 | 2 | regions in the heatmap |
+//! | 10 | 2 | records in the dump |
+//! | 12 | 4 | accesses traced since the trace was cleared |
+//! | 16 | 8 per region | accesses touching the region, reads then writes, as two u32 |
+//! | | 16 per record | records, oldest first, padded with zeros to [`TRACE_RECORDS`] |
+//!
+//! A record is the timestamp of the access in microseconds since boot (u64), its first block
+//! (u32), its length in blocks (u16, saturating), the operation (`R` or `W`) and a zero byte.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::Instant;
+
+pub const HEATMAP_REGIONS: usize = 64;
+/// Older records are overwritten
+pub const TRACE_RECORDS: usize = 128;
+
+const MAGIC: &[u8; 4] = b"LBAT";
+const HEADER_BYTES: usize = 16;
+const REGION_BYTES: usize = 8;
+const RECORD_BYTES: usize = 16;
+pub const EXPORT_BYTES: usize =
+    HEADER_BYTES + HEATMAP_REGIONS * REGION_BYTES + TRACE_RECORDS * RECORD_BYTES;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+#[repr(u8)]
+pub enum TraceOp {
+    Read = b'R',
+    Write = b'W',
+}
+
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct TraceRecord {
+    pub op: TraceOp,
+    pub lba: u32,
+    pub blocks: u32,
+    pub timestamp: Instant,
+}
+
+impl TraceRecord {
+    fn to_bytes(self) -> [u8; RECORD_BYTES] {
+        let mut bytes = [0u8; RECORD_BYTES];
+        bytes[0..8].copy_from_slice(&self.timestamp.as_micros().to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.lba.to_le_bytes());
+        let blocks = self.blocks.min(u16::MAX as u32) as u16;
+        bytes[12..14].copy_from_slice(&blocks.to_le_bytes());
+        bytes[14] = self.op as u8;
+        bytes
+    }
+}
+
+struct Tracer {
+    /// The size of the disk the heatmap covers
+    block_count: u32,
+    reads: [u32; HEATMAP_REGIONS],
+    writes: [u32; HEATMAP_REGIONS],
+    records: [Option<TraceRecord>; TRACE_RECORDS],
+    /// Where the next record goes, the oldest one once the buffer is full
+    next: usize,
+    total: u32,
+}
+
+impl Tracer {
+    const fn new() -> Self {
+        Self {
+            block_count: 0,
+            reads: [0; HEATMAP_REGIONS],
+            writes: [0; HEATMAP_REGIONS],
+            records: [None; TRACE_RECORDS],
+            next: 0,
+            total: 0,
+        }
+    }
+
+    fn region(&self, lba: u32) -> usize {
+        let region = lba as u64 * HEATMAP_REGIONS as u64 / self.block_count.max(1) as u64;
+        (region as usize).min(HEATMAP_REGIONS - 1)
+    }
+
+    fn record(&mut self, record: TraceRecord, block_count: u32) {
+        // the regions move with the size of the disk, e.g. when another medium is inserted
+        if block_count != self.block_count {
+            self.block_count = block_count;
+            self.reads = [0; HEATMAP_REGIONS];
+            self.writes = [0; HEATMAP_REGIONS];
+        }
+
+        let first = self.region(record.lba);
+        let last = self.region(record.lba.saturating_add(record.blocks.max(1) - 1));
+        let heatmap = match record.op {
+            TraceOp::Read => &mut self.reads,
+            TraceOp::Write => &mut self.writes,
+        };
+        for count in &mut heatmap[first..=last] {
+            *count = count.saturating_add(1);
+        }
+
+        self.records[self.next] = Some(record);
+        self.next = (self.next + 1) % TRACE_RECORDS;
+        self.total = self.total.wrapping_add(1);
+    }
+
+    /// The records, oldest first
+    fn records(&self) -> impl Iterator<Item = &TraceRecord> {
+        let (newer, older) = self.records.split_at(self.next);
+        older.iter().chain(newer).flatten()
+    }
+}
+
+/// Copies the part of a dump that lands in `buf`, which holds the bytes from `offset`
+struct Export<'a> {
+    buf: &'a mut [u8],
+    offset: usize,
+    /// The position in the dump of the next bytes
+    position: usize,
+    len: usize,
+}
+
+impl Export<'_> {
+    fn put(&mut self, bytes: &[u8]) {
+        let start = self.position.max(self.offset);
+        let end = (self.position + bytes.len()).min(self.offset + self.buf.len());
+        if start < end {
+            self.buf[start - self.offset..end - self.offset]
+                .copy_from_slice(&bytes[start - self.position..end - self.position]);
+            self.len = self.len.max(end - self.offset);
+        }
+        self.position += bytes.len();
+    }
+}
+
+static TRACER: Mutex<ThreadModeRawMutex, RefCell<Tracer>> = Mutex::new(RefCell::new(Tracer::new()));
+
+/// Traces an access to `blocks` blocks from `lba` on a disk of `block_count` blocks
+pub fn record(op: TraceOp, lba: u32, blocks: u32, block_count: u32) {
+    let record = TraceRecord {
+        op,
+        lba,
+        blocks,
+        timestamp: Instant::now(),
+    };
+    TRACER.lock(|tracer| tracer.borrow_mut().record(record, block_count));
+}
+
+/// Empties the heatmap and the ring buffer
+pub fn clear() {
+    TRACER.lock(|tracer| *tracer.borrow_mut() = Tracer::new());
+}
+
+/// Fills `buf` with the dump from byte `offset`, returning the number of bytes written, 0
+/// past its end. Each call takes the trace as it is then, so a dump read in parts may have
+/// accesses in a part that came after the one before it
+pub fn export(offset: usize, buf: &mut [u8]) -> usize {
+    TRACER.lock(|tracer| {
+        let tracer = tracer.borrow();
+        let mut export = Export {
+            buf,
+            offset,
+            position: 0,
+            len: 0,
+        };
+
+        let records = tracer.records().count();
+        export.put(MAGIC);
+        export.put(&tracer.block_count.to_le_bytes());
+        export.put(&(HEATMAP_REGIONS as u16).to_le_bytes());
+        export.put(&(records as u16).to_le_bytes());
+        export.put(&tracer.total.to_le_bytes());
+        for (reads, writes) in tracer.reads.iter().zip(&tracer.writes) {
+            export.put(&reads.to_le_bytes());
+            export.put(&writes.to_le_bytes());
+        }
+        for record in tracer.records() {
+            export.put(&record.to_bytes());
+        }
+        for _ in records..TRACE_RECORDS {
+            export.put(&[0; RECORD_BYTES]);
+        }
+        export.len
+    })
+}
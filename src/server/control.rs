@@ -0,0 +1,99 @@
+use defmt::warn;
+use embassy_net::tcp::TcpSocket;
+use embedded_io_async::Write as _;
+use zeroize::Zeroize as _;
+
+use super::SocketServer;
+
+/// The reply to a line that was carried out
+pub const OK: &[u8] = b"OK\r\n";
+/// The reply to a line that couldn't be
+pub const ERR: &[u8] = b"ERR\r\n";
+
+/// Controls the disk, one command per line, each answered with `OK` or `ERR`. A line goes to
+/// the first of the enabled features that takes it:
+///
+/// - `unlock` and `lock` for the encrypted disk, see [`super::unlock`]
+/// - `commit` and `discard` for the overlay disk, see [`super::overlay`]
+/// - `take`, `list`, `delete` and `rollback` for snapshots, see [`super::snapshot`]
+/// - `format` to format the disk, see [`super::mkfs`]
+/// - the lines of a fault script, `clear` and `reload` for fault injection, see
+///   [`super::fault`]
+///
+/// Lines none of them take are answered with `ERR`. As a line may hold a key, it's wiped
+/// from the buffer once it's been handled.
+pub struct Server {}
+
+impl Server {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl SocketServer for Server {
+    async fn run(&mut self, mut socket: TcpSocket<'_>) {
+        let mut buf = [0; 128];
+        let mut len = 0;
+        'connection: loop {
+            let n = match socket.read(&mut buf[len..]).await {
+                Ok(0) => {
+                    warn!("read EOF");
+                    break;
+                }
+                Ok(n) => n,
+                Err(e) => {
+                    warn!("read error: {:?}", e);
+                    break;
+                }
+            };
+            len += n;
+
+            while let Some(end) = buf[..len].iter().position(|&byte| byte == b'\n') {
+                let reply = match core::str::from_utf8(&buf[..end]) {
+                    Ok(line) => handle(line.trim(), &mut socket).await,
+                    Err(_) => ERR,
+                };
+                if let Err(e) = socket.write_all(reply).await {
+                    warn!("write error: {:?}", e);
+                    break 'connection;
+                }
+
+                buf.copy_within(end + 1..len, 0);
+                buf[len - end - 1..len].zeroize();
+                len -= end + 1;
+            }
+
+            if len == buf.len() {
+                warn!("line too long");
+                break;
+            }
+        }
+        buf.zeroize();
+    }
+}
+
+/// Passes `line` to each enabled feature in turn, until one takes it
+async fn handle(line: &str, socket: &mut TcpSocket<'_>) -> &'static [u8] {
+    #[cfg(feature = "encrypted")]
+    if let Some(reply) = super::unlock::handle(line, socket).await {
+        return reply;
+    }
+    #[cfg(disk = "overlay")]
+    if let Some(reply) = super::overlay::handle(line, socket).await {
+        return reply;
+    }
+    #[cfg(feature = "snapshots")]
+    if let Some(reply) = super::snapshot::handle(line, socket).await {
+        return reply;
+    }
+    #[cfg(feature = "mkfs")]
+    if let Some(reply) = super::mkfs::handle(line, socket).await {
+        return reply;
+    }
+    // last, as it takes blank lines and comments
+    #[cfg(feature = "faults")]
+    if let Some(reply) = super::fault::handle(line, socket).await {
+        return reply;
+    }
+    ERR
+}
@@ -0,0 +1,31 @@
+//! Scripts the fault injecting disk, through the [control server](super::control). Takes one
+//! line of a script at a time, see `block_devices::fault`, along with:
+//!
+//! ```text
+//! clear
+//! reload
+//! ```
+//!
+//! to remove every rule or load them from the script on the drive again. The rules are applied
+//! before the next access or media poll.
+
+use defmt::info;
+use embassy_net::tcp::TcpSocket;
+
+use super::control::{ERR, OK};
+use crate::block_devices::fault::{parse_line, ScriptError, COMMANDS};
+
+/// Carries out `line` if it's a line of a script or one of the commands above, including
+/// blank lines and comments
+pub async fn handle(line: &str, _socket: &mut TcpSocket<'_>) -> Option<&'static [u8]> {
+    match parse_line(line) {
+        Ok(Some(command)) => {
+            info!("fault command: {}", command);
+            COMMANDS.send(command).await;
+            Some(OK)
+        }
+        Ok(None) => Some(OK),
+        Err(ScriptError::UnknownCommand) => None,
+        Err(_) => Some(ERR),
+    }
+}
@@ -0,0 +1,25 @@
+//! Formats the disk, through the [control server](super::control):
+//!
+//! ```text
+//! format
+//! ```
+//!
+//! The disk is formatted from the next media poll on, between the host's commands, see
+//! [`crate::block_devices::mkfs`], and the host is told the medium changed once it's done.
+//! `OK` only means the command was taken, not that the format worked.
+
+use defmt::info;
+use embassy_net::tcp::TcpSocket;
+
+use super::control::OK;
+use crate::block_devices::mkfs::REQUEST;
+
+/// Carries out `line` if it's the command above
+pub async fn handle(line: &str, _socket: &mut TcpSocket<'_>) -> Option<&'static [u8]> {
+    if line != "format" {
+        return None;
+    }
+    info!("mkfs command: format");
+    REQUEST.signal(());
+    Some(OK)
+}
@@ -1,7 +1,27 @@
 use embassy_net::tcp::TcpSocket;
 
+#[cfg(any(
+    feature = "encrypted",
+    feature = "faults",
+    feature = "mkfs",
+    feature = "snapshots",
+    disk = "overlay"
+))]
+pub mod control;
 //pub mod echo;
-pub mod okay;
+#[cfg(feature = "faults")]
+pub mod fault;
+#[cfg(feature = "mkfs")]
+pub mod mkfs;
+#[cfg(feature = "snapshots")]
+pub mod snapshot;
+//pub mod okay;
+#[cfg(disk = "overlay")]
+pub mod overlay;
+#[cfg(feature = "statistics")]
+pub mod statistics;
+#[cfg(feature = "encrypted")]
+pub mod unlock;
 
 pub trait SocketServer {
     async fn run(&mut self, socket: TcpSocket)
@@ -0,0 +1,27 @@
+//! Commits or discards the changes the host made to the overlay disk, through the
+//! [control server](super::control):
+//!
+//! ```text
+//! commit
+//! discard
+//! ```
+//!
+//! The command is carried out on the next media poll.
+
+use defmt::info;
+use embassy_net::tcp::TcpSocket;
+
+use super::control::OK;
+use crate::block_devices::overlay::{OverlayCommand, COMMANDS};
+
+/// Carries out `line` if it's one of the commands above
+pub async fn handle(line: &str, _socket: &mut TcpSocket<'_>) -> Option<&'static [u8]> {
+    let command = match line {
+        "commit" => OverlayCommand::Commit,
+        "discard" => OverlayCommand::Discard,
+        _ => return None,
+    };
+    info!("overlay command: {}", command);
+    COMMANDS.signal(command);
+    Some(OK)
+}
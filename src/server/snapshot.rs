@@ -0,0 +1,107 @@
+//! Manages the snapshots of the drive, through the [control server](super::control):
+//!
+//! ```text
+//! take
+//! list
+//! delete <id>
+//! rollback <id>
+//! rollback
+//! ```
+//!
+//! `rollback` without an id rolls back to the oldest snapshot. `list` answers with a line per
+//! snapshot, its id, age in seconds and the blocks it holds, followed by `OK`. The others are
+//! applied on the next media poll.
+
+use core::fmt::Write as _;
+
+use defmt::{info, warn};
+use embassy_net::tcp::TcpSocket;
+use embassy_time::Instant;
+use embedded_io_async::Write as _;
+
+use super::control::{ERR, OK};
+use crate::block_devices::snapshot::{list, SnapshotCommand, COMMANDS};
+
+enum Request {
+    List,
+    Command(SnapshotCommand),
+}
+
+/// The first words of the commands above
+const WORDS: [&str; 4] = ["take", "list", "delete", "rollback"];
+
+/// `None` if `line` isn't a well formed command
+fn parse_line(line: &str) -> Option<Request> {
+    let mut words = line.split_whitespace();
+    let request = match (words.next()?, words.next()) {
+        ("take", None) => Request::Command(SnapshotCommand::Take),
+        ("list", None) => Request::List,
+        ("delete", Some(id)) => Request::Command(SnapshotCommand::Delete(id.parse().ok()?)),
+        ("rollback", Some(id)) => Request::Command(SnapshotCommand::Rollback(id.parse().ok()?)),
+        ("rollback", None) => Request::Command(SnapshotCommand::RollbackOldest),
+        _ => return None,
+    };
+    words.next().is_none().then_some(request)
+}
+
+/// A line of the snapshot list
+struct Line {
+    buf: [u8; 48],
+    len: usize,
+}
+
+impl core::fmt::Write for Line {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let bytes = s.as_bytes();
+        let end = self.len + bytes.len();
+        if end > self.buf.len() {
+            return Err(core::fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+}
+
+async fn send_list(socket: &mut TcpSocket<'_>) -> Result<(), embassy_net::tcp::Error> {
+    let now = Instant::now();
+    for snapshot in list().iter().flatten() {
+        let mut line = Line {
+            buf: [0; 48],
+            len: 0,
+        };
+        // a u32 and two u64s always fit
+        let _ = write!(
+            line,
+            "{} {} {}\r\n",
+            snapshot.id,
+            (now - snapshot.taken).as_secs(),
+            snapshot.blocks
+        );
+        socket.write_all(&line.buf[..line.len]).await?;
+    }
+    Ok(())
+}
+
+/// Carries out `line` if it's one of the commands above
+pub async fn handle(line: &str, socket: &mut TcpSocket<'_>) -> Option<&'static [u8]> {
+    let word = line.split_whitespace().next()?;
+    if !WORDS.contains(&word) {
+        return None;
+    }
+    Some(match parse_line(line) {
+        Some(Request::List) => match send_list(socket).await {
+            Ok(()) => OK,
+            Err(e) => {
+                warn!("write error: {:?}", e);
+                ERR
+            }
+        },
+        Some(Request::Command(command)) => {
+            info!("snapshot command: {}", command);
+            COMMANDS.send(command).await;
+            OK
+        }
+        None => ERR,
+    })
+}
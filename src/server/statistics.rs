@@ -0,0 +1,230 @@
+use core::fmt::Write as _;
+
+use defmt::warn;
+use embassy_net::tcp::{self, TcpSocket};
+use embedded_io_async::Write as _;
+
+use super::SocketServer;
+use crate::scsi::statistics::{self, CommandClass, Histogram, LATENCY_BUCKETS, LUNS};
+#[cfg(feature = "trace")]
+use crate::scsi::trace;
+
+const TRACE_REQUEST: &[u8] = b"GET /trace ";
+const CLEAR_TRACE_REQUEST: &[u8] = b"DELETE /trace ";
+
+/// Answers any HTTP request with the I/O statistics of every LUN as plain text, so they can be
+/// read with a browser or `curl`. With the `trace` feature, `GET /trace` returns the access
+/// trace instead, in the binary format described in [`crate::scsi::trace`], and `DELETE
+/// /trace` clears it.
+pub struct Server {}
+
+enum Request {
+    Statistics,
+    Trace,
+    ClearTrace,
+}
+
+impl Request {
+    fn parse(request: &[u8]) -> Self {
+        if request.starts_with(TRACE_REQUEST) {
+            Self::Trace
+        } else if request.starts_with(CLEAR_TRACE_REQUEST) {
+            Self::ClearTrace
+        } else {
+            Self::Statistics
+        }
+    }
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl SocketServer for Server {
+    async fn run(&mut self, mut socket: TcpSocket<'_>) {
+        // only the request line matters, and where the request ends
+        let mut buf = [0; 512];
+        let mut len = 0;
+        let mut request = None;
+        loop {
+            match socket.read(&mut buf[len..]).await {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) => {
+                    warn!("read error: {:?}", e);
+                    return;
+                }
+            }
+            if request.is_none() && buf[..len].windows(2).any(|end| end == b"\r\n") {
+                request = Some(Request::parse(&buf[..len]));
+            }
+            if buf[..len].windows(4).any(|end| end == b"\r\n\r\n") {
+                break;
+            }
+            // keeps the end of a long request
+            if len == buf.len() {
+                buf.copy_within(len - 3.., 0);
+                len = 3;
+            }
+        }
+
+        let result = match request {
+            #[cfg(feature = "trace")]
+            Some(Request::Trace) => write_trace(&mut socket).await,
+            #[cfg(feature = "trace")]
+            Some(Request::ClearTrace) => {
+                trace::clear();
+                send(&mut socket, format_args!("HTTP/1.1 204 No Content\r\n\r\n")).await
+            }
+            _ => write_statistics(&mut socket).await,
+        };
+        if let Err(e) = result {
+            warn!("write error: {:?}", e);
+        }
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
+
+async fn write_statistics(socket: &mut TcpSocket<'_>) -> Result<(), tcp::Error> {
+    send(
+        socket,
+        format_args!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n"),
+    )
+    .await?;
+
+    for lun in 0..LUNS as u8 {
+        let Some(statistics) = statistics::snapshot(lun) else {
+            continue;
+        };
+        send(socket, format_args!("lun {}\r\n", lun)).await?;
+        send(
+            socket,
+            format_args!(
+                "commands {} failed {} transport errors {}\r\n",
+                statistics.total_commands(),
+                statistics.total_errors(),
+                statistics.transport_errors
+            ),
+        )
+        .await?;
+        send(
+            socket,
+            format_args!(
+                "read {} blocks {} bytes, written {} blocks {} bytes, {} bytes/s\r\n",
+                statistics.blocks_read,
+                statistics.bytes_read,
+                statistics.blocks_written,
+                statistics.bytes_written,
+                statistics.bytes_per_second()
+            ),
+        )
+        .await?;
+
+        for (op_code, &count) in statistics.commands.iter().enumerate() {
+            if count > 0 {
+                send(
+                    socket,
+                    format_args!("op code {:02x}: {}\r\n", op_code, count),
+                )
+                .await?;
+            }
+        }
+        for (sense_key, &count) in statistics.errors.iter().enumerate() {
+            if count > 0 {
+                send(
+                    socket,
+                    format_args!("sense key {:x}: {}\r\n", sense_key, count),
+                )
+                .await?;
+            }
+        }
+
+        for class in [CommandClass::Read, CommandClass::Write, CommandClass::Other] {
+            let latency = statistics.latency(class);
+            send(
+                socket,
+                format_args!(
+                    "{:?} latency: {} commands, {} failed, mean {} us, max {} us\r\n",
+                    class,
+                    latency.count(),
+                    statistics.failures(class),
+                    latency.mean_micros(),
+                    latency.max_micros
+                ),
+            )
+            .await?;
+            for bucket in 0..LATENCY_BUCKETS {
+                let count = latency.buckets[bucket];
+                if count == 0 {
+                    continue;
+                }
+                match Histogram::bucket_limit_micros(bucket) {
+                    Some(limit) => {
+                        send(socket, format_args!("  < {} us: {}\r\n", limit, count)).await?
+                    }
+                    None => send(socket, format_args!("  longer: {}\r\n", count)).await?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "trace")]
+async fn write_trace(socket: &mut TcpSocket<'_>) -> Result<(), tcp::Error> {
+    send(
+        socket,
+        format_args!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n",
+            trace::EXPORT_BYTES
+        ),
+    )
+    .await?;
+
+    let mut buf = [0; 512];
+    let mut offset = 0;
+    loop {
+        let len = trace::export(offset, &mut buf);
+        if len == 0 {
+            return Ok(());
+        }
+        socket.write_all(&buf[..len]).await?;
+        offset += len;
+    }
+}
+
+/// Formats one line into a buffer and sends it
+async fn send(
+    socket: &mut TcpSocket<'_>,
+    args: core::fmt::Arguments<'_>,
+) -> Result<(), tcp::Error> {
+    let mut line = Line {
+        buf: [0; 128],
+        len: 0,
+    };
+    // a line that doesn't fit is cut short
+    let _ = line.write_fmt(args);
+    socket.write_all(&line.buf[..line.len]).await
+}
+
+struct Line {
+    buf: [u8; 128],
+    len: usize,
+}
+
+impl core::fmt::Write for Line {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let bytes = s.as_bytes();
+        let n = bytes.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+        if n < bytes.len() {
+            Err(core::fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
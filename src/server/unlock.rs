@@ -0,0 +1,71 @@
+//! Locks and unlocks the encrypted disk, through the [control server](super::control):
+//!
+//! ```text
+//! unlock <key as 64 hex digits>
+//! lock
+//! ```
+//!
+//! The command is carried out on the next media poll, and answered once it has been: `ERR`
+//! for a wrong key, or if the host doesn't poll within [`REPLY_TIMEOUT`].
+
+use defmt::{info, warn};
+use embassy_net::tcp::TcpSocket;
+use embassy_time::{with_timeout, Duration};
+use zeroize::Zeroize;
+
+use super::control::{ERR, OK};
+use crate::block_devices::encrypted::{EncryptionCommand, Key, COMMANDS, KEY_BYTES, RESULTS};
+
+/// How long a command waits for the media poll that carries it out, the host polls every
+/// second or two while the drive is mounted
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Carries out `line` if it's one of the commands above
+pub async fn handle(line: &str, _socket: &mut TcpSocket<'_>) -> Option<&'static [u8]> {
+    match line.split_once(' ') {
+        None if line == "lock" => {
+            info!("lock requested");
+            Some(carry_out(EncryptionCommand::Lock).await)
+        }
+        Some(("unlock", hex)) => match parse_key(hex.trim().as_bytes()) {
+            Some(mut key) => {
+                info!("unlock requested");
+                let command = EncryptionCommand::Unlock(key);
+                key.zeroize();
+                Some(carry_out(command).await)
+            }
+            None => Some(ERR),
+        },
+        _ => None,
+    }
+}
+
+/// Passes `command` to the encrypted device and waits for its outcome
+async fn carry_out(command: EncryptionCommand) -> &'static [u8] {
+    RESULTS.reset();
+    COMMANDS.signal(command);
+    match with_timeout(REPLY_TIMEOUT, RESULTS.wait()).await {
+        Ok(Ok(())) => OK,
+        Ok(Err(_)) => ERR,
+        Err(_) => {
+            warn!("no media poll to carry out the command");
+            // so it isn't carried out after all, once the host is back
+            if let Some(EncryptionCommand::Unlock(mut key)) = COMMANDS.try_take() {
+                key.zeroize();
+            }
+            ERR
+        }
+    }
+}
+
+fn parse_key(hex: &[u8]) -> Option<Key> {
+    if hex.len() != KEY_BYTES * 2 {
+        return None;
+    }
+    let mut key: Key = [0; KEY_BYTES];
+    for (byte, digits) in key.iter_mut().zip(hex.chunks_exact(2)) {
+        let digits = core::str::from_utf8(digits).ok()?;
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+    Some(key)
+}
@@ -1,5 +1,12 @@
+/// Flash size and the region reserved for the flash disk, generated by build.rs
+#[allow(dead_code)]
+pub mod flash_layout {
+    include!(concat!(env!("OUT_DIR"), "/disk_region.rs"));
+}
+
 pub const BLOCK_SIZE: usize = 512;
-pub const BLOCKS: u32 = 200;
+// `BLOCKS`, the size of the RAM disk, generated by build.rs from the image it starts with
+include!(concat!(env!("OUT_DIR"), "/disk_image.rs"));
 
 #[derive(Copy, Clone)]
 pub struct Block([u8; BLOCK_SIZE]);
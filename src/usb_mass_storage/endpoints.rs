@@ -14,6 +14,7 @@ pub struct Endpoints<'d, D: Driver<'d>, M: RawMutex> {
     in_ep: D::EndpointIn,
     out_ep: D::EndpointOut,
     reset_signal: &'d Signal<M, ()>,
+    suspend_signal: &'d Signal<M, ()>,
 }
 
 impl<'d, D: Driver<'d>, M: RawMutex> Endpoints<'d, D, M> {
@@ -21,14 +22,21 @@ impl<'d, D: Driver<'d>, M: RawMutex> Endpoints<'d, D, M> {
         in_ep: D::EndpointIn,
         out_ep: D::EndpointOut,
         reset_signal: &'d Signal<M, ()>,
+        suspend_signal: &'d Signal<M, ()>,
     ) -> Self {
         assert_eq!(in_ep.info().max_packet_size, out_ep.info().max_packet_size);
         Self {
             in_ep,
             out_ep,
             reset_signal,
+            suspend_signal,
         }
     }
+
+    /// Signalled when the bus is suspended
+    pub fn suspend_signal(&self) -> &'d Signal<M, ()> {
+        self.suspend_signal
+    }
 }
 
 impl From<EndpointError> for TransportError {
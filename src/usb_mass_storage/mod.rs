@@ -72,11 +72,13 @@ impl<'d, 'bd, D: Driver<'d>, BD: BlockDevice, M: RawMutex> UsbMassStorage<'d, 'b
             alt.endpoint_bulk_in(packet_size),
             alt.endpoint_bulk_out(packet_size),
             &state.reset_signal,
+            &state.suspend_signal,
         );
         drop(func);
 
         let control = state.control.write(Control {
             reset_signal: &state.reset_signal,
+            suspend_signal: &state.suspend_signal,
             max_lun,
         });
         builder.handler(control);
@@ -100,6 +102,7 @@ impl<'d, 'bd, D: Driver<'d>, BD: BlockDevice, M: RawMutex> UsbMassStorage<'d, 'b
 
 pub struct State<'d, M: RawMutex> {
     reset_signal: Signal<M, ()>,
+    suspend_signal: Signal<M, ()>,
     control: MaybeUninit<Control<'d, M>>,
 }
 
@@ -107,6 +110,7 @@ impl<'d, M: RawMutex> Default for State<'d, M> {
     fn default() -> Self {
         Self {
             reset_signal: Signal::new(),
+            suspend_signal: Signal::new(),
             control: MaybeUninit::uninit(),
         }
     }
@@ -114,10 +118,18 @@ impl<'d, M: RawMutex> Default for State<'d, M> {
 
 pub struct Control<'d, M: RawMutex> {
     reset_signal: &'d Signal<M, ()>,
+    suspend_signal: &'d Signal<M, ()>,
     max_lun: u8,
 }
 
 impl<'d, M: RawMutex> embassy_usb::Handler for Control<'d, M> {
+    fn suspended(&mut self, suspended: bool) {
+        if suspended {
+            info!("usb: bbb: suspended");
+            self.suspend_signal.signal(());
+        }
+    }
+
     fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
         // not interested in this request
         if !(req.request_type == RequestType::Class && req.recipient == Recipient::Interface) {
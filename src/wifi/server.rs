@@ -1,5 +1,5 @@
+use crate::display::{DisplayState, SIGNAL};
 use crate::server::SocketServer;
-use crate::{DisplayState, SIGNAL};
 
 use cyw43::Control;
 use cyw43_pio::PioSpi;